@@ -0,0 +1,153 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+//! Example of connecting to a peer through a rendezvous server.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(bad_style, exceeding_bitshifts, mutable_transmutes, no_mangle_const_items,
+          unknown_crate_types, warnings)]
+#![deny(deprecated, drop_with_repr_extern, improper_ctypes, missing_docs,
+        non_shorthand_field_patterns, overflowing_literals, plugin_as_library,
+        private_no_mangle_fns, private_no_mangle_statics, stable_features, unconditional_recursion,
+        unknown_lints, unsafe_code, unused, unused_allocation, unused_attributes,
+        unused_comparisons, unused_features, unused_parens, while_true)]
+#![warn(trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+        unused_qualifications, unused_results)]
+#![allow(box_pointers, fat_ptr_transmutes, missing_copy_implementations,
+         missing_debug_implementations, variant_size_differences)]
+
+#![cfg_attr(feature="clippy", feature(plugin))]
+#![cfg_attr(feature="clippy", plugin(clippy))]
+#![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
+extern crate nat_traversal;
+extern crate socket_addr;
+extern crate w_result;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::{Instant, Duration};
+
+use nat_traversal::{connect, ConnectedSocket, MappingContext, RelayServerInfo, RendezvousClient};
+use w_result::{WOk, WErr};
+
+fn main() {
+    println!("This example connects to a peer through a rendezvous server.");
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 3 && args.len() != 5 {
+        println!("Usage: rendezvous-connect <server address> <our id> <peer id> \
+                  [<relay server address> <relay server secret>]");
+        return;
+    }
+    let server_addr = match args[0].parse::<SocketAddr>() {
+        Ok(server_addr) => server_addr,
+        Err(e) => {
+            println!("Error parsing the server address: {}", e);
+            return;
+        }
+    };
+    let (our_id, peer_id) = (&args[1], &args[2]);
+
+    let mapping_context = match MappingContext::new() {
+        WOk(mapping_context, warnings) => {
+            for warning in warnings {
+                println!("Warning when creating mapping context: {}", warning);
+            }
+            mapping_context
+        }
+        WErr(e) => {
+            println!("Error creating mapping context: {}", e);
+            println!("Exiting.");
+            return;
+        }
+    };
+
+    // A relay server to fall back on if we can't punch a hole to the peer.
+    if let Some(relay_addr) = args.get(3) {
+        match relay_addr.parse::<SocketAddr>() {
+            Ok(relay_addr) => {
+                mapping_context.add_relay_servers(Some(RelayServerInfo {
+                    addr: socket_addr::SocketAddr(relay_addr),
+                    secret: args[4].clone(),
+                }))
+            },
+            Err(e) => {
+                println!("Error parsing the relay server address: {}", e);
+                return;
+            }
+        }
+    }
+
+    let mut client = match RendezvousClient::register(&server_addr, our_id) {
+        Ok(client) => client,
+        Err(e) => {
+            println!("Error registering with the rendezvous server: {}", e);
+            println!("Exiting.");
+            return;
+        }
+    };
+    println!("Registered as {}. Connecting to {}.", our_id, peer_id);
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let connection = match connect(&mapping_context, &mut client, peer_id, deadline) {
+        WOk(connection, warnings) => {
+            for warning in warnings {
+                println!("Warning when connecting: {}", warning);
+            }
+            connection
+        }
+        WErr(e) => {
+            println!("Error connecting: {}", e);
+            println!("Exiting.");
+            return;
+        }
+    };
+
+    let greeting = format!("Hello from {}!", our_id);
+    let mut buf = [0; 1024];
+    match connection.socket {
+        ConnectedSocket::Udp(punched_socket) => {
+            println!("Connected over udp to {}", punched_socket.peer_addr.0);
+            let peer_addr = punched_socket.peer_addr.0;
+            let _ = punched_socket.socket.send_to(greeting.as_bytes(), peer_addr);
+            if let Ok((n, _)) = punched_socket.socket.recv_from(&mut buf) {
+                println!("Received: {}", String::from_utf8_lossy(&buf[..n]));
+            }
+        },
+        ConnectedSocket::Relayed(relayed_socket) => {
+            println!("Connected through the relay to {}", relayed_socket.peer_addr.0);
+            let _ = relayed_socket.send(greeting.as_bytes());
+            if let Ok(n) = relayed_socket.recv(&mut buf) {
+                println!("Received: {}", String::from_utf8_lossy(&buf[..n]));
+            }
+        },
+        ConnectedSocket::Turn(relayed_socket) => {
+            println!("Connected through the TURN server to {}", relayed_socket.peer_addr.0);
+            let _ = relayed_socket.send(greeting.as_bytes());
+            if let Ok(n) = relayed_socket.recv(&mut buf) {
+                println!("Received: {}", String::from_utf8_lossy(&buf[..n]));
+            }
+        },
+        ConnectedSocket::Tcp(mut punched_stream) => {
+            println!("Connected over tcp");
+            let _ = punched_stream.stream.write_all(greeting.as_bytes());
+            if let Ok(n) = punched_stream.stream.read(&mut buf) {
+                println!("Received: {}", String::from_utf8_lossy(&buf[..n]));
+            }
+        },
+    }
+}
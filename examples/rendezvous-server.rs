@@ -0,0 +1,70 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+//! Rendezvous server example.
+
+// For explanation of lint checks, run `rustc -W help` or see
+// https://github.com/maidsafe/QA/blob/master/Documentation/Rust%20Lint%20Checks.md
+#![forbid(bad_style, exceeding_bitshifts, mutable_transmutes, no_mangle_const_items,
+          unknown_crate_types, warnings)]
+#![deny(deprecated, drop_with_repr_extern, improper_ctypes, missing_docs,
+        non_shorthand_field_patterns, overflowing_literals, plugin_as_library,
+        private_no_mangle_fns, private_no_mangle_statics, stable_features, unconditional_recursion,
+        unknown_lints, unsafe_code, unused, unused_allocation, unused_attributes,
+        unused_comparisons, unused_features, unused_parens, while_true)]
+#![warn(trivial_casts, trivial_numeric_casts, unused_extern_crates, unused_import_braces,
+        unused_qualifications, unused_results)]
+#![allow(box_pointers, fat_ptr_transmutes, missing_copy_implementations,
+         missing_debug_implementations, variant_size_differences)]
+
+#![cfg_attr(feature="clippy", feature(plugin))]
+#![cfg_attr(feature="clippy", plugin(clippy))]
+#![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
+extern crate nat_traversal;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use nat_traversal::RendezvousServer;
+
+fn main() {
+    println!("This example runs a rendezvous server which introduces peers to each other.");
+
+    let bind_addr = std::env::args().nth(1).unwrap_or_else(|| String::from("0.0.0.0:5484"));
+    let bind_addr = match bind_addr.parse::<SocketAddr>() {
+        Ok(bind_addr) => bind_addr,
+        Err(e) => {
+            println!("Error parsing the address to listen on: {}", e);
+            println!("Usage: rendezvous-server [<address to listen on>]");
+            return;
+        }
+    };
+
+    // Tell peers to wait a moment after receiving each other's info so they start punching at
+    // about the same time.
+    let server = match RendezvousServer::new(&bind_addr, Duration::from_millis(500)) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Error creating rendezvous server: {}", e);
+            println!("Exiting.");
+            return;
+        }
+    };
+
+    println!("Listening on {}", server.local_addr());
+
+    std::thread::park();
+}
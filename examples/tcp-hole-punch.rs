@@ -40,7 +40,6 @@
 extern crate maidsafe_utilities;
 extern crate nat_traversal;
 extern crate w_result;
-extern crate rustc_serialize;
 extern crate socket_addr;
 
 use std::net::ToSocketAddrs;
@@ -48,7 +47,8 @@ use std::io::{Read, Write};
 use std::time::{Instant, Duration};
 
 use socket_addr::SocketAddr;
-use nat_traversal::{MappingContext, gen_rendezvous_info, MappedTcpSocket, tcp_punch_hole};
+use nat_traversal::{MappingContext, gen_rendezvous_info, MappedTcpSocket, PubRendezvousInfo,
+                    tcp_punch_hole};
 use w_result::{WOk, WErr};
 
 fn main() {
@@ -125,8 +125,10 @@ fn main() {
         }
     };
 
-    // A MappedTcpSocket is just a socket and set of known endpoints of the socket;
-    let MappedTcpSocket { socket, endpoints } = mapped_socket;
+    // A MappedTcpSocket is just a socket and set of known endpoints of the socket. It also owns
+    // any port mappings made on our router, which get removed when `_mapping_guard` goes out of
+    // scope.
+    let MappedTcpSocket { socket, endpoints, mapping_guard: _mapping_guard } = mapped_socket;
     println!("Created a socket. It's endpoints are: {:#?}", endpoints);
 
     // Now we use the endpoints to create a rendezvous info pair
@@ -137,7 +139,7 @@ fn main() {
     // address translation sucks.
     println!("Your public rendezvous info is:");
     println!("");
-    println!("{}", unwrap_result!(rustc_serialize::json::encode(&our_pub_info)));
+    println!("{}", our_pub_info);
     println!("");
 
     let their_pub_info;
@@ -159,14 +161,14 @@ fn main() {
                 return;
             },
         };
-        match rustc_serialize::json::decode(&info_str) {
+        match info_str.parse::<PubRendezvousInfo>() {
             Ok(info) => {
                 their_pub_info = info;
                 break;
             },
             Err(e) => {
                 println!("Error decoding their public rendezvous info: {}", e);
-                println!("Push sure to paste their complete info all in one line.");
+                println!("Make sure to paste their complete info all in one line.");
             }
         }
     };
@@ -40,14 +40,14 @@
 extern crate maidsafe_utilities;
 extern crate nat_traversal;
 extern crate w_result;
-extern crate rustc_serialize;
 extern crate socket_addr;
 
 use std::net::ToSocketAddrs;
 use std::time::{Instant, Duration};
 
 use socket_addr::SocketAddr;
-use nat_traversal::{MappingContext, gen_rendezvous_info, MappedUdpSocket, PunchedUdpSocket};
+use nat_traversal::{MappingContext, gen_rendezvous_info_with_port_prediction, MappedUdpSocket,
+                    PubRendezvousInfo, PunchedUdpSocket};
 use w_result::{WOk, WErr};
 
 fn main() {
@@ -126,19 +126,23 @@ fn main() {
         }
     };
 
-    // A MappedUdpSocket is just a socket and set of known endpoints of the socket;
-    let MappedUdpSocket { socket, endpoints } = mapped_socket;
+    // A MappedUdpSocket is just a socket, a set of known endpoints of the socket and, if we're
+    // behind a symmetric NAT, a guess at how the NAT allocates ports. It also owns any port
+    // mappings made on our router, which get removed when `_mapping_guard` goes out of scope.
+    let MappedUdpSocket { socket, endpoints, port_prediction, mapping_guard: _mapping_guard }
+        = mapped_socket;
     println!("Created a socket. It's endpoints are: {:#?}", endpoints);
 
     // Now we use the endpoints to create a rendezvous info pair
-    let (our_priv_info, our_pub_info) = gen_rendezvous_info(endpoints);
+    let (our_priv_info, our_pub_info) = gen_rendezvous_info_with_port_prediction(endpoints,
+                                                                                 port_prediction);
 
     // Now we exchange our public rendezvous info with the remote peer out-of-band somehow. Yes, to
     // connect to the peer you already need to be able to communicate with them. Yes, network
     // address translation sucks.
     println!("Your public rendezvous info is:");
     println!("");
-    println!("{}", unwrap_result!(rustc_serialize::json::encode(&our_pub_info)));
+    println!("{}", our_pub_info);
     println!("");
 
     let their_pub_info;
@@ -160,14 +164,14 @@ fn main() {
                 return;
             },
         };
-        match rustc_serialize::json::decode(&info_str) {
+        match info_str.parse::<PubRendezvousInfo>() {
             Ok(info) => {
                 their_pub_info = info;
                 break;
             },
             Err(e) => {
                 println!("Error decoding their public rendezvous info: {}", e);
-                println!("Push sure to paste their complete info all in one line.");
+                println!("Make sure to paste their complete info all in one line.");
             }
         }
     };
@@ -192,8 +196,13 @@ fn main() {
     };
 
     // A PunchedUdpSocket is just a socket and an address that we should have unrestricted
-    // communication to.
-    let PunchedUdpSocket { socket, peer_addr } = punched_socket;
+    // communication to. It also tells us which pair of endpoints the hole was punched through.
+    let PunchedUdpSocket { socket, peer_addr, candidate_pair } = punched_socket;
+    println!("Punched a hole between {} and {} ({:?} to {:?}).",
+             *candidate_pair.local.addr,
+             *candidate_pair.remote.addr,
+             candidate_pair.local.candidate_type,
+             candidate_pair.remote.candidate_type);
 
     let recv_socket = match socket.try_clone() {
         Ok(recv_socket) => recv_socket,
@@ -0,0 +1,280 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Hole punching between two peers that are both behind NATs with endpoint-dependent mapping.
+//!
+//! Neither peer can know which external port the other will be given so instead one peer opens
+//! many sockets (and hence many external ports) while the other sprays packets at random ports on
+//! the first peer's IP address. By the birthday paradox, a few hundred sockets and a few hundred
+//! probes give a good chance that a probe hits one of the open ports.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{self, IpAddr, UdpSocket};
+use std::thread;
+use std::time::{Instant, Duration};
+
+use maidsafe_utilities::serialisation::deserialise;
+use rand::{self, Rng};
+use socket_addr::SocketAddr;
+use w_result::{WResult, WOk, WErr};
+
+use check_list::{CandidatePair, CandidatePairState};
+use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+use port_mapping::PortMappingGuard;
+use punched_udp_socket::{self, HolePunch, PunchedUdpSocket, UdpPunchHoleError,
+                         UdpPunchHoleWarning};
+use rendezvous_info::{self, PrivRendezvousInfo, PubRendezvousInfo};
+use secret::{self, Nonce};
+
+/// How long to sleep between checking the sockets for incoming packets.
+const POLL_INTERVAL_MS: u64 = 5;
+/// How often we resend hole punch messages to addresses the peer has punched through from until
+/// they're acked.
+const TRIGGERED_RESEND_MS: u64 = 100;
+/// Only probe ports in the range that NATs allocate from.
+const MIN_PROBE_PORT: u16 = 1024;
+
+/// Limits on the resources used by `PunchedUdpSocket::punch_hole_birthday`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BirthdayPunchConfig {
+    /// The number of local sockets to open on the side which opens many sockets.
+    pub num_sockets: usize,
+    /// The maximum number of packets to send per second, across all sockets.
+    pub packets_per_sec: u32,
+}
+
+impl Default for BirthdayPunchConfig {
+    fn default() -> BirthdayPunchConfig {
+        BirthdayPunchConfig {
+            num_sockets: 256,
+            packets_per_sec: 100,
+        }
+    }
+}
+
+impl PunchedUdpSocket {
+    /// Punch a hole to a peer when both of us are behind NATs with endpoint-dependent mapping
+    /// (symmetric NATs). Both peers must call this at the same time.
+    ///
+    /// The peer whose secret compares lower opens `config.num_sockets` sockets while the other
+    /// probes random ports on the first peer's IP addresses from a single socket. The first socket
+    /// to complete the handshake is returned and all the others are closed. Unlike `punch_hole`
+    /// this creates its own sockets since the mapping of any existing socket is useless against
+    /// this kind of NAT.
+    pub fn punch_hole_birthday(our_priv_rendezvous_info: PrivRendezvousInfo,
+                               mut their_pub_rendezvous_info: PubRendezvousInfo,
+                               config: BirthdayPunchConfig,
+                               deadline: Instant)
+        -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        let mut warnings =
+            punched_udp_socket::apply_endpoint_policy(&mut their_pub_rendezvous_info);
+
+        let (endpoints, their_secret) = rendezvous_info::decompose(their_pub_rendezvous_info);
+        let our_secret = rendezvous_info::get_priv_secret(our_priv_rendezvous_info);
+
+        let mut their_ips: Vec<IpAddr> = Vec::new();
+        for endpoint in endpoints {
+            let ip = endpoint.addr.ip();
+            if !their_ips.contains(&ip) {
+                their_ips.push(ip);
+            }
+        }
+        if their_ips.is_empty() {
+            return WErr(UdpPunchHoleError::NoEndpoints);
+        }
+
+        let num_sockets = if our_secret < their_secret {
+            cmp::max(config.num_sockets, 1)
+        }
+        else {
+            1
+        };
+        let mut sockets = Vec::with_capacity(num_sockets);
+        for _ in 0..num_sockets {
+            let res = UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+                try!(socket.set_nonblocking(true));
+                Ok(socket)
+            });
+            match res {
+                Ok(socket) => sockets.push(socket),
+                // We may hit the file descriptor limit. Make do with what we've got.
+                Err(e) => {
+                    if sockets.is_empty() {
+                        return WErr(UdpPunchHoleError::Io { err: e });
+                    }
+                    warnings.push(UdpPunchHoleWarning::CreateSocket { err: e });
+                    break;
+                },
+            }
+        }
+
+        let our_nonce = secret::gen_nonce();
+        let send_data = punched_udp_socket::hole_punch_data(&our_secret, &our_nonce);
+        let send_interval = Duration::from_millis(1000 / cmp::max(config.packets_per_sec, 1) as u64);
+        let mut rng = rand::thread_rng();
+        let mut recv_data = [0u8; punched_udp_socket::MAX_DATAGRAM_SIZE];
+        let mut next_send = Instant::now();
+        let mut sent: usize = 0;
+        // A socket and peer address only make a working pair once the peer has acked a hole punch
+        // message which that socket sent to that address, and the peer has punched through to the
+        // socket from there too. These are keyed by socket index and peer address.
+        let mut probed: HashSet<(usize, net::SocketAddr)> = HashSet::new();
+        let mut acked_by_them: HashSet<(usize, net::SocketAddr)> = HashSet::new();
+        let mut punched_by_them: HashMap<(usize, net::SocketAddr), Nonce> = HashMap::new();
+        // The peer's hole punch messages arrive from addresses we haven't necessarily probed. We
+        // send hole punch messages back to those addresses until they're acked.
+        let mut triggered: Vec<(usize, net::SocketAddr)> = Vec::new();
+        let mut next_resend = Instant::now();
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return WErr(UdpPunchHoleError::TimedOut);
+            }
+
+            // Send probes to random ports, cycling through our sockets.
+            while next_send <= now {
+                let ip = their_ips[rng.gen_range(0, their_ips.len())];
+                // The upper bound is exclusive, so use a u32 range to include port 65535.
+                let port = rng.gen_range(MIN_PROBE_PORT as u32, 0x10000) as u16;
+                let addr = SocketAddr(net::SocketAddr::new(ip, port));
+                let i = sent % sockets.len();
+                let _ = probed.insert((i, *addr));
+                match sockets[i].send_to(&send_data[..], &*addr) {
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => {
+                        // Protect against flooding the caller with warnings.
+                        if warnings.len() < 10 {
+                            warnings.push(UdpPunchHoleWarning::MsgEndpoint {
+                                endpoint: MappedSocketAddr::new(
+                                    CandidateType::Predicted,
+                                    addr,
+                                    addr,
+                                    true
+                                ),
+                                err: e,
+                            });
+                        }
+                    },
+                };
+                sent = sent.wrapping_add(1);
+                next_send = next_send + send_interval;
+            }
+            if now >= next_resend {
+                for &(i, addr) in &triggered {
+                    let _ = sockets[i].send_to(&send_data[..], addr);
+                }
+                next_resend = now + Duration::from_millis(TRIGGERED_RESEND_MS);
+            }
+
+            // Check every socket for a packet from the peer.
+            let mut punched = None;
+            'sockets: for (i, socket) in sockets.iter().enumerate() {
+                loop {
+                    let (read_size, addr) = match socket.recv_from(&mut recv_data[..]) {
+                        Ok(x) => x,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        // See the comment in `RecvUntil` about ICMP port unreachable on Windows.
+                        Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                        Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
+                    };
+                    // Random ports on the internet may well send us junk. Ignore it.
+                    let hp = match deserialise::<HolePunch>(&recv_data[..read_size]) {
+                        Ok(hp) => hp,
+                        Err(_) => continue,
+                    };
+                    let key = (i, addr);
+                    if hp.is_ack_from(&their_secret, &our_nonce) {
+                        // Acks only count if they come from an address this socket probed.
+                        if !probed.contains(&key) {
+                            continue;
+                        }
+                        let _ = acked_by_them.insert(key);
+                        triggered.retain(|&t| t != key);
+                    } else if hp.is_hole_punch_from(&their_secret) {
+                        let ack = punched_udp_socket::ack_data(&our_secret, &our_nonce, &hp.nonce);
+                        let _ = socket.send_to(&ack[..], addr);
+                        let _ = punched_by_them.insert(key, hp.nonce);
+                        // This only shows that the peer can reach us. Check that we can reach it
+                        // too.
+                        if !acked_by_them.contains(&key) && !triggered.contains(&key) {
+                            let _ = probed.insert(key);
+                            let _ = socket.send_to(&send_data[..], addr);
+                            triggered.push(key);
+                        }
+                    } else {
+                        continue;
+                    }
+                    if acked_by_them.contains(&key) {
+                        if let Some(&their_nonce) = punched_by_them.get(&key) {
+                            punched = Some((i, SocketAddr(addr), their_nonce));
+                            break 'sockets;
+                        }
+                    }
+                }
+            }
+
+            if let Some((i, peer_addr, their_nonce)) = punched {
+                // Dropping the other sockets closes them.
+                let socket = sockets.swap_remove(i);
+                drop(sockets);
+                if let Err(e) = socket.set_nonblocking(false) {
+                    return WErr(UdpPunchHoleError::Io { err: e });
+                }
+                if let Err(e) = punched_udp_socket::send_acks(&socket, &peer_addr, &our_secret,
+                                                              &our_nonce, &their_nonce, deadline) {
+                    return WErr(e);
+                }
+                let local_addr = match socket.local_addr() {
+                    Ok(local_addr) => SocketAddr(local_addr),
+                    Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
+                };
+                // The probing peer found us at one of the ports it guessed. The peer with many
+                // sockets only learns the prober's address when a packet arrives from it.
+                let remote_type = if num_sockets > 1 {
+                    CandidateType::PeerReflexive
+                }
+                else {
+                    CandidateType::Predicted
+                };
+                let mut candidate_pair = CandidatePair::new(
+                    MappedSocketAddr::new(CandidateType::Host, local_addr, local_addr, false),
+                    MappedSocketAddr::new(remote_type, peer_addr, peer_addr, true),
+                    our_secret > their_secret
+                );
+                candidate_pair.state = CandidatePairState::Succeeded;
+                return WOk(PunchedUdpSocket {
+                    socket: socket,
+                    peer_addr: peer_addr,
+                    candidate_pair: candidate_pair,
+                    mapping_guard: PortMappingGuard::new(Vec::new()),
+                }, warnings);
+            }
+
+            let now = Instant::now();
+            let poll_deadline = cmp::min(now + Duration::from_millis(POLL_INTERVAL_MS),
+                                         cmp::min(cmp::min(next_send, next_resend), deadline));
+            if poll_deadline > now {
+                thread::sleep(poll_deadline - now);
+            }
+        }
+    }
+}
@@ -0,0 +1,509 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! An ICE-style (RFC 8445) check list used to schedule hole punching checks.
+
+use std::cmp;
+use std::net;
+use std::time::{Duration, Instant};
+
+use socket_addr::SocketAddr;
+
+use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+
+/// How long to wait between sending checks for different pairs. This is the `Ta` value
+/// recommended by RFC 8445.
+const CHECK_PACING_MS: u64 = 50;
+/// How long to wait for a response before sending another check for a pair.
+const RETRANSMIT_INTERVAL_MS: u64 = 600;
+/// How many checks to send on a pair before giving up on it if none get answered. This is the `Rc`
+/// value recommended by RFC 5389.
+const MAX_CHECKS: u32 = 7;
+/// How long to keep checking higher priority pairs once a lower priority pair has succeeded.
+const NOMINATION_WAIT_MS: u64 = 300;
+
+/// The state of the connectivity checks for a `CandidatePair`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidatePairState {
+    /// No check has been sent for this pair yet.
+    Waiting,
+    /// Checks have been sent for this pair but it hasn't been shown to work both ways yet.
+    InProgress,
+    /// One of our checks has been answered over this pair and the peer's checks have got through
+    /// it too.
+    Succeeded,
+    /// A check could not be sent for this pair, or none of the checks sent were answered.
+    Failed,
+}
+
+/// One of our endpoints paired with one of the peer's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidatePair {
+    /// Our endpoint.
+    pub local: MappedSocketAddr,
+    /// The peer's endpoint.
+    pub remote: MappedSocketAddr,
+    /// The RFC 8445 priority of this pair. Both peers compute the same priority for a pair.
+    pub priority: u64,
+    /// How far the connectivity checks for this pair have got.
+    pub state: CandidatePairState,
+}
+
+impl CandidatePair {
+    /// Pair two endpoints. The peer which is controlling is decided when hole punching starts.
+    pub fn new(local: MappedSocketAddr, remote: MappedSocketAddr, controlling: bool)
+               -> CandidatePair {
+        let priority = if controlling {
+            pair_priority(local.priority, remote.priority)
+        } else {
+            pair_priority(remote.priority, local.priority)
+        };
+        CandidatePair {
+            local: local,
+            remote: remote,
+            priority: priority,
+            state: CandidatePairState::Waiting,
+        }
+    }
+}
+
+/// Compute the priority of a candidate pair as described in section 6.1.2.3 of RFC 8445.
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let g = controlling as u64;
+    let d = controlled as u64;
+    (cmp::min(g, d) << 32) + 2 * cmp::max(g, d) + if g > d { 1 } else { 0 }
+}
+
+struct Check {
+    pair: CandidatePair,
+    retransmit_at: Instant,
+    // How many checks we've sent on this pair since it was last triggered.
+    num_sent: u32,
+    // Whether we've sent a check on this pair and whether one has been answered.
+    sent: bool,
+    answered: bool,
+    // Whether the peer has sent us a check over this pair.
+    received: bool,
+    // Whether a check should be sent on this pair ahead of the others.
+    triggered: bool,
+}
+
+/// Decides which pair to send a check on next and which pair to nominate once checks start
+/// succeeding. Pairs are checked in priority order, one every `CHECK_PACING_MS`, and checks are
+/// resent every `RETRANSMIT_INTERVAL_MS` until the pair succeeds or a higher priority pair wins.
+/// A pair fails if none of `MAX_CHECKS` checks get answered. A check from the peer triggers a check
+/// on its pair ahead of the others, giving it a fresh set of checks even if it had failed.
+pub struct CheckList {
+    // Sorted highest priority first.
+    checks: Vec<Check>,
+    local: Vec<MappedSocketAddr>,
+    default_local: MappedSocketAddr,
+    controlling: bool,
+    next_check_at: Instant,
+    first_success_at: Option<Instant>,
+}
+
+impl CheckList {
+    /// Pair our endpoints with theirs. `socket_addr` is the local address of the socket doing the
+    /// punching and is used for any of their endpoints that none of ours have a matching address
+    /// family for.
+    pub fn new(local: Vec<MappedSocketAddr>,
+               remote: Vec<MappedSocketAddr>,
+               socket_addr: SocketAddr,
+               controlling: bool)
+               -> CheckList {
+        let now = Instant::now();
+        let mut check_list = CheckList {
+            checks: Vec::new(),
+            local: local,
+            default_local: MappedSocketAddr::new(CandidateType::Host,
+                                                 socket_addr,
+                                                 socket_addr,
+                                                 false),
+            controlling: controlling,
+            next_check_at: now,
+            first_success_at: None,
+        };
+        for remote in remote {
+            check_list.add_pair(remote, CandidatePairState::Waiting, now);
+        }
+        check_list
+    }
+
+    /// The pair at index `i`.
+    pub fn pair(&self, i: usize) -> &CandidatePair {
+        &self.checks[i].pair
+    }
+
+    /// Returns the index of the pair that a check should be sent on now, if any, and records that
+    /// the check has been sent.
+    pub fn next_check(&mut self, now: Instant) -> Option<usize> {
+        self.fail_unanswered(now);
+        if now < self.next_check_at {
+            return None;
+        }
+        let checkable = self.num_checkable();
+        // Triggered checks go first, then new pairs, then retransmissions.
+        let mut next = self.checks[..checkable].iter().position(|check| check.triggered);
+        if next.is_none() {
+            next = self.checks[..checkable]
+                       .iter()
+                       .position(|check| check.pair.state == CandidatePairState::Waiting);
+        }
+        if next.is_none() {
+            let mut earliest: Option<(usize, Instant)> = None;
+            for (i, check) in self.checks[..checkable].iter().enumerate() {
+                if check.pair.state != CandidatePairState::InProgress || check.retransmit_at > now {
+                    continue;
+                }
+                if earliest.map_or(true, |(_, at)| check.retransmit_at < at) {
+                    earliest = Some((i, check.retransmit_at));
+                }
+            }
+            next = earliest.map(|(i, _)| i);
+        }
+        if let Some(i) = next {
+            let check = &mut self.checks[i];
+            check.pair.state = CandidatePairState::InProgress;
+            check.sent = true;
+            check.num_sent += 1;
+            check.triggered = false;
+            check.retransmit_at = now + Duration::from_millis(RETRANSMIT_INTERVAL_MS);
+            self.next_check_at = now + Duration::from_millis(CHECK_PACING_MS);
+        }
+        next
+    }
+
+    /// Record that a check could not be sent on the pair at index `i`.
+    pub fn check_failed(&mut self, i: usize) {
+        self.checks[i].pair.state = CandidatePairState::Failed;
+    }
+
+    /// Record that the peer has sent us a check from `addr`. If `addr` isn't one of the peer's
+    /// endpoints it gets added as a peer reflexive endpoint. This only shows that the peer can
+    /// reach us, so unless one of our checks has already been answered from `addr` it triggers a
+    /// check on the pair. Returns the index of the pair.
+    pub fn check_received(&mut self, addr: SocketAddr, now: Instant) -> usize {
+        let i = match self.checks.iter().position(|check| check.pair.remote.addr == addr) {
+            Some(i) => i,
+            None => {
+                let remote = MappedSocketAddr::new(CandidateType::PeerReflexive, addr, addr, true);
+                self.add_pair(remote, CandidatePairState::Waiting, now)
+            },
+        };
+        self.checks[i].received = true;
+        if self.checks[i].answered {
+            self.succeeded(i, now);
+        } else {
+            if self.checks[i].pair.state == CandidatePairState::Failed {
+                self.checks[i].pair.state = CandidatePairState::Waiting;
+            }
+            self.checks[i].num_sent = 0;
+            self.checks[i].triggered = true;
+        }
+        i
+    }
+
+    /// Record that one of our checks has been answered from `addr`. Answers only count if they
+    /// come from an address we've sent a check to. Returns the index of the pair if they do.
+    pub fn response_received(&mut self, addr: SocketAddr, now: Instant) -> Option<usize> {
+        let i = match self.checks.iter().position(|check| check.pair.remote.addr == addr) {
+            Some(i) if self.checks[i].sent => i,
+            _ => return None,
+        };
+        self.checks[i].answered = true;
+        self.checks[i].triggered = false;
+        if self.checks[i].received {
+            self.succeeded(i, now);
+        }
+        Some(i)
+    }
+
+    /// Returns the index of the highest priority pair that has succeeded once there is no longer
+    /// any point waiting for a higher priority pair to succeed.
+    pub fn nominate(&self, now: Instant) -> Option<usize> {
+        let best = match self.checks
+                             .iter()
+                             .position(|check| check.pair.state == CandidatePairState::Succeeded) {
+            Some(best) => best,
+            None => return None,
+        };
+        let waited = match self.first_success_at {
+            Some(at) => now >= at + Duration::from_millis(NOMINATION_WAIT_MS),
+            None => false,
+        };
+        let higher_pending = self.checks[..best].iter().any(|check| {
+            check.pair.state == CandidatePairState::Waiting ||
+            check.pair.state == CandidatePairState::InProgress
+        });
+        if waited || !higher_pending {
+            Some(best)
+        } else {
+            None
+        }
+    }
+
+    /// When `next_check` or `nominate` may next return something.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        let mut wakeup = self.first_success_at
+                             .map(|at| at + Duration::from_millis(NOMINATION_WAIT_MS));
+        let checkable = self.num_checkable();
+        for check in &self.checks[..checkable] {
+            let at = match check.pair.state {
+                _ if check.triggered => self.next_check_at,
+                CandidatePairState::Waiting => self.next_check_at,
+                CandidatePairState::InProgress => cmp::max(check.retransmit_at, self.next_check_at),
+                CandidatePairState::Succeeded | CandidatePairState::Failed => continue,
+            };
+            wakeup = Some(wakeup.map_or(at, |wakeup| cmp::min(wakeup, at)));
+        }
+        wakeup
+    }
+
+    /// Take the pair at index `i`.
+    pub fn into_pair(mut self, i: usize) -> CandidatePair {
+        self.checks.swap_remove(i).pair
+    }
+
+    // Give up on pairs whose last check has gone unanswered for a whole retransmit interval.
+    fn fail_unanswered(&mut self, now: Instant) {
+        for check in &mut self.checks {
+            if check.pair.state == CandidatePairState::InProgress && !check.answered &&
+               !check.triggered && check.num_sent >= MAX_CHECKS && check.retransmit_at <= now {
+                check.pair.state = CandidatePairState::Failed;
+            }
+        }
+    }
+
+    fn succeeded(&mut self, i: usize, now: Instant) {
+        if self.first_success_at.is_none() {
+            self.first_success_at = Some(now);
+        }
+        self.checks[i].pair.state = CandidatePairState::Succeeded;
+    }
+
+    // Pairs below the highest priority pair that has succeeded aren't worth checking any more.
+    fn num_checkable(&self) -> usize {
+        self.checks
+            .iter()
+            .position(|check| check.pair.state == CandidatePairState::Succeeded)
+            .unwrap_or(self.checks.len())
+    }
+
+    // All of our endpoints share one socket, so checks on pairs with the same remote endpoint
+    // would be identical. Each remote endpoint is only paired with the highest priority local
+    // endpoint of the same address family.
+    fn add_pair(&mut self, remote: MappedSocketAddr, state: CandidatePairState, now: Instant)
+                -> usize {
+        let local = self.local
+                        .iter()
+                        .filter(|local| same_family(&local.addr, &remote.addr))
+                        .max_by_key(|local| local.priority)
+                        .unwrap_or(&self.default_local)
+                        .clone();
+        let mut pair = CandidatePair::new(local, remote, self.controlling);
+        pair.state = state;
+        let existing = self.checks.iter().position(|check| check.pair.remote.addr == pair.remote.addr);
+        if let Some(i) = existing {
+            if self.checks[i].pair.priority >= pair.priority {
+                return i;
+            }
+            let _ = self.checks.remove(i);
+        }
+        let i = self.checks
+                    .iter()
+                    .position(|check| check.pair.priority < pair.priority)
+                    .unwrap_or(self.checks.len());
+        self.checks.insert(i, Check {
+            pair: pair,
+            retransmit_at: now,
+            num_sent: 0,
+            sent: false,
+            answered: false,
+            received: false,
+            triggered: false,
+        });
+        i
+    }
+}
+
+fn same_family(a: &SocketAddr, b: &SocketAddr) -> bool {
+    match (**a, **b) {
+        (net::SocketAddr::V4(..), net::SocketAddr::V4(..)) |
+        (net::SocketAddr::V6(..), net::SocketAddr::V6(..)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+
+    use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+
+    fn socket_addr(s: &str) -> SocketAddr {
+        SocketAddr(unwrap_result!(net::SocketAddr::from_str(s)))
+    }
+
+    fn check_list() -> CheckList {
+        let base = socket_addr("192.168.0.2:1234");
+        let local = vec![
+            MappedSocketAddr::new(CandidateType::Host, base, base, false),
+            MappedSocketAddr::new(CandidateType::ServerReflexive,
+                                  socket_addr("1.2.3.4:1234"), base, true),
+        ];
+        let remote = vec![
+            MappedSocketAddr::new(CandidateType::ServerReflexive,
+                                  socket_addr("5.6.7.8:4321"), socket_addr("10.0.0.2:4321"), true),
+            MappedSocketAddr::new(CandidateType::Host,
+                                  socket_addr("10.0.0.2:4321"), socket_addr("10.0.0.2:4321"), false),
+            MappedSocketAddr::new(CandidateType::Predicted,
+                                  socket_addr("5.6.7.8:4322"), socket_addr("5.6.7.8:4322"), true),
+        ];
+        CheckList::new(local, remote, socket_addr("0.0.0.0:1234"), true)
+    }
+
+    #[test]
+    fn pair_priority_matches_rfc() {
+        let host = 2130706431;
+        let srflx = 1694498815;
+        assert_eq!(pair_priority(host, srflx), (srflx as u64) * (1 << 32) + 2 * (host as u64) + 1);
+        assert_eq!(pair_priority(srflx, host), (srflx as u64) * (1 << 32) + 2 * (host as u64));
+    }
+
+    #[test]
+    fn checks_are_paced_in_priority_order() {
+        let mut check_list = check_list();
+        let start = Instant::now();
+        let now = start;
+        let i = unwrap_option!(check_list.next_check(now), "No check to send");
+        assert_eq!(check_list.pair(i).remote.addr, socket_addr("10.0.0.2:4321"));
+        assert_eq!(check_list.pair(i).local.candidate_type, CandidateType::Host);
+        assert_eq!(check_list.pair(i).state, CandidatePairState::InProgress);
+        assert!(check_list.next_check(now).is_none());
+
+        let now = now + Duration::from_millis(CHECK_PACING_MS);
+        let i = unwrap_option!(check_list.next_check(now), "No check to send");
+        assert_eq!(check_list.pair(i).remote.addr, socket_addr("5.6.7.8:4321"));
+        check_list.check_failed(i);
+        assert_eq!(check_list.pair(i).state, CandidatePairState::Failed);
+
+        let now = now + Duration::from_millis(CHECK_PACING_MS);
+        let i = unwrap_option!(check_list.next_check(now), "No check to send");
+        assert_eq!(check_list.pair(i).remote.addr, socket_addr("5.6.7.8:4322"));
+
+        // Everything has been checked once. The next check is a retransmission to the first pair.
+        let now = now + Duration::from_millis(CHECK_PACING_MS);
+        assert!(check_list.next_check(now).is_none());
+        let first_retransmit = start + Duration::from_millis(RETRANSMIT_INTERVAL_MS);
+        assert_eq!(check_list.next_wakeup(), Some(first_retransmit));
+        let i = unwrap_option!(check_list.next_check(first_retransmit), "No check to send");
+        assert_eq!(check_list.pair(i).remote.addr, socket_addr("10.0.0.2:4321"));
+    }
+
+    #[test]
+    fn nomination_waits_for_higher_priority_pairs() {
+        let mut check_list = check_list();
+        let now = Instant::now();
+        for i in 0..3 {
+            let at = now + Duration::from_millis(CHECK_PACING_MS * i);
+            let _ = unwrap_option!(check_list.next_check(at), "No check to send");
+        }
+
+        // A peer reflexive address comes in below the host pair, which is still in progress.
+        let peer_reflexive = socket_addr("5.6.7.8:5000");
+        let i = check_list.check_received(peer_reflexive, now);
+        assert_eq!(check_list.pair(i).remote.candidate_type, CandidateType::PeerReflexive);
+        let later = now + Duration::from_millis(CHECK_PACING_MS * 20);
+        let i = unwrap_option!(check_list.next_check(later), "No check to send");
+        assert_eq!(check_list.pair(i).remote.addr, peer_reflexive);
+        let _ = unwrap_option!(check_list.response_received(peer_reflexive, now), "Unknown pair");
+        assert!(check_list.nominate(now).is_none());
+        let i = unwrap_option!(check_list.nominate(now + Duration::from_millis(NOMINATION_WAIT_MS)),
+                               "Nothing nominated");
+        assert_eq!(check_list.pair(i).remote.addr, peer_reflexive);
+
+        // The host pair succeeding gets it nominated straight away.
+        let host = socket_addr("10.0.0.2:4321");
+        let _ = check_list.check_received(host, now);
+        let _ = unwrap_option!(check_list.response_received(host, now), "Unknown pair");
+        let i = unwrap_option!(check_list.nominate(now), "Nothing nominated");
+        let pair = check_list.into_pair(i);
+        assert_eq!(pair.remote.addr, socket_addr("10.0.0.2:4321"));
+        assert_eq!(pair.state, CandidatePairState::Succeeded);
+    }
+
+    #[test]
+    fn unsolicited_checks_only_trigger_a_check() {
+        let mut check_list = check_list();
+        let now = Instant::now();
+        let predicted = socket_addr("5.6.7.8:4322");
+
+        // Answers from an address we haven't sent a check to don't count.
+        assert!(check_list.response_received(predicted, now).is_none());
+
+        // A check from the peer puts its pair at the front of the queue but doesn't make it
+        // succeed.
+        let i = check_list.check_received(predicted, now);
+        assert_eq!(check_list.pair(i).state, CandidatePairState::Waiting);
+        assert!(check_list.nominate(now + Duration::from_millis(NOMINATION_WAIT_MS)).is_none());
+        let i = unwrap_option!(check_list.next_check(now), "No check to send");
+        assert_eq!(check_list.pair(i).remote.addr, predicted);
+
+        // The pair succeeds once our check gets answered from there.
+        let i = unwrap_option!(check_list.response_received(predicted, now), "Unknown pair");
+        assert_eq!(check_list.pair(i).state, CandidatePairState::Succeeded);
+    }
+
+    #[test]
+    fn unanswered_pairs_fail_until_triggered() {
+        let mut check_list = CheckList::new(Vec::new(),
+                                            vec![MappedSocketAddr::new(CandidateType::Host,
+                                                                       socket_addr("10.0.0.2:4321"),
+                                                                       socket_addr("10.0.0.2:4321"),
+                                                                       false)],
+                                            socket_addr("0.0.0.0:1234"),
+                                            true);
+        let mut now = Instant::now();
+        for _ in 0..MAX_CHECKS {
+            let i = unwrap_option!(check_list.next_check(now), "No check to send");
+            assert_eq!(check_list.pair(i).state, CandidatePairState::InProgress);
+            now = unwrap_option!(check_list.next_wakeup(), "Nothing to wake up for");
+        }
+
+        // The last check has gone unanswered too.
+        assert!(check_list.next_check(now).is_none());
+        assert_eq!(check_list.pair(0).state, CandidatePairState::Failed);
+        assert!(check_list.next_wakeup().is_none());
+
+        // A check from the peer gives the pair another go.
+        let i = check_list.check_received(socket_addr("10.0.0.2:4321"), now);
+        assert_eq!(check_list.pair(i).state, CandidatePairState::Waiting);
+        for _ in 0..MAX_CHECKS {
+            let _ = unwrap_option!(check_list.next_check(now), "No check to send");
+            now = unwrap_option!(check_list.next_wakeup(), "Nothing to wake up for");
+        }
+        assert!(check_list.next_check(now).is_none());
+        assert_eq!(check_list.pair(0).state, CandidatePairState::Failed);
+    }
+}
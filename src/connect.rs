@@ -0,0 +1,420 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Connecting to a peer in one call: mapping a socket, swapping rendezvous info through a
+//! `Signaller` and punching a hole, or relaying through a relay server if punching fails.
+
+use std::fmt;
+use std::io;
+use std::time::Instant;
+
+use w_result::{WResult, WErr, WOk};
+
+use mapping_context::{self, MappingContext};
+use mapped_tcp_socket::{self, MappedTcpSocket, MappedTcpSocketMapWarning, PunchedTcpStream,
+                        TcpPunchHoleWarning};
+use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapWarning};
+use punched_udp_socket::{PunchedUdpSocket, UdpPunchHoleWarning};
+use relay::{Relay, RelayAllocation, RelayConnectWarning, RelayedUdpSocket};
+use rendezvous_info;
+use signalling::{ExchangeTag, Signaller};
+use turn::{TurnAllocation, TurnError};
+use utils::DisplaySlice;
+
+/// A transport protocol that `connect_with` can punch a hole with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Punch a hole with `PunchedUdpSocket::punch_hole`.
+    Udp,
+    /// Punch a hole with `tcp_punch_hole`.
+    Tcp,
+    /// Relay udp traffic through the relay servers known to the mapping context. See
+    /// `RelayedUdpSocket::connect`.
+    Relay,
+    /// Relay udp traffic through the TURN servers known to the mapping context.
+    Turn,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Relay => write!(f, "relay"),
+            Protocol::Turn => write!(f, "turn"),
+        }
+    }
+}
+
+/// A socket connected to the peer.
+pub enum ConnectedSocket {
+    /// A hole punched udp socket.
+    Udp(PunchedUdpSocket),
+    /// A hole punched tcp stream.
+    Tcp(PunchedTcpStream),
+    /// A udp socket relayed through a relay server.
+    Relayed(RelayedUdpSocket),
+    /// A udp socket relayed through a TURN server.
+    Turn(RelayedUdpSocket<TurnAllocation>),
+}
+
+/// The result of a successful `connect`.
+pub struct Connection {
+    /// The socket connected to the peer. Hole punched sockets own the port mappings made for them.
+    pub socket: ConnectedSocket,
+}
+
+/// The reason that connecting with one protocol failed.
+#[derive(Debug)]
+pub struct ProtocolError {
+    /// The protocol we were trying to connect with.
+    pub protocol: Protocol,
+    /// What went wrong.
+    pub err: io::Error,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connecting over {} failed: {}", self.protocol, self.err)
+    }
+}
+
+quick_error! {
+    /// Warnings raised by `connect`.
+    #[derive(Debug)]
+    pub enum ConnectWarning {
+        /// Warning raised while mapping a udp socket.
+        MapUdpSocket { warning: MappedUdpSocketMapWarning } {
+            description("Warning raised while mapping a udp socket")
+            display("Warning raised while mapping a udp socket: {}", warning)
+            cause(warning)
+        }
+        /// Warning raised while punching a udp hole.
+        PunchUdpHole { warning: UdpPunchHoleWarning } {
+            description("Warning raised while punching a udp hole")
+            display("Warning raised while punching a udp hole: {}", warning)
+            cause(warning)
+        }
+        /// Warning raised while mapping a tcp socket.
+        MapTcpSocket { warning: MappedTcpSocketMapWarning } {
+            description("Warning raised while mapping a tcp socket")
+            display("Warning raised while mapping a tcp socket: {}", warning)
+            cause(warning)
+        }
+        /// Warning raised while punching a tcp hole.
+        PunchTcpHole { warning: TcpPunchHoleWarning } {
+            description("Warning raised while punching a tcp hole")
+            display("Warning raised while punching a tcp hole: {}", warning)
+            cause(warning)
+        }
+        /// Warning raised while connecting through a relay.
+        Relay { warning: RelayConnectWarning } {
+            description("Warning raised while connecting through a relay")
+            display("Warning raised while connecting through a relay: {}", warning)
+            cause(warning)
+        }
+        /// Connecting with one protocol failed before connecting with another succeeded.
+        ProtocolFailed { err: ProtocolError } {
+            description("Connecting with one of the protocols failed")
+            display("{}", err)
+        }
+    }
+}
+
+quick_error! {
+    /// Error returned by `connect`.
+    #[derive(Debug)]
+    pub enum ConnectError {
+        /// No protocols were given to connect with.
+        NoProtocols {
+            description("No protocols were given to connect with")
+        }
+        /// Connecting failed with every protocol.
+        AllProtocolsFailed { errors: Vec<ProtocolError> } {
+            description("Connecting failed with every protocol")
+            display("Connecting failed with every protocol. {}",
+                    DisplaySlice("protocol error", &errors))
+        }
+    }
+}
+
+impl From<ConnectError> for io::Error {
+    fn from(e: ConnectError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            ConnectError::NoProtocols => io::ErrorKind::InvalidInput,
+            ConnectError::AllProtocolsFailed { errors } => {
+                errors.last().map(|e| e.err.kind()).unwrap_or(io::ErrorKind::Other)
+            },
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// Connect to the peer identified by `peer_id`, trying udp and then tcp. If `mc` knows about any
+/// relay or TURN servers we fall back to relaying when neither works. See `connect_with`.
+pub fn connect<S: Signaller>(mc: &MappingContext,
+                             signaller: &mut S,
+                             peer_id: &str,
+                             deadline: Instant)
+                             -> WResult<Connection, ConnectWarning, ConnectError> {
+    let mut protocols = vec![Protocol::Udp, Protocol::Tcp];
+    if !mapping_context::relay_servers(mc).is_empty() {
+        protocols.push(Protocol::Relay);
+    }
+    if !mapping_context::turn_servers(mc).is_empty() {
+        protocols.push(Protocol::Turn);
+    }
+    connect_with(mc, signaller, peer_id, &protocols, deadline)
+}
+
+/// Connect to the peer identified by `peer_id` with each of `protocols` in turn until one works.
+/// For each protocol we map a new socket, swap rendezvous info with the peer through `signaller`
+/// and punch a hole. The remaining time is split evenly between the protocols left to try. Each
+/// info we swap is tagged with the attempt and protocol it's for, so that if the peer falls behind
+/// or gets ahead we don't punch with an info it made for a different attempt.
+///
+/// The peer must call this with the same protocols, in the same order, at about the same time.
+pub fn connect_with<S: Signaller>(mc: &MappingContext,
+                                  signaller: &mut S,
+                                  peer_id: &str,
+                                  protocols: &[Protocol],
+                                  deadline: Instant)
+                                  -> WResult<Connection, ConnectWarning, ConnectError> {
+    if protocols.is_empty() {
+        return WErr(ConnectError::NoProtocols);
+    }
+
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+    for (i, protocol) in protocols.iter().enumerate() {
+        let now = Instant::now();
+        let res = if now >= deadline {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Ran out of time before trying protocol"))
+        } else {
+            let protocol_deadline = now + (deadline - now) / (protocols.len() - i) as u32;
+            let tag = ExchangeTag {
+                attempt: i as u32,
+                protocol: protocol.to_string(),
+            };
+            match *protocol {
+                Protocol::Udp => {
+                    connect_udp(mc, signaller, peer_id, &tag, protocol_deadline, &mut warnings)
+                },
+                Protocol::Tcp => {
+                    connect_tcp(mc, signaller, peer_id, &tag, protocol_deadline, &mut warnings)
+                },
+                Protocol::Relay => {
+                    connect_relay(mc, signaller, peer_id, &tag, protocol_deadline, &mut warnings)
+                },
+                Protocol::Turn => {
+                    connect_turn(mc, signaller, peer_id, &tag, protocol_deadline, &mut warnings)
+                },
+            }
+        };
+        match res {
+            Ok(connection) => {
+                warnings.extend(errors.into_iter().map(|e| {
+                    ConnectWarning::ProtocolFailed { err: e }
+                }));
+                return WOk(connection, warnings);
+            },
+            Err(e) => {
+                errors.push(ProtocolError {
+                    protocol: *protocol,
+                    err: e,
+                });
+            },
+        }
+    }
+    WErr(ConnectError::AllProtocolsFailed { errors: errors })
+}
+
+/// Spend at most a third of the time available for a protocol on mapping, so that there's time
+/// left to swap rendezvous info and punch.
+fn mapping_deadline(deadline: Instant) -> Instant {
+    let now = Instant::now();
+    if now >= deadline {
+        return deadline;
+    }
+    now + (deadline - now) / 3
+}
+
+fn connect_udp<S: Signaller>(mc: &MappingContext,
+                             signaller: &mut S,
+                             peer_id: &str,
+                             tag: &ExchangeTag,
+                             deadline: Instant,
+                             warnings: &mut Vec<ConnectWarning>)
+                             -> io::Result<Connection> {
+    let mapped_socket = match MappedUdpSocket::new(mc, mapping_deadline(deadline)) {
+        WOk(mapped_socket, ws) => {
+            warnings.extend(ws.into_iter().map(|w| ConnectWarning::MapUdpSocket { warning: w }));
+            mapped_socket
+        },
+        WErr(e) => return Err(From::from(e)),
+    };
+    let endpoints = mapped_socket.endpoints.clone();
+    let (our_priv_info, our_pub_info) =
+        rendezvous_info::gen_rendezvous_info_with_port_prediction(endpoints,
+                                                                  mapped_socket.port_prediction);
+    let their_pub_info = try!(signaller.exchange(peer_id, tag, &our_pub_info, deadline));
+    match PunchedUdpSocket::punch_hole_mapped(mapped_socket,
+                                              our_priv_info,
+                                              their_pub_info,
+                                              deadline) {
+        WOk(punched_socket, ws) => {
+            warnings.extend(ws.into_iter().map(|w| ConnectWarning::PunchUdpHole { warning: w }));
+            Ok(Connection { socket: ConnectedSocket::Udp(punched_socket) })
+        },
+        WErr(e) => Err(From::from(e)),
+    }
+}
+
+fn connect_tcp<S: Signaller>(mc: &MappingContext,
+                             signaller: &mut S,
+                             peer_id: &str,
+                             tag: &ExchangeTag,
+                             deadline: Instant,
+                             warnings: &mut Vec<ConnectWarning>)
+                             -> io::Result<Connection> {
+    let mapped_socket = match MappedTcpSocket::new(mc, mapping_deadline(deadline)) {
+        WOk(mapped_socket, ws) => {
+            warnings.extend(ws.into_iter().map(|w| ConnectWarning::MapTcpSocket { warning: w }));
+            mapped_socket
+        },
+        WErr(e) => return Err(From::from(e)),
+    };
+    let (our_priv_info, our_pub_info) =
+        rendezvous_info::gen_rendezvous_info(mapped_socket.endpoints.clone());
+    let their_pub_info = try!(signaller.exchange(peer_id, tag, &our_pub_info, deadline));
+    match mapped_tcp_socket::tcp_punch_hole_mapped(mapped_socket,
+                                                   our_priv_info,
+                                                   their_pub_info,
+                                                   deadline) {
+        WOk(punched_stream, ws) => {
+            warnings.extend(ws.into_iter().map(|w| ConnectWarning::PunchTcpHole { warning: w }));
+            Ok(Connection { socket: ConnectedSocket::Tcp(punched_stream) })
+        },
+        WErr(e) => Err(From::from(e)),
+    }
+}
+
+fn connect_relay<S: Signaller>(mc: &MappingContext,
+                               signaller: &mut S,
+                               peer_id: &str,
+                               tag: &ExchangeTag,
+                               deadline: Instant,
+                               warnings: &mut Vec<ConnectWarning>)
+                               -> io::Result<Connection> {
+    let allocation = match RelayAllocation::allocate(mc, mapping_deadline(deadline)) {
+        Ok(allocation) => allocation,
+        Err(e) => return Err(From::from(e)),
+    };
+    let relayed_socket =
+        try!(connect_relayed(allocation, signaller, peer_id, tag, deadline, warnings));
+    Ok(Connection { socket: ConnectedSocket::Relayed(relayed_socket) })
+}
+
+fn connect_turn<S: Signaller>(mc: &MappingContext,
+                              signaller: &mut S,
+                              peer_id: &str,
+                              tag: &ExchangeTag,
+                              deadline: Instant,
+                              warnings: &mut Vec<ConnectWarning>)
+                              -> io::Result<Connection> {
+    // Try the servers in turn, giving each an equal share of the time for allocating.
+    let servers = mapping_context::turn_servers(mc);
+    if servers.is_empty() {
+        return Err(From::from(TurnError::NoTurnServers));
+    }
+    let allocate_deadline = mapping_deadline(deadline);
+    let mut allocation: io::Result<TurnAllocation> = Err(From::from(TurnError::TimedOut));
+    for (i, server) in servers.iter().enumerate() {
+        let now = Instant::now();
+        if now >= allocate_deadline {
+            break;
+        }
+        let server_deadline = now + (allocate_deadline - now) / (servers.len() - i) as u32;
+        allocation = TurnAllocation::allocate(server, server_deadline).map_err(From::from);
+        if allocation.is_ok() {
+            break;
+        }
+    }
+    let allocation = try!(allocation);
+    let relayed_socket =
+        try!(connect_relayed(allocation, signaller, peer_id, tag, deadline, warnings));
+    Ok(Connection { socket: ConnectedSocket::Turn(relayed_socket) })
+}
+
+/// Swap rendezvous info containing our relayed address with the peer and connect through the
+/// relays.
+fn connect_relayed<S: Signaller, R: Relay>(allocation: R,
+                                           signaller: &mut S,
+                                           peer_id: &str,
+                                           tag: &ExchangeTag,
+                                           deadline: Instant,
+                                           warnings: &mut Vec<ConnectWarning>)
+                                           -> io::Result<RelayedUdpSocket<R>> {
+    let (our_priv_info, our_pub_info) =
+        rendezvous_info::gen_rendezvous_info(vec![allocation.endpoint()]);
+    let their_pub_info = try!(signaller.exchange(peer_id, tag, &our_pub_info, deadline));
+    match RelayedUdpSocket::connect(allocation, our_priv_info, their_pub_info, deadline) {
+        WOk(relayed_socket, ws) => {
+            warnings.extend(ws.into_iter().map(|w| ConnectWarning::Relay { warning: w }));
+            Ok(relayed_socket)
+        },
+        WErr(e) => Err(From::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use connect::{self, ConnectedSocket};
+    use mapping_context::MappingContext;
+    use signalling::MemorySignallingHub;
+
+    #[test]
+    fn two_peers_connect_over_loopback() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let hub = MemorySignallingHub::new();
+        let mut signaller_1 = hub.signaller("bob");
+        let jh = thread!("two_peers_connect_over_loopback bob", move || {
+            let mc = unwrap_result!(MappingContext::new().result_discard());
+            let res = connect::connect(&mc, &mut signaller_1, "alice", deadline);
+            match unwrap_result!(res.result_discard()).socket {
+                ConnectedSocket::Udp(..) => (),
+                ConnectedSocket::Tcp(..) |
+                ConnectedSocket::Relayed(..) |
+                ConnectedSocket::Turn(..) => panic!("Expected a udp socket"),
+            }
+        });
+
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        let mut signaller_0 = hub.signaller("alice");
+        let res = connect::connect(&mc, &mut signaller_0, "bob", deadline);
+        match unwrap_result!(res.result_discard()).socket {
+            ConnectedSocket::Udp(..) => (),
+            ConnectedSocket::Tcp(..) |
+            ConnectedSocket::Relayed(..) |
+            ConnectedSocket::Turn(..) => panic!("Expected a udp socket"),
+        }
+        unwrap_result!(jh.join());
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Limits on the endpoints we're willing to send hole punching traffic to. Rendezvous info comes
+//! from the peer and can't be trusted, without these a peer could list thousands of endpoints or
+//! the addresses of third parties and use us to flood them.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use socket_addr::SocketAddr;
+
+use mapped_socket_addr::MappedSocketAddr;
+use socket_utils;
+
+/// Which of a peer's endpoints we accept. Applied to a `PubRendezvousInfo` with
+/// `PubRendezvousInfo::apply_endpoint_policy`. The hole punching functions always apply the
+/// default policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointPolicy {
+    /// The most endpoints we'll try. Endpoints beyond this, in priority order, are dropped.
+    pub max_endpoints: usize,
+    /// Whether to accept loopback, private, link-local and unique-local addresses. These are
+    /// needed to connect to peers on the same machine or network.
+    pub allow_private: bool,
+}
+
+impl Default for EndpointPolicy {
+    fn default() -> EndpointPolicy {
+        EndpointPolicy {
+            max_endpoints: 32,
+            allow_private: true,
+        }
+    }
+}
+
+quick_error! {
+    /// An endpoint that was dropped from a peer's rendezvous info by an `EndpointPolicy`.
+    #[derive(Debug)]
+    pub enum RejectedEndpoint {
+        /// The peer listed more endpoints than the policy allows.
+        TooMany {
+            addr: SocketAddr,
+        } {
+            description("The peer listed more endpoints than the policy allows")
+            display("Dropped endpoint {}: the peer listed more endpoints than the policy \
+                     allows", addr)
+        }
+        /// The address is unspecified, multicast, broadcast, reserved or for documentation, or
+        /// the port is zero.
+        SpecialPurpose {
+            addr: SocketAddr,
+        } {
+            description("The endpoint is a special-purpose address")
+            display("Dropped endpoint {}: it is a special-purpose address", addr)
+        }
+        /// The address is private and the policy doesn't allow private addresses.
+        Private {
+            addr: SocketAddr,
+        } {
+            description("The endpoint is a private address")
+            display("Dropped endpoint {}: private addresses are not allowed", addr)
+        }
+    }
+}
+
+impl EndpointPolicy {
+    /// Check that we're allowed to send to `addr`, ignoring the limit on the number of endpoints.
+    pub fn check(&self, addr: &SocketAddr) -> Result<(), RejectedEndpoint> {
+        if addr.port() == 0 {
+            return Err(RejectedEndpoint::SpecialPurpose { addr: *addr });
+        }
+        match classify(&addr.ip()) {
+            AddrClass::Public => Ok(()),
+            AddrClass::Private => {
+                if self.allow_private {
+                    Ok(())
+                } else {
+                    Err(RejectedEndpoint::Private { addr: *addr })
+                }
+            },
+            AddrClass::SpecialPurpose => Err(RejectedEndpoint::SpecialPurpose { addr: *addr }),
+        }
+    }
+}
+
+/// Drop the endpoints that `policy` doesn't allow. `endpoints` should be in priority order.
+pub fn apply(policy: &EndpointPolicy, endpoints: Vec<MappedSocketAddr>)
+             -> (Vec<MappedSocketAddr>, Vec<RejectedEndpoint>) {
+    let mut accepted: Vec<MappedSocketAddr> = Vec::new();
+    let mut rejected = Vec::new();
+    for endpoint in endpoints {
+        if let Err(e) = policy.check(&endpoint.addr) {
+            rejected.push(e);
+            continue;
+        }
+        if accepted.iter().any(|a| a.addr == endpoint.addr) {
+            continue;
+        }
+        if accepted.len() >= policy.max_endpoints {
+            rejected.push(RejectedEndpoint::TooMany { addr: endpoint.addr });
+            continue;
+        }
+        accepted.push(endpoint);
+    }
+    (accepted, rejected)
+}
+
+enum AddrClass {
+    Public,
+    Private,
+    SpecialPurpose,
+}
+
+fn classify(ip: &IpAddr) -> AddrClass {
+    match *ip {
+        IpAddr::V4(ref ip) => classify_v4(ip),
+        IpAddr::V6(ref ip) => classify_v6(ip),
+    }
+}
+
+fn classify_v4(ip: &Ipv4Addr) -> AddrClass {
+    let octets = ip.octets();
+    // 0.0.0.0/8 is "this network" and 240.0.0.0/4 is reserved.
+    if octets[0] == 0 || octets[0] >= 240 || ip.is_multicast() || ip.is_documentation() {
+        return AddrClass::SpecialPurpose;
+    }
+    // 100.64.0.0/10 is shared address space used by carrier-grade NATs.
+    let shared = octets[0] == 100 && (octets[1] & 0xc0) == 64;
+    if socket_utils::ipv4_is_loopback(ip) || ip.is_private() || ip.is_link_local() || shared {
+        return AddrClass::Private;
+    }
+    AddrClass::Public
+}
+
+fn classify_v6(ip: &Ipv6Addr) -> AddrClass {
+    let segments = ip.segments();
+    // IPv4-mapped addresses get the IPv4 rules.
+    if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+        let v4 = Ipv4Addr::new((segments[6] >> 8) as u8,
+                               segments[6] as u8,
+                               (segments[7] >> 8) as u8,
+                               segments[7] as u8);
+        return classify_v4(&v4);
+    }
+    // 2001:db8::/32 is for documentation.
+    if socket_utils::ipv6_is_unspecified(ip) || (segments[0] & 0xff00) == 0xff00 ||
+       (segments[0] == 0x2001 && segments[1] == 0x0db8) {
+        return AddrClass::SpecialPurpose;
+    }
+    if !socket_utils::ipv6_is_global(ip) {
+        return AddrClass::Private;
+    }
+    AddrClass::Public
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::str::FromStr;
+    use socket_addr::SocketAddr;
+
+    use endpoint_policy::{self, EndpointPolicy, RejectedEndpoint};
+    use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+
+    fn endpoint(addr: &str) -> MappedSocketAddr {
+        let addr = SocketAddr(unwrap_result!(net::SocketAddr::from_str(addr)));
+        MappedSocketAddr::new(CandidateType::ServerReflexive, addr, addr, true)
+    }
+
+    #[test]
+    fn special_purpose_private_and_excess_endpoints_are_rejected() {
+        let endpoints = vec![endpoint("8.8.8.8:1000"),
+                             endpoint("8.8.8.8:1000"),
+                             endpoint("192.168.1.2:1000"),
+                             endpoint("0.0.0.0:1000"),
+                             endpoint("255.255.255.255:1000"),
+                             endpoint("224.0.0.1:1000"),
+                             endpoint("8.8.8.8:0"),
+                             endpoint("[ff02::1]:1000"),
+                             endpoint("[::ffff:10.0.0.1]:1000"),
+                             endpoint("[2a00:1450::1]:1000"),
+                             endpoint("8.8.4.4:1000")];
+
+        let policy = EndpointPolicy::default();
+        let (accepted, rejected) = endpoint_policy::apply(&policy, endpoints.clone());
+        let accepted: Vec<String> = accepted.iter().map(|e| format!("{}", e.addr)).collect();
+        assert_eq!(accepted,
+                   vec!["8.8.8.8:1000", "192.168.1.2:1000", "[::ffff:10.0.0.1]:1000",
+                        "[2a00:1450::1]:1000", "8.8.4.4:1000"]);
+        assert_eq!(rejected.len(), 5);
+
+        let policy = EndpointPolicy {
+            max_endpoints: 2,
+            allow_private: false,
+        };
+        let (accepted, rejected) = endpoint_policy::apply(&policy, endpoints);
+        let accepted: Vec<String> = accepted.iter().map(|e| format!("{}", e.addr)).collect();
+        assert_eq!(accepted, vec!["8.8.8.8:1000", "[2a00:1450::1]:1000"]);
+        let num_private = rejected.iter().filter(|r| {
+            match **r {
+                RejectedEndpoint::Private { .. } => true,
+                _ => false,
+            }
+        }).count();
+        let num_too_many = rejected.iter().filter(|r| {
+            match **r {
+                RejectedEndpoint::TooMany { .. } => true,
+                _ => false,
+            }
+        }).count();
+        assert_eq!(num_private, 2);
+        assert_eq!(num_too_many, 1);
+    }
+}
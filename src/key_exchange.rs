@@ -0,0 +1,283 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Authenticated key exchange run over a freshly punched connection. Each peer generates an
+//! ephemeral key pair and sends the public half to the other, sealed with the long-term box keys
+//! from the rendezvous info. Session keys are derived from the ephemeral keys, the long-term keys
+//! and both rendezvous secrets, so they are bound to the peer's identity and to this rendezvous.
+
+use std::io;
+
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use sodiumoxide;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::hash::sha512;
+use sodiumoxide::crypto::secretbox;
+
+use rendezvous_info::{self, PrivRendezvousInfo, PubRendezvousInfo};
+use secret::Secret;
+
+/// Keys for encrypting traffic with a peer after hole punching.
+///
+/// Each direction gets its own key, so both peers can safely use a counter as the nonce when
+/// sealing messages with `sodiumoxide::crypto::secretbox`.
+#[derive(Clone)]
+pub struct SessionKeys {
+    /// The long-term public key that the peer put in its rendezvous info, ie. its identity.
+    pub peer_public_key: box_::PublicKey,
+    /// Key for encrypting data sent to the peer.
+    pub encrypt_key: secretbox::Key,
+    /// Key for decrypting data received from the peer.
+    pub decrypt_key: secretbox::Key,
+}
+
+quick_error! {
+    /// Errors raised while exchanging keys with a peer.
+    #[derive(Debug)]
+    pub enum KeyExchangeError {
+        /// The peer's rendezvous info doesn't contain a public key.
+        NoPublicKey {
+            description("The peer's rendezvous info doesn't contain a public key")
+        }
+        /// Our rendezvous info wasn't generated with a key pair.
+        NoKeyPair {
+            description("Our rendezvous info wasn't generated with a key pair")
+        }
+        /// IO error talking to the peer.
+        Io {
+            err: io::Error,
+        } {
+            description("IO error talking to the peer")
+            display("IO error talking to the peer: {}", err)
+            cause(err)
+        }
+        /// Timed out waiting for the peer's key exchange message.
+        TimedOut {
+            description("Timed out waiting for the peer's key exchange message")
+        }
+        /// The peer sent a key exchange message which couldn't be authenticated.
+        InvalidMessage {
+            description("The peer sent a key exchange message which couldn't be authenticated")
+        }
+    }
+}
+
+impl From<KeyExchangeError> for io::Error {
+    fn from(e: KeyExchangeError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            KeyExchangeError::NoPublicKey => io::ErrorKind::InvalidInput,
+            KeyExchangeError::NoKeyPair => io::ErrorKind::InvalidInput,
+            KeyExchangeError::Io { err } => err.kind(),
+            KeyExchangeError::TimedOut => io::ErrorKind::TimedOut,
+            KeyExchangeError::InvalidMessage => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// A key exchange message. `sealed` holds the sender's ephemeral public key followed by a flag
+/// saying whether the sender has already got our ephemeral public key, sealed with the long-term
+/// keys of both peers.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub struct KeyExchangeMsg {
+    nonce: [u8; box_::NONCEBYTES],
+    sealed: Vec<u8>,
+}
+
+/// The contents of a key exchange message received from the peer.
+pub struct TheirHalf {
+    ephemeral_public_key: box_::PublicKey,
+    /// Whether the peer has already got our half of the exchange.
+    pub got_ours: bool,
+}
+
+/// One side of a key exchange.
+pub struct KeyExchange {
+    our_public_key: box_::PublicKey,
+    their_public_key: box_::PublicKey,
+    long_term_key: box_::PrecomputedKey,
+    ephemeral_public_key: box_::PublicKey,
+    ephemeral_secret_key: box_::SecretKey,
+    our_secret: Secret,
+    their_secret: Secret,
+}
+
+impl KeyExchange {
+    /// Prepare a key exchange with a fresh ephemeral key pair. Fails if either rendezvous info is
+    /// missing its keys.
+    pub fn new(our_priv_rendezvous_info: &PrivRendezvousInfo,
+               their_pub_rendezvous_info: &PubRendezvousInfo)
+               -> Result<KeyExchange, KeyExchangeError> {
+        let (our_public_key, our_secret_key) =
+            match rendezvous_info::get_key_pair(our_priv_rendezvous_info) {
+                Some(key_pair) => key_pair,
+                None => return Err(KeyExchangeError::NoKeyPair),
+            };
+        let their_public_key = match their_pub_rendezvous_info.public_key() {
+            Some(public_key) => public_key,
+            None => return Err(KeyExchangeError::NoPublicKey),
+        };
+        let _ = sodiumoxide::init();
+        let (ephemeral_public_key, ephemeral_secret_key) = box_::gen_keypair();
+        Ok(KeyExchange {
+            long_term_key: box_::precompute(&their_public_key, &our_secret_key),
+            our_public_key: our_public_key,
+            their_public_key: their_public_key,
+            ephemeral_public_key: ephemeral_public_key,
+            ephemeral_secret_key: ephemeral_secret_key,
+            our_secret: rendezvous_info::priv_secret(our_priv_rendezvous_info),
+            their_secret: rendezvous_info::pub_secret(their_pub_rendezvous_info),
+        })
+    }
+
+    /// Serialise our half of the exchange, telling the peer whether we've got theirs.
+    pub fn our_half(&self, got_theirs: bool) -> Vec<u8> {
+        let mut plain = Vec::with_capacity(box_::PUBLICKEYBYTES + 1);
+        plain.extend_from_slice(&self.ephemeral_public_key.0[..]);
+        plain.push(got_theirs as u8);
+        let nonce = box_::gen_nonce();
+        let msg = KeyExchangeMsg {
+            nonce: nonce.0,
+            sealed: box_::seal_precomputed(&plain, &nonce, &self.long_term_key),
+        };
+        unwrap_result!(serialise(&msg))
+    }
+
+    /// Authenticate and parse a key exchange message from the peer.
+    pub fn their_half(&self, data: &[u8]) -> Result<TheirHalf, KeyExchangeError> {
+        let msg = match deserialise::<KeyExchangeMsg>(data) {
+            Ok(msg) => msg,
+            Err(_) => return Err(KeyExchangeError::InvalidMessage),
+        };
+        let plain = match box_::open_precomputed(&msg.sealed,
+                                                 &box_::Nonce(msg.nonce),
+                                                 &self.long_term_key) {
+            Ok(plain) => plain,
+            Err(()) => return Err(KeyExchangeError::InvalidMessage),
+        };
+        if plain.len() != box_::PUBLICKEYBYTES + 1 {
+            return Err(KeyExchangeError::InvalidMessage);
+        }
+        let mut ephemeral_public_key = [0u8; box_::PUBLICKEYBYTES];
+        ephemeral_public_key.clone_from_slice(&plain[..box_::PUBLICKEYBYTES]);
+        // Both directions are sealed with the same key, so make sure this isn't our own message
+        // being reflected back at us.
+        if ephemeral_public_key == self.ephemeral_public_key.0 {
+            return Err(KeyExchangeError::InvalidMessage);
+        }
+        Ok(TheirHalf {
+            ephemeral_public_key: box_::PublicKey(ephemeral_public_key),
+            got_ours: plain[box_::PUBLICKEYBYTES] != 0,
+        })
+    }
+
+    /// Derive the session keys once we have the peer's half of the exchange.
+    pub fn session_keys(&self, their_half: &TheirHalf) -> SessionKeys {
+        let box_::PrecomputedKey(shared) = box_::precompute(&their_half.ephemeral_public_key,
+                                                            &self.ephemeral_secret_key);
+        let ours = (&self.ephemeral_public_key.0, &self.our_public_key.0, &self.our_secret);
+        let theirs = (&their_half.ephemeral_public_key.0,
+                      &self.their_public_key.0,
+                      &self.their_secret);
+        // Both peers need to feed the keys into the hash in the same order.
+        let we_are_first = self.ephemeral_public_key.0 < their_half.ephemeral_public_key.0;
+        let (first, second) = if we_are_first {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+
+        let mut input = Vec::new();
+        input.extend_from_slice(&shared[..]);
+        input.extend_from_slice(&first.0[..]);
+        input.extend_from_slice(&second.0[..]);
+        input.extend_from_slice(&first.1[..]);
+        input.extend_from_slice(&second.1[..]);
+        input.extend_from_slice(&first.2[..]);
+        input.extend_from_slice(&second.2[..]);
+        let sha512::Digest(digest) = sha512::hash(&input);
+
+        let mut first_key = [0u8; secretbox::KEYBYTES];
+        let mut second_key = [0u8; secretbox::KEYBYTES];
+        first_key.clone_from_slice(&digest[..secretbox::KEYBYTES]);
+        second_key.clone_from_slice(&digest[secretbox::KEYBYTES..(2 * secretbox::KEYBYTES)]);
+        let (encrypt_key, decrypt_key) = if we_are_first {
+            (first_key, second_key)
+        } else {
+            (second_key, first_key)
+        };
+        SessionKeys {
+            peer_public_key: self.their_public_key,
+            encrypt_key: secretbox::Key(encrypt_key),
+            decrypt_key: secretbox::Key(decrypt_key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sodiumoxide::crypto::box_;
+
+    use key_exchange::{KeyExchange, KeyExchangeError};
+    use rendezvous_info::{gen_rendezvous_info, gen_rendezvous_info_with_key_pair};
+
+    #[test]
+    fn session_keys_match_and_are_bound_to_identities() {
+        let (public_key_0, secret_key_0) = box_::gen_keypair();
+        let (public_key_1, secret_key_1) = box_::gen_keypair();
+        let (public_key_2, secret_key_2) = box_::gen_keypair();
+        let (priv_info_0, pub_info_0) =
+            gen_rendezvous_info_with_key_pair(Vec::new(), None, &public_key_0, &secret_key_0);
+        let (priv_info_1, pub_info_1) =
+            gen_rendezvous_info_with_key_pair(Vec::new(), None, &public_key_1, &secret_key_1);
+        let (priv_info_2, _) =
+            gen_rendezvous_info_with_key_pair(Vec::new(), None, &public_key_2, &secret_key_2);
+
+        let kx_0 = unwrap_result!(KeyExchange::new(&priv_info_0, &pub_info_1));
+        let kx_1 = unwrap_result!(KeyExchange::new(&priv_info_1, &pub_info_0));
+        let their_half_0 = unwrap_result!(kx_0.their_half(&kx_1.our_half(false)));
+        let their_half_1 = unwrap_result!(kx_1.their_half(&kx_0.our_half(true)));
+        assert!(!their_half_0.got_ours);
+        assert!(their_half_1.got_ours);
+
+        let keys_0 = kx_0.session_keys(&their_half_0);
+        let keys_1 = kx_1.session_keys(&their_half_1);
+        assert!(keys_0.encrypt_key == keys_1.decrypt_key);
+        assert!(keys_0.decrypt_key == keys_1.encrypt_key);
+        assert!(keys_0.encrypt_key != keys_0.decrypt_key);
+        assert!(keys_0.peer_public_key == public_key_1);
+        assert!(keys_1.peer_public_key == public_key_0);
+
+        // Our own messages and messages from someone without the peer's secret key are rejected.
+        match kx_0.their_half(&kx_0.our_half(false)) {
+            Err(KeyExchangeError::InvalidMessage) => (),
+            _ => panic!("Accepted a reflected key exchange message"),
+        }
+        let kx_2 = unwrap_result!(KeyExchange::new(&priv_info_2, &pub_info_0));
+        match kx_0.their_half(&kx_2.our_half(false)) {
+            Err(KeyExchangeError::InvalidMessage) => (),
+            _ => panic!("Accepted a key exchange message from the wrong peer"),
+        }
+
+        let (priv_info_3, _) = gen_rendezvous_info(Vec::new());
+        match KeyExchange::new(&priv_info_3, &pub_info_1) {
+            Err(KeyExchangeError::NoKeyPair) => (),
+            _ => panic!("Started a key exchange without a key pair"),
+        }
+    }
+}
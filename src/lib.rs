@@ -46,9 +46,14 @@
 #![allow(missing_docs)]
 
 extern crate byteorder;
+extern crate hmac;
+#[cfg(target_family = "unix")]
+extern crate libc;
+extern crate md5;
 extern crate net2;
 extern crate rand;
 extern crate rustc_serialize;
+extern crate sha1;
 extern crate void;
 #[macro_use]
 extern crate maidsafe_utilities;
@@ -56,33 +61,92 @@ extern crate igd;
 extern crate socket_addr;
 extern crate get_if_addrs;
 extern crate w_result;
+extern crate sodiumoxide;
 #[allow(unused_extern_crates)] // Needed because the crate is only used for macros
 #[macro_use]
 extern crate quick_error;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio_core;
 
+pub use cancellation::CancellationToken;
+pub use check_list::{CandidatePair, CandidatePairState};
+pub use connect::{connect, connect_with, Connection, ConnectedSocket, ConnectError,
+                  ConnectWarning, Protocol, ProtocolError};
+pub use endpoint_policy::{EndpointPolicy, RejectedEndpoint};
+pub use key_exchange::{KeyExchangeError, SessionKeys};
 pub use mapping_context::{MappingContext, MappingContextNewError, MappingContextNewWarning};
-pub use mapped_socket_addr::MappedSocketAddr;
-pub use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo,
-                         gen_rendezvous_info};
+pub use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+pub use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo, SignedPubRendezvousInfo,
+                         RendezvousInfoVerifier, VerifyRendezvousInfoError,
+                         DecodeRendezvousInfoError,
+                         gen_rendezvous_info, gen_rendezvous_info_with_port_prediction,
+                         gen_rendezvous_info_with_key_pair};
+pub use port_prediction::PortPrediction;
+pub use port_mapping::{PortMappingGuard, PortMapping, PortMappingGateway, MapPortWarning,
+                       MapPortError};
+pub use igd::PortMappingProtocol;
+pub use stale_port_mappings::{RemovedPortMapping, RemoveStalePortMappingsError,
+                              RemoveStalePortMappingsWarning, GetPortMappingEntryError};
 pub use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapError,
                             MappedUdpSocketMapWarning, MappedUdpSocketNewError};
-pub use punched_udp_socket::{PunchedUdpSocket, filter_udp_hole_punch_packet};
-pub use mapped_tcp_socket::{new_reusably_bound_tcp_socket, MappedTcpSocket, tcp_punch_hole,
+pub use stun::StunDecodeError;
+pub use nat_pmp::NatPmpError;
+pub use pcp::PcpError;
+pub use nat_behaviour::{NatBehaviour, MappingBehaviour, FilteringBehaviour, NatBehaviourError,
+                        NatBehaviourWarning};
+pub use punched_udp_socket::{PeerSocket, PunchedUdpSocket, filter_udp_hole_punch_packet};
+pub use birthday_punch::BirthdayPunchConfig;
+pub use mapped_tcp_socket::{new_reusably_bound_tcp_socket, MappedTcpSocket, PunchedTcpStream,
+                            tcp_punch_hole, tcp_punch_hole_cancellable, tcp_punch_hole_mapped,
+                            tcp_punch_hole_secure,
                             MappedTcpSocketMapError, MappedTcpSocketMapWarning,
                             MappedTcpSocketNewError, NewReusablyBoundTcpSocketError,
                             TcpPunchHoleWarning, TcpPunchHoleError};
+pub use relay::{Relay, RelayAllocation, RelayAllocateError, RelayedUdpSocket,
+                RelayConnectWarning, RelayConnectError, RelayServerInfo};
+pub use relay_server::{RelayServer, RelayServerNewError};
+pub use rendezvous_server::{RendezvousServer, RendezvousServerNewError, RendezvousClient};
+pub use signalling::{ExchangeTag, Signaller, MemorySignallingHub, MemorySignaller, TcpSignaller};
 pub use simple_udp_hole_punch_server::{SimpleUdpHolePunchServer, SimpleUdpHolePunchServerNewError};
 pub use simple_tcp_hole_punch_server::{SimpleTcpHolePunchServer, SimpleTcpHolePunchServerNewError};
+#[cfg(feature = "async")]
+pub use tokio_support::{MapSocketFuture, UdpPunchHoleFuture, TcpPunchHoleFuture,
+                        TcpPunchHoleMappedFuture, TokioPunchedUdpSocket, TokioPunchedTcpStream,
+                        tcp_punch_hole_async, tcp_punch_hole_mapped_async};
+pub use turn::{TurnAllocation, TurnError, TurnServer};
 
+mod cancellation;
+mod check_list;
+mod connect;
+mod endpoint_policy;
+mod key_exchange;
 mod mapping_context;
 mod mapped_socket_addr;
 mod rendezvous_info;
+mod rendezvous_server;
+mod relay;
+mod relay_server;
 mod mapped_udp_socket;
 mod punched_udp_socket;
+mod birthday_punch;
 mod mapped_tcp_socket;
 mod simple_udp_hole_punch_server;
 mod simple_tcp_hole_punch_server;
 mod socket_utils;
 mod listener_message;
+mod stun;
+mod nat_pmp;
+mod pcp;
+mod port_mapping;
+mod stale_port_mappings;
+mod nat_behaviour;
+mod port_prediction;
 mod utils;
+mod secret;
+mod signalling;
+mod turn;
+#[cfg(feature = "async")]
+mod tokio_support;
 
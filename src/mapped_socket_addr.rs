@@ -18,8 +18,46 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
+use std::net;
+
 use socket_addr::SocketAddr;
 
+/// How a `MappedSocketAddr` was obtained. These correspond to the candidate types of ICE
+/// (RFC 8445), with port mappings obtained from a gateway given a type of their own.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, RustcEncodable, RustcDecodable)]
+pub enum CandidateType {
+    /// The address of one of our local interfaces.
+    Host,
+    /// An external address mapped for us by an IGD, PCP or NAT-PMP gateway.
+    Mapped,
+    /// An address of the peer's that we only learned about because a hole punch packet arrived
+    /// from it.
+    PeerReflexive,
+    /// Our external address as seen by a simple hole punch server or a STUN server.
+    ServerReflexive,
+    /// An external address guessed from the way a symmetric NAT allocates ports.
+    Predicted,
+    /// An address on a relay server which forwards traffic to us.
+    Relayed,
+}
+
+impl CandidateType {
+    /// The type preference used when computing the priority of a candidate of this type. Higher
+    /// is better. Host, peer reflexive, server reflexive and relayed candidates use the values
+    /// recommended by RFC 8445. A mapped address is more likely to work than a reflexive one since
+    /// the gateway has promised to forward to it, whereas a predicted address is only a guess.
+    pub fn type_preference(&self) -> u32 {
+        match *self {
+            CandidateType::Host => 126,
+            CandidateType::Mapped => 120,
+            CandidateType::PeerReflexive => 110,
+            CandidateType::ServerReflexive => 100,
+            CandidateType::Predicted => 50,
+            CandidateType::Relayed => 0,
+        }
+    }
+}
+
 /// A socket address obtained through some mapping technique.
 #[derive(Debug, PartialEq, Eq, Clone, RustcEncodable, RustcDecodable)]
 pub struct MappedSocketAddr {
@@ -30,5 +68,92 @@ pub struct MappedSocketAddr {
     /// address. `nat_restricted` will not be set if this is a fully mapped address such as the
     /// external address of a full-cone NAT or one obtained through UPnP.
     pub nat_restricted: bool,
+
+    /// How this address was obtained.
+    pub candidate_type: CandidateType,
+
+    /// The local address that traffic sent to `addr` ends up arriving at. For host candidates
+    /// this is `addr` itself. It may be unspecified if the socket was bound to an unspecified
+    /// address and we can't tell which interface the traffic arrives on.
+    pub base: SocketAddr,
+
+    /// The RFC 8445 priority of this address. Peers should try addresses with a higher priority
+    /// first.
+    pub priority: u32,
+}
+
+impl MappedSocketAddr {
+    /// Create a new `MappedSocketAddr`, computing its priority from its type and address family.
+    pub fn new(candidate_type: CandidateType,
+               addr: SocketAddr,
+               base: SocketAddr,
+               nat_restricted: bool)
+               -> MappedSocketAddr {
+        let priority = candidate_priority(candidate_type, &addr);
+        MappedSocketAddr {
+            addr: addr,
+            nat_restricted: nat_restricted,
+            candidate_type: candidate_type,
+            base: base,
+            priority: priority,
+        }
+    }
+}
+
+/// Compute a candidate priority as described in section 5.1.2.1 of RFC 8445. Every candidate has
+/// the same component ID since we only ever punch a single socket. IPv6 addresses are preferred
+/// over IPv4 addresses of the same type, as recommended by RFC 8421.
+pub fn candidate_priority(candidate_type: CandidateType, addr: &SocketAddr) -> u32 {
+    const COMPONENT_ID: u32 = 1;
+
+    let local_preference = match **addr {
+        net::SocketAddr::V6(..) => 0xffff,
+        net::SocketAddr::V4(..) => 0xfffe,
+    };
+    (candidate_type.type_preference() << 24) + (local_preference << 8) + (256 - COMPONENT_ID)
+}
+
+/// Sort addresses so that the ones with the highest priority come first.
+pub fn sort_by_priority(addrs: &mut Vec<MappedSocketAddr>) {
+    addrs.sort_by(|a, b| b.priority.cmp(&a.priority));
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net;
+    use std::str::FromStr;
+
+    use socket_addr::SocketAddr;
+
+    fn socket_addr(s: &str) -> SocketAddr {
+        SocketAddr(unwrap_result!(net::SocketAddr::from_str(s)))
+    }
+
+    #[test]
+    fn priorities_follow_candidate_type() {
+        let base = socket_addr("192.168.0.2:1234");
+        let host = MappedSocketAddr::new(CandidateType::Host, base, base, false);
+        let mapped = MappedSocketAddr::new(CandidateType::Mapped,
+                                           socket_addr("1.2.3.4:1234"), base, false);
+        let reflexive = MappedSocketAddr::new(CandidateType::ServerReflexive,
+                                              socket_addr("1.2.3.4:4321"), base, true);
+        let predicted = MappedSocketAddr::new(CandidateType::Predicted,
+                                              socket_addr("1.2.3.4:4322"), base, true);
+        let relayed = MappedSocketAddr::new(CandidateType::Relayed,
+                                            socket_addr("5.6.7.8:1234"), base, false);
+        let host_v6 = MappedSocketAddr::new(CandidateType::Host,
+                                            socket_addr("[2001:db8::1]:1234"),
+                                            socket_addr("[2001:db8::1]:1234"),
+                                            true);
+
+        // RFC 8445 gives a host candidate with a local preference of 65535 this priority.
+        assert_eq!(host_v6.priority, 2130706431);
+
+        let mut addrs = vec![relayed.clone(), predicted.clone(), host.clone(), reflexive.clone(),
+                             mapped.clone(), host_v6.clone()];
+        sort_by_priority(&mut addrs);
+        assert_eq!(addrs, vec![host_v6, host, mapped, reflexive, predicted, relayed]);
+    }
+}
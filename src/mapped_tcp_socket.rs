@@ -18,17 +18,15 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
+use std::cmp;
+use std::collections::VecDeque;
 use std::net;
 use std::net::{IpAddr, Ipv4Addr, TcpStream};
 use std::io;
 use std::io::{Read, Write};
 use std::time::{Instant, Duration};
-use std::thread;
 use std::str;
-use std::sync::mpsc;
 use std::fmt;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
 
 use igd;
 use net2;
@@ -38,15 +36,33 @@ use maidsafe_utilities::serialisation::{deserialise, SerialisationError};
 use rand::random;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
-use mapping_context::MappingContext;
-use mapped_socket_addr::MappedSocketAddr;
+use cancellation::CancellationToken;
+use endpoint_policy::{EndpointPolicy, RejectedEndpoint};
+use key_exchange::{KeyExchange, KeyExchangeError, SessionKeys};
+use mapping_context::{MappingContext, InterfaceV4, InterfaceV6};
+use mapped_socket_addr::{self, CandidateType, MappedSocketAddr};
+use port_mapping;
+use port_mapping::{MapPortWarning, PortMappingGuard};
 use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo};
 use rendezvous_info;
+use secret::{self, MacPurpose, Secret, Nonce, MAC_LEN, NONCE_LEN};
 use socket_utils;
+use socket_utils::PollSocket;
 use mapping_context;
 use listener_message;
+use utils;
 use utils::DisplaySlice;
 
+/// The most simple servers that `MappedTcpSocket::map` queries at the same time.
+const MAX_CONCURRENT_MAPPING_QUERIES: usize = 8;
+/// The largest response we'll accept from a simple server.
+const MAX_MAPPING_RESPONSE_SIZE: usize = 256;
+
+/// How long `tcp_punch_hole` waits before retrying a connection to one of the peer's endpoints.
+pub const CONNECT_RETRY_DELAY_SECS: u64 = 1;
+/// The largest key exchange message we'll accept from the peer.
+const MAX_KEY_EXCHANGE_MSG_SIZE: usize = 256;
+
 /// A tcp socket for which we know our external endpoints.
 pub struct MappedTcpSocket {
     /// A bound, but neither listening or connected tcp socket. The socket is
@@ -55,6 +71,9 @@ pub struct MappedTcpSocket {
     pub socket: net2::TcpBuilder,
     /// The known endpoints of this socket.
     pub endpoints: Vec<MappedSocketAddr>,
+    /// Owns the port mappings made for this socket on the local network's gateways. The mappings
+    /// are removed when this is dropped so keep it alive for as long as the socket is in use.
+    pub mapping_guard: PortMappingGuard,
 }
 
 quick_error! {
@@ -72,6 +91,14 @@ quick_error! {
                      err)
             cause(err)
         }
+        /// Mapping was cancelled through a `CancellationToken`.
+        Cancelled {
+            description("Mapping was cancelled")
+        }
+        /// The thread doing the mapping for one of the `_async` functions panicked.
+        WorkerPanicked {
+            description("The thread doing the mapping panicked")
+        }
     }
 }
 
@@ -80,6 +107,8 @@ impl From<MappedTcpSocketMapError> for io::Error {
         let err_str = format!("{}", e);
         let kind = match e {
             MappedTcpSocketMapError::SocketLocalAddr { err } => err.kind(),
+            MappedTcpSocketMapError::Cancelled => io::ErrorKind::Other,
+            MappedTcpSocketMapError::WorkerPanicked => io::ErrorKind::Other,
         };
         io::Error::new(kind, err_str)
     }
@@ -111,6 +140,16 @@ quick_error! {
                      returned an error: {}", gateway_addr, err)
             cause(err)
         }
+        /// Error mapping external address and port, or opening a firewall pinhole, through a PCP
+        /// server or NAT-PMP gateway.
+        MapPort {
+            err: MapPortWarning,
+        } {
+            description("Error mapping external address and port through a PCP server or \
+                         NAT-PMP gateway")
+            display("Error mapping external address and port: {}", err)
+            cause(err)
+        }
         /// Error creating a reusably bound temporary socket for mapping.
         NewReusablyBoundTcpSocket { err: NewReusablyBoundTcpSocketError } {
             description("Error creating a reusably bound temporary socket for mapping.")
@@ -139,6 +178,12 @@ quick_error! {
             display("Error reading from temporary socket: {}", err)
             cause(err)
         }
+        /// Error waiting for the mapping sockets to become ready.
+        Poll { err: io::Error } {
+            description("Error waiting for the mapping sockets to become ready.")
+            display("Error waiting for the mapping sockets to become ready: {}", err)
+            cause(err)
+        }
         /// Error deserialising a response from a mapping server.
         Deserialise { addr: SocketAddr, err: SerialisationError, response: Vec<u8> } {
             description("Error deserialising a response from a mapping server. Are you sure \
@@ -156,6 +201,21 @@ quick_error! {
     }
 }
 
+// The sockets have always reported IGD failures as `GetExternalPort`.
+impl From<MapPortWarning> for MappedTcpSocketMapWarning {
+    fn from(e: MapPortWarning) -> MappedTcpSocketMapWarning {
+        match e {
+            MapPortWarning::IgdGetAnyAddress { gateway_addr, err } => {
+                MappedTcpSocketMapWarning::GetExternalPort {
+                    gateway_addr: gateway_addr,
+                    err: err,
+                }
+            },
+            e => MappedTcpSocketMapWarning::MapPort { err: e },
+        }
+    }
+}
+
 quick_error! {
     /// Errors returned by MappedTcpSocket::new
     #[derive(Debug)]
@@ -273,235 +333,428 @@ impl MappedTcpSocket {
     pub fn map(socket: net2::TcpBuilder, mc: &MappingContext, deadline: Instant)
                -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
     {
-        let mut endpoints = Vec::new();
-        let mut warnings = Vec::new();
+        map_cancellable(socket, mc, deadline, &CancellationToken::new())
+    }
 
-        let local_addr = match socket_utils::tcp_builder_local_addr(&socket) {
-            Ok(local_addr) => local_addr,
-            Err(e) => return WErr(MappedTcpSocketMapError::SocketLocalAddr { err: e }),
-        };
-        match local_addr.ip() {
-            IpAddr::V4(ipv4_addr) => {
-                if socket_utils::ipv4_is_unspecified(&ipv4_addr) {
-                    // If the socket address is unspecified we add an address for every local
-                    // interface. We also ask the interface's IGD gateway (if there is one) for
-                    // an address.
-                    for iface_v4 in mapping_context::interfaces_v4(&mc) {
-                        let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
-                        endpoints.push(MappedSocketAddr {
-                            addr: SocketAddr(net::SocketAddr::V4(local_iface_addr)),
-                            nat_restricted: false,
-                        });
-                        if let Some(gateway) = iface_v4.gateway {
-                            match gateway.get_any_address(igd::PortMappingProtocol::TCP,
-                                                          local_iface_addr, 0,
-                                                          "rust nat_traversal")
-                            {
-                                Ok(external_addr) => {
-                                    endpoints.push(MappedSocketAddr {
-                                        addr: SocketAddr(net::SocketAddr::V4(external_addr)),
-                                        nat_restricted: false,
-                                    });
-                                },
-                                Err(e) => {
-                                    warnings.push(MappedTcpSocketMapWarning::GetExternalPort {
-                                        gateway_addr: gateway.addr,
-                                        err: e,
-                                    });
-                                }
-                            }
-                        };
-                    };
-                }
-                else {
-                    let local_addr_v4 = net::SocketAddrV4::new(ipv4_addr, local_addr.port());
-                    endpoints.push(MappedSocketAddr {
-                        addr: SocketAddr(net::SocketAddr::V4(local_addr_v4)),
-                        nat_restricted: false,
-                    });
+    /// Map an existing tcp socket. Returns `MappedTcpSocketMapError::Cancelled` if `cancel` gets
+    /// triggered first.
+    pub fn map_cancellable(socket: net2::TcpBuilder,
+                           mc: &MappingContext,
+                           deadline: Instant,
+                           cancel: &CancellationToken)
+        -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+    {
+        map_cancellable(socket, mc, deadline, cancel)
+    }
 
-                    // If the local address is the address of an interface then we can avoid
-                    // searching for an IGD gateway, just reuse the search result from when we
-                    // found this interface.
-                    let mut gateway_opt_opt = None;
-                    for iface_v4 in mapping_context::interfaces_v4(&mc) {
-                        if iface_v4.addr == ipv4_addr {
-                            gateway_opt_opt = Some(iface_v4.gateway);
-                            break;
-                        }
-                    };
-                    let gateway_opt = match gateway_opt_opt {
-                        Some(gateway_opt) => gateway_opt,
-                        // We don't where this local address came from so search for an IGD gateway
-                        // at it.
-                        None => {
-                            match igd::search_gateway_from_timeout(ipv4_addr, Duration::from_secs(1)) {
-                                Ok(gateway) => Some(gateway),
-                                Err(e) => {
-                                    warnings.push(MappedTcpSocketMapWarning::FindGateway {
-                                        err: e
-                                    });
-                                    None
-                                }
-                            }
+    /// Create a new `MappedTcpSocket`
+    pub fn new(mc: &MappingContext, deadline: Instant)
+            -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
+    {
+        new_cancellable(mc, deadline, &CancellationToken::new())
+    }
+}
+
+/// Map an existing tcp socket, giving up if `cancel` gets triggered. See `MappedTcpSocket::map`.
+pub fn map_cancellable(socket: net2::TcpBuilder,
+                       mc: &MappingContext,
+                       deadline: Instant,
+                       cancel: &CancellationToken)
+    -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+{
+    let mut endpoints = Vec::new();
+    let mut warnings = Vec::new();
+    let mut leases = Vec::new();
+    let description = mapping_context::port_mapping_description(&mc);
+
+    let local_addr = match socket_utils::tcp_builder_local_addr(&socket) {
+        Ok(local_addr) => local_addr,
+        Err(e) => return WErr(MappedTcpSocketMapError::SocketLocalAddr { err: e }),
+    };
+    match local_addr.ip() {
+        IpAddr::V4(ipv4_addr) => {
+            if socket_utils::ipv4_is_unspecified(&ipv4_addr) {
+                // If the socket address is unspecified we add an address for every local
+                // interface. We also ask the interface's IGD gateway (if there is one) for
+                // an address.
+                for iface_v4 in mapping_context::interfaces_v4(&mc) {
+                    let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
+                    endpoints.push(MappedSocketAddr::new(
+                        CandidateType::Host,
+                        SocketAddr(net::SocketAddr::V4(local_iface_addr)),
+                        SocketAddr(net::SocketAddr::V4(local_iface_addr)),
+                        false
+                    ));
+                    if let Some(res) = port_mapping::interface_v4_get_address(
+                        &iface_v4,
+                        igd::PortMappingProtocol::TCP,
+                        local_addr.port(),
+                        &description
+                    ) {
+                        match res {
+                            Ok((external_addr, lease)) => {
+                                leases.push(lease);
+                                endpoints.push(MappedSocketAddr::new(
+                                    CandidateType::Mapped,
+                                    SocketAddr(external_addr),
+                                    SocketAddr(net::SocketAddr::V4(local_iface_addr)),
+                                    false
+                                ));
+                            },
+                            Err(e) => warnings.push(MappedTcpSocketMapWarning::from(e)),
                         }
                     };
-                    // If we have a gateway, ask it for an external address.
-                    if let Some(gateway) = gateway_opt {
-                        match gateway.get_any_address(igd::PortMappingProtocol::TCP,
-                                                      local_addr_v4, 0,
-                                                      "rust nat_traversal")
-                        {
-                            Ok(external_addr) => {
-                                endpoints.push(MappedSocketAddr {
-                                    addr: SocketAddr(net::SocketAddr::V4(external_addr)),
-                                    nat_restricted: false,
-                                });
-                            },
+                };
+            }
+            else {
+                let local_addr_v4 = net::SocketAddrV4::new(ipv4_addr, local_addr.port());
+                endpoints.push(MappedSocketAddr::new(
+                    CandidateType::Host,
+                    SocketAddr(net::SocketAddr::V4(local_addr_v4)),
+                    SocketAddr(net::SocketAddr::V4(local_addr_v4)),
+                    false
+                ));
+
+                // If the local address is the address of an interface then we can avoid
+                // searching for an IGD gateway, just reuse the search result from when we
+                // found this interface.
+                let iface_v4_opt = mapping_context::interfaces_v4(&mc)
+                                       .into_iter()
+                                       .find(|iface_v4| iface_v4.addr == ipv4_addr);
+                let iface_v4 = match iface_v4_opt {
+                    Some(iface_v4) => iface_v4,
+                    // We don't where this local address came from so search for an IGD gateway
+                    // at it.
+                    None => {
+                        let gateway_opt = match igd::search_gateway_from_timeout(
+                            ipv4_addr, Duration::from_secs(1)
+                        ) {
+                            Ok(gateway) => Some(gateway),
                             Err(e) => {
-                                warnings.push(MappedTcpSocketMapWarning::GetExternalPort {
-                                    gateway_addr: gateway.addr,
-                                    err: e,
+                                warnings.push(MappedTcpSocketMapWarning::FindGateway {
+                                    err: e
                                 });
+                                None
                             }
+                        };
+                        InterfaceV4 {
+                            gateway: gateway_opt,
+                            nat_pmp_gateway: None,
+                            pcp_server: None,
+                            addr: ipv4_addr,
                         }
-                    };
+                    }
                 };
-            },
-            IpAddr::V6(ipv6_addr) => {
-                if socket_utils::ipv6_is_unspecified(&ipv6_addr) {
-                    // If the socket address is unspecified add an address for every interface.
-                    for iface_v6 in mapping_context::interfaces_v6(&mc) {
-                        let local_iface_addr = net::SocketAddr::V6(net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0));
-                        endpoints.push(MappedSocketAddr {
-                            addr: SocketAddr(local_iface_addr),
-                            nat_restricted: false,
-                        });
-                    };
-                }
-                else {
-                    endpoints.push(MappedSocketAddr {
-                        addr: SocketAddr(net::SocketAddr::V6(net::SocketAddrV6::new(ipv6_addr, local_addr.port(), 0, 0))),
-                        nat_restricted: false,
+                if let Some(res) = port_mapping::interface_v4_get_address(
+                    &iface_v4,
+                    igd::PortMappingProtocol::TCP,
+                    local_addr.port(),
+                    &description
+                ) {
+                    match res {
+                        Ok((external_addr, lease)) => {
+                            leases.push(lease);
+                            endpoints.push(MappedSocketAddr::new(
+                                CandidateType::Mapped,
+                                SocketAddr(external_addr),
+                                SocketAddr(net::SocketAddr::V4(local_addr_v4)),
+                                false
+                            ));
+                        },
+                        Err(e) => warnings.push(MappedTcpSocketMapWarning::from(e)),
+                    }
+                };
+            };
+        },
+        IpAddr::V6(ipv6_addr) => {
+            // If the socket address is unspecified add an address for every interface.
+            // Otherwise just use the interface with the socket's address.
+            let mut ifaces_v6 = mapping_context::interfaces_v6(&mc);
+            if !socket_utils::ipv6_is_unspecified(&ipv6_addr) {
+                ifaces_v6.retain(|iface_v6| iface_v6.addr == ipv6_addr);
+                if ifaces_v6.is_empty() {
+                    ifaces_v6.push(InterfaceV6 {
+                        pcp_server: None,
+                        addr: ipv6_addr,
                     });
                 }
+            }
+            for iface_v6 in ifaces_v6 {
+                let local_iface_addr = net::SocketAddr::V6(net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0));
+                // Global addresses are likely to be behind a stateful firewall unless we can
+                // get the router to open a pinhole for us.
+                let mut nat_restricted = socket_utils::ipv6_is_global(&iface_v6.addr);
+                if let Some(res) = port_mapping::pcp_or_nat_pmp_get_address(
+                    iface_v6.pcp_server.as_ref(),
+                    None,
+                    igd::PortMappingProtocol::TCP,
+                    local_addr.port()
+                ) {
+                    match res {
+                        Ok((external_addr, lease)) => {
+                            leases.push(lease);
+                            if external_addr == local_iface_addr {
+                                nat_restricted = false;
+                            }
+                            else {
+                                endpoints.push(MappedSocketAddr::new(
+                                    CandidateType::Mapped,
+                                    SocketAddr(external_addr),
+                                    SocketAddr(local_iface_addr),
+                                    false
+                                ));
+                            }
+                        },
+                        Err(e) => warnings.push(MappedTcpSocketMapWarning::MapPort { err: e }),
+                    }
+                }
+                endpoints.push(MappedSocketAddr::new(
+                    CandidateType::Host,
+                    SocketAddr(local_iface_addr),
+                    SocketAddr(local_iface_addr),
+                    nat_restricted
+                ));
+            };
+        },
+    };
+    // Take ownership of the mappings now so that they get removed if we bail out below.
+    let mapping_guard = PortMappingGuard::new(leases);
+    // Ask the simple servers what our external address is. The queries are all driven from this
+    // thread using non-blocking sockets, and we limit how many are in flight at once.
+    let mut queued_servers = VecDeque::new();
+    for simple_server in mapping_context::simple_tcp_servers(&mc) {
+        // TODO(canndrew): Remove this. Ideally we should use servers that are on private
+        // networks in case we're behind multiple private networks.
+        match simple_server.ip() {
+            IpAddr::V4(ipv4_addr) => {
+                if ipv4_addr.is_private() || ipv4_addr.is_loopback() {
+                    continue;
+                };
+            },
+            IpAddr::V6(ipv6_addr) => {
+                if ipv6_addr.is_loopback() {
+                    continue;
+                };
             },
         };
-        
-        let (results_tx, results_rx) = mpsc::channel();
-        let mut mapping_threads = Vec::new();
-        let simple_servers = mapping_context::simple_tcp_servers(&mc);
-        for simple_server in simple_servers {
-            // TODO(canndrew): Remove this. Ideally we should use servers that are on private
-            // networks in case we're behind multiple private networks. This will require using
-            // non-blocking IO however.
-            match simple_server.ip() {
-                IpAddr::V4(ipv4_addr) => {
-                    if ipv4_addr.is_private() || ipv4_addr.is_loopback() {
-                        continue;
-                    };
-                },
-                IpAddr::V6(ipv6_addr) => {
-                    if ipv6_addr.is_loopback() {
-                        continue;
-                    };
-                },
-            };
-            let results_tx = results_tx.clone();
-            mapping_threads.push(thread::spawn(move || {
-                let map = move || {
-                    let mapping_socket = match new_reusably_bound_tcp_socket(&local_addr) {
-                        Ok(mapping_socket) => mapping_socket,
-                        Err(e) => return Err(MappedTcpSocketMapWarning::NewReusablyBoundTcpSocket { err: e }),
-                    };
-                    let mut stream = match mapping_socket.connect(&*simple_server) {
-                        Ok(stream) => stream,
-                        Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketConnect {
-                            addr: simple_server,
-                            err: e
-                        }),
-                    };
-                    let send_data = listener_message::REQUEST_MAGIC_CONSTANT;
-                    // TODO(canndrew): What should we do if we get a partial write?
-                    let _ = match stream.write(&send_data[..]) {
-                        Ok(n) => n,
-                        Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketWrite { err: e }),
-                    };
+        queued_servers.push_back(simple_server);
+    }
 
-                    const MAX_DATAGRAM_SIZE: usize = 256;
-                    let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
-                    let n = match stream.read(&mut recv_data[..]) {
-                        Ok(n) => n,
-                        Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketRead { err: e }),
-                    };
-                    let listener_message::EchoExternalAddr { external_addr } = match deserialise::<listener_message::EchoExternalAddr>(&recv_data[..n]) {
-                        Ok(msg) => msg,
-                        Err(e) => return Err(MappedTcpSocketMapWarning::Deserialise {
-                            addr: simple_server,
-                            err: e,
-                            response: recv_data[..n].to_vec(),
-                        }),
-                    };
-                    Ok(external_addr)
-                };
-                let _ = results_tx.send(Some(map()));
-            }));
+    let mut queries: Vec<MappingQuery> = Vec::new();
+    let mut num_results = 0;
+    loop {
+        let now = Instant::now();
+        if now >= deadline || cancel.is_cancelled() {
+            break;
         }
-        let timeout_thread = thread!("MappedTcpSocket::map timeout", move || {
-            let now = Instant::now();
-            if deadline > now {
-                let timeout = deadline - now;
-                thread::park_timeout(timeout);
-            }
-            let _ = results_tx.send(None);
-        });
 
-        let mut num_results = 0;
-        for result in results_rx {
-            match result {
-                Some(Ok(external_addr)) => {
-                    endpoints.push(MappedSocketAddr {
-                        addr: external_addr,
-                        nat_restricted: true,
-                    });
+        while queries.len() < MAX_CONCURRENT_MAPPING_QUERIES {
+            let simple_server = match queued_servers.pop_front() {
+                Some(simple_server) => simple_server,
+                None => break,
+            };
+            match MappingQuery::start(&local_addr, simple_server) {
+                Ok(query) => queries.push(query),
+                Err(e) => warnings.push(e),
+            };
+        }
+
+        let mut i = 0;
+        while i < queries.len() {
+            match queries[i].progress() {
+                Ok(None) => {
+                    i += 1;
+                },
+                Ok(Some(external_addr)) => {
+                    let _ = queries.swap_remove(i);
+                    endpoints.push(MappedSocketAddr::new(
+                        CandidateType::ServerReflexive,
+                        external_addr,
+                        SocketAddr(local_addr),
+                        true
+                    ));
                     num_results += 1;
-                    if num_results >= 2 {
-                        break;
-                    }
                 },
-                Some(Err(e)) => {
+                Err(e) => {
+                    let _ = queries.swap_remove(i);
                     warnings.push(e);
                 },
-                None => {
+            }
+        }
+
+        if num_results >= 2 || (queries.is_empty() && queued_servers.is_empty()) {
+            break;
+        }
+        if queries.is_empty() {
+            continue;
+        }
+
+        // Wait for something to happen, waking up now and then to check whether we've been
+        // cancelled.
+        let wake_at = cmp::min(deadline,
+                               now + Duration::from_millis(utils::CANCEL_POLL_INTERVAL_MS));
+        let poll_sockets: Vec<PollSocket> = queries.iter().map(|q| q.poll_socket()).collect();
+        let now = Instant::now();
+        if wake_at > now {
+            match socket_utils::wait_until_ready(&poll_sockets, wake_at - now) {
+                Ok(()) => (),
+                Err(e) => {
+                    warnings.push(MappedTcpSocketMapWarning::Poll { err: e });
                     break;
                 },
-            }
+            };
         }
+    }
+    // Any queries that are still outstanding get aborted when their sockets are dropped here.
+    drop(queries);
 
-        timeout_thread.thread().unpark();
-        WOk(MappedTcpSocket {
-            socket: socket,
-            endpoints: endpoints,
-        }, warnings)
+    if cancel.is_cancelled() {
+        return WErr(MappedTcpSocketMapError::Cancelled);
     }
 
-    /// Create a new `MappedTcpSocket`
-    pub fn new(mc: &MappingContext, deadline: Instant)
-            -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
+    mapped_socket_addr::sort_by_priority(&mut endpoints);
+    WOk(MappedTcpSocket {
+        socket: socket,
+        endpoints: endpoints,
+        mapping_guard: mapping_guard,
+    }, warnings)
+}
+
+/// Create a new `MappedTcpSocket`, giving up if `cancel` gets triggered.
+pub fn new_cancellable(mc: &MappingContext, deadline: Instant, cancel: &CancellationToken)
+    -> WResult<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
+{
+    let unspec_addr = net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let socket = match new_reusably_bound_tcp_socket(&unspec_addr) {
+        Ok(socket) => socket,
+        Err(e) => return WErr(MappedTcpSocketNewError::NewReusablyBoundTcpSocket { err: e }),
+    };
+
+    map_cancellable(socket, mc, deadline, cancel)
+        .map_err(|e| MappedTcpSocketNewError::Map { err: e })
+}
+
+/// A query asking one of the simple tcp servers for our external address.
+struct MappingQuery {
+    server_addr: SocketAddr,
+    stream: TcpStream,
+    /// Whether we're still waiting for the connection to be established.
+    connecting: bool,
+    /// How many bytes of the request we've written.
+    written: usize,
+    /// As much of the response as we've read so far.
+    recv_data: Vec<u8>,
+}
+
+impl MappingQuery {
+    /// Start connecting to the server from a new socket bound to `local_addr`.
+    fn start(local_addr: &net::SocketAddr, server_addr: SocketAddr)
+            -> Result<MappingQuery, MappedTcpSocketMapWarning>
     {
-        let unspec_addr = net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
-        let socket = match new_reusably_bound_tcp_socket(&unspec_addr) {
-            Ok(socket) => socket,
-            Err(e) => return WErr(MappedTcpSocketNewError::NewReusablyBoundTcpSocket { err: e }),
+        let mapping_socket = match new_reusably_bound_tcp_socket(local_addr) {
+            Ok(mapping_socket) => mapping_socket,
+            Err(e) => return Err(MappedTcpSocketMapWarning::NewReusablyBoundTcpSocket { err: e }),
+        };
+        let connect = || {
+            let stream = try!(mapping_socket.to_tcp_stream());
+            try!(stream.set_nonblocking(true));
+            match net2::TcpStreamExt::connect(&stream, &*server_addr) {
+                Ok(()) => Ok(stream),
+                Err(ref e) if socket_utils::connect_in_progress(e) => Ok(stream),
+                Err(e) => Err(e),
+            }
         };
+        match connect() {
+            Ok(stream) => Ok(MappingQuery {
+                server_addr: server_addr,
+                stream: stream,
+                connecting: true,
+                written: 0,
+                recv_data: Vec::new(),
+            }),
+            Err(e) => Err(MappedTcpSocketMapWarning::MappingSocketConnect {
+                addr: server_addr,
+                err: e,
+            }),
+        }
+    }
 
-        MappedTcpSocket::map(socket, mc, deadline).map_err(|e| MappedTcpSocketNewError::Map { err: e })
+    /// Do as much of the query as we can without blocking. Returns `Ok(Some(external_addr))` once
+    /// the server has told us our external address.
+    fn progress(&mut self) -> Result<Option<SocketAddr>, MappedTcpSocketMapWarning> {
+        if self.connecting {
+            match socket_utils::finished_connecting(&self.stream) {
+                Ok(true) => self.connecting = false,
+                Ok(false) => return Ok(None),
+                Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketConnect {
+                    addr: self.server_addr,
+                    err: e,
+                }),
+            };
+        }
+        let send_data = listener_message::REQUEST_MAGIC_CONSTANT;
+        while self.written < send_data.len() {
+            match self.stream.write(&send_data[self.written..]) {
+                Ok(0) => return Err(MappedTcpSocketMapWarning::MappingSocketWrite {
+                    err: io::Error::new(io::ErrorKind::WriteZero, "failed to write request"),
+                }),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketWrite { err: e }),
+            };
+        }
+        // The server closes the connection after sending its response, but we don't need to wait
+        // for that if we can already make sense of what it's sent us.
+        let mut recv_buf = [0u8; MAX_MAPPING_RESPONSE_SIZE];
+        loop {
+            let closed = match self.stream.read(&mut recv_buf[..]) {
+                Ok(0) => true,
+                Ok(n) => {
+                    self.recv_data.extend_from_slice(&recv_buf[..n]);
+                    false
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(MappedTcpSocketMapWarning::MappingSocketRead { err: e }),
+            };
+            match deserialise::<listener_message::EchoExternalAddr>(&self.recv_data[..]) {
+                Ok(listener_message::EchoExternalAddr { external_addr }) => {
+                    return Ok(Some(external_addr));
+                },
+                Err(e) => {
+                    if closed || self.recv_data.len() >= MAX_MAPPING_RESPONSE_SIZE {
+                        return Err(MappedTcpSocketMapWarning::Deserialise {
+                            addr: self.server_addr,
+                            err: e,
+                            response: self.recv_data.clone(),
+                        });
+                    }
+                },
+            };
+        }
+    }
+
+    /// What `wait_until_ready` should wait for this query's socket to do.
+    fn poll_socket(&self) -> PollSocket {
+        if self.connecting || self.written < listener_message::REQUEST_MAGIC_CONSTANT.len() {
+            PollSocket::Write(&self.stream)
+        }
+        else {
+            PollSocket::Read(&self.stream)
+        }
     }
 }
 
 quick_error! {
     #[derive(Debug)]
     pub enum TcpPunchHoleWarning {
+        /// One of the peer's endpoints was dropped by the endpoint policy.
+        RejectedEndpoint { err: RejectedEndpoint } {
+            description("One of the peer's endpoints was dropped by the endpoint policy.")
+            display("One of the peer's endpoints was dropped by the endpoint policy: {}", err)
+            cause(err)
+        }
         /// Connecting to endpoint failed.
         Connect { peer_addr: SocketAddr, err: io::Error } {
             description("Connecting to endpoint failed.")
@@ -527,7 +780,7 @@ quick_error! {
             cause(err)
         }
         /// A connected host provided an invalid response to the handshake.
-        InvalidResponse { peer_addr: SocketAddr, data: [u8; 4] } {
+        InvalidResponse { peer_addr: SocketAddr, data: Vec<u8> } {
             description("A connected host provided an invalid response to the handshake.")
             display("The connected host at {} provided an invalid response to the handshake: {:?}", peer_addr, data)
         }
@@ -536,8 +789,10 @@ quick_error! {
 
 #[derive(Debug)]
 pub struct TcpPunchHoleBrokenStream {
-    peer_addr: SocketAddr,
-    error: io::Error,
+    /// The address of the peer at the other end of the stream.
+    pub peer_addr: SocketAddr,
+    /// The error the stream failed with.
+    pub error: io::Error,
 }
 
 impl fmt::Display for TcpPunchHoleBrokenStream {
@@ -578,6 +833,28 @@ quick_error! {
             description("Multiple streams were successfully punched to the peer but all of them died.")
             display("Multiple streams were successfully punched to the peer but all of them died. {}", DisplaySlice("broken stream", &errors))
         }
+        /// Error waiting for sockets to become ready.
+        Poll { err: io::Error } {
+            description("Error waiting for sockets to become ready.")
+            display("Error waiting for sockets to become ready: {}", err)
+            cause(err)
+        }
+        /// Error registering the punched stream with an event loop.
+        RegisterStream { err: io::Error } {
+            description("Error registering the punched stream with an event loop.")
+            display("Error registering the punched stream with an event loop: {}", err)
+            cause(err)
+        }
+        /// Tcp hole punching was cancelled through a `CancellationToken`.
+        Cancelled {
+            description("Tcp hole punching was cancelled")
+        }
+        /// Error exchanging session keys with the peer over the punched stream.
+        KeyExchange { err: KeyExchangeError } {
+            description("Error exchanging session keys with the peer over the punched stream.")
+            display("Error exchanging session keys with the peer over the punched stream: {}", err)
+            cause(err)
+        }
     }
 }
 
@@ -594,6 +871,13 @@ impl From<TcpPunchHoleError> for io::Error {
             TcpPunchHoleError::TimedOut { .. } => io::ErrorKind::TimedOut,
             TcpPunchHoleError::DecideStream { errors }
                 => errors.first().map(|bs| bs.error.kind()).unwrap_or(io::ErrorKind::Other),
+            TcpPunchHoleError::Poll { err } => err.kind(),
+            TcpPunchHoleError::RegisterStream { err } => err.kind(),
+            TcpPunchHoleError::Cancelled => io::ErrorKind::Other,
+            TcpPunchHoleError::KeyExchange { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
         };
         io::Error::new(kind, err_str)
     }
@@ -606,348 +890,619 @@ pub fn tcp_punch_hole(socket: net2::TcpBuilder,
                       their_pub_rendezvous_info: PubRendezvousInfo,
                       deadline: Instant)
                       -> WResult<TcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
-    // In order to do tcp hole punching we connect to all of their endpoints in parallel while
-    // simultaneously listening. All the sockets we use must be bound to the same local address. As
-    // soon as we successfully connect and exchange secrets, or accept and exchange secrets, we
-    // return.
-    //
-    // It would be way better to implement this using non-blocking sockets but at the moment mio
-    // doesn't provide any way to convert a non-blocking socket back into a blocking socket. So
-    // we're stuck with spawning (and then detaching) loads of threads. Setting the read/write
-    // timeouts should prevent the detached threads from leaking indefinitely.
+    tcp_punch_hole_cancellable(socket,
+                               our_priv_rendezvous_info,
+                               their_pub_rendezvous_info,
+                               deadline,
+                               &CancellationToken::new())
+}
 
-    let mut warnings = Vec::new();
+/// A tcp stream punched with `tcp_punch_hole_mapped`, along with the port mappings made for the
+/// socket it was punched from.
+pub struct PunchedTcpStream {
+    /// The stream connected to the peer.
+    pub stream: TcpStream,
+    /// Owns the port mappings made for the stream's socket. The mappings are removed when this is
+    /// dropped so keep it alive for as long as the stream is in use.
+    pub mapping_guard: PortMappingGuard,
+}
 
-    let shutdown = Arc::new(AtomicBool::new(false));
+/// Perform a tcp rendezvous connect with a mapped socket. The socket's port mappings are handed on
+/// to the punched stream, or removed if punching fails.
+pub fn tcp_punch_hole_mapped(mapped_socket: MappedTcpSocket,
+                             our_priv_rendezvous_info: PrivRendezvousInfo,
+                             their_pub_rendezvous_info: PubRendezvousInfo,
+                             deadline: Instant)
+                             -> WResult<PunchedTcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+    let MappedTcpSocket { socket, mapping_guard, .. } = mapped_socket;
+    match tcp_punch_hole(socket, our_priv_rendezvous_info, their_pub_rendezvous_info, deadline) {
+        WOk(stream, warnings) => {
+            WOk(PunchedTcpStream {
+                stream: stream,
+                mapping_guard: mapping_guard,
+            }, warnings)
+        },
+        WErr(e) => WErr(e),
+    }
+}
 
-    // The channel we will use to collect the results from the many worker threads.
-    let (results_tx, results_rx) = mpsc::channel::<Option<Result<(TcpStream, SocketAddr), TcpPunchHoleWarning>>>();
+/// Perform a tcp rendezvous connect then exchange session keys with the peer over the punched
+/// stream. Both rendezvous infos must have been generated with `gen_rendezvous_info_with_key_pair`.
+pub fn tcp_punch_hole_secure(socket: net2::TcpBuilder,
+                             our_priv_rendezvous_info: PrivRendezvousInfo,
+                             their_pub_rendezvous_info: PubRendezvousInfo,
+                             deadline: Instant)
+                             -> WResult<(TcpStream, SessionKeys),
+                                        TcpPunchHoleWarning,
+                                        TcpPunchHoleError> {
+    let key_exchange = match KeyExchange::new(&our_priv_rendezvous_info,
+                                              &their_pub_rendezvous_info) {
+        Ok(key_exchange) => key_exchange,
+        Err(e) => return WErr(TcpPunchHoleError::KeyExchange { err: e }),
+    };
+    let (mut stream, warnings) = match tcp_punch_hole(socket,
+                                                      our_priv_rendezvous_info,
+                                                      their_pub_rendezvous_info,
+                                                      deadline) {
+        WOk(stream, warnings) => (stream, warnings),
+        WErr(e) => return WErr(e),
+    };
+    match exchange_keys(&mut stream, &key_exchange, deadline) {
+        Ok(session_keys) => WOk((stream, session_keys), warnings),
+        Err(e) => WErr(TcpPunchHoleError::KeyExchange { err: e }),
+    }
+}
 
-    let our_secret = rendezvous_info::get_priv_secret(our_priv_rendezvous_info);
-    let (their_endpoints, their_secret) = rendezvous_info::decompose(their_pub_rendezvous_info);
+/// Send our half of a key exchange to the peer and read theirs. Messages are prefixed with their
+/// length.
+fn exchange_keys(stream: &mut TcpStream, key_exchange: &KeyExchange, deadline: Instant)
+                 -> Result<SessionKeys, KeyExchangeError> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(KeyExchangeError::TimedOut);
+    }
+    let timeout = Some(deadline - now);
+    try!(stream.set_write_timeout(timeout).map_err(|e| KeyExchangeError::Io { err: e }));
+    try!(stream.set_read_timeout(timeout).map_err(|e| KeyExchangeError::Io { err: e }));
 
-    let local_addr = match socket_utils::tcp_builder_local_addr(&socket) {
-        Ok(local_addr) => local_addr,
-        Err(e) => return WErr(TcpPunchHoleError::SocketLocalAddr { err: e }),
+    let send_data = key_exchange.our_half(false);
+    let mut recv_data = [0u8; MAX_KEY_EXCHANGE_MSG_SIZE];
+    let res = stream.write_u16::<BigEndian>(send_data.len() as u16)
+                    .and_then(|()| stream.write_all(&send_data[..]))
+                    .and_then(|()| stream.read_u16::<BigEndian>());
+    let res = match res {
+        Ok(len) if len as usize <= MAX_KEY_EXCHANGE_MSG_SIZE => {
+            let len = len as usize;
+            stream.read_exact(&mut recv_data[..len]).map(|()| len)
+        },
+        Ok(_) => return Err(KeyExchangeError::InvalidMessage),
+        Err(e) => Err(e),
     };
-
-    // Try connecting to every potential endpoint in a seperate thread.
-    for endpoint in their_endpoints {
-        let addr = endpoint.addr;
-        // Important to call new_reusably_bound_tcp_socket outside the inner thread so that it's called
-        // before the listen() call below.
-        let mapping_socket = match new_reusably_bound_tcp_socket(&local_addr) {
-            Ok(mapping_socket) => mapping_socket,
-            Err(e) => return WErr(TcpPunchHoleError::NewReusablyBoundTcpSocket { err: e }),
-        };
-        let results_tx_clone = results_tx.clone();
-        let shutdown_clone = shutdown.clone();
-        let _ = thread!("tcp_punch_hole connect", move || {
-            let f = |timeout| {
-                let mut stream = match mapping_socket.connect(&*addr) {
-                    Ok(stream) => stream,
-                    Err(e) => return Err(TcpPunchHoleWarning::Connect {
-                        peer_addr: addr,
-                        err: e,
-                    }),
-                };
-                match stream.set_write_timeout(Some(timeout)) {
-                    Ok(()) => (),
-                    Err(e) => return Err(TcpPunchHoleWarning::StreamSetTimeout { err: e }),
-                };
-                match stream.set_read_timeout(Some(timeout)) {
-                    Ok(()) => (),
-                    Err(e) => return Err(TcpPunchHoleWarning::StreamSetTimeout { err: e }),
-                };
-                match stream.write_all(&our_secret[..]) {
-                    Ok(()) => (),
-                    Err(e) => return Err(TcpPunchHoleWarning::StreamIo {
-                        peer_addr: addr,
-                        err: e,
-                    }),
-                };
-                let mut recv_data = [0u8; 4];
-                match stream.read_exact(&mut recv_data[..]) {
-                    Ok(()) => (),
-                    Err(e) => return Err(TcpPunchHoleWarning::StreamIo {
-                        peer_addr: addr,
-                        err: e,
-                    }),
-                };
-                if recv_data != their_secret {
-                    return Err(TcpPunchHoleWarning::InvalidResponse {
-                        peer_addr: addr,
-                        data: recv_data,
-                    });
-                };
-                match stream.set_write_timeout(None) {
-                    Ok(()) => (),
-                    Err(e) => return Err(TcpPunchHoleWarning::StreamSetTimeout { err: e }),
-                };
-                match stream.set_read_timeout(None) {
-                    Ok(()) => (),
-                    Err(e) => return Err(TcpPunchHoleWarning::StreamSetTimeout { err: e }),
-                };
-                Ok(stream)
-            };
-            loop {
-                let now = Instant::now();
-                if now >= deadline || shutdown_clone.load(Ordering::SeqCst) {
-                    break;
-                }
-                else {
-                    let timeout = deadline - now;
-                    match f(timeout) {
-                        Ok(stream) => {
-                            let _ = results_tx_clone.send(Some(Ok((stream, addr))));
-                            break;
-                        },
-                        Err(e) => {
-                            let _ = results_tx_clone.send(Some(Err(e)));
-                            // So we don't continuously hammer an address we can't connect to.
-                            thread::sleep(Duration::from_secs(1));
-                            continue;
-                        },
-                    }
-                }
-            }
-        });
+    let len = match res {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                      e.kind() == io::ErrorKind::TimedOut => {
+            return Err(KeyExchangeError::TimedOut);
+        },
+        Err(e) => return Err(KeyExchangeError::Io { err: e }),
     };
+    let their_half = try!(key_exchange.their_half(&recv_data[..len]));
 
-    // Listen for incoming connections.
-    let listener = match socket.listen(128) {
-        Ok(listener) => listener,
+    try!(stream.set_write_timeout(None).map_err(|e| KeyExchangeError::Io { err: e }));
+    try!(stream.set_read_timeout(None).map_err(|e| KeyExchangeError::Io { err: e }));
+    Ok(key_exchange.session_keys(&their_half))
+}
+
+/// Perform a tcp rendezvous connect. Returns `TcpPunchHoleError::Cancelled` if `cancel` gets
+/// triggered before hole punching completes.
+pub fn tcp_punch_hole_cancellable(socket: net2::TcpBuilder,
+                                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                                  their_pub_rendezvous_info: PubRendezvousInfo,
+                                  deadline: Instant,
+                                  cancel: &CancellationToken)
+                                  -> WResult<TcpStream, TcpPunchHoleWarning, TcpPunchHoleError> {
+    // In order to do tcp hole punching we connect to all of their endpoints in parallel while
+    // simultaneously listening. All the sockets we use must be bound to the same local address. As
+    // soon as we successfully connect and authenticate the peer, or accept and authenticate the
+    // peer, we return.
+    //
+    // All the connecting, accepting and authenticating is done with non-blocking sockets
+    // from a single loop so that we don't leave anything running in the background once we
+    // return.
+
+    let TcpPunchSockets {
+        mut warnings,
+        our_secret,
+        their_secret,
+        mut targets,
+        listener,
+    } = match tcp_punch_sockets(socket, our_priv_rendezvous_info, their_pub_rendezvous_info) {
+        Ok(sockets) => sockets,
+        Err(e) => return WErr(e),
+    };
+    match listener.set_nonblocking(true) {
+        Ok(()) => (),
         Err(e) => return WErr(TcpPunchHoleError::Listen { err: e }),
     };
-    let results_tx_clone = results_tx.clone();
-    let shutdown_clone = shutdown.clone();
-    let _ = thread!("tcp_punch_hole listen", move || {
-        loop {
-            let res = listener.accept();
-            // First, check if we should shutdown.
-            if shutdown_clone.load(Ordering::SeqCst) {
-                break;
-            };
-            let (mut stream, addr) = match res {
-                Ok(x) => x,
-                Err(e) => {
-                    match results_tx_clone.send(Some(Err(TcpPunchHoleWarning::Accept { err: e }))) {
-                        Ok(()) => (),
-                        Err(_) => {
-                            break;
-                        },
-                    }
-                    continue;
-                },
-            };
+    let local_addr = match listener.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(e) => return WErr(TcpPunchHoleError::SocketLocalAddr { err: e }),
+    };
 
-            // Spawn a new thread here to prevent someone from connecting then not sending any data
-            // and preventing us from accepting any more connections.
-            let results_tx_clone = results_tx_clone.clone();
-            let now = Instant::now();
-            if now >= deadline {
-                break;
-            };
-            let _ = thread!("tcp_punch_hole listen handshake", move || {
-                let timeout = deadline - now;
-                match stream.set_write_timeout(Some(timeout)) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::StreamSetTimeout { err: e })));
-                        return;
-                    },
+    let mut pending: Vec<PunchStream<TcpStream>> = Vec::new();
+    let mut punched: Vec<(TcpStream, SocketAddr)> = Vec::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline || cancel.is_cancelled() {
+            break;
+        }
+
+        if punched.is_empty() {
+            // Start any connection attempts that are due.
+            for (i, target) in targets.iter_mut().enumerate() {
+                if target.connect_at > now {
+                    continue;
+                }
+                let stream = match target.socket.take() {
+                    Some(stream) => stream,
+                    None => continue,
                 };
-                match stream.set_read_timeout(Some(timeout)) {
+                let res = net2::TcpStreamExt::connect(&stream, &*target.addr);
+                match res {
                     Ok(()) => (),
+                    Err(ref e) if socket_utils::connect_in_progress(e) => (),
                     Err(e) => {
-                        let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::StreamSetTimeout { err: e })));
-                        return;
+                        warnings.push(TcpPunchHoleWarning::Connect {
+                            peer_addr: target.addr,
+                            err: e,
+                        });
+                        // So we don't continuously hammer an address we can't connect to.
+                        if let Err(e) = retry_later(target, &local_addr, now) {
+                            warnings.push(TcpPunchHoleWarning::Connect {
+                                peer_addr: target.addr,
+                                err: e,
+                            });
+                        }
+                        continue;
                     },
                 };
-                match stream.write_all(&our_secret[..]) {
-                    Ok(()) => (),
+                pending.push(PunchStream::connecting(stream, target.addr, i));
+            }
+
+            // Accept any incoming connections.
+            loop {
+                let (stream, addr) = match listener.accept() {
+                    Ok(x) => x,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
                     Err(e) => {
-                        let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::StreamIo {
-                            peer_addr: SocketAddr(addr),
-                            err: e,
-                        })));
-                        return;
+                        warnings.push(TcpPunchHoleWarning::Accept { err: e });
+                        break;
                     },
                 };
-                let mut recv_data = [0u8; 4];
-                match stream.read_exact(&mut recv_data[..]) {
+                match stream.set_nonblocking(true) {
                     Ok(()) => (),
                     Err(e) => {
-                        let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::StreamIo {
+                        warnings.push(TcpPunchHoleWarning::StreamIo {
                             peer_addr: SocketAddr(addr),
                             err: e,
-                        })));
-                        return;
+                        });
+                        continue;
                     },
                 };
-                if recv_data != their_secret {
-                    let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::InvalidResponse {
-                        peer_addr: SocketAddr(addr),
-                        data: recv_data,
-                    })));
-                    return;
+                pending.push(PunchStream::accepted(stream, SocketAddr(addr)));
+            }
+        }
+
+        // Move every connection along as far as it can go without blocking.
+        let mut i = 0;
+        while i < pending.len() {
+            match pending[i].progress(&our_secret, &their_secret) {
+                Ok(false) => {
+                    i += 1;
+                },
+                Ok(true) => {
+                    let punch_stream = pending.swap_remove(i);
+                    punched.push((punch_stream.stream, punch_stream.peer_addr));
+                },
+                Err(e) => {
+                    let punch_stream = pending.swap_remove(i);
+                    warnings.push(e);
+                    // If we never managed to connect then try again later.
+                    if let (true, Some(target)) = (punch_stream.connecting, punch_stream.target) {
+                        let target = &mut targets[target];
+                        if let Err(e) = retry_later(target, &local_addr, now) {
+                            warnings.push(TcpPunchHoleWarning::Connect {
+                                peer_addr: target.addr,
+                                err: e,
+                            });
+                        }
+                    }
+                },
+            }
+        }
+
+        if !punched.is_empty() {
+            // Once we have a connection we stop making new ones. But connections which are already
+            // authenticating may get counted by the peer, so give them a chance to finish.
+            pending.retain(|punch_stream| !punch_stream.connecting);
+            if pending.is_empty() {
+                break;
+            }
+        }
+
+        // Wait for something to happen, waking up now and then to check whether we've been
+        // cancelled.
+        let mut wake_at = cmp::min(deadline,
+                                   now + Duration::from_millis(utils::CANCEL_POLL_INTERVAL_MS));
+        let mut poll_sockets: Vec<PollSocket> = pending.iter().map(|ps| ps.poll_socket()).collect();
+        if punched.is_empty() {
+            poll_sockets.push(PollSocket::Accept(&listener));
+            for target in &targets {
+                if target.socket.is_some() {
+                    wake_at = cmp::min(wake_at, target.connect_at);
                 }
-                match stream.set_write_timeout(None) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::StreamSetTimeout { err: e })));
-                        return;
-                    },
-                };
-                match stream.set_read_timeout(None) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        let _ = results_tx_clone.send(Some(Err(TcpPunchHoleWarning::StreamSetTimeout { err: e })));
-                        return;
-                    },
-                };
-                let _ = results_tx_clone.send(Some(Ok((stream, SocketAddr(addr)))));
-            });
+            }
         }
-    });
-    
-    // Create a separate thread for timing out.
-    // TODO(canndrew): We won't need to do this one this is fixed: https://github.com/rust-lang/rfcs/issues/962
-    let results_tx_clone = results_tx.clone();
-    let timeout_thread = thread!("tcp_punch_hole timeout", move || {
         let now = Instant::now();
-        if deadline > now {
-            let timeout = deadline - now;
-            thread::park_timeout(timeout);
+        if wake_at > now {
+            match socket_utils::wait_until_ready(&poll_sockets, wake_at - now) {
+                Ok(()) => (),
+                Err(e) => return WErr(TcpPunchHoleError::Poll { err: e }),
+            };
         }
-        let _ = results_tx_clone.send(None);
-    });
-    let timeout_thread_handle = timeout_thread.thread();
+    }
 
-    drop(results_tx);
-    let acceptor_addr = net::SocketAddr::new(socket_utils::ip_unspecified_to_loopback(local_addr.ip()),
-                                             local_addr.port());
-    // Process the results that the worker threads send us.
-    loop {
-        match results_rx.recv() {
-            // All the senders have closed. This could only happen if all of the worker threads
-            // panicked. Propogate the panic.
-            Err(_) => panic!("In tcp_punch_hole results_rx.recv() returned Err"),
-
-            // We timed out.
-            Ok(None) => {
-                timeout_thread_handle.unpark();
-                shutdown.store(true, Ordering::SeqCst);
-                let _ = TcpStream::connect(&acceptor_addr);
-                return WErr(TcpPunchHoleError::TimedOut { warnings: warnings });
-            },
-            
-            // One of the worker threads raised a warning.
-            Ok(Some(Err(e))) => {
-                warnings.push(e);
+    if cancel.is_cancelled() {
+        return WErr(TcpPunchHoleError::Cancelled);
+    }
+    if punched.is_empty() {
+        return WErr(TcpPunchHoleError::TimedOut { warnings: warnings });
+    }
+
+    // Give the caller back ordinary blocking streams.
+    let mut errors = Vec::new();
+    let mut streams = Vec::new();
+    let multiple_streams = punched.len() > 1;
+    for (stream, stream_addr) in punched {
+        match stream.set_nonblocking(false) {
+            Ok(()) => streams.push((stream, stream_addr)),
+            Err(e) => {
+                errors.push(TcpPunchHoleBrokenStream {
+                    peer_addr: stream_addr,
+                    error: e,
+                });
             },
+        }
+    }
 
-            // Success!
-            Ok(Some(Ok((stream, stream_addr)))) => {
-                shutdown.store(true, Ordering::SeqCst);
-                // Cause the acceptor to shut down.
-                let _ = TcpStream::connect(&acceptor_addr);
-
-                let mut other_streams = Vec::new();
-                // Collect any other successful connections that may have occured simultaneously to
-                // the one we've got.
-                for stream_res_opt in results_rx {
-                    match stream_res_opt {
-                        // Timed out. Assume all other connections failed.
-                        None => break,
-                        // A worker thread had an error. But we don't care because we've already
-                        // got a successful connection.
-                        Some(Err(_)) => (),
-                        // We made another connection.
-                        Some(Ok((stream, stream_addr))) => other_streams.push((stream, stream_addr)),
-                    };
-                }
+    if !multiple_streams {
+        return match streams.pop() {
+            Some((stream, _)) => WOk(stream, warnings),
+            None => WErr(TcpPunchHoleError::DecideStream { errors: errors }),
+        };
+    }
 
-                timeout_thread_handle.unpark();
+    // We have more than one stream. Both sides need to agree on which stream to keep and which
+    // streams to discard. To decide, we write and read a random u64 to each stream, sum the read
+    // and written values, and take the stream with the highest sum.
 
-                if other_streams.len() == 0 {
-                    return WOk(stream, warnings);
+    // Write the random u64 to each stream.
+    let streams: Vec<(TcpStream, SocketAddr, u64)> = streams.into_iter().filter_map(|(mut stream, stream_addr)| {
+        let w = random();
+        match stream.write_u64::<BigEndian>(w) {
+            Ok(()) => (),
+            Err(e) => {
+                errors.push(TcpPunchHoleBrokenStream {
+                    peer_addr: stream_addr,
+                    error: e,
+                });
+                return None;
+            },
+        };
+        Some((stream, stream_addr, w))
+    }).collect();
+
+    // Read the random u64 from each stream while keeping hold of the stream with the highest sum
+    // so far.
+    let stream_opt = streams.into_iter().fold(None, |opt, (mut this_stream, this_stream_addr, w)| {
+        // Calculate the sum for this stream.
+        let this_sum = match this_stream.read_u64::<BigEndian>() {
+            Ok(r) => r.wrapping_add(w),
+            Err(e) => {
+                errors.push(TcpPunchHoleBrokenStream {
+                    peer_addr: this_stream_addr,
+                    error: e,
+                });
+                return opt;
+            },
+        };
+        // If the sum is greater than the current highest (or we don't have a current highest
+        // yet), replace the highest stream and sum with this stream and sum.
+        match opt {
+            Some((top_stream, top_sum)) => {
+                if this_sum > top_sum {
+                    Some((this_stream, this_sum))
                 }
                 else {
-                    // We have more than one stream. Both sides need to agree on which stream to
-                    // keep and which streams to discard. To decide, we write and read a random u64
-                    // to each stream, sum the read and written values, and take the stream with
-                    // the highest sum.
-                    
-                    let mut errors = Vec::new();
-                    other_streams.push((stream, stream_addr));
-
-                    // Write the random u64 to each stream.
-                    let streams: Vec<(TcpStream, SocketAddr, u64)> = other_streams.into_iter().filter_map(|(mut stream, stream_addr)| {
-                        let w = random();
-                        match stream.write_u64::<BigEndian>(w) {
-                            Ok(()) => (),
-                            Err(e) => {
-                                errors.push(TcpPunchHoleBrokenStream {
-                                    peer_addr: stream_addr,
-                                    error: e,
-                                });
-                                return None;
-                            },
-                        };
-                        Some((stream, stream_addr, w))
-                    }).collect();
-
-                    // Read the random u64 from each stream while keeping hold of the stream with
-                    // the highest sum so far.
-                    let stream_opt = streams.into_iter().fold(None, |opt, (mut this_stream, this_stream_addr, w)| {
-                        // Calculate the sum for this stream.
-                        let this_sum = match this_stream.read_u64::<BigEndian>() {
-                            Ok(r) => r.wrapping_add(w),
-                            Err(e) => {
-                                errors.push(TcpPunchHoleBrokenStream {
-                                    peer_addr: this_stream_addr,
-                                    error: e,
-                                });
-                                return opt;
-                            },
-                        };
-                        // If the sum is greater than the current highest (or we don't have a
-                        // current highest yet), replace the highest stream and sum with this
-                        // stream and sum.
-                        match opt {
-                            Some((top_stream, top_sum)) => {
-                                if this_sum > top_sum {
-                                    Some((this_stream, this_sum))
-                                }
-                                else {
-                                    Some((top_stream, top_sum))
-                                }
-                            },
-                            None => Some((this_stream, this_sum))
-                        }
-                    });
+                    Some((top_stream, top_sum))
+                }
+            },
+            None => Some((this_stream, this_sum))
+        }
+    });
 
-                    match stream_opt {
-                        // Return the chosen stream.
-                        Some((stream, _sum)) => {
-                            warnings.extend(errors.into_iter().map(|bs| {
-                                TcpPunchHoleWarning::StreamIo {
-                                    peer_addr: bs.peer_addr,
-                                    err: bs.error,
-                                }
-                            }));
-                            return WOk(stream, warnings);
-                        },
-                        // Every stream died while deciding which stream to use.
-                        None => {
-                            return WErr(TcpPunchHoleError::DecideStream {
-                                errors: errors,
-                            });
-                        }
-                    }
+    match stream_opt {
+        // Return the chosen stream.
+        Some((stream, _sum)) => {
+            warnings.extend(errors.into_iter().map(|bs| {
+                TcpPunchHoleWarning::StreamIo {
+                    peer_addr: bs.peer_addr,
+                    err: bs.error,
                 }
+            }));
+            WOk(stream, warnings)
+        },
+        // Every stream died while deciding which stream to use.
+        None => {
+            WErr(TcpPunchHoleError::DecideStream {
+                errors: errors,
+            })
+        }
+    }
+}
+
+/// The sockets `tcp_punch_hole` starts out with, all bound to the same local address, along with
+/// the secrets for authenticating the peer.
+pub struct TcpPunchSockets {
+    pub warnings: Vec<TcpPunchHoleWarning>,
+    pub our_secret: Secret,
+    pub their_secret: Secret,
+    /// The peer's endpoints, most promising first.
+    pub targets: Vec<ConnectTarget>,
+    pub listener: net::TcpListener,
+}
+
+/// Create a non-blocking socket for connecting to each of the peer's endpoints, then start
+/// listening on `socket`.
+pub fn tcp_punch_sockets(socket: net2::TcpBuilder,
+                         our_priv_rendezvous_info: PrivRendezvousInfo,
+                         mut their_pub_rendezvous_info: PubRendezvousInfo)
+                         -> Result<TcpPunchSockets, TcpPunchHoleError> {
+    let mut warnings: Vec<TcpPunchHoleWarning>
+        = their_pub_rendezvous_info.apply_endpoint_policy(&EndpointPolicy::default())
+                                   .into_iter()
+                                   .map(|e| TcpPunchHoleWarning::RejectedEndpoint { err: e })
+                                   .collect();
+
+    let our_secret = rendezvous_info::get_priv_secret(our_priv_rendezvous_info);
+    let (mut their_endpoints, their_secret)
+        = rendezvous_info::decompose(their_pub_rendezvous_info);
+    // Start connecting to the most promising endpoints first.
+    mapped_socket_addr::sort_by_priority(&mut their_endpoints);
+
+    let local_addr = match socket_utils::tcp_builder_local_addr(&socket) {
+        Ok(local_addr) => local_addr,
+        Err(e) => return Err(TcpPunchHoleError::SocketLocalAddr { err: e }),
+    };
+
+    // Create a socket for connecting to each of their endpoints. Important to do this before the
+    // listen() call below.
+    let now = Instant::now();
+    let mut targets = Vec::new();
+    for endpoint in their_endpoints {
+        let mapping_socket = match new_reusably_bound_tcp_socket(&local_addr) {
+            Ok(mapping_socket) => mapping_socket,
+            Err(e) => return Err(TcpPunchHoleError::NewReusablyBoundTcpSocket { err: e }),
+        };
+        let stream = match mapping_socket.to_tcp_stream() {
+            Ok(stream) => stream,
+            Err(e) => {
+                warnings.push(TcpPunchHoleWarning::Connect {
+                    peer_addr: endpoint.addr,
+                    err: e,
+                });
+                continue;
             },
+        };
+        match stream.set_nonblocking(true) {
+            Ok(()) => (),
+            Err(e) => {
+                warnings.push(TcpPunchHoleWarning::Connect {
+                    peer_addr: endpoint.addr,
+                    err: e,
+                });
+                continue;
+            },
+        };
+        targets.push(ConnectTarget {
+            addr: endpoint.addr,
+            socket: Some(stream),
+            connect_at: now,
+        });
+    };
+
+    // Listen for incoming connections.
+    let listener = match socket.listen(128) {
+        Ok(listener) => listener,
+        Err(e) => return Err(TcpPunchHoleError::Listen { err: e }),
+    };
+
+    Ok(TcpPunchSockets {
+        warnings: warnings,
+        our_secret: our_secret,
+        their_secret: their_secret,
+        targets: targets,
+        listener: listener,
+    })
+}
+
+/// One of the peer's endpoints that `tcp_punch_hole` is trying to connect to.
+pub struct ConnectTarget {
+    pub addr: SocketAddr,
+    /// The unconnected socket to connect with. `None` while a connection attempt is in progress or
+    /// once we've given up on this endpoint.
+    pub socket: Option<TcpStream>,
+    /// When to next try connecting.
+    pub connect_at: Instant,
+}
+
+/// Give `target` a new socket bound to `local_addr` to connect with after a while. The failed
+/// attempt used up the old socket: connecting a socket again after a failed connect isn't
+/// portable.
+fn retry_later(target: &mut ConnectTarget, local_addr: &net::SocketAddr, now: Instant)
+               -> io::Result<()> {
+    let socket = try!(new_reusably_bound_tcp_socket(local_addr).map_err(io::Error::from));
+    let stream = try!(socket.to_tcp_stream());
+    try!(stream.set_nonblocking(true));
+    target.socket = Some(stream);
+    target.connect_at = now + Duration::from_secs(CONNECT_RETRY_DELAY_SECS);
+    Ok(())
+}
+
+/// A connection that `tcp_punch_hole` is authenticating the peer over. Both sides send a fresh
+/// nonce, then a MAC of both nonces keyed with their own secret. This proves to each side that
+/// the other knows the secret from its rendezvous info without the secret being sent.
+pub struct PunchStream<S> {
+    pub stream: S,
+    pub peer_addr: SocketAddr,
+    /// The index of the `ConnectTarget` this stream was created for, if it's an outgoing
+    /// connection.
+    target: Option<usize>,
+    /// Whether we're still waiting for an outgoing connection to be established.
+    connecting: bool,
+    our_nonce: Nonce,
+    /// Our nonce, followed by our MAC once we've received their nonce.
+    send_data: Vec<u8>,
+    /// How many bytes of `send_data` we've written.
+    written: usize,
+    /// Their nonce followed by their MAC, once we've read all of it.
+    recv_data: [u8; NONCE_LEN + MAC_LEN],
+    /// How many bytes of `recv_data` we've read.
+    read: usize,
+}
+
+impl PunchStream<TcpStream> {
+    fn connecting(stream: TcpStream, peer_addr: SocketAddr, target: usize)
+                  -> PunchStream<TcpStream> {
+        let mut punch_stream = PunchStream::accepted(stream, peer_addr);
+        punch_stream.target = Some(target);
+        punch_stream.connecting = true;
+        punch_stream
+    }
+
+    /// Do as much of the connect and handshake as we can without blocking. Returns `Ok(true)` once
+    /// the peer has proven that it knows `their_secret`.
+    fn progress(&mut self, our_secret: &Secret, their_secret: &Secret)
+            -> Result<bool, TcpPunchHoleWarning>
+    {
+        if self.connecting {
+            match socket_utils::finished_connecting(&self.stream) {
+                Ok(true) => self.connecting = false,
+                Ok(false) => return Ok(false),
+                Err(e) => return Err(TcpPunchHoleWarning::Connect {
+                    peer_addr: self.peer_addr,
+                    err: e,
+                }),
+            };
+        }
+        self.handshake(our_secret, their_secret)
+    }
+
+    /// What `wait_until_ready` should wait for this stream to do.
+    fn poll_socket(&self) -> PollSocket {
+        if self.connecting || self.written < self.send_data.len() {
+            PollSocket::Write(&self.stream)
+        }
+        else {
+            PollSocket::Read(&self.stream)
+        }
+    }
+}
+
+impl<S: Read + Write> PunchStream<S> {
+    /// Start authenticating the peer over a connected stream.
+    pub fn accepted(stream: S, peer_addr: SocketAddr) -> PunchStream<S> {
+        let our_nonce = secret::gen_nonce();
+        PunchStream {
+            stream: stream,
+            peer_addr: peer_addr,
+            target: None,
+            connecting: false,
+            our_nonce: our_nonce,
+            send_data: our_nonce.to_vec(),
+            written: 0,
+            recv_data: [0u8; NONCE_LEN + MAC_LEN],
+            read: 0,
+        }
+    }
+
+    /// Do as much of the handshake as we can without blocking. Returns `Ok(true)` once the peer
+    /// has proven that it knows `their_secret`.
+    pub fn handshake(&mut self, our_secret: &Secret, their_secret: &Secret)
+                     -> Result<bool, TcpPunchHoleWarning>
+    {
+        let peer_addr = self.peer_addr;
+        loop {
+            while self.written < self.send_data.len() {
+                match self.stream.write(&self.send_data[self.written..]) {
+                    Ok(0) => return Err(TcpPunchHoleWarning::StreamIo {
+                        peer_addr: peer_addr,
+                        err: io::Error::new(io::ErrorKind::WriteZero,
+                                            "failed to write handshake"),
+                    }),
+                    Ok(n) => self.written += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(TcpPunchHoleWarning::StreamIo {
+                        peer_addr: peer_addr,
+                        err: e,
+                    }),
+                };
+            }
+            // We can't send our MAC until we know their nonce.
+            let recv_len = if self.send_data.len() == NONCE_LEN {
+                NONCE_LEN
+            }
+            else {
+                NONCE_LEN + MAC_LEN
+            };
+            while self.read < recv_len {
+                match self.stream.read(&mut self.recv_data[self.read..recv_len]) {
+                    Ok(0) => return Err(TcpPunchHoleWarning::StreamIo {
+                        peer_addr: peer_addr,
+                        err: io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed \
+                                                                         during handshake"),
+                    }),
+                    Ok(n) => self.read += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(TcpPunchHoleWarning::StreamIo {
+                        peer_addr: peer_addr,
+                        err: e,
+                    }),
+                };
+            }
+            let mut their_nonce = [0u8; NONCE_LEN];
+            their_nonce.copy_from_slice(&self.recv_data[..NONCE_LEN]);
+            if recv_len == NONCE_LEN {
+                let our_mac = secret::mac(our_secret,
+                                          MacPurpose::TcpHandshake,
+                                          &[&their_nonce, &self.our_nonce]);
+                self.send_data.extend_from_slice(&our_mac[..]);
+                continue;
+            }
+            let mut their_mac = [0u8; MAC_LEN];
+            their_mac.copy_from_slice(&self.recv_data[NONCE_LEN..]);
+            if !secret::verify_mac(their_secret,
+                                   MacPurpose::TcpHandshake,
+                                   &[&self.our_nonce, &their_nonce],
+                                   &their_mac) {
+                return Err(TcpPunchHoleWarning::InvalidResponse {
+                    peer_addr: peer_addr,
+                    data: self.recv_data.to_vec(),
+                });
+            }
+            return Ok(true);
         }
     }
 }
@@ -972,9 +1527,9 @@ mod test {
         let endpoints_0 = mapped_socket_0.endpoints;
         let (priv_info_0, pub_info_0) = gen_rendezvous_info(endpoints_0);
 
+        // The second peer hands its port mappings on to the punched stream.
         let mapped_socket_1 = unwrap_result!(MappedTcpSocket::new(&mapping_context, deadline).result_log());
-        let socket_1 = mapped_socket_1.socket;
-        let endpoints_1 = mapped_socket_1.endpoints;
+        let endpoints_1 = mapped_socket_1.endpoints.clone();
         let (priv_info_1, pub_info_1) = gen_rendezvous_info(endpoints_1);
 
         let deadline = Instant::now() + Duration::from_secs(5);
@@ -989,7 +1544,8 @@ mod test {
         });
 
         let thread_1 = thread!("two_peers_tcp_hole_punch_over_loopback_1", move || {
-            let mut stream = unwrap_result!(tcp_punch_hole(socket_1, priv_info_1, pub_info_0, deadline).result_log());
+            let punched_stream = unwrap_result!(tcp_punch_hole_mapped(mapped_socket_1, priv_info_1, pub_info_0, deadline).result_log());
+            let mut stream = punched_stream.stream;
             let mut data = [1u8; 4];
             let n = unwrap_result!(stream.write(&data));
             assert_eq!(n, 4);
@@ -1002,4 +1558,3 @@ mod test {
         unwrap_result!(thread_1.join());
     }
 }
-
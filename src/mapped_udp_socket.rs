@@ -23,26 +23,37 @@ use std::net::UdpSocket;
 use std::net;
 use std::net::IpAddr;
 use std::time::{Instant, Duration};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use igd;
 use maidsafe_utilities::serialisation::deserialise;
 use socket_addr::SocketAddr;
 use w_result::{WResult, WOk, WErr};
 
+use cancellation::CancellationToken;
 use listener_message;
 use mapping_context;
-use mapping_context::MappingContext;
-use mapped_socket_addr::MappedSocketAddr;
+use mapping_context::{MappingContext, InterfaceV4, InterfaceV6};
+use mapped_socket_addr::{self, CandidateType, MappedSocketAddr};
+use port_mapping;
+use port_mapping::{MapPortWarning, PortMappingGuard};
+use port_prediction::PortPrediction;
 use socket_utils;
 use socket_utils::RecvUntil;
+use stun;
 
 /// A bound udp socket for which we know our external endpoints.
 pub struct MappedUdpSocket {
     /// The socket.
     pub socket: UdpSocket,
     /// The known endpoints of this socket.
-    pub endpoints: Vec<MappedSocketAddr>
+    pub endpoints: Vec<MappedSocketAddr>,
+    /// Owns the port mappings made for this socket on the local network's gateways. The mappings
+    /// are removed when this is dropped so keep it alive for as long as the socket is in use.
+    pub mapping_guard: PortMappingGuard,
+    /// How our NAT allocates external ports for this socket, if the servers we queried saw a
+    /// different port each and the ports followed a pattern.
+    pub port_prediction: Option<PortPrediction>,
 }
 
 quick_error! {
@@ -77,6 +88,14 @@ quick_error! {
             display("IO error sending data on socket: {}", err)
             cause(err)
         }
+        /// Mapping was cancelled through a `CancellationToken`.
+        Cancelled {
+            description("Mapping was cancelled")
+        }
+        /// The thread doing the mapping for one of the `_async` functions panicked.
+        WorkerPanicked {
+            description("The thread doing the mapping panicked")
+        }
     }
 }
 
@@ -87,6 +106,8 @@ impl From<MappedUdpSocketMapError> for io::Error {
             MappedUdpSocketMapError::SocketLocalAddr { err } => err.kind(),
             MappedUdpSocketMapError::RecvError { err } => err.kind(),
             MappedUdpSocketMapError::SendError { err } => err.kind(),
+            MappedUdpSocketMapError::Cancelled => io::ErrorKind::Other,
+            MappedUdpSocketMapError::WorkerPanicked => io::ErrorKind::Other,
         };
         io::Error::new(kind, err_str)
     }
@@ -119,6 +140,42 @@ quick_error! {
                      returned an error: {}", gateway_addr, err)
             cause(err)
         }
+        /// Error mapping external address and port, or opening a firewall pinhole, through a PCP
+        /// server or NAT-PMP gateway.
+        MapPort {
+            err: MapPortWarning,
+        } {
+            description("Error mapping external address and port through a PCP server or \
+                         NAT-PMP gateway")
+            display("Error mapping external address and port: {}", err)
+            cause(err)
+        }
+        /// A STUN server sent us a response that we couldn't use. `server_addr` is the address of
+        /// the server that sent the response.
+        StunResponse {
+            server_addr: SocketAddr,
+            err: stun::StunDecodeError,
+        } {
+            description("Received an unusable response from a STUN server")
+            display("Received an unusable response from STUN server at address {}: {}",
+                    server_addr, err)
+            cause(err)
+        }
+    }
+}
+
+// The sockets have always reported IGD failures as `GetExternalPort`.
+impl From<MapPortWarning> for MappedUdpSocketMapWarning {
+    fn from(e: MapPortWarning) -> MappedUdpSocketMapWarning {
+        match e {
+            MapPortWarning::IgdGetAnyAddress { gateway_addr, err } => {
+                MappedUdpSocketMapWarning::GetExternalPort {
+                    gateway_addr: gateway_addr,
+                    err: err,
+                }
+            },
+            e => MappedUdpSocketMapWarning::MapPort { err: e },
+        }
     }
 }
 
@@ -166,229 +223,377 @@ impl MappedUdpSocket {
     pub fn map(socket: UdpSocket, mc: &MappingContext, deadline: Instant)
                -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
     {
-        let mut endpoints = Vec::new();
-        let mut warnings = Vec::new();
+        map_cancellable(socket, mc, deadline, &CancellationToken::new())
+    }
 
-        // Add the local addresses of this socket for the sake of peers on the name machine or
-        // same local network as us.
-        let local_addr = match socket.local_addr() {
-            Ok(local_addr) => local_addr,
-            Err(e) => return WErr(MappedUdpSocketMapError::SocketLocalAddr { err: e })
-        };
-        match local_addr.ip() {
-            IpAddr::V4(ipv4_addr) => {
-                if socket_utils::ipv4_is_unspecified(&ipv4_addr) {
-                    // If the socket address is unspecified we add an address for every local
-                    // interface. We also ask the interface's IGD gateway (if there is one) for
-                    // an address.
-                    for iface_v4 in mapping_context::interfaces_v4(&mc) {
-                        let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
-                        endpoints.push(MappedSocketAddr {
-                            addr: SocketAddr(net::SocketAddr::V4(local_iface_addr)),
-                            nat_restricted: false,
-                        });
-                        if let Some(gateway) = iface_v4.gateway {
-                            match gateway.get_any_address(igd::PortMappingProtocol::UDP,
-                                                          local_iface_addr, 0,
-                                                          "rust nat_traversal")
-                            {
-                                Ok(external_addr) => {
-                                    endpoints.push(MappedSocketAddr {
-                                        addr: SocketAddr(net::SocketAddr::V4(external_addr)),
-                                        nat_restricted: false,
-                                    });
-                                },
-                                Err(e) => {
-                                    warnings.push(MappedUdpSocketMapWarning::GetExternalPort {
-                                        gateway_addr: gateway.addr,
-                                        err: e,
-                                    });
-                                }
-                            }
-                        };
-                    };
-                }
-                else {
-                    let local_addr_v4 = net::SocketAddrV4::new(ipv4_addr, local_addr.port());
-                    endpoints.push(MappedSocketAddr {
-                        addr: SocketAddr(net::SocketAddr::V4(local_addr_v4)),
-                        nat_restricted: false,
-                    });
+    /// Map an existing `UdpSocket`. Returns `MappedUdpSocketMapError::Cancelled` if `cancel` gets
+    /// triggered first.
+    pub fn map_cancellable(socket: UdpSocket,
+                           mc: &MappingContext,
+                           deadline: Instant,
+                           cancel: &CancellationToken)
+        -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+    {
+        map_cancellable(socket, mc, deadline, cancel)
+    }
 
-                    // If the local address is the address of an interface then we can avoid
-                    // searching for an IGD gateway, just reuse the search result from when we
-                    // found this interface.
-                    let mut gateway_opt_opt = None;
-                    for iface_v4 in mapping_context::interfaces_v4(&mc) {
-                        if iface_v4.addr == ipv4_addr {
-                            gateway_opt_opt = Some(iface_v4.gateway);
-                            break;
-                        }
-                    };
-                    let gateway_opt = match gateway_opt_opt {
-                        Some(gateway_opt) => gateway_opt,
-                        // We don't where this local address came from so search for an IGD gateway
-                        // at it.
-                        None => {
-                            match igd::search_gateway_from_timeout(ipv4_addr, Duration::from_secs(1)) {
-                                Ok(gateway) => Some(gateway),
-                                Err(e) => {
-                                    warnings.push(MappedUdpSocketMapWarning::FindGateway {
-                                        err: e
-                                    });
-                                    None
-                                }
-                            }
+    /// Create a new `MappedUdpSocket`
+    pub fn new(mc: &MappingContext, deadline: Instant)
+            -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
+    {
+        new_cancellable(mc, deadline, &CancellationToken::new())
+    }
+}
+
+/// Map an existing `UdpSocket`, giving up if `cancel` gets triggered.
+pub fn map_cancellable(socket: UdpSocket,
+                       mc: &MappingContext,
+                       deadline: Instant,
+                       cancel: &CancellationToken)
+    -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+{
+    let mut endpoints = Vec::new();
+    let mut warnings = Vec::new();
+    let mut leases = Vec::new();
+    let description = mapping_context::port_mapping_description(&mc);
+
+    // Add the local addresses of this socket for the sake of peers on the name machine or
+    // same local network as us.
+    let local_addr = match socket.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(e) => return WErr(MappedUdpSocketMapError::SocketLocalAddr { err: e })
+    };
+    match local_addr.ip() {
+        IpAddr::V4(ipv4_addr) => {
+            if socket_utils::ipv4_is_unspecified(&ipv4_addr) {
+                // If the socket address is unspecified we add an address for every local
+                // interface. We also ask the interface's IGD gateway (if there is one) for
+                // an address.
+                for iface_v4 in mapping_context::interfaces_v4(&mc) {
+                    let local_iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
+                    endpoints.push(MappedSocketAddr::new(
+                        CandidateType::Host,
+                        SocketAddr(net::SocketAddr::V4(local_iface_addr)),
+                        SocketAddr(net::SocketAddr::V4(local_iface_addr)),
+                        false
+                    ));
+                    if let Some(res) = port_mapping::interface_v4_get_address(
+                        &iface_v4,
+                        igd::PortMappingProtocol::UDP,
+                        local_addr.port(),
+                        &description
+                    ) {
+                        match res {
+                            Ok((external_addr, lease)) => {
+                                leases.push(lease);
+                                endpoints.push(MappedSocketAddr::new(
+                                    CandidateType::Mapped,
+                                    SocketAddr(external_addr),
+                                    SocketAddr(net::SocketAddr::V4(local_iface_addr)),
+                                    false
+                                ));
+                            },
+                            Err(e) => warnings.push(MappedUdpSocketMapWarning::from(e)),
                         }
                     };
-                    // If we have a gateway, ask it for an external address.
-                    if let Some(gateway) = gateway_opt {
-                        match gateway.get_any_address(igd::PortMappingProtocol::UDP,
-                                                      local_addr_v4, 0,
-                                                      "rust nat_traversal")
-                        {
-                            Ok(external_addr) => {
-                                endpoints.push(MappedSocketAddr {
-                                    addr: SocketAddr(net::SocketAddr::V4(external_addr)),
-                                    nat_restricted: false,
-                                });
-                            },
+                };
+            }
+            else {
+                let local_addr_v4 = net::SocketAddrV4::new(ipv4_addr, local_addr.port());
+                endpoints.push(MappedSocketAddr::new(
+                    CandidateType::Host,
+                    SocketAddr(net::SocketAddr::V4(local_addr_v4)),
+                    SocketAddr(net::SocketAddr::V4(local_addr_v4)),
+                    false
+                ));
+
+                // If the local address is the address of an interface then we can avoid
+                // searching for an IGD gateway, just reuse the search result from when we
+                // found this interface.
+                let iface_v4_opt = mapping_context::interfaces_v4(&mc)
+                                       .into_iter()
+                                       .find(|iface_v4| iface_v4.addr == ipv4_addr);
+                let iface_v4 = match iface_v4_opt {
+                    Some(iface_v4) => iface_v4,
+                    // We don't where this local address came from so search for an IGD gateway
+                    // at it.
+                    None => {
+                        let gateway_opt = match igd::search_gateway_from_timeout(
+                            ipv4_addr, Duration::from_secs(1)
+                        ) {
+                            Ok(gateway) => Some(gateway),
                             Err(e) => {
-                                warnings.push(MappedUdpSocketMapWarning::GetExternalPort {
-                                    gateway_addr: gateway.addr,
-                                    err: e,
+                                warnings.push(MappedUdpSocketMapWarning::FindGateway {
+                                    err: e
                                 });
+                                None
                             }
+                        };
+                        InterfaceV4 {
+                            gateway: gateway_opt,
+                            nat_pmp_gateway: None,
+                            pcp_server: None,
+                            addr: ipv4_addr,
                         }
-                    };
+                    }
                 };
-            },
-            IpAddr::V6(ipv6_addr) => {
-                if socket_utils::ipv6_is_unspecified(&ipv6_addr) {
-                    // If the socket address is unspecified add an address for every interface.
-                    for iface_v6 in mapping_context::interfaces_v6(&mc) {
-                        let local_iface_addr = net::SocketAddr::V6(net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0));
-                        endpoints.push(MappedSocketAddr {
-                            addr: SocketAddr(local_iface_addr),
-                            nat_restricted: false,
-                        });
-                    };
-                }
-                else {
-                    endpoints.push(MappedSocketAddr {
-                        addr: SocketAddr(net::SocketAddr::V6(net::SocketAddrV6::new(ipv6_addr, local_addr.port(), 0, 0))),
-                        nat_restricted: false,
+                if let Some(res) = port_mapping::interface_v4_get_address(
+                    &iface_v4,
+                    igd::PortMappingProtocol::UDP,
+                    local_addr.port(),
+                    &description
+                ) {
+                    match res {
+                        Ok((external_addr, lease)) => {
+                            leases.push(lease);
+                            endpoints.push(MappedSocketAddr::new(
+                                CandidateType::Mapped,
+                                SocketAddr(external_addr),
+                                SocketAddr(net::SocketAddr::V4(local_addr_v4)),
+                                false
+                            ));
+                        },
+                        Err(e) => warnings.push(MappedUdpSocketMapWarning::from(e)),
+                    }
+                };
+            };
+        },
+        IpAddr::V6(ipv6_addr) => {
+            // If the socket address is unspecified add an address for every interface.
+            // Otherwise just use the interface with the socket's address.
+            let mut ifaces_v6 = mapping_context::interfaces_v6(&mc);
+            if !socket_utils::ipv6_is_unspecified(&ipv6_addr) {
+                ifaces_v6.retain(|iface_v6| iface_v6.addr == ipv6_addr);
+                if ifaces_v6.is_empty() {
+                    ifaces_v6.push(InterfaceV6 {
+                        pcp_server: None,
+                        addr: ipv6_addr,
                     });
                 }
-            },
-        };
+            }
+            for iface_v6 in ifaces_v6 {
+                let local_iface_addr = net::SocketAddr::V6(net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0));
+                // Global addresses are likely to be behind a stateful firewall unless we can
+                // get the router to open a pinhole for us.
+                let mut nat_restricted = socket_utils::ipv6_is_global(&iface_v6.addr);
+                if let Some(res) = port_mapping::pcp_or_nat_pmp_get_address(
+                    iface_v6.pcp_server.as_ref(),
+                    None,
+                    igd::PortMappingProtocol::UDP,
+                    local_addr.port()
+                ) {
+                    match res {
+                        Ok((external_addr, lease)) => {
+                            leases.push(lease);
+                            if external_addr == local_iface_addr {
+                                nat_restricted = false;
+                            }
+                            else {
+                                endpoints.push(MappedSocketAddr::new(
+                                    CandidateType::Mapped,
+                                    SocketAddr(external_addr),
+                                    SocketAddr(local_iface_addr),
+                                    false
+                                ));
+                            }
+                        },
+                        Err(e) => warnings.push(MappedUdpSocketMapWarning::MapPort { err: e }),
+                    }
+                }
+                endpoints.push(MappedSocketAddr::new(
+                    CandidateType::Host,
+                    SocketAddr(local_iface_addr),
+                    SocketAddr(local_iface_addr),
+                    nat_restricted
+                ));
+            };
+        },
+    };
+    // Take ownership of the mappings now so that they get removed if we bail out below.
+    let mapping_guard = PortMappingGuard::new(leases);
 
-        const MAX_DATAGRAM_SIZE: usize = 256;
+    const MAX_DATAGRAM_SIZE: usize = 256;
 
-        let send_data = listener_message::REQUEST_MAGIC_CONSTANT;
-        let mut simple_servers: HashSet<SocketAddr> = mapping_context::simple_udp_servers(&mc)
-                                                                      .into_iter().collect();
+    let send_data = listener_message::REQUEST_MAGIC_CONSTANT;
+    let mut simple_servers: HashSet<SocketAddr> = mapping_context::simple_udp_servers(&mc)
+                                                                  .into_iter().collect();
+    // Each STUN server gets its own transaction ID which we reuse for retransmissions, as
+    // recommended by RFC 5389.
+    let mut stun_servers: HashMap<SocketAddr, stun::TransactionId>
+        = mapping_context::stun_udp_servers(&mc).into_iter().map(|server| {
+            (server, stun::new_transaction_id())
+        }).collect();
 
-        // Ping all the simple servers and waiting for a response.
-        let start_time = Instant::now();
-        let mut recv_deadline = start_time;
-        let mut deadline = deadline;
-        while recv_deadline < deadline && simple_servers.len() > 0 {
-            recv_deadline = recv_deadline + Duration::from_millis(250);
+    // If we already know that we're behind a full-cone NAT (or none at all) then anyone can
+    // reach us on the address the servers see.
+    let reflexive_restricted = match mc.nat_behaviour() {
+        Some(behaviour) => !behaviour.is_unrestricted(),
+        None => true,
+    };
 
-            // TODO(canndrew): We should limit the number of servers that we send to. If the user
-            // has added two thousand servers we really don't want to be pinging all of them. We
-            // should be smart about it though and try to ping servers that are on different
-            // networks, not just the first ten in the list or something.
-            for simple_server in &simple_servers {
-                // TODO(canndrew): What should we do if we get a partial write?
-                let _ = match socket.send_to(&send_data[..], &**simple_server) {
-                    Ok(n) => n,
-                    Err(e) => return WErr(MappedUdpSocketMapError::SendError { err: e }),
-                };
-            };
-            let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
-            loop {
-                let (read_size, recv_addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
-                    Ok(Some(res)) => res,
-                    Ok(None) => break,
-                    Err(e) => return WErr(MappedUdpSocketMapError::RecvError { err: e }),
-                };
-                if let Ok(listener_message::EchoExternalAddr { external_addr }) =
-                       deserialise::<listener_message::EchoExternalAddr>(&recv_data[..read_size]) {
-                    // Don't ping this simple server again while mapping this socket.
-                    simple_servers.remove(&recv_addr);
+    // The order in which we first sent to each server, and the external address each server
+    // saw. If our NAT allocates a new port per destination, it will have allocated them in
+    // this order.
+    let mut send_order: Vec<SocketAddr> = Vec::new();
+    let mut reflexive_addrs: Vec<(SocketAddr, SocketAddr)> = Vec::new();
 
-                    // If the address that responded to us is global then drop max_attempts to exit
-                    // the loop more quickly. The logic here is that global addresses are the ones
-                    // that are likely to take the longest to respond and they're all likely to
-                    // give us the same address. By contrast, servers on the same subnet as us or
-                    // behind the same carrier-level NAT are likely to respond in under a second.
-                    // So once we have one global address drop the timeout.
+    // Ping all the simple servers and waiting for a response.
+    let start_time = Instant::now();
+    let mut recv_deadline = start_time;
+    let mut deadline = deadline;
+    while recv_deadline < deadline && (simple_servers.len() > 0 || stun_servers.len() > 0) &&
+          !cancel.is_cancelled()
+    {
+        recv_deadline = recv_deadline + Duration::from_millis(250);
 
-                    // TODO(canndrew): Use IpAddr::is_global when it's available
-                    // let is_global = recv_addr.is_global();
-                    let is_global = false;
-                    if is_global {
-                        let now = Instant::now();
-                        if deadline > now {
-                            deadline = now + (now - deadline) / 2;
+        // TODO(canndrew): We should limit the number of servers that we send to. If the user
+        // has added two thousand servers we really don't want to be pinging all of them. We
+        // should be smart about it though and try to ping servers that are on different
+        // networks, not just the first ten in the list or something.
+        for simple_server in &simple_servers {
+            // TODO(canndrew): What should we do if we get a partial write?
+            let _ = match socket.send_to(&send_data[..], &**simple_server) {
+                Ok(n) => n,
+                Err(e) => return WErr(MappedUdpSocketMapError::SendError { err: e }),
+            };
+            if !send_order.contains(simple_server) {
+                send_order.push(*simple_server);
+            }
+        };
+        for (stun_server, transaction_id) in &stun_servers {
+            let request = stun::binding_request(transaction_id);
+            let _ = match socket.send_to(&request[..], &**stun_server) {
+                Ok(n) => n,
+                Err(e) => return WErr(MappedUdpSocketMapError::SendError { err: e }),
+            };
+            if !send_order.contains(stun_server) {
+                send_order.push(*stun_server);
+            }
+        };
+        let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (read_size, recv_addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return WErr(MappedUdpSocketMapError::RecvError { err: e }),
+            };
+            let external_addr = if stun::is_stun_message(&recv_data[..read_size]) {
+                // Only accept responses to transactions that are still outstanding.
+                let expected = match stun_servers.get(&recv_addr) {
+                    Some(transaction_id) => *transaction_id,
+                    None => continue,
+                };
+                match stun::decode_binding_response(&recv_data[..read_size]) {
+                    Ok(stun::BindingResponse { transaction_id, mapped_addr, .. }) => {
+                        if transaction_id != expected {
+                            continue;
                         }
-                    };
-
-                    // Add this endpoint if we don't already know about it. We may have found it
-                    // through IGD or it may be a local interface.
-                    if endpoints.iter().all(|e| e.addr != external_addr) {
-                        endpoints.push(MappedSocketAddr {
-                            addr: external_addr,
-                            // TODO(canndrew): We should consider ways to determine whether this is
-                            // actually an restricted port. For now, just assume it's restricted. It
-                            // usually will be.
-                            nat_restricted: true,
+                        let _ = stun_servers.remove(&recv_addr);
+                        SocketAddr(mapped_addr)
+                    },
+                    Err(e) => {
+                        // Don't keep asking a server which is refusing to answer us.
+                        let _ = stun_servers.remove(&recv_addr);
+                        warnings.push(MappedUdpSocketMapWarning::StunResponse {
+                            server_addr: recv_addr,
+                            err: e,
                         });
-                    }
+                        continue;
+                    },
+                }
+            }
+            else {
+                match deserialise::<listener_message::EchoExternalAddr>(&recv_data[..read_size]) {
+                    Ok(listener_message::EchoExternalAddr { external_addr }) => {
+                        // Don't ping this simple server again while mapping this socket.
+                        simple_servers.remove(&recv_addr);
+                        external_addr
+                    },
+                    Err(_) => continue,
+                }
+            };
+
+            // If the address that responded to us is global then drop max_attempts to exit
+            // the loop more quickly. The logic here is that global addresses are the ones
+            // that are likely to take the longest to respond and they're all likely to
+            // give us the same address. By contrast, servers on the same subnet as us or
+            // behind the same carrier-level NAT are likely to respond in under a second.
+            // So once we have one global address drop the timeout.
+
+            // TODO(canndrew): Use IpAddr::is_global when it's available
+            // let is_global = recv_addr.is_global();
+            let is_global = false;
+            if is_global {
+                let now = Instant::now();
+                if deadline > now {
+                    deadline = now + (now - deadline) / 2;
                 }
+            };
+
+            reflexive_addrs.push((recv_addr, external_addr));
+
+            // Add this endpoint if we don't already know about it. We may have found it
+            // through IGD or it may be a local interface.
+            if endpoints.iter().all(|e| e.addr != external_addr) {
+                endpoints.push(MappedSocketAddr::new(
+                    CandidateType::ServerReflexive,
+                    external_addr,
+                    SocketAddr(local_addr),
+                    // Unless NAT behaviour discovery has told us otherwise, assume it's
+                    // restricted. It usually will be.
+                    reflexive_restricted
+                ));
             }
         }
+    }
 
-        WOk(MappedUdpSocket {
-            socket: socket,
-            endpoints: endpoints,
-        }, warnings)
+    if cancel.is_cancelled() {
+        return WErr(MappedUdpSocketMapError::Cancelled);
     }
 
-    /// Create a new `MappedUdpSocket`
-    pub fn new(mc: &MappingContext, deadline: Instant)
-            -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
-    {
-        // Sometimes we might bind a socket to a random port then find that we have an IGD gateway
-        // that could give us an unrestricted external port but that it can't map the random port
-        // number we got. Hence we might need to try several times with different port numbers.
-        let mut attempt = 0;
-        'attempt: loop {
-            attempt += 1;
-            let socket = match UdpSocket::bind("0.0.0.0:0") {
-                Ok(socket) => socket,
-                Err(e) => return WErr(MappedUdpSocketNewError::CreateSocket { err: e }),
-            };
-            let (socket, warnings) = match Self::map(socket, mc, deadline) {
-                WOk(s, ws) => (s, ws),
-                WErr(e) => return WErr(MappedUdpSocketNewError::MapSocket { err: e }),
-            };
-            if attempt < 3 {
-                for warning in &warnings {
-                    match *warning {
-                        // If we bound to a port that the IGD gateway can't map, rebind and try again.
-                        MappedUdpSocketMapWarning::GetExternalPort {
-                            err: igd::AddAnyPortError::ExternalPortInUse,
-                            ..
-                        } => continue 'attempt,
-                        _ => (),
-                    }
+    reflexive_addrs.sort_by_key(|&(server, _)| send_order.iter().position(|s| *s == server));
+    let allocated: Vec<SocketAddr> = reflexive_addrs.into_iter().map(|(_, addr)| addr).collect();
+    let port_prediction = PortPrediction::from_allocations(&allocated);
+
+    mapped_socket_addr::sort_by_priority(&mut endpoints);
+    WOk(MappedUdpSocket {
+        socket: socket,
+        endpoints: endpoints,
+        mapping_guard: mapping_guard,
+        port_prediction: port_prediction,
+    }, warnings)
+}
+
+/// Create a new `MappedUdpSocket`, giving up if `cancel` gets triggered.
+pub fn new_cancellable(mc: &MappingContext, deadline: Instant, cancel: &CancellationToken)
+    -> WResult<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
+{
+    // Sometimes we might bind a socket to a random port then find that we have an IGD gateway
+    // that could give us an unrestricted external port but that it can't map the random port
+    // number we got. Hence we might need to try several times with different port numbers.
+    let mut attempt = 0;
+    'attempt: loop {
+        attempt += 1;
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(socket) => socket,
+            Err(e) => return WErr(MappedUdpSocketNewError::CreateSocket { err: e }),
+        };
+        let (socket, warnings) = match map_cancellable(socket, mc, deadline, cancel) {
+            WOk(s, ws) => (s, ws),
+            WErr(e) => return WErr(MappedUdpSocketNewError::MapSocket { err: e }),
+        };
+        if attempt < 3 {
+            for warning in &warnings {
+                match *warning {
+                    // If we bound to a port that the IGD gateway can't map, rebind and try again.
+                    MappedUdpSocketMapWarning::GetExternalPort {
+                        err: igd::AddAnyPortError::ExternalPortInUse,
+                        ..
+                    } => continue 'attempt,
+                    _ => (),
                 }
             }
-            return WOk(socket, warnings);
         }
+        return WOk(socket, warnings);
     }
 }
-
@@ -20,9 +20,9 @@
 
 use std::sync::RwLock;
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::thread;
-use std::time::Duration;
+use std::time::{Instant, Duration};
 
 use igd;
 use socket_addr::SocketAddr;
@@ -30,7 +30,22 @@ use w_result::{WResult, WOk, WErr};
 use get_if_addrs;
 use void::Void;
 
+use cancellation::CancellationToken;
+use nat_behaviour;
+use nat_behaviour::{NatBehaviour, NatBehaviourError, NatBehaviourWarning};
+use nat_pmp::{NatPmpError, NatPmpGateway};
+use pcp::{self, PcpError, PcpServer};
+use port_mapping;
+use port_mapping::{MapPortError, MapPortWarning, PortMapping};
+use relay::RelayServerInfo;
 use socket_utils;
+use stale_port_mappings;
+use stale_port_mappings::{RemovedPortMapping, RemoveStalePortMappingsError,
+                          RemoveStalePortMappingsWarning};
+use turn::TurnServer;
+
+/// The description we give IGD port mappings unless told otherwise.
+pub const DEFAULT_PORT_MAPPING_DESCRIPTION: &'static str = "rust nat_traversal";
 
 /// You need to create a `MappingContext` before doing any socket mapping. This
 /// `MappingContext` should ideally be kept throughout the lifetime of the
@@ -41,17 +56,25 @@ pub struct MappingContext {
     interfaces_v6: RwLock<Vec<InterfaceV6>>,
     simple_udp_servers: RwLock<Vec<SocketAddr>>,
     simple_tcp_servers: RwLock<Vec<SocketAddr>>,
+    stun_udp_servers: RwLock<Vec<SocketAddr>>,
+    relay_servers: RwLock<Vec<RelayServerInfo>>,
+    turn_servers: RwLock<Vec<TurnServer>>,
+    nat_behaviour: RwLock<Option<NatBehaviour>>,
+    port_mapping_description: RwLock<String>,
 }
 
 #[derive(Clone)]
 pub struct InterfaceV4 {
     pub gateway: Option<igd::Gateway>,
+    pub nat_pmp_gateway: Option<NatPmpGateway>,
+    pub pcp_server: Option<PcpServer>,
     pub addr: Ipv4Addr,
 }
 
-// TODO(canndrew): Can we support IGD on ipv6?
+// IGD doesn't do IPv6 but a PCP server on the router can open pinholes in its firewall for us.
 #[derive(Clone)]
 pub struct InterfaceV6 {
+    pub pcp_server: Option<PcpServer>,
     pub addr: Ipv6Addr,
 }
 
@@ -76,6 +99,10 @@ quick_error! {
                      thread::spawn returned an error: {}", err)
             cause(err)
         }
+        /// Creating the context was cancelled through a `CancellationToken`.
+        Cancelled {
+            description("Creating the mapping context was cancelled")
+        }
     }
 }
 
@@ -85,6 +112,7 @@ impl From<MappingContextNewError> for io::Error {
         let kind = match e {
             MappingContextNewError::ListInterfaces { err } => err.kind(),
             MappingContextNewError::SpawnThread { err } => err.kind(),
+            MappingContextNewError::Cancelled => io::ErrorKind::Other,
         };
         io::Error::new(kind, err_str)
     }
@@ -106,13 +134,51 @@ quick_error! {
                      if_name, if_addr, err)
             cause(err)
         }
+        /// No NAT-PMP gateway answered at `gateway_addr`, which we guessed is the gateway for the
+        /// network interface `if_name` with address `if_addr`.
+        ProbeNatPmp {
+            if_name: String,
+            if_addr: Ipv4Addr,
+            gateway_addr: Ipv4Addr,
+            err: NatPmpError
+        } {
+            description("Failed to find a NAT-PMP gateway")
+            display("Failed to find a NAT-PMP gateway at {} on network interface {} {}: {}",
+                     gateway_addr, if_name, if_addr, err)
+            cause(err)
+        }
+        /// No PCP server answered at `server_addr`, which we guessed is the gateway for the
+        /// network interface `if_name` with address `if_addr`.
+        ProbePcp {
+            if_name: String,
+            if_addr: IpAddr,
+            server_addr: net::SocketAddr,
+            err: PcpError
+        } {
+            description("Failed to find a PCP server")
+            display("Failed to find a PCP server at {} on network interface {} {}: {}",
+                     server_addr, if_name, if_addr, err)
+            cause(err)
+        }
     }
 }
 
 impl MappingContext {
     /// Create a new mapping context. This will block breifly while it searches
-    /// the network for UPnP servers.
+    /// the network for UPnP, PCP and NAT-PMP servers.
     pub fn new() -> WResult<MappingContext, MappingContextNewWarning, MappingContextNewError> {
+        MappingContext::new_cancellable(&CancellationToken::new())
+    }
+
+    /// Create a new mapping context. Returns `MappingContextNewError::Cancelled` if `cancel` gets
+    /// triggered before the search for UPnP, PCP and NAT-PMP servers completes. The search threads
+    /// give up between probes once `cancel` is triggered, and are joined before this returns.
+    pub fn new_cancellable(cancel: &CancellationToken)
+        -> WResult<MappingContext, MappingContextNewWarning, MappingContextNewError>
+    {
+        if cancel.is_cancelled() {
+            return WErr(MappingContextNewError::Cancelled);
+        }
         let interfaces = match get_if_addrs::get_if_addrs() {
             Ok(if_addrs) => if_addrs,
             Err(e) => return WErr(MappingContextNewError::ListInterfaces { err: e }),
@@ -121,51 +187,147 @@ impl MappingContext {
         let mut interfaces_v6 = Vec::new();
         let mut warnings = Vec::new();
         let mut search_threads = Vec::new();
+        let mut search_threads_v6 = Vec::new();
         for interface in interfaces {
-            let addr_v4 = match interface.addr {
+            let (addr_v4, netmask_v4) = match interface.addr {
                 get_if_addrs::IfAddr::V4(v4_addr) => {
-                    v4_addr.ip
+                    (v4_addr.ip, v4_addr.netmask)
                 },
                 get_if_addrs::IfAddr::V6(v6_addr) => {
-                    interfaces_v6.push(InterfaceV6 {
-                        addr: v6_addr.ip,
-                    });
+                    let addr_v6 = v6_addr.ip;
+                    // There's no firewall between us and peers on the local network.
+                    if !socket_utils::ipv6_is_global(&addr_v6) {
+                        interfaces_v6.push(InterfaceV6 {
+                            pcp_server: None,
+                            addr: addr_v6,
+                        });
+                        continue;
+                    }
+                    let if_name = interface.name;
+                    let cancel = cancel.clone();
+                    search_threads_v6.push(thread::Builder::new()
+                                                          .name(From::from("PCP search"))
+                                                          .spawn(move || -> WResult<_, _, Void> {
+                        let mut warnings = Vec::new();
+                        let mut pcp_server = None;
+                        let gateway = if cancel.is_cancelled() {
+                            None
+                        }
+                        else {
+                            socket_utils::default_gateway_v6(&if_name)
+                        };
+                        if let Some((gateway_ip, scope_id)) = gateway {
+                            let server_addr = net::SocketAddr::V6(
+                                net::SocketAddrV6::new(gateway_ip, pcp::PCP_PORT, 0, scope_id)
+                            );
+                            match PcpServer::probe(server_addr, IpAddr::V6(addr_v6),
+                                                   Duration::from_secs(1)) {
+                                Ok(s) => pcp_server = Some(s),
+                                Err(e) => {
+                                    warnings.push(MappingContextNewWarning::ProbePcp {
+                                        if_name: if_name,
+                                        if_addr: IpAddr::V6(addr_v6),
+                                        server_addr: server_addr,
+                                        err: e,
+                                    });
+                                },
+                            }
+                        }
+                        WOk(InterfaceV6 {
+                            pcp_server: pcp_server,
+                            addr: addr_v6,
+                        }, warnings)
+                    }));
                     continue;
                 },
             };
             if socket_utils::ipv4_is_loopback(&addr_v4) {
                 interfaces_v4.push(InterfaceV4 {
                     gateway: None,
+                    nat_pmp_gateway: None,
+                    pcp_server: None,
                     addr: addr_v4,
                 });
                 continue;
             };
             let if_name = interface.name;
+            let cancel = cancel.clone();
             search_threads.push(thread::Builder::new()
                                                 .name(From::from("IGD search"))
                                                 .spawn(move || -> WResult<_, _, Void> {
                 let mut warnings = Vec::new();
-                let gateway = match igd::search_gateway_from_timeout(addr_v4, Duration::from_secs(1)) {
-                    Ok(gateway) => Some(gateway),
-                    Err(e) => {
+                let res = if cancel.is_cancelled() {
+                    None
+                }
+                else {
+                    Some(igd::search_gateway_from_timeout(addr_v4, Duration::from_secs(1)))
+                };
+                let gateway = match res {
+                    Some(Ok(gateway)) => Some(gateway),
+                    Some(Err(e)) => {
                         warnings.push(MappingContextNewWarning::SearchGateway {
-                            if_name: if_name,
+                            if_name: if_name.clone(),
                             if_addr: addr_v4,
                             err: e,
                         });
                         None
                     },
+                    None => None,
                 };
+                // Plenty of routers only speak PCP or its predecessor NAT-PMP. We only need them if
+                // there's no IGD gateway.
+                let mut pcp_server = None;
+                let mut nat_pmp_gateway = None;
+                if gateway.is_none() && !cancel.is_cancelled() {
+                    if let Some(gateway_ip) = socket_utils::guess_gateway_v4(addr_v4, netmask_v4) {
+                        let server_addr = net::SocketAddr::V4(
+                            net::SocketAddrV4::new(gateway_ip, pcp::PCP_PORT)
+                        );
+                        match PcpServer::probe(server_addr, IpAddr::V4(addr_v4),
+                                               Duration::from_secs(1)) {
+                            Ok(s) => pcp_server = Some(s),
+                            Err(e) => {
+                                warnings.push(MappingContextNewWarning::ProbePcp {
+                                    if_name: if_name.clone(),
+                                    if_addr: IpAddr::V4(addr_v4),
+                                    server_addr: server_addr,
+                                    err: e,
+                                });
+                            },
+                        }
+                        if pcp_server.is_none() && !cancel.is_cancelled() {
+                            match NatPmpGateway::probe(gateway_ip, Duration::from_secs(1)) {
+                                Ok(g) => nat_pmp_gateway = Some(g),
+                                Err(e) => {
+                                    warnings.push(MappingContextNewWarning::ProbeNatPmp {
+                                        if_name: if_name,
+                                        if_addr: addr_v4,
+                                        gateway_addr: gateway_ip,
+                                        err: e,
+                                    });
+                                },
+                            }
+                        }
+                    }
+                }
                 WOk(InterfaceV4 {
                     gateway: gateway,
+                    nat_pmp_gateway: nat_pmp_gateway,
+                    pcp_server: pcp_server,
                     addr: addr_v4,
                 }, warnings)
             }));
         };
-
+        // If we've been cancelled, the search threads will see it and give up after their current
+        // probe. Join them either way so that none are left running once we return.
+        let mut spawn_error = None;
         for search_thread in search_threads {
             match search_thread {
-                Err(e) => return WErr(MappingContextNewError::SpawnThread { err: e }),
+                Err(e) => {
+                    if spawn_error.is_none() {
+                        spawn_error = Some(e);
+                    }
+                },
                 Ok(jh) => {
                     // If the child thread panicked, propogate the panic.
                     let res = unwrap_result!(jh.join());
@@ -179,13 +341,33 @@ impl MappingContext {
                 }
             }
         }
-        let mc = MappingContext {
-            interfaces_v4: RwLock::new(interfaces_v4),
-            interfaces_v6: RwLock::new(interfaces_v6),
-            simple_udp_servers: RwLock::new(Vec::new()),
-            simple_tcp_servers: RwLock::new(Vec::new()),
-        };
-        WOk(mc, warnings)
+        for search_thread in search_threads_v6 {
+            match search_thread {
+                Err(e) => {
+                    if spawn_error.is_none() {
+                        spawn_error = Some(e);
+                    }
+                },
+                Ok(jh) => {
+                    // If the child thread panicked, propogate the panic.
+                    let res = unwrap_result!(jh.join());
+                    match res {
+                        WErr(e) => match e {},
+                        WOk(interface, ws) => {
+                            interfaces_v6.push(interface);
+                            warnings.extend(ws);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(e) = spawn_error {
+            return WErr(MappingContextNewError::SpawnThread { err: e });
+        }
+        if cancel.is_cancelled() {
+            return WErr(MappingContextNewError::Cancelled);
+        }
+        WOk(from_interfaces(interfaces_v4, interfaces_v6), warnings)
     }
 
     /// Inform the context about external servers that speak the UDP simple hole punch server
@@ -205,6 +387,111 @@ impl MappingContext {
         let mut s = unwrap_result!(self.simple_tcp_servers.write());
         s.extend(servers)
     }
+
+    /// Inform the context about external servers that speak STUN (RFC 5389) over UDP. These are
+    /// used alongside the simple UDP servers when mapping UDP sockets.
+    pub fn add_stun_udp_servers<S>(&self, servers: S)
+        where S: IntoIterator<Item=SocketAddr>
+    {
+        let mut s = unwrap_result!(self.stun_udp_servers.write());
+        s.extend(servers)
+    }
+
+    /// Inform the context about `RelayServer`s. These are used to relay traffic to peers that we
+    /// can't punch a hole to.
+    pub fn add_relay_servers<S>(&self, servers: S)
+        where S: IntoIterator<Item=RelayServerInfo>
+    {
+        let mut s = unwrap_result!(self.relay_servers.write());
+        s.extend(servers)
+    }
+
+    /// Inform the context about TURN (RFC 5766) servers. Like relay servers, these are used to
+    /// relay traffic to peers that we can't punch a hole to.
+    pub fn add_turn_servers<S>(&self, servers: S)
+        where S: IntoIterator<Item=TurnServer>
+    {
+        let mut s = unwrap_result!(self.turn_servers.write());
+        s.extend(servers)
+    }
+
+    /// Classify the mapping and filtering behaviour of the NAT we are behind (see RFC 5780) by
+    /// querying the simple and STUN UDP servers known to this context. Filtering behaviour can
+    /// only be tested with STUN servers that have an alternate address.
+    ///
+    /// The result is remembered by the context. Once we know that we're behind a full-cone NAT (or
+    /// no NAT at all), mapped UDP sockets will report their server-reflexive addresses as
+    /// unrestricted.
+    pub fn discover_nat_behaviour(&self, deadline: Instant)
+            -> WResult<NatBehaviour, NatBehaviourWarning, NatBehaviourError>
+    {
+        let res = nat_behaviour::discover(self, deadline);
+        if let WOk(ref behaviour, _) = res {
+            *unwrap_result!(self.nat_behaviour.write()) = Some(*behaviour);
+        }
+        res
+    }
+
+    /// The NAT behaviour found by the last successful call to `discover_nat_behaviour`.
+    pub fn nat_behaviour(&self) -> Option<NatBehaviour> {
+        *unwrap_result!(self.nat_behaviour.read())
+    }
+
+    /// Set the description given to the IGD port mappings made by sockets mapped with this
+    /// context. Giving each application its own description means that
+    /// `remove_stale_port_mappings` won't touch other applications' mappings. The default is
+    /// `"rust nat_traversal"`.
+    pub fn set_port_mapping_description(&self, description: String) {
+        *unwrap_result!(self.port_mapping_description.write()) = description;
+    }
+
+    /// Ask the gateways on our network to forward an external port to `local_addr`. This tries
+    /// every gateway known to the context (IGD, PCP and NAT-PMP) in turn until one succeeds. Each
+    /// gateway is first asked to map `preferred_external_port`, or the same port as `local_addr`
+    /// if that's `None`, then any port it likes. The mapping is leased for `lease` (or
+    /// permanently, on gateways that don't support leases) and is removed when the returned
+    /// `PortMapping` is dropped.
+    ///
+    /// If `local_addr` is unspecified the port is mapped on whichever interface we can. IPv6
+    /// addresses can only be mapped through PCP, which opens a pinhole in the router's firewall.
+    pub fn map_port(&self,
+                    protocol: igd::PortMappingProtocol,
+                    local_addr: net::SocketAddr,
+                    preferred_external_port: Option<u16>,
+                    lease: Duration)
+                    -> WResult<PortMapping, MapPortWarning, MapPortError>
+    {
+        port_mapping::map_port(self, protocol, local_addr, preferred_external_port, lease)
+    }
+
+    /// Remove port mappings left behind on our IGD gateways by processes which exited without
+    /// cleaning up. A mapping is considered stale if it has our port mapping description and
+    /// forwards to a port on one of our addresses which nothing is bound to any more. Returns the
+    /// mappings that were removed.
+    pub fn remove_stale_port_mappings(&self)
+            -> WResult<Vec<RemovedPortMapping>,
+                       RemoveStalePortMappingsWarning,
+                       RemoveStalePortMappingsError>
+    {
+        stale_port_mappings::remove_stale_port_mappings(self)
+    }
+}
+
+/// A context for the given interfaces which doesn't know about any servers yet.
+pub fn from_interfaces(interfaces_v4: Vec<InterfaceV4>,
+                       interfaces_v6: Vec<InterfaceV6>)
+                       -> MappingContext {
+    MappingContext {
+        interfaces_v4: RwLock::new(interfaces_v4),
+        interfaces_v6: RwLock::new(interfaces_v6),
+        simple_udp_servers: RwLock::new(Vec::new()),
+        simple_tcp_servers: RwLock::new(Vec::new()),
+        stun_udp_servers: RwLock::new(Vec::new()),
+        relay_servers: RwLock::new(Vec::new()),
+        turn_servers: RwLock::new(Vec::new()),
+        nat_behaviour: RwLock::new(None),
+        port_mapping_description: RwLock::new(String::from(DEFAULT_PORT_MAPPING_DESCRIPTION)),
+    }
 }
 
 pub fn interfaces_v4(mc: &MappingContext) -> Vec<InterfaceV4> {
@@ -215,6 +502,10 @@ pub fn interfaces_v6(mc: &MappingContext) -> Vec<InterfaceV6> {
     unwrap_result!(mc.interfaces_v6.read()).clone()
 }
 
+pub fn port_mapping_description(mc: &MappingContext) -> String {
+    unwrap_result!(mc.port_mapping_description.read()).clone()
+}
+
 pub fn simple_udp_servers(mc: &MappingContext) -> Vec<SocketAddr> {
     unwrap_result!(mc.simple_udp_servers.read()).clone()
 }
@@ -223,13 +514,38 @@ pub fn simple_tcp_servers(mc: &MappingContext) -> Vec<SocketAddr> {
     unwrap_result!(mc.simple_tcp_servers.read()).clone()
 }
 
+pub fn stun_udp_servers(mc: &MappingContext) -> Vec<SocketAddr> {
+    unwrap_result!(mc.stun_udp_servers.read()).clone()
+}
+
+pub fn relay_servers(mc: &MappingContext) -> Vec<RelayServerInfo> {
+    unwrap_result!(mc.relay_servers.read()).clone()
+}
+
+pub fn turn_servers(mc: &MappingContext) -> Vec<TurnServer> {
+    unwrap_result!(mc.turn_servers.read()).clone()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use cancellation::CancellationToken;
+
     #[test]
     fn create_mapping_context() {
         let _ = unwrap_result!(MappingContext::new().result_discard());
     }
+
+    #[test]
+    fn create_cancelled_mapping_context() {
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        match MappingContext::new_cancellable(&cancel).result_discard() {
+            Err(MappingContextNewError::Cancelled) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(..) => panic!("Created a mapping context despite being cancelled"),
+        }
+    }
 }
 
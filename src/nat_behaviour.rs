@@ -0,0 +1,493 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Classification of NAT mapping and filtering behaviour, as described in RFC 4787 and RFC 5780.
+
+use std::cmp;
+use std::io;
+use std::net::{self, IpAddr, UdpSocket};
+use std::time::{Instant, Duration};
+
+use maidsafe_utilities::serialisation::deserialise;
+use socket_addr::SocketAddr;
+use w_result::{WResult, WOk, WErr};
+
+use listener_message;
+use mapping_context;
+use mapping_context::MappingContext;
+use socket_utils::RecvUntil;
+use stun;
+
+/// How long to wait for the response to a single test before concluding that there is none.
+const TEST_TIMEOUT_MS: u64 = 1000;
+/// How often to retransmit a request while waiting for its response.
+const RETRANSMIT_INTERVAL_MS: u64 = 250;
+
+/// How a NAT chooses the external endpoint for packets sent from an internal endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum MappingBehaviour {
+    /// There is no NAT. Our external address is the address of one of our interfaces.
+    NoNat,
+    /// The same external endpoint is used regardless of the destination.
+    EndpointIndependent,
+    /// The external endpoint is reused for destinations with the same IP address.
+    AddressDependent,
+    /// A new external endpoint is used for every destination IP address and port.
+    AddressAndPortDependent,
+    /// Not enough servers responded to classify the mapping behaviour.
+    Unknown,
+}
+
+/// Which incoming packets a NAT (or firewall) lets through to an internal endpoint once that
+/// endpoint has sent packets out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum FilteringBehaviour {
+    /// Packets from any remote endpoint are let through.
+    EndpointIndependent,
+    /// Only packets from IP addresses we have sent to are let through.
+    AddressDependent,
+    /// Only packets from endpoints we have sent to are let through.
+    AddressAndPortDependent,
+    /// No server supporting RFC 5780 responded so filtering could not be tested.
+    Unknown,
+}
+
+/// The observed behaviour of the NAT(s) between us and the internet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct NatBehaviour {
+    /// The mapping behaviour.
+    pub mapping: MappingBehaviour,
+    /// The filtering behaviour.
+    pub filtering: FilteringBehaviour,
+}
+
+impl NatBehaviour {
+    /// Returns `true` if a server-reflexive endpoint can be reached by anyone without hole
+    /// punching. ie. we are not behind a NAT or firewall, or we are behind a full-cone NAT.
+    pub fn is_unrestricted(&self) -> bool {
+        match self.mapping {
+            MappingBehaviour::NoNat | MappingBehaviour::EndpointIndependent => {
+                self.filtering == FilteringBehaviour::EndpointIndependent
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns `false` if hole punching between us and a peer with the given behaviour is not
+    /// going to work and the connection should go through a relay instead. Unknown behaviours are
+    /// treated optimistically.
+    pub fn hole_punching_viable_with(&self, peer: &NatBehaviour) -> bool {
+        fn endpoint_dependent(b: &NatBehaviour) -> bool {
+            match b.mapping {
+                MappingBehaviour::AddressDependent |
+                MappingBehaviour::AddressAndPortDependent => true,
+                _ => false,
+            }
+        }
+        // When one side allocates a fresh port for every destination the other side can only
+        // reach it if it doesn't filter on port.
+        fn blocks_unknown_ports(b: &NatBehaviour) -> bool {
+            b.filtering == FilteringBehaviour::AddressAndPortDependent
+        }
+
+        if endpoint_dependent(self) && endpoint_dependent(peer) {
+            return false;
+        }
+        if self.mapping == MappingBehaviour::AddressAndPortDependent && blocks_unknown_ports(peer) {
+            return false;
+        }
+        if peer.mapping == MappingBehaviour::AddressAndPortDependent && blocks_unknown_ports(self) {
+            return false;
+        }
+        true
+    }
+}
+
+quick_error! {
+    /// Errors returned by `MappingContext::discover_nat_behaviour`
+    #[derive(Debug)]
+    pub enum NatBehaviourError {
+        /// No simple or STUN UDP servers have been added to the `MappingContext`.
+        NoServers {
+            description("No simple or STUN UDP servers are known to the mapping context")
+        }
+        /// Error creating a new udp socket bound to 0.0.0.0:0
+        CreateSocket { err: io::Error } {
+            description("Error creating a new udp socket bound to 0.0.0.0:0")
+            display("Error creating a new udp socket bound to 0.0.0.0:0. \
+                     UdpSocket::bind returned an IO error: {}", err)
+            cause(err)
+        }
+        /// Error getting the local address of the socket.
+        SocketLocalAddr { err: io::Error } {
+            description("Error getting local address of socket")
+            display("Error getting local address of socket: {}", err)
+            cause(err)
+        }
+        /// IO error sending data on the socket.
+        SendError { err: io::Error } {
+            description("IO error sending data on socket")
+            display("IO error sending data on socket: {}", err)
+            cause(err)
+        }
+        /// IO error receiving data on the socket.
+        RecvError { err: io::Error } {
+            description("IO error receiving data on socket")
+            display("IO error receiving data on socket: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<NatBehaviourError> for io::Error {
+    fn from(e: NatBehaviourError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            NatBehaviourError::NoServers => io::ErrorKind::NotFound,
+            NatBehaviourError::CreateSocket { err } => err.kind(),
+            NatBehaviourError::SocketLocalAddr { err } => err.kind(),
+            NatBehaviourError::SendError { err } => err.kind(),
+            NatBehaviourError::RecvError { err } => err.kind(),
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+quick_error! {
+    /// Warnings raised by `MappingContext::discover_nat_behaviour`
+    #[derive(Debug)]
+    pub enum NatBehaviourWarning {
+        /// A STUN server sent us a response that we couldn't use.
+        StunResponse { server_addr: SocketAddr, err: stun::StunDecodeError } {
+            description("Received an unusable response from a STUN server")
+            display("Received an unusable response from STUN server at address {}: {}",
+                    server_addr, err)
+            cause(err)
+        }
+        /// A server did not respond to our requests.
+        NoResponse { server_addr: SocketAddr } {
+            description("A server did not respond to our requests")
+            display("Server at address {} did not respond to our requests", server_addr)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Request {
+    Simple,
+    Stun { change_ip: bool, change_port: bool },
+}
+
+struct Response {
+    mapped_addr: SocketAddr,
+    other_addr: Option<net::SocketAddr>,
+}
+
+pub fn discover(mc: &MappingContext, deadline: Instant)
+        -> WResult<NatBehaviour, NatBehaviourWarning, NatBehaviourError>
+{
+    let stun_servers = mapping_context::stun_udp_servers(mc);
+    let simple_servers = mapping_context::simple_udp_servers(mc);
+    if stun_servers.is_empty() && simple_servers.is_empty() {
+        return WErr(NatBehaviourError::NoServers);
+    }
+
+    let mut warnings = Vec::new();
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => return WErr(NatBehaviourError::CreateSocket { err: e }),
+    };
+    let local_port = match socket.local_addr() {
+        Ok(local_addr) => local_addr.port(),
+        Err(e) => return WErr(NatBehaviourError::SocketLocalAddr { err: e }),
+    };
+    let mut local_ips: Vec<IpAddr> = mapping_context::interfaces_v4(mc).into_iter().map(|iface| {
+        IpAddr::V4(iface.addr)
+    }).collect();
+    local_ips.extend(mapping_context::interfaces_v6(mc).into_iter().map(|iface| IpAddr::V6(iface.addr)));
+    let is_local = |addr: &SocketAddr| addr.port() == local_port && local_ips.contains(&addr.ip());
+
+    // Every response to a plain request. Used to guess the mapping behaviour if none of the STUN
+    // servers support RFC 5780.
+    let mut observed = Vec::new();
+
+    // RFC 5780 section 4.3 and 4.4. These tests need a server with an alternate address.
+    for server in &stun_servers {
+        let plain = Request::Stun { change_ip: false, change_port: false };
+        let test_1 = match query(&socket, server, plain, deadline, &mut warnings) {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                warnings.push(NatBehaviourWarning::NoResponse { server_addr: *server });
+                continue;
+            },
+            Err(e) => return WErr(e),
+        };
+        observed.push((*server, test_1.mapped_addr));
+        let other_addr = match test_1.other_addr {
+            Some(other_addr) => other_addr,
+            None => continue,
+        };
+
+        let mapping = if is_local(&test_1.mapped_addr) {
+            MappingBehaviour::NoNat
+        }
+        else {
+            let alt_ip = SocketAddr(net::SocketAddr::new(other_addr.ip(), server.port()));
+            match query(&socket, &alt_ip, plain, deadline, &mut warnings) {
+                Ok(Some(test_2)) => {
+                    if test_2.mapped_addr == test_1.mapped_addr {
+                        MappingBehaviour::EndpointIndependent
+                    }
+                    else {
+                        let alt_ip_port = SocketAddr(other_addr);
+                        match query(&socket, &alt_ip_port, plain, deadline, &mut warnings) {
+                            Ok(Some(test_3)) => {
+                                if test_3.mapped_addr == test_2.mapped_addr {
+                                    MappingBehaviour::AddressDependent
+                                }
+                                else {
+                                    MappingBehaviour::AddressAndPortDependent
+                                }
+                            },
+                            Ok(None) => MappingBehaviour::Unknown,
+                            Err(e) => return WErr(e),
+                        }
+                    }
+                },
+                Ok(None) => MappingBehaviour::Unknown,
+                Err(e) => return WErr(e),
+            }
+        };
+
+        let filtering = match discover_filtering(server, deadline, &mut warnings) {
+            Ok(filtering) => filtering,
+            Err(e) => return WErr(e),
+        };
+
+        return WOk(NatBehaviour {
+            mapping: mapping,
+            filtering: filtering,
+        }, warnings);
+    }
+
+    // None of the STUN servers could run the RFC 5780 tests. Fall back to comparing the
+    // addresses seen by each of the servers.
+    for server in &simple_servers {
+        match query(&socket, server, Request::Simple, deadline, &mut warnings) {
+            Ok(Some(response)) => observed.push((*server, response.mapped_addr)),
+            Ok(None) => warnings.push(NatBehaviourWarning::NoResponse { server_addr: *server }),
+            Err(e) => return WErr(e),
+        }
+    }
+
+    WOk(NatBehaviour {
+        mapping: classify_mapping(&observed, is_local),
+        filtering: FilteringBehaviour::Unknown,
+    }, warnings)
+}
+
+/// RFC 5780 section 4.4. The mapping tests have already sent from our socket to the alternate
+/// address of the server, which would let the responses to a change-IP request through any NAT.
+/// So these tests use a fresh socket that has only talked to the server's primary address.
+fn discover_filtering(server: &SocketAddr,
+                      deadline: Instant,
+                      warnings: &mut Vec<NatBehaviourWarning>)
+                      -> Result<FilteringBehaviour, NatBehaviourError> {
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => return Err(NatBehaviourError::CreateSocket { err: e }),
+    };
+    let plain = Request::Stun { change_ip: false, change_port: false };
+    if try!(query(&socket, server, plain, deadline, warnings)).is_none() {
+        warnings.push(NatBehaviourWarning::NoResponse { server_addr: *server });
+        return Ok(FilteringBehaviour::Unknown);
+    }
+    let change_both = Request::Stun { change_ip: true, change_port: true };
+    if try!(query(&socket, server, change_both, deadline, warnings)).is_some() {
+        return Ok(FilteringBehaviour::EndpointIndependent);
+    }
+    let change_port = Request::Stun { change_ip: false, change_port: true };
+    if try!(query(&socket, server, change_port, deadline, warnings)).is_some() {
+        return Ok(FilteringBehaviour::AddressDependent);
+    }
+    Ok(FilteringBehaviour::AddressAndPortDependent)
+}
+
+/// Guess the mapping behaviour from the external addresses reported by several servers.
+fn classify_mapping<F>(observed: &[(SocketAddr, SocketAddr)], is_local: F) -> MappingBehaviour
+    where F: Fn(&SocketAddr) -> bool
+{
+    let (first_server, first_mapped) = match observed.first() {
+        Some(&(server, mapped)) => (server, mapped),
+        None => return MappingBehaviour::Unknown,
+    };
+    if is_local(&first_mapped) {
+        return MappingBehaviour::NoNat;
+    }
+    if observed.iter().all(|&(server, mapped)| server == first_server || mapped == first_mapped) {
+        if observed.iter().any(|&(server, _)| server != first_server) {
+            return MappingBehaviour::EndpointIndependent;
+        }
+        return MappingBehaviour::Unknown;
+    }
+    // The mapping is endpoint dependent. Servers sharing an IP address tell us whether it also
+    // depends on the port. If there are no such servers we assume the worst.
+    let mut same_ip_same_mapping = false;
+    for (i, &(server_a, mapped_a)) in observed.iter().enumerate() {
+        for &(server_b, mapped_b) in &observed[i + 1..] {
+            if server_a.ip() == server_b.ip() && server_a.port() != server_b.port() {
+                if mapped_a != mapped_b {
+                    return MappingBehaviour::AddressAndPortDependent;
+                }
+                same_ip_same_mapping = true;
+            }
+        }
+    }
+    if same_ip_same_mapping {
+        MappingBehaviour::AddressDependent
+    }
+    else {
+        MappingBehaviour::AddressAndPortDependent
+    }
+}
+
+/// Send a request to `server`, retransmitting until we get a response or the test times out.
+fn query(socket: &UdpSocket,
+         server: &SocketAddr,
+         request: Request,
+         deadline: Instant,
+         warnings: &mut Vec<NatBehaviourWarning>)
+         -> Result<Option<Response>, NatBehaviourError> {
+    let deadline = cmp::min(deadline, Instant::now() + Duration::from_millis(TEST_TIMEOUT_MS));
+    let transaction_id = stun::new_transaction_id();
+    let send_data = match request {
+        Request::Simple => listener_message::REQUEST_MAGIC_CONSTANT.to_vec(),
+        Request::Stun { change_ip, change_port } => {
+            stun::binding_request_with_change(&transaction_id, change_ip, change_port)
+        },
+    };
+
+    let mut recv_data = [0u8; 512];
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = cmp::min(recv_deadline + Duration::from_millis(RETRANSMIT_INTERVAL_MS),
+                                 deadline);
+        let _ = match socket.send_to(&send_data[..], &**server) {
+            Ok(n) => n,
+            Err(e) => return Err(NatBehaviourError::SendError { err: e }),
+        };
+        loop {
+            let (read_size, recv_addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                Ok(Some(res)) => res,
+                Ok(None) => break,
+                Err(e) => return Err(NatBehaviourError::RecvError { err: e }),
+            };
+            let data = &recv_data[..read_size];
+            match request {
+                Request::Simple => {
+                    if recv_addr != *server {
+                        continue;
+                    }
+                    if let Ok(listener_message::EchoExternalAddr { external_addr })
+                            = deserialise::<listener_message::EchoExternalAddr>(data) {
+                        return Ok(Some(Response {
+                            mapped_addr: external_addr,
+                            other_addr: None,
+                        }));
+                    }
+                },
+                // Responses to CHANGE-REQUESTs come from a different address so we can only match
+                // them up by transaction ID.
+                Request::Stun { .. } => {
+                    if !stun::is_stun_message(data) {
+                        continue;
+                    }
+                    match stun::decode_binding_response(data) {
+                        Ok(response) => {
+                            if response.transaction_id == transaction_id {
+                                return Ok(Some(Response {
+                                    mapped_addr: SocketAddr(response.mapped_addr),
+                                    other_addr: response.other_addr,
+                                }));
+                            }
+                        },
+                        Err(e) => {
+                            warnings.push(NatBehaviourWarning::StunResponse {
+                                server_addr: recv_addr,
+                                err: e,
+                            });
+                        },
+                    }
+                },
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::classify_mapping;
+
+    use std::net;
+    use std::str::FromStr;
+
+    use socket_addr::SocketAddr;
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr(unwrap_result!(net::SocketAddr::from_str(s)))
+    }
+
+    #[test]
+    fn classify_mapping_from_observations() {
+        let not_local = |_: &SocketAddr| false;
+
+        let eim = [(addr("198.51.100.1:3478"), addr("203.0.113.5:4000")),
+                   (addr("198.51.100.2:3478"), addr("203.0.113.5:4000"))];
+        assert_eq!(classify_mapping(&eim, &not_local), MappingBehaviour::EndpointIndependent);
+
+        let adm = [(addr("198.51.100.1:3478"), addr("203.0.113.5:4000")),
+                   (addr("198.51.100.1:3479"), addr("203.0.113.5:4000")),
+                   (addr("198.51.100.2:3478"), addr("203.0.113.5:4001"))];
+        assert_eq!(classify_mapping(&adm, &not_local), MappingBehaviour::AddressDependent);
+
+        let apdm = [(addr("198.51.100.1:3478"), addr("203.0.113.5:4000")),
+                    (addr("198.51.100.1:3479"), addr("203.0.113.5:4001"))];
+        assert_eq!(classify_mapping(&apdm, &not_local),
+                   MappingBehaviour::AddressAndPortDependent);
+
+        assert_eq!(classify_mapping(&eim[..1], &not_local), MappingBehaviour::Unknown);
+        assert_eq!(classify_mapping(&eim, &|_: &SocketAddr| true), MappingBehaviour::NoNat);
+    }
+
+    #[test]
+    fn symmetric_nats_cannot_punch_each_other() {
+        let symmetric = NatBehaviour {
+            mapping: MappingBehaviour::AddressAndPortDependent,
+            filtering: FilteringBehaviour::AddressAndPortDependent,
+        };
+        let full_cone = NatBehaviour {
+            mapping: MappingBehaviour::EndpointIndependent,
+            filtering: FilteringBehaviour::EndpointIndependent,
+        };
+        assert!(full_cone.is_unrestricted());
+        assert!(!symmetric.is_unrestricted());
+        assert!(!symmetric.hole_punching_viable_with(&symmetric));
+        assert!(symmetric.hole_punching_viable_with(&full_cone));
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A minimal NAT-PMP (RFC 6886) client.
+
+use std::cmp;
+use std::io;
+use std::net::{self, Ipv4Addr, UdpSocket};
+use std::time::{Instant, Duration};
+
+use byteorder::{BigEndian, ByteOrder};
+use igd::PortMappingProtocol;
+
+use socket_utils::RecvUntil;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// The lifetime we ask for when mapping a port. This is the value recommended by RFC 6886.
+pub const DEFAULT_LIFETIME_SECS: u32 = 7200;
+
+const OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const OPCODE_MAP_UDP: u8 = 1;
+const OPCODE_MAP_TCP: u8 = 2;
+const RESPONSE_FLAG: u8 = 128;
+const INITIAL_RETRANSMIT_MS: u64 = 250;
+
+quick_error! {
+    /// Errors raised when talking to a NAT-PMP gateway.
+    #[derive(Debug)]
+    pub enum NatPmpError {
+        /// IO error talking to the gateway.
+        Io { err: io::Error } {
+            description("IO error talking to NAT-PMP gateway")
+            display("IO error talking to NAT-PMP gateway: {}", err)
+            cause(err)
+        }
+        /// The gateway did not respond.
+        TimedOut {
+            description("NAT-PMP gateway did not respond")
+        }
+        /// The gateway sent a response we couldn't parse.
+        InvalidResponse {
+            description("NAT-PMP gateway sent an invalid response")
+        }
+        /// The gateway responded with a non-zero result code.
+        ResultCode { code: u16 } {
+            description("NAT-PMP gateway returned an error")
+            display("NAT-PMP gateway returned error code {} ({})", code, match *code {
+                1 => "unsupported version",
+                2 => "not authorized or refused",
+                3 => "network failure",
+                4 => "out of resources",
+                5 => "unsupported opcode",
+                _ => "unknown error",
+            })
+        }
+    }
+}
+
+/// A gateway which has responded to NAT-PMP requests.
+#[derive(Debug, Clone)]
+pub struct NatPmpGateway {
+    /// The address of the gateway's NAT-PMP server.
+    pub addr: net::SocketAddrV4,
+    /// The gateway's external IP address.
+    pub external_ip: Ipv4Addr,
+}
+
+/// A port mapping granted by a NAT-PMP gateway.
+#[derive(Debug, Clone, Copy)]
+pub struct NatPmpMapping {
+    /// The external port.
+    pub external_port: u16,
+    /// How long the gateway will keep the mapping for, in seconds.
+    pub lifetime_secs: u32,
+}
+
+impl NatPmpGateway {
+    /// Check whether there is a NAT-PMP server at `gateway_ip` and ask it for its external
+    /// address.
+    pub fn probe(gateway_ip: Ipv4Addr, timeout: Duration) -> Result<NatPmpGateway, NatPmpError> {
+        let addr = net::SocketAddrV4::new(gateway_ip, NAT_PMP_PORT);
+        let request = [0, OPCODE_EXTERNAL_ADDRESS];
+        let response = try!(request_response(&addr, &request[..], OPCODE_EXTERNAL_ADDRESS, 12,
+                                             timeout));
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+        Ok(NatPmpGateway {
+            addr: addr,
+            external_ip: external_ip,
+        })
+    }
+
+    /// Ask the gateway to forward `external_port` (or some other port if that one is not
+    /// available, or if `external_port` is zero) to `internal_port` on this machine. A
+    /// `lifetime_secs` of zero deletes the mapping.
+    pub fn map_port(&self,
+                    protocol: PortMappingProtocol,
+                    internal_port: u16,
+                    external_port: u16,
+                    lifetime_secs: u32,
+                    timeout: Duration)
+                    -> Result<NatPmpMapping, NatPmpError> {
+        let opcode = match protocol {
+            PortMappingProtocol::UDP => OPCODE_MAP_UDP,
+            PortMappingProtocol::TCP => OPCODE_MAP_TCP,
+        };
+        let mut request = [0u8; 12];
+        request[1] = opcode;
+        BigEndian::write_u16(&mut request[4..6], internal_port);
+        BigEndian::write_u16(&mut request[6..8], external_port);
+        BigEndian::write_u32(&mut request[8..12], lifetime_secs);
+        let response = try!(request_response(&self.addr, &request[..], opcode, 16, timeout));
+        if BigEndian::read_u16(&response[8..10]) != internal_port {
+            return Err(NatPmpError::InvalidResponse);
+        }
+        Ok(NatPmpMapping {
+            external_port: BigEndian::read_u16(&response[10..12]),
+            lifetime_secs: BigEndian::read_u32(&response[12..16]),
+        })
+    }
+
+    /// The external address of a mapping.
+    pub fn external_addr(&self, mapping: &NatPmpMapping) -> net::SocketAddrV4 {
+        net::SocketAddrV4::new(self.external_ip, mapping.external_port)
+    }
+}
+
+/// Send `request` to the gateway, retransmitting with exponential backoff as described in RFC
+/// 6886, until we get a response of at least `min_len` bytes or time out.
+fn request_response(gateway: &net::SocketAddrV4,
+                    request: &[u8],
+                    opcode: u8,
+                    min_len: usize,
+                    timeout: Duration)
+                    -> Result<Vec<u8>, NatPmpError> {
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => return Err(NatPmpError::Io { err: e }),
+    };
+    let deadline = Instant::now() + timeout;
+    let mut retransmit = Duration::from_millis(INITIAL_RETRANSMIT_MS);
+    let mut recv_data = [0u8; 64];
+    let mut recv_deadline = Instant::now();
+    while recv_deadline < deadline {
+        recv_deadline = cmp::min(recv_deadline + retransmit, deadline);
+        retransmit = retransmit * 2;
+        if let Err(e) = socket.send_to(request, gateway) {
+            return Err(NatPmpError::Io { err: e });
+        }
+        loop {
+            let (n, addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(e) => return Err(NatPmpError::Io { err: e }),
+            };
+            // Only the gateway is allowed to answer us.
+            if *addr != net::SocketAddr::V4(*gateway) {
+                continue;
+            }
+            if n < 4 || recv_data[0] != 0 {
+                return Err(NatPmpError::InvalidResponse);
+            }
+            // Skip responses to other requests, such as a late answer to an external address
+            // request.
+            if recv_data[1] != RESPONSE_FLAG + opcode {
+                continue;
+            }
+            let code = BigEndian::read_u16(&recv_data[2..4]);
+            if code != 0 {
+                return Err(NatPmpError::ResultCode { code: code });
+            }
+            if n < min_len {
+                return Err(NatPmpError::InvalidResponse);
+            }
+            return Ok(recv_data[..n].to_vec());
+        }
+    }
+    Err(NatPmpError::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{OPCODE_EXTERNAL_ADDRESS, OPCODE_MAP_UDP, RESPONSE_FLAG};
+
+    use std::net::{self, Ipv4Addr, UdpSocket};
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+    use igd::PortMappingProtocol;
+
+    #[test]
+    fn responses_to_other_requests_are_skipped() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let addr = match unwrap_result!(socket.local_addr()) {
+            net::SocketAddr::V4(addr) => addr,
+            net::SocketAddr::V6(..) => panic!("Expected an IPv4 address"),
+        };
+        let jh = thread!("responses_to_other_requests_are_skipped", move || {
+            let mut buf = [0u8; 64];
+            let (n, client) = unwrap_result!(socket.recv_from(&mut buf));
+            assert_eq!((n, buf[1]), (12, OPCODE_MAP_UDP));
+
+            // A late answer to an external address request comes first.
+            let mut stale = [0u8; 12];
+            stale[1] = RESPONSE_FLAG + OPCODE_EXTERNAL_ADDRESS;
+            let _ = unwrap_result!(socket.send_to(&stale[..], client));
+
+            let mut response = [0u8; 16];
+            response[1] = RESPONSE_FLAG + OPCODE_MAP_UDP;
+            BigEndian::write_u16(&mut response[8..10], BigEndian::read_u16(&buf[4..6]));
+            BigEndian::write_u16(&mut response[10..12], 40000);
+            BigEndian::write_u32(&mut response[12..16], 3600);
+            let _ = unwrap_result!(socket.send_to(&response[..], client));
+        });
+
+        let gateway = NatPmpGateway {
+            addr: addr,
+            external_ip: Ipv4Addr::new(203, 0, 113, 5),
+        };
+        let mapping = unwrap_result!(gateway.map_port(PortMappingProtocol::UDP, 5000, 5000, 3600,
+                                                      Duration::from_secs(5)));
+        assert_eq!((mapping.external_port, mapping.lifetime_secs), (40000, 3600));
+        assert_eq!(gateway.external_addr(&mapping),
+                   net::SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), 40000));
+        unwrap_result!(jh.join());
+    }
+}
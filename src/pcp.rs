@@ -0,0 +1,358 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A minimal Port Control Protocol (RFC 6887) client. We use it to map ports on IPv4 NATs and to
+//! open pinholes in IPv6 firewalls.
+
+use std::cmp;
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::time::{Instant, Duration};
+
+use byteorder::{BigEndian, ByteOrder};
+use igd::PortMappingProtocol;
+use rand;
+
+use socket_utils::RecvUntil;
+
+/// PCP shares its port with NAT-PMP.
+pub const PCP_PORT: u16 = 5351;
+
+/// The lifetime we ask for when mapping a port.
+pub const DEFAULT_LIFETIME_SECS: u32 = 7200;
+
+pub type Nonce = [u8; 12];
+
+const VERSION: u8 = 2;
+const OPCODE_ANNOUNCE: u8 = 0;
+const OPCODE_MAP: u8 = 1;
+const RESPONSE_FLAG: u8 = 0x80;
+const HEADER_LEN: usize = 24;
+const MAP_PAYLOAD_LEN: usize = 36;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const INITIAL_RETRANSMIT_MS: u64 = 250;
+
+quick_error! {
+    /// Errors raised when talking to a PCP server.
+    #[derive(Debug)]
+    pub enum PcpError {
+        /// IO error talking to the server.
+        Io { err: io::Error } {
+            description("IO error talking to PCP server")
+            display("IO error talking to PCP server: {}", err)
+            cause(err)
+        }
+        /// The server did not respond.
+        TimedOut {
+            description("PCP server did not respond")
+        }
+        /// The server sent a response we couldn't parse.
+        InvalidResponse {
+            description("PCP server sent an invalid response")
+        }
+        /// The server responded with a non-zero result code.
+        ResultCode { code: u8 } {
+            description("PCP server returned an error")
+            display("PCP server returned error code {} ({})", code, match *code {
+                1 => "UNSUPP_VERSION",
+                2 => "NOT_AUTHORIZED",
+                3 => "MALFORMED_REQUEST",
+                4 => "UNSUPP_OPCODE",
+                5 => "UNSUPP_OPTION",
+                6 => "MALFORMED_OPTION",
+                7 => "NETWORK_FAILURE",
+                8 => "NO_RESOURCES",
+                9 => "UNSUPP_PROTOCOL",
+                10 => "USER_EX_QUOTA",
+                11 => "CANNOT_PROVIDE_EXTERNAL",
+                12 => "ADDRESS_MISMATCH",
+                13 => "EXCESSIVE_REMOTE_PEERS",
+                _ => "unknown error",
+            })
+        }
+    }
+}
+
+/// A PCP server which has responded to our requests.
+#[derive(Debug, Clone)]
+pub struct PcpServer {
+    /// The address of the server.
+    pub addr: net::SocketAddr,
+    /// The local address we talk to the server from. PCP servers refuse requests which don't come
+    /// from the address they claim to be on behalf of, so we always send from this address.
+    pub client_ip: IpAddr,
+}
+
+/// A mapping (or, for IPv6, a firewall pinhole) granted by a PCP server.
+#[derive(Debug, Clone, Copy)]
+pub struct PcpMapping {
+    /// Identifies the mapping to the server. Requests to renew or delete the mapping must carry
+    /// the same nonce.
+    pub nonce: Nonce,
+    /// The external address that peers can reach us on.
+    pub external_addr: net::SocketAddr,
+    /// How long the server will keep the mapping for, in seconds.
+    pub lifetime_secs: u32,
+}
+
+impl PcpServer {
+    /// Check whether there is a PCP server at `server_addr` which will accept requests from
+    /// `client_ip`.
+    pub fn probe(server_addr: net::SocketAddr, client_ip: IpAddr, timeout: Duration)
+        -> Result<PcpServer, PcpError>
+    {
+        let server = PcpServer {
+            addr: server_addr,
+            client_ip: client_ip,
+        };
+        let request = server.encode_header(OPCODE_ANNOUNCE, 0);
+        let _ = try!(server.request_response(&request[..], OPCODE_ANNOUNCE, None, timeout));
+        Ok(server)
+    }
+
+    /// Ask the server to forward `external_port` (or some other port if that one is not
+    /// available, or if `external_port` is zero) to `internal_port` on `client_ip`. On an IPv6
+    /// network without NAT this opens a pinhole in the firewall and the external address will be
+    /// the same as the internal one.
+    pub fn map_port(&self,
+                    protocol: PortMappingProtocol,
+                    internal_port: u16,
+                    external_port: u16,
+                    lifetime_secs: u32,
+                    timeout: Duration)
+                    -> Result<PcpMapping, PcpError> {
+        self.map_port_with_nonce(rand::random(), protocol, internal_port, external_port,
+                                 lifetime_secs, timeout)
+    }
+
+    /// As `map_port` but reuses a nonce from an earlier mapping, which is how a mapping is renewed
+    /// or (with a `lifetime_secs` of zero) deleted.
+    pub fn map_port_with_nonce(&self,
+                               nonce: Nonce,
+                               protocol: PortMappingProtocol,
+                               internal_port: u16,
+                               external_port: u16,
+                               lifetime_secs: u32,
+                               timeout: Duration)
+                               -> Result<PcpMapping, PcpError> {
+        let mut request = self.encode_header(OPCODE_MAP, lifetime_secs);
+        request.extend_from_slice(&encode_map_payload(&nonce, protocol, internal_port,
+                                                      external_port, self.client_ip)[..]);
+        let response = try!(self.request_response(&request[..], OPCODE_MAP, Some(&nonce),
+                                                  timeout));
+        let payload = &response[HEADER_LEN..];
+        if BigEndian::read_u16(&payload[16..18]) != internal_port {
+            return Err(PcpError::InvalidResponse);
+        }
+        let external_port = BigEndian::read_u16(&payload[18..20]);
+        let external_ip = decode_ip(&payload[20..36]);
+        Ok(PcpMapping {
+            nonce: nonce,
+            external_addr: net::SocketAddr::new(external_ip, external_port),
+            lifetime_secs: BigEndian::read_u32(&response[4..8]),
+        })
+    }
+
+    fn encode_header(&self, opcode: u8, lifetime_secs: u32) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_LEN];
+        header[0] = VERSION;
+        header[1] = opcode;
+        BigEndian::write_u32(&mut header[4..8], lifetime_secs);
+        header[8..24].copy_from_slice(&encode_ip(self.client_ip)[..]);
+        header
+    }
+
+    /// Send `request` to the server, retransmitting with exponential backoff, until we get a
+    /// successful response or time out. If `nonce` is given the response must carry a MAP
+    /// payload with the same nonce.
+    fn request_response(&self,
+                        request: &[u8],
+                        opcode: u8,
+                        nonce: Option<&Nonce>,
+                        timeout: Duration)
+                        -> Result<Vec<u8>, PcpError> {
+        let socket = match UdpSocket::bind(&net::SocketAddr::new(self.client_ip, 0)) {
+            Ok(socket) => socket,
+            Err(e) => return Err(PcpError::Io { err: e }),
+        };
+        let deadline = Instant::now() + timeout;
+        let mut retransmit = Duration::from_millis(INITIAL_RETRANSMIT_MS);
+        let mut recv_data = [0u8; 1100];
+        let mut recv_deadline = Instant::now();
+        while recv_deadline < deadline {
+            recv_deadline = cmp::min(recv_deadline + retransmit, deadline);
+            retransmit = retransmit * 2;
+            if let Err(e) = socket.send_to(request, &self.addr) {
+                return Err(PcpError::Io { err: e });
+            }
+            loop {
+                let (n, addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
+                    Ok(Some(x)) => x,
+                    Ok(None) => break,
+                    Err(e) => return Err(PcpError::Io { err: e }),
+                };
+                // Only the server is allowed to answer us. Compare IPs and ports separately since
+                // the scope id of a link-local address won't survive the trip.
+                if addr.ip() != self.addr.ip() || addr.port() != self.addr.port() {
+                    continue;
+                }
+                let response = &recv_data[..n];
+                if n < HEADER_LEN || response[0] != VERSION {
+                    return Err(PcpError::InvalidResponse);
+                }
+                // A late response to some other kind of request, such as an ANNOUNCE.
+                if response[1] != RESPONSE_FLAG + opcode {
+                    continue;
+                }
+                if let Some(nonce) = nonce {
+                    if n < HEADER_LEN + MAP_PAYLOAD_LEN {
+                        return Err(PcpError::InvalidResponse);
+                    }
+                    // A late response to a request for some other mapping.
+                    if &response[HEADER_LEN..HEADER_LEN + 12] != &nonce[..] {
+                        continue;
+                    }
+                }
+                if response[3] != 0 {
+                    return Err(PcpError::ResultCode { code: response[3] });
+                }
+                return Ok(response.to_vec());
+            }
+        }
+        Err(PcpError::TimedOut)
+    }
+}
+
+fn encode_map_payload(nonce: &Nonce,
+                      protocol: PortMappingProtocol,
+                      internal_port: u16,
+                      external_port: u16,
+                      client_ip: IpAddr)
+                      -> [u8; MAP_PAYLOAD_LEN] {
+    let mut payload = [0u8; MAP_PAYLOAD_LEN];
+    payload[..12].copy_from_slice(&nonce[..]);
+    payload[12] = match protocol {
+        PortMappingProtocol::UDP => PROTOCOL_UDP,
+        PortMappingProtocol::TCP => PROTOCOL_TCP,
+    };
+    BigEndian::write_u16(&mut payload[16..18], internal_port);
+    BigEndian::write_u16(&mut payload[18..20], external_port);
+    // We don't care which external address we get but we need to say which address family we
+    // want.
+    let any_ip = match client_ip {
+        IpAddr::V4(..) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        IpAddr::V6(..) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    };
+    payload[20..36].copy_from_slice(&encode_ip(any_ip)[..]);
+    payload
+}
+
+/// PCP always sends 128 bit addresses. IPv4 addresses are sent as IPv4-mapped IPv6 addresses.
+fn encode_ip(ip: IpAddr) -> [u8; 16] {
+    let ipv6 = match ip {
+        IpAddr::V4(ipv4) => ipv4.to_ipv6_mapped(),
+        IpAddr::V6(ipv6) => ipv6,
+    };
+    let mut ret = [0u8; 16];
+    for (i, segment) in ipv6.segments().iter().enumerate() {
+        BigEndian::write_u16(&mut ret[2 * i..2 * i + 2], *segment);
+    }
+    ret
+}
+
+fn decode_ip(data: &[u8]) -> IpAddr {
+    if data[..10].iter().all(|b| *b == 0) && data[10] == 0xff && data[11] == 0xff {
+        return IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15]));
+    }
+    let mut segments = [0u16; 8];
+    for (i, segment) in segments.iter_mut().enumerate() {
+        *segment = BigEndian::read_u16(&data[2 * i..2 * i + 2]);
+    }
+    IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                             segments[4], segments[5], segments[6], segments[7]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_ip, decode_ip, encode_map_payload, PcpServer, HEADER_LEN, MAP_PAYLOAD_LEN,
+                OPCODE_ANNOUNCE, OPCODE_MAP, RESPONSE_FLAG, VERSION};
+
+    use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+    use igd::PortMappingProtocol;
+
+    #[test]
+    fn ip_round_trip() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let encoded = encode_ip(ipv4);
+        assert_eq!(&encoded[..12], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff][..]);
+        assert_eq!(decode_ip(&encoded[..]), ipv4);
+
+        let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        assert_eq!(decode_ip(&encode_ip(ipv6)[..]), ipv6);
+    }
+
+    #[test]
+    fn map_payload_layout() {
+        let nonce = [7u8; 12];
+        let client_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        let payload = encode_map_payload(&nonce, PortMappingProtocol::UDP, 1234, 5678, client_ip);
+        assert_eq!(&payload[..12], &nonce[..]);
+        assert_eq!(payload[12], 17);
+        assert_eq!(&payload[16..20], &[0x04, 0xd2, 0x16, 0x2e][..]);
+        assert_eq!(decode_ip(&payload[20..36]), IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn responses_to_other_requests_are_skipped() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let server_addr = unwrap_result!(socket.local_addr());
+        let jh = thread!("responses_to_other_requests_are_skipped", move || {
+            let mut buf = [0u8; 1100];
+            let (n, client) = unwrap_result!(socket.recv_from(&mut buf));
+            assert_eq!((n, buf[1]), (HEADER_LEN + MAP_PAYLOAD_LEN, OPCODE_MAP));
+
+            // A late answer to an ANNOUNCE comes first.
+            let mut stale = [0u8; HEADER_LEN];
+            stale[0] = VERSION;
+            stale[1] = RESPONSE_FLAG + OPCODE_ANNOUNCE;
+            let _ = unwrap_result!(socket.send_to(&stale[..], client));
+
+            let mut response = buf[..n].to_vec();
+            response[1] = RESPONSE_FLAG + OPCODE_MAP;
+            BigEndian::write_u32(&mut response[4..8], 3600);
+            BigEndian::write_u16(&mut response[HEADER_LEN + 18..HEADER_LEN + 20], 40000);
+            let external_ip = encode_ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)));
+            response[HEADER_LEN + 20..HEADER_LEN + 36].copy_from_slice(&external_ip[..]);
+            let _ = unwrap_result!(socket.send_to(&response[..], client));
+        });
+
+        let server = PcpServer {
+            addr: server_addr,
+            client_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        };
+        let mapping = unwrap_result!(server.map_port(PortMappingProtocol::UDP, 5000, 5000, 3600,
+                                                     Duration::from_secs(5)));
+        assert_eq!(mapping.external_addr,
+                   net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)), 40000));
+        assert_eq!(mapping.lifetime_secs, 3600);
+        unwrap_result!(jh.join());
+    }
+}
@@ -0,0 +1,896 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Port mappings on gateways are leased for a limited time. This module keeps them alive while a
+//! socket is in use and removes them afterwards.
+
+use std::cmp;
+use std::io;
+use std::net::{self, IpAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Instant, Duration};
+
+use igd::{self, PortMappingProtocol};
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+use w_result::{WResult, WOk, WErr};
+
+use mapping_context::{self, InterfaceV4, MappingContext};
+use nat_pmp::{self, NatPmpError, NatPmpGateway, NatPmpMapping};
+use pcp::{self, PcpError, PcpMapping, PcpServer};
+use socket_utils;
+use utils::DisplaySlice;
+
+/// The lease we ask IGD gateways for. If we crash without removing a mapping the gateway will
+/// drop it after this long.
+pub const IGD_LEASE_DURATION_SECS: u32 = 1200;
+
+/// How often the renewal thread checks whether it should stop.
+const POLL_INTERVAL_MS: u64 = 100;
+/// How long to wait before trying again when renewing a lease fails.
+const RETRY_INTERVAL_SECS: u64 = 30;
+/// How long to wait for a gateway to respond when renewing or removing a NAT-PMP or PCP mapping.
+const REQUEST_TIMEOUT_SECS: u64 = 1;
+
+/// Ask an IGD gateway to map any external port to `local_addr`. Some gateways only support
+/// permanent leases, in which case we ask for one of those. The returned lease should be passed
+/// to a `PortMappingGuard` so that the mapping gets removed eventually.
+pub fn igd_get_any_address(gateway: &igd::Gateway,
+                           protocol: PortMappingProtocol,
+                           local_addr: net::SocketAddrV4,
+                           description: &str)
+                           -> Result<(net::SocketAddrV4, PortMappingLease), igd::AddAnyPortError> {
+    let (external_addr, lease, _) = try!(igd_get_any_address_with_lease(gateway, protocol,
+                                                                        local_addr, description,
+                                                                        IGD_LEASE_DURATION_SECS));
+    Ok((external_addr, lease))
+}
+
+/// As `igd_get_any_address` but with a lease of `lease_duration_secs`. Also returns the lease
+/// the gateway gave us, which is zero if it's permanent.
+fn igd_get_any_address_with_lease(gateway: &igd::Gateway,
+                                  protocol: PortMappingProtocol,
+                                  local_addr: net::SocketAddrV4,
+                                  description: &str,
+                                  lease_duration_secs: u32)
+        -> Result<(net::SocketAddrV4, PortMappingLease, u32), igd::AddAnyPortError>
+{
+    let (external_addr, lease_duration_secs) = try!(with_permanent_lease_fallback(
+        |lease_duration_secs| {
+            gateway.get_any_address(protocol, local_addr, lease_duration_secs, description)
+        },
+        lease_duration_secs
+    ));
+    let lease = PortMappingLease::igd(gateway, protocol, local_addr, external_addr.port(),
+                                      description, lease_duration_secs);
+    Ok((external_addr, lease, lease_duration_secs))
+}
+
+/// Call `get_any_address` with `lease_duration_secs`, then again with a permanent lease if the
+/// gateway says it only supports those. Any other error is returned as it is rather than risk a
+/// permanent mapping. Also returns the lease we got.
+fn with_permanent_lease_fallback<F>(get_any_address: F, lease_duration_secs: u32)
+        -> Result<(net::SocketAddrV4, u32), igd::AddAnyPortError>
+    where F: Fn(u32) -> Result<net::SocketAddrV4, igd::AddAnyPortError>
+{
+    match get_any_address(lease_duration_secs) {
+        Ok(external_addr) => Ok((external_addr, lease_duration_secs)),
+        Err(igd::AddAnyPortError::OnlyPermanentLeasesSupported) => {
+            let external_addr = try!(get_any_address(0));
+            Ok((external_addr, 0))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Ask a NAT-PMP gateway to map the same external port to `internal_port`, or any port if that
+/// one is taken. The returned lease should be passed to a `PortMappingGuard` so that the mapping
+/// gets renewed and eventually removed.
+pub fn nat_pmp_get_address(gateway: &NatPmpGateway,
+                           protocol: PortMappingProtocol,
+                           internal_port: u16)
+                           -> Result<(net::SocketAddrV4, PortMappingLease), NatPmpError> {
+    let (external_addr, lease, _) = try!(nat_pmp_get_address_with_lease(
+        gateway, protocol, internal_port, internal_port, nat_pmp::DEFAULT_LIFETIME_SECS
+    ));
+    Ok((external_addr, lease))
+}
+
+/// As `nat_pmp_get_address` but preferring `external_port` and with a lease of
+/// `lifetime_secs`. Also returns the lease the gateway gave us.
+fn nat_pmp_get_address_with_lease(gateway: &NatPmpGateway,
+                                  protocol: PortMappingProtocol,
+                                  internal_port: u16,
+                                  external_port: u16,
+                                  lifetime_secs: u32)
+        -> Result<(net::SocketAddrV4, PortMappingLease, u32), NatPmpError>
+{
+    let mapping = try!(gateway.map_port(protocol, internal_port, external_port, lifetime_secs,
+                                        Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+    let lease = PortMappingLease::nat_pmp(gateway.clone(), protocol, internal_port, &mapping);
+    Ok((gateway.external_addr(&mapping), lease, mapping.lifetime_secs))
+}
+
+/// Ask a PCP server to map the same external port to `internal_port`, or any port if that one is
+/// taken. On an IPv6 network without NAT this opens a firewall pinhole instead, and the external
+/// address is the internal one. The returned lease should be passed to a `PortMappingGuard`.
+pub fn pcp_get_address(server: &PcpServer,
+                       protocol: PortMappingProtocol,
+                       internal_port: u16)
+                       -> Result<(net::SocketAddr, PortMappingLease), PcpError> {
+    let (external_addr, lease, _) = try!(pcp_get_address_with_lease(
+        server, protocol, internal_port, internal_port, pcp::DEFAULT_LIFETIME_SECS
+    ));
+    Ok((external_addr, lease))
+}
+
+/// As `pcp_get_address` but suggesting `external_port` and with a lease of `lifetime_secs`. Also
+/// returns the lease the server gave us.
+fn pcp_get_address_with_lease(server: &PcpServer,
+                              protocol: PortMappingProtocol,
+                              internal_port: u16,
+                              external_port: u16,
+                              lifetime_secs: u32)
+        -> Result<(net::SocketAddr, PortMappingLease, u32), PcpError>
+{
+    let mapping = try!(server.map_port(protocol, internal_port, external_port, lifetime_secs,
+                                       Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+    let lease = PortMappingLease::pcp(server.clone(), protocol, internal_port, &mapping);
+    Ok((mapping.external_addr, lease, mapping.lifetime_secs))
+}
+
+/// Map `internal_port` on an interface without an IGD gateway. We ask the interface's PCP server
+/// if it has one, since PCP can also open IPv6 pinholes, and its NAT-PMP gateway otherwise.
+/// Returns `None` if the interface has neither.
+pub fn pcp_or_nat_pmp_get_address(pcp_server: Option<&PcpServer>,
+                                  nat_pmp_gateway: Option<&NatPmpGateway>,
+                                  protocol: PortMappingProtocol,
+                                  internal_port: u16)
+        -> Option<Result<(net::SocketAddr, PortMappingLease), MapPortWarning>>
+{
+    if let Some(server) = pcp_server {
+        return Some(pcp_get_address(server, protocol, internal_port).map_err(|e| {
+            MapPortWarning::Pcp {
+                server_addr: server.addr,
+                err: e,
+            }
+        }));
+    }
+    nat_pmp_gateway.map(|gateway| {
+        match nat_pmp_get_address(gateway, protocol, internal_port) {
+            Ok((external_addr, lease)) => Ok((net::SocketAddr::V4(external_addr), lease)),
+            Err(e) => {
+                Err(MapPortWarning::NatPmp {
+                    gateway_addr: gateway.addr,
+                    err: e,
+                })
+            },
+        }
+    })
+}
+
+/// Map `local_port` on `iface` through its gateways. We ask the interface's IGD gateway if it has
+/// one, then its PCP server and then its NAT-PMP gateway. This is the order that both udp and tcp
+/// sockets get mapped in. Returns `None` if the interface has no gateways.
+pub fn interface_v4_get_address(iface: &InterfaceV4,
+                                protocol: PortMappingProtocol,
+                                local_port: u16,
+                                description: &str)
+        -> Option<Result<(net::SocketAddr, PortMappingLease), MapPortWarning>>
+{
+    if let Some(ref gateway) = iface.gateway {
+        let local_addr = net::SocketAddrV4::new(iface.addr, local_port);
+        return Some(match igd_get_any_address(gateway, protocol, local_addr, description) {
+            Ok((external_addr, lease)) => Ok((net::SocketAddr::V4(external_addr), lease)),
+            Err(e) => {
+                Err(MapPortWarning::IgdGetAnyAddress {
+                    gateway_addr: gateway.addr,
+                    err: e,
+                })
+            },
+        });
+    }
+    pcp_or_nat_pmp_get_address(iface.pcp_server.as_ref(),
+                               iface.nat_pmp_gateway.as_ref(),
+                               protocol,
+                               local_port)
+}
+
+enum Lease {
+    Igd {
+        gateway: igd::Gateway,
+        local_addr: net::SocketAddrV4,
+        external_port: u16,
+        description: String,
+    },
+    NatPmp {
+        gateway: NatPmpGateway,
+        internal_port: u16,
+        external_port: u16,
+    },
+    Pcp {
+        server: PcpServer,
+        internal_port: u16,
+        mapping: PcpMapping,
+    },
+}
+
+/// A port mapping which a `PortMappingGuard` will keep renewed.
+pub struct PortMappingLease {
+    lease: Lease,
+    protocol: PortMappingProtocol,
+    /// `None` if the lease is permanent.
+    renew_at: Option<Instant>,
+}
+
+impl PortMappingLease {
+    fn igd(gateway: &igd::Gateway,
+           protocol: PortMappingProtocol,
+           local_addr: net::SocketAddrV4,
+           external_port: u16,
+           description: &str,
+           lease_duration_secs: u32)
+           -> PortMappingLease {
+        PortMappingLease {
+            lease: Lease::Igd {
+                gateway: gateway.clone(),
+                local_addr: local_addr,
+                external_port: external_port,
+                description: description.to_owned(),
+            },
+            protocol: protocol,
+            renew_at: renew_at(lease_duration_secs),
+        }
+    }
+
+    /// A mapping made through a NAT-PMP gateway.
+    pub fn nat_pmp(gateway: NatPmpGateway,
+                   protocol: PortMappingProtocol,
+                   internal_port: u16,
+                   mapping: &NatPmpMapping)
+                   -> PortMappingLease {
+        PortMappingLease {
+            lease: Lease::NatPmp {
+                gateway: gateway,
+                internal_port: internal_port,
+                external_port: mapping.external_port,
+            },
+            protocol: protocol,
+            renew_at: renew_at(mapping.lifetime_secs),
+        }
+    }
+
+    /// A mapping or pinhole made through a PCP server.
+    pub fn pcp(server: PcpServer,
+               protocol: PortMappingProtocol,
+               internal_port: u16,
+               mapping: &PcpMapping)
+               -> PortMappingLease {
+        PortMappingLease {
+            lease: Lease::Pcp {
+                server: server,
+                internal_port: internal_port,
+                mapping: *mapping,
+            },
+            protocol: protocol,
+            renew_at: renew_at(mapping.lifetime_secs),
+        }
+    }
+
+    /// Extend the lease.
+    fn renew(&mut self) {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        let lifetime_secs = match self.lease {
+            Lease::Igd { ref gateway, local_addr, external_port, ref description } => {
+                match gateway.add_port(self.protocol, external_port, local_addr,
+                                       IGD_LEASE_DURATION_SECS, description) {
+                    Ok(()) => Some(IGD_LEASE_DURATION_SECS),
+                    Err(_) => None,
+                }
+            },
+            Lease::NatPmp { ref gateway, internal_port, external_port } => {
+                match gateway.map_port(self.protocol, internal_port, external_port,
+                                       nat_pmp::DEFAULT_LIFETIME_SECS, timeout) {
+                    Ok(mapping) => Some(mapping.lifetime_secs),
+                    Err(_) => None,
+                }
+            },
+            Lease::Pcp { ref server, internal_port, ref mut mapping } => {
+                match server.map_port_with_nonce(mapping.nonce, self.protocol, internal_port,
+                                                 mapping.external_addr.port(),
+                                                 pcp::DEFAULT_LIFETIME_SECS, timeout) {
+                    Ok(new_mapping) => {
+                        *mapping = new_mapping;
+                        Some(new_mapping.lifetime_secs)
+                    },
+                    Err(_) => None,
+                }
+            },
+        };
+        self.renew_at = match lifetime_secs {
+            Some(lifetime_secs) => renew_at(lifetime_secs),
+            // There's nobody to report the error to. Keep trying while the lease lasts.
+            None => Some(Instant::now() + Duration::from_secs(RETRY_INTERVAL_SECS)),
+        };
+    }
+
+    /// Ask the gateway to delete the mapping.
+    fn remove(self) {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        // There's nobody to report errors to. If this fails the lease will expire eventually.
+        match self.lease {
+            Lease::Igd { gateway, external_port, .. } => {
+                let _ = gateway.remove_port(self.protocol, external_port);
+            },
+            Lease::NatPmp { gateway, internal_port, .. } => {
+                let _ = gateway.map_port(self.protocol, internal_port, 0, 0, timeout);
+            },
+            Lease::Pcp { server, internal_port, mapping } => {
+                let _ = server.map_port_with_nonce(mapping.nonce, self.protocol, internal_port,
+                                                   mapping.external_addr.port(), 0, timeout);
+            },
+        }
+    }
+}
+
+/// Renew leases halfway through their lifetime. A lifetime of zero means the lease is permanent.
+fn renew_at(lifetime_secs: u32) -> Option<Instant> {
+    if lifetime_secs == 0 {
+        return None;
+    }
+    Some(Instant::now() + Duration::from_secs(cmp::max(lifetime_secs / 2, 1) as u64))
+}
+
+/// RAII type which owns the port mappings made for a socket. A background thread renews the
+/// mappings until the guard is dropped, then removes them from the gateways. Keep the guard alive
+/// for as long as the socket, or anything made from it, is in use.
+pub struct PortMappingGuard {
+    stop_flag: Arc<AtomicBool>,
+    _raii_joiner: Option<RaiiThreadJoiner>,
+}
+
+impl PortMappingGuard {
+    /// Start renewing `leases`.
+    pub fn new(leases: Vec<PortMappingLease>) -> PortMappingGuard {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let raii_joiner = if leases.is_empty() {
+            None
+        }
+        else {
+            let cloned_stop_flag = stop_flag.clone();
+            Some(RaiiThreadJoiner::new(thread!("PortMappingGuard", move || {
+                Self::run(leases, cloned_stop_flag);
+            })))
+        };
+        PortMappingGuard {
+            stop_flag: stop_flag,
+            _raii_joiner: raii_joiner,
+        }
+    }
+
+    fn run(mut leases: Vec<PortMappingLease>, stop_flag: Arc<AtomicBool>) {
+        while !stop_flag.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            for lease in leases.iter_mut() {
+                if lease.renew_at.map_or(false, |renew_at| renew_at <= now) {
+                    lease.renew();
+                }
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        for lease in leases {
+            lease.remove();
+        }
+    }
+}
+
+impl Drop for PortMappingGuard {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+    }
+}
+
+quick_error! {
+    /// Warnings raised by `MappingContext::map_port`, and when mapping sockets. Each is a failed
+    /// attempt to map the port through one of the gateways known to the context.
+    #[derive(Debug)]
+    pub enum MapPortWarning {
+        /// Error asking the IGD gateway at `gateway_addr` for its external IP address.
+        IgdGetExternalIp {
+            gateway_addr: net::SocketAddrV4,
+            err: igd::GetExternalIpError,
+        } {
+            description("Error getting the external IP address of an IGD gateway")
+            display("Error getting the external IP address of the IGD gateway at {}: {}",
+                    gateway_addr, err)
+            cause(err)
+        }
+        /// The IGD gateway at `gateway_addr` refused to map the preferred external port.
+        IgdAddPort {
+            gateway_addr: net::SocketAddrV4,
+            external_port: u16,
+            err: igd::AddPortError,
+        } {
+            description("IGD gateway refused to map the preferred external port")
+            display("The IGD gateway at {} refused to map external port {}: {}",
+                    gateway_addr, external_port, err)
+            cause(err)
+        }
+        /// The IGD gateway at `gateway_addr` refused to map any external port.
+        IgdGetAnyAddress {
+            gateway_addr: net::SocketAddrV4,
+            err: igd::AddAnyPortError,
+        } {
+            description("IGD gateway refused to map any external port")
+            display("The IGD gateway at {} refused to map any external port: {}",
+                    gateway_addr, err)
+            cause(err)
+        }
+        /// Error mapping the port through the PCP server at `server_addr`.
+        Pcp {
+            server_addr: net::SocketAddr,
+            err: PcpError,
+        } {
+            description("Error mapping the port through a PCP server")
+            display("Error mapping the port through the PCP server at {}: {}", server_addr, err)
+            cause(err)
+        }
+        /// Error mapping the port through the NAT-PMP gateway at `gateway_addr`.
+        NatPmp {
+            gateway_addr: net::SocketAddrV4,
+            err: NatPmpError,
+        } {
+            description("Error mapping the port through a NAT-PMP gateway")
+            display("Error mapping the port through the NAT-PMP gateway at {}: {}",
+                    gateway_addr, err)
+            cause(err)
+        }
+    }
+}
+
+quick_error! {
+    /// Errors returned by `MappingContext::map_port`.
+    #[derive(Debug)]
+    pub enum MapPortError {
+        /// The context doesn't know of any gateway which could map the address.
+        NoGateways {
+            description("No gateways are known which could map this address")
+        }
+        /// Every gateway failed to map the port.
+        AllGatewaysFailed { warnings: Vec<MapPortWarning> } {
+            description("Every gateway failed to map the port")
+            display("Every gateway failed to map the port. {}",
+                    DisplaySlice("warning", &warnings))
+        }
+    }
+}
+
+impl From<MapPortError> for io::Error {
+    fn from(e: MapPortError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            MapPortError::NoGateways => io::ErrorKind::NotFound,
+            MapPortError::AllGatewaysFailed { .. } => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// The device which made a `PortMapping`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMappingGateway {
+    /// A UPnP IGD gateway at the given address.
+    Igd(net::SocketAddrV4),
+    /// A PCP server at the given address.
+    Pcp(net::SocketAddr),
+    /// A NAT-PMP gateway at the given address.
+    NatPmp(net::SocketAddrV4),
+}
+
+/// A port mapping made with `MappingContext::map_port`. The mapping is not renewed; it lasts until
+/// `expires` or until this is dropped, whichever comes first.
+pub struct PortMapping {
+    /// The device which made the mapping.
+    pub gateway: PortMappingGateway,
+    /// The local address which the mapping forwards to.
+    pub local_addr: net::SocketAddr,
+    /// The address that peers on the other side of the gateway can reach us on.
+    pub external_addr: net::SocketAddr,
+    /// When the gateway will drop the mapping. `None` if the gateway only grants permanent
+    /// mappings.
+    pub expires: Option<Instant>,
+    lease: Option<PortMappingLease>,
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        if let Some(lease) = self.lease.take() {
+            lease.remove();
+        }
+    }
+}
+
+pub fn map_port(mc: &MappingContext,
+                protocol: PortMappingProtocol,
+                local_addr: net::SocketAddr,
+                preferred_external_port: Option<u16>,
+                lease: Duration)
+                -> WResult<PortMapping, MapPortWarning, MapPortError> {
+    let mut warnings = Vec::new();
+    let mut tried_any = false;
+
+    let preferred_external_port = preferred_external_port.unwrap_or(local_addr.port());
+    // A lifetime of zero means "delete" to NAT-PMP and PCP and "forever" to IGD.
+    let lifetime_secs = cmp::max(cmp::min(lease.as_secs(), u32::max_value() as u64), 1) as u32;
+    // If the address is unspecified, map it on whichever interface we can.
+    let unspecified = match local_addr.ip() {
+        IpAddr::V4(ip) => socket_utils::ipv4_is_unspecified(&ip),
+        IpAddr::V6(ip) => socket_utils::ipv6_is_unspecified(&ip),
+    };
+
+    match local_addr.ip() {
+        IpAddr::V4(ipv4_addr) => {
+            let description = mapping_context::port_mapping_description(mc);
+            for iface_v4 in mapping_context::interfaces_v4(mc) {
+                if !(unspecified || iface_v4.addr == ipv4_addr) {
+                    continue;
+                }
+                let iface_addr = net::SocketAddrV4::new(iface_v4.addr, local_addr.port());
+
+                if let Some(gateway) = iface_v4.gateway {
+                    tried_any = true;
+                    // Try port preservation first. IGD doesn't tell us our external IP when
+                    // we ask for a specific port so we need to ask for it separately.
+                    let external_ip = if preferred_external_port == 0 {
+                        None
+                    }
+                    else {
+                        match gateway.get_external_ip() {
+                            Ok(external_ip) => Some(external_ip),
+                            Err(e) => {
+                                warnings.push(MapPortWarning::IgdGetExternalIp {
+                                    gateway_addr: gateway.addr,
+                                    err: e,
+                                });
+                                None
+                            },
+                        }
+                    };
+                    if let Some(external_ip) = external_ip {
+                        match gateway.add_port(protocol, preferred_external_port, iface_addr,
+                                               lifetime_secs, &description) {
+                            Ok(()) => {
+                                let lease = PortMappingLease::igd(&gateway, protocol, iface_addr,
+                                                                  preferred_external_port,
+                                                                  &description, lifetime_secs);
+                                let external_addr = net::SocketAddrV4::new(external_ip,
+                                                                           preferred_external_port);
+                                return WOk(PortMapping {
+                                    gateway: PortMappingGateway::Igd(gateway.addr),
+                                    local_addr: net::SocketAddr::V4(iface_addr),
+                                    external_addr: net::SocketAddr::V4(external_addr),
+                                    expires: expires(lifetime_secs),
+                                    lease: Some(lease),
+                                }, warnings);
+                            },
+                            Err(e) => {
+                                warnings.push(MapPortWarning::IgdAddPort {
+                                    gateway_addr: gateway.addr,
+                                    external_port: preferred_external_port,
+                                    err: e,
+                                });
+                            },
+                        }
+                    }
+                    match igd_get_any_address_with_lease(&gateway, protocol, iface_addr,
+                                                         &description, lifetime_secs) {
+                        Ok((external_addr, lease, lifetime_secs)) => {
+                            return WOk(PortMapping {
+                                gateway: PortMappingGateway::Igd(gateway.addr),
+                                local_addr: net::SocketAddr::V4(iface_addr),
+                                external_addr: net::SocketAddr::V4(external_addr),
+                                expires: expires(lifetime_secs),
+                                lease: Some(lease),
+                            }, warnings);
+                        },
+                        Err(e) => {
+                            warnings.push(MapPortWarning::IgdGetAnyAddress {
+                                gateway_addr: gateway.addr,
+                                err: e,
+                            });
+                        },
+                    }
+                }
+
+                if let Some(pcp_server) = iface_v4.pcp_server {
+                    tried_any = true;
+                    match map_port_pcp(pcp_server, protocol, net::SocketAddr::V4(iface_addr),
+                                       preferred_external_port, lifetime_secs) {
+                        Ok(mapping) => return WOk(mapping, warnings),
+                        Err(w) => warnings.push(w),
+                    }
+                }
+
+                if let Some(nat_pmp_gateway) = iface_v4.nat_pmp_gateway {
+                    tried_any = true;
+                    // NAT-PMP gateways give us the port we ask for if they can, otherwise they
+                    // pick one.
+                    match nat_pmp_get_address_with_lease(&nat_pmp_gateway, protocol,
+                                                         local_addr.port(),
+                                                         preferred_external_port, lifetime_secs) {
+                        Ok((external_addr, lease, lifetime_secs)) => {
+                            return WOk(PortMapping {
+                                gateway: PortMappingGateway::NatPmp(nat_pmp_gateway.addr),
+                                local_addr: net::SocketAddr::V4(iface_addr),
+                                external_addr: net::SocketAddr::V4(external_addr),
+                                expires: expires(lifetime_secs),
+                                lease: Some(lease),
+                            }, warnings);
+                        },
+                        Err(e) => {
+                            warnings.push(MapPortWarning::NatPmp {
+                                gateway_addr: nat_pmp_gateway.addr,
+                                err: e,
+                            });
+                        },
+                    }
+                }
+            }
+        },
+        IpAddr::V6(ipv6_addr) => {
+            for iface_v6 in mapping_context::interfaces_v6(mc) {
+                if !(unspecified || iface_v6.addr == ipv6_addr) {
+                    continue;
+                }
+                if let Some(pcp_server) = iface_v6.pcp_server {
+                    tried_any = true;
+                    let iface_addr = net::SocketAddrV6::new(iface_v6.addr, local_addr.port(), 0, 0);
+                    match map_port_pcp(pcp_server, protocol, net::SocketAddr::V6(iface_addr),
+                                       preferred_external_port, lifetime_secs) {
+                        Ok(mapping) => return WOk(mapping, warnings),
+                        Err(w) => warnings.push(w),
+                    }
+                }
+            }
+        },
+    }
+
+    if tried_any {
+        WErr(MapPortError::AllGatewaysFailed { warnings: warnings })
+    }
+    else {
+        WErr(MapPortError::NoGateways)
+    }
+}
+
+/// PCP servers give us the port we suggest if they can, otherwise they pick one.
+fn map_port_pcp(pcp_server: PcpServer,
+                protocol: PortMappingProtocol,
+                local_addr: net::SocketAddr,
+                preferred_external_port: u16,
+                lifetime_secs: u32)
+                -> Result<PortMapping, MapPortWarning> {
+    match pcp_get_address_with_lease(&pcp_server, protocol, local_addr.port(),
+                                     preferred_external_port, lifetime_secs) {
+        Ok((external_addr, lease, lifetime_secs)) => {
+            Ok(PortMapping {
+                gateway: PortMappingGateway::Pcp(pcp_server.addr),
+                local_addr: local_addr,
+                external_addr: external_addr,
+                expires: expires(lifetime_secs),
+                lease: Some(lease),
+            })
+        },
+        Err(e) => {
+            Err(MapPortWarning::Pcp {
+                server_addr: pcp_server.addr,
+                err: e,
+            })
+        },
+    }
+}
+
+fn expires(lifetime_secs: u32) -> Option<Instant> {
+    if lifetime_secs == 0 {
+        return None;
+    }
+    Some(Instant::now() + Duration::from_secs(lifetime_secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::net::{self, Ipv4Addr, Ipv6Addr, UdpSocket};
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+    use igd::{self, PortMappingProtocol};
+    use w_result::{WOk, WErr};
+
+    use mapping_context::{self, InterfaceV4, InterfaceV6, MappingContext};
+    use nat_pmp::NatPmpGateway;
+
+    /// A NAT-PMP gateway which grants the external port it's asked for, unless that's
+    /// `taken_port` in which case it grants the next one. Returns the external ports it was asked
+    /// for once it's asked to delete a mapping.
+    fn run_nat_pmp_gateway(socket: UdpSocket, taken_port: Option<u16>) -> Vec<u16> {
+        let mut requested = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            let (n, client) = unwrap_result!(socket.recv_from(&mut buf));
+            assert_eq!(n, 12);
+            let external_port = BigEndian::read_u16(&buf[6..8]);
+            let lifetime_secs = BigEndian::read_u32(&buf[8..12]);
+            let granted_port = if Some(external_port) == taken_port {
+                external_port + 1
+            }
+            else {
+                external_port
+            };
+
+            let mut response = [0u8; 16];
+            response[1] = 128 + buf[1];
+            BigEndian::write_u16(&mut response[8..10], BigEndian::read_u16(&buf[4..6]));
+            BigEndian::write_u16(&mut response[10..12], granted_port);
+            BigEndian::write_u32(&mut response[12..16], lifetime_secs);
+            let _ = unwrap_result!(socket.send_to(&response[..], client));
+            if lifetime_secs == 0 {
+                return requested;
+            }
+            requested.push(external_port);
+        }
+    }
+
+    /// A context whose only interface is the loopback interface, with a NAT-PMP gateway on it.
+    fn nat_pmp_context() -> (MappingContext, net::SocketAddrV4, UdpSocket) {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let gateway_addr = match unwrap_result!(socket.local_addr()) {
+            net::SocketAddr::V4(addr) => addr,
+            net::SocketAddr::V6(..) => panic!("Expected an IPv4 address"),
+        };
+        let interface = InterfaceV4 {
+            gateway: None,
+            nat_pmp_gateway: Some(NatPmpGateway {
+                addr: gateway_addr,
+                external_ip: Ipv4Addr::new(203, 0, 113, 5),
+            }),
+            pcp_server: None,
+            addr: Ipv4Addr::new(127, 0, 0, 1),
+        };
+        (mapping_context::from_interfaces(vec![interface], Vec::new()), gateway_addr, socket)
+    }
+
+    fn external_addr(port: u16) -> net::SocketAddr {
+        net::SocketAddr::V4(net::SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), port))
+    }
+
+    #[test]
+    fn preferred_port_is_tried_first() {
+        let (mc, gateway_addr, socket) = nat_pmp_context();
+        let jh = thread!("preferred_port_is_tried_first gateway", move || {
+            run_nat_pmp_gateway(socket, None)
+        });
+
+        let local_addr = unwrap_result!("127.0.0.1:5000".parse());
+        let mapping = unwrap_result!(map_port(&mc, PortMappingProtocol::UDP, local_addr,
+                                              Some(40000), Duration::from_secs(600))
+                                         .result_discard());
+        assert_eq!(mapping.gateway, PortMappingGateway::NatPmp(gateway_addr));
+        assert_eq!(mapping.local_addr, local_addr);
+        assert_eq!(mapping.external_addr, external_addr(40000));
+        assert!(mapping.expires.is_some());
+
+        drop(mapping);
+        assert_eq!(unwrap_result!(jh.join()), vec![40000]);
+    }
+
+    #[test]
+    fn falls_back_to_any_port() {
+        let (mc, _, socket) = nat_pmp_context();
+        let jh = thread!("falls_back_to_any_port gateway", move || {
+            run_nat_pmp_gateway(socket, Some(5000))
+        });
+
+        // Without a preferred port we ask for the local port, which is taken here.
+        let local_addr = unwrap_result!("127.0.0.1:5000".parse());
+        let mapping = unwrap_result!(map_port(&mc, PortMappingProtocol::TCP, local_addr, None,
+                                              Duration::from_secs(600))
+                                         .result_discard());
+        assert_eq!(mapping.external_addr, external_addr(5001));
+
+        drop(mapping);
+        assert_eq!(unwrap_result!(jh.join()), vec![5000]);
+    }
+
+    #[test]
+    fn no_gateways() {
+        let interface_v4 = InterfaceV4 {
+            gateway: None,
+            nat_pmp_gateway: None,
+            pcp_server: None,
+            addr: Ipv4Addr::new(127, 0, 0, 1),
+        };
+        let interface_v6 = InterfaceV6 {
+            pcp_server: None,
+            addr: Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        };
+        let mc = mapping_context::from_interfaces(vec![interface_v4], vec![interface_v6]);
+        for local_addr in &["0.0.0.0:5000", "[::]:5000"] {
+            let local_addr = unwrap_result!(local_addr.parse());
+            match map_port(&mc, PortMappingProtocol::UDP, local_addr, None,
+                           Duration::from_secs(600)) {
+                WErr(MapPortError::NoGateways) => (),
+                WErr(e) => panic!("Unexpected error: {}", e),
+                WOk(..) => panic!("Mapped a port without any gateways"),
+            }
+        }
+    }
+
+    #[test]
+    fn interfaces_without_igd_are_mapped_through_nat_pmp() {
+        let (mc, _, socket) = nat_pmp_context();
+        let jh = thread!("interfaces_without_igd_are_mapped_through_nat_pmp gateway", move || {
+            run_nat_pmp_gateway(socket, None)
+        });
+
+        let mut ifaces_v4 = mapping_context::interfaces_v4(&mc);
+        let res = interface_v4_get_address(&ifaces_v4[0], PortMappingProtocol::UDP, 5000, "test");
+        let (mapped_addr, lease) = unwrap_result!(unwrap_option!(res, "Expected a mapping"));
+        assert_eq!(mapped_addr, external_addr(5000));
+        lease.remove();
+        assert_eq!(unwrap_result!(jh.join()), vec![5000]);
+
+        ifaces_v4[0].nat_pmp_gateway = None;
+        let res = interface_v4_get_address(&ifaces_v4[0], PortMappingProtocol::UDP, 5000, "test");
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn permanent_lease_is_only_asked_for_when_required() {
+        let external_addr = net::SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), 5000);
+        let requested = RefCell::new(Vec::new());
+
+        let res = with_permanent_lease_fallback(|lease_duration_secs| {
+            requested.borrow_mut().push(lease_duration_secs);
+            match lease_duration_secs {
+                0 => Ok(external_addr),
+                _ => Err(igd::AddAnyPortError::OnlyPermanentLeasesSupported),
+            }
+        }, IGD_LEASE_DURATION_SECS);
+        assert_eq!(unwrap_result!(res), (external_addr, 0));
+        assert_eq!(*requested.borrow(), vec![IGD_LEASE_DURATION_SECS, 0]);
+
+        // Any other error is passed on rather than retried with a permanent lease.
+        requested.borrow_mut().clear();
+        let res = with_permanent_lease_fallback(|lease_duration_secs| {
+            requested.borrow_mut().push(lease_duration_secs);
+            match lease_duration_secs {
+                0 => Ok(external_addr),
+                _ => Err(igd::AddAnyPortError::ExternalPortInUse),
+            }
+        }, IGD_LEASE_DURATION_SECS);
+        match res {
+            Err(igd::AddAnyPortError::ExternalPortInUse) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(..) => panic!("Fell back to a permanent lease"),
+        }
+        assert_eq!(*requested.borrow(), vec![IGD_LEASE_DURATION_SECS]);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Port prediction for NATs which allocate a new external port for every destination.
+
+use std::net;
+
+use socket_addr::SocketAddr;
+
+/// The largest port allocation delta we believe. Anything bigger is more likely to be noise from
+/// other hosts behind the NAT than a real allocation pattern.
+const MAX_DELTA: i32 = 64;
+
+/// Describes how a NAT with endpoint-dependent mapping (a "symmetric" NAT) allocated external
+/// ports for a socket. Symmetric NATs often hand out ports sequentially so the next port can be
+/// guessed from the previous ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct PortPrediction {
+    /// The last external address the NAT was observed to allocate for the socket.
+    pub last_addr: SocketAddr,
+    /// The difference between successively allocated external ports.
+    pub delta: i32,
+}
+
+impl PortPrediction {
+    /// Estimate the allocation pattern from external addresses observed in the order they were
+    /// allocated. Returns `None` if the addresses don't show a usable pattern, for instance
+    /// because the NAT reuses the same external port for every destination.
+    pub fn from_allocations(allocated: &[SocketAddr]) -> Option<PortPrediction> {
+        let last_addr = match allocated.last() {
+            Some(last_addr) => *last_addr,
+            None => return None,
+        };
+        if allocated.iter().any(|addr| addr.ip() != last_addr.ip()) {
+            return None;
+        }
+
+        // Other hosts behind the NAT may grab ports between our allocations so the gaps won't
+        // always be the same. Take the smallest one, as long as they all go in the same direction.
+        let mut delta: Option<i32> = None;
+        for pair in allocated.windows(2) {
+            let d = pair[1].port() as i32 - pair[0].port() as i32;
+            if d == 0 {
+                continue;
+            }
+            if d.abs() > MAX_DELTA {
+                return None;
+            }
+            delta = match delta {
+                None => Some(d),
+                Some(prev) => {
+                    if prev.signum() != d.signum() {
+                        return None;
+                    }
+                    if d.abs() < prev.abs() { Some(d) } else { Some(prev) }
+                },
+            };
+        }
+
+        delta.map(|delta| {
+            PortPrediction {
+                last_addr: last_addr,
+                delta: delta,
+            }
+        })
+    }
+
+    /// Up to `count` addresses around the last observed one that we expect the NAT to allocate
+    /// next. Other hosts behind the NAT may have grabbed ports in the meantime, or the NAT may
+    /// have reused freed ports, so we go out from the last port in both directions. Nearer ports
+    /// come first and, at the same distance, the one in the direction of `delta`.
+    pub fn predicted_addrs(&self, count: usize) -> Vec<SocketAddr> {
+        let mut ret = Vec::with_capacity(count);
+        if !is_valid_delta(self.delta) {
+            return ret;
+        }
+        let last_port = self.last_addr.port() as i32;
+        let mut step: i32 = 0;
+        while ret.len() < count {
+            step += 1;
+            let offset = match step.checked_mul(self.delta) {
+                Some(offset) => offset,
+                None => break,
+            };
+            let mut in_range = false;
+            for port in &[last_port.checked_add(offset), last_port.checked_sub(offset)] {
+                if let Some(port) = *port {
+                    if port > 0 && port <= 0xffff {
+                        in_range = true;
+                        if ret.len() < count {
+                            ret.push(SocketAddr(net::SocketAddr::new(self.last_addr.ip(),
+                                                                     port as u16)));
+                        }
+                    }
+                }
+            }
+            if !in_range {
+                break;
+            }
+        }
+        ret
+    }
+}
+
+/// Whether `delta` is a plausible port allocation delta. Used to reject predictions received
+/// from peers.
+pub fn is_valid_delta(delta: i32) -> bool {
+    delta != 0 && delta >= -MAX_DELTA && delta <= MAX_DELTA
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net;
+    use std::str::FromStr;
+
+    use socket_addr::SocketAddr;
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr(unwrap_result!(net::SocketAddr::from_str(s)))
+    }
+
+    #[test]
+    fn predict_sequential_allocation() {
+        let allocated = [addr("203.0.113.5:40000"),
+                         addr("203.0.113.5:40002"),
+                         addr("203.0.113.5:40003")];
+        let prediction = unwrap_option!(PortPrediction::from_allocations(&allocated), "");
+        assert_eq!(prediction.last_addr, addr("203.0.113.5:40003"));
+        assert_eq!(prediction.delta, 1);
+        assert_eq!(prediction.predicted_addrs(4),
+                   vec![addr("203.0.113.5:40004"),
+                        addr("203.0.113.5:40002"),
+                        addr("203.0.113.5:40005"),
+                        addr("203.0.113.5:40001")]);
+    }
+
+    #[test]
+    fn predictions_stay_within_port_range() {
+        let near_top = PortPrediction {
+            last_addr: addr("203.0.113.5:65534"),
+            delta: 1,
+        };
+        assert_eq!(near_top.predicted_addrs(3),
+                   vec![addr("203.0.113.5:65535"),
+                        addr("203.0.113.5:65533"),
+                        addr("203.0.113.5:65532")]);
+
+        // A peer could send us any delta. Implausible ones predict nothing rather than overflow.
+        for &delta in &[0, 65, i32::max_value(), i32::min_value()] {
+            let bogus = PortPrediction {
+                last_addr: addr("203.0.113.5:40000"),
+                delta: delta,
+            };
+            assert_eq!(bogus.predicted_addrs(16), Vec::new());
+        }
+    }
+
+    #[test]
+    fn no_prediction_without_pattern() {
+        let same_port = [addr("203.0.113.5:40000"), addr("203.0.113.5:40000")];
+        assert_eq!(PortPrediction::from_allocations(&same_port), None);
+
+        let random = [addr("203.0.113.5:40000"), addr("203.0.113.5:51234")];
+        assert_eq!(PortPrediction::from_allocations(&random), None);
+
+        let different_ips = [addr("203.0.113.5:40000"), addr("203.0.113.6:40001")];
+        assert_eq!(PortPrediction::from_allocations(&different_ips), None);
+
+        assert_eq!(PortPrediction::from_allocations(&[]), None);
+    }
+}
@@ -19,7 +19,7 @@
 //! NAT traversal utilities.
 
 use maidsafe_utilities::serialisation::{deserialise, SerialisationError, serialise};
-use std::io;
+use std::{cmp, io};
 use std::net::UdpSocket;
 use std::time::{Instant, Duration};
 use std::thread;
@@ -27,15 +27,57 @@ use std::thread;
 use socket_addr::SocketAddr;
 use w_result::{WResult, WOk, WErr};
 
+use cancellation::CancellationToken;
+use key_exchange::{KeyExchange, KeyExchangeError, KeyExchangeMsg, SessionKeys};
 use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo};
+use secret::{self, MacPurpose, Mac, Nonce, Secret};
 use rendezvous_info;
 use socket_utils::RecvUntil;
-use mapped_socket_addr::MappedSocketAddr;
-
+use check_list::{CandidatePair, CheckList};
+use endpoint_policy::{EndpointPolicy, RejectedEndpoint};
+use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+use mapped_udp_socket::MappedUdpSocket;
+use port_mapping::PortMappingGuard;
+use utils::CANCEL_POLL_INTERVAL_MS;
+
+/// How many of the ports predicted for a peer behind a symmetric NAT we probe.
+const PREDICTED_PORT_WINDOW: usize = 16;
+/// How long to wait between the two acks we send once we've nominated a pair.
+pub const ACK_INTERVAL_MS: u64 = 100;
+/// How often we resend our half of a key exchange until the peer has acknowledged it.
+const KEY_EXCHANGE_RESEND_MS: u64 = 100;
+
+// Cbor seems to serialize into bytes of different sizes. A hole punch message carries up to two
+// nonces and a MAC which can take up to two bytes per byte, let's be safe and use 256.
+pub const MAX_DATAGRAM_SIZE: usize = 256;
+
+/// A hole punch message. Hole punch messages carry the nonce the sender picked for this attempt
+/// along with a MAC of the nonce keyed with the sender's secret. Acks answer a hole punch message:
+/// they carry the acking peer's own nonce as well as the nonce being acked, and are MACed over both
+/// with the acking peer's secret.
 #[derive(Debug, RustcEncodable, RustcDecodable)]
-struct HolePunch {
-    pub secret: [u8; 4],
-    pub ack: bool,
+pub struct HolePunch {
+    pub nonce: Nonce,
+    pub acked_nonce: Option<Nonce>,
+    pub mac: Mac,
+}
+
+impl HolePunch {
+    /// Whether this is a hole punch message sent by the peer whose secret is `their_secret`.
+    pub fn is_hole_punch_from(&self, their_secret: &Secret) -> bool {
+        self.acked_nonce.is_none() &&
+        secret::verify_mac(their_secret, MacPurpose::UdpHolePunch, &[&self.nonce], &self.mac)
+    }
+
+    /// Whether this is the peer whose secret is `their_secret` acking our hole punch message with
+    /// nonce `our_nonce`.
+    pub fn is_ack_from(&self, their_secret: &Secret, our_nonce: &Nonce) -> bool {
+        self.acked_nonce == Some(*our_nonce) &&
+        secret::verify_mac(their_secret,
+                           MacPurpose::UdpAck,
+                           &[our_nonce, &self.nonce],
+                           &self.mac)
+    }
 }
 
 /// Used for reporting warnings inside `UdpPunchHoleWarning`
@@ -50,6 +92,54 @@ pub struct PunchedUdpSocket {
     pub socket: UdpSocket,
     /// The remote address that this socket is able to send messages to and receive messages from.
     pub peer_addr: SocketAddr,
+    /// The pair of endpoints that hole punching succeeded with. `candidate_pair.remote.addr` is
+    /// the same as `peer_addr`.
+    pub candidate_pair: CandidatePair,
+    /// Owns the port mappings made for the socket when it was punched with `punch_hole_mapped`.
+    /// The mappings are removed when this is dropped so keep it alive for as long as the socket is
+    /// in use. Sockets punched any other way get a guard with no mappings.
+    pub mapping_guard: PortMappingGuard,
+}
+
+/// A datagram socket that exchanges data with a single peer, whichever way the path to the peer
+/// was set up. Code that only sends to and receives from the peer can take any `PeerSocket`, such
+/// as a `PunchedUdpSocket` or a `RelayedUdpSocket` relayed through a TURN server.
+///
+/// Hole punch messages may still arrive after punching. Use `filter_udp_hole_punch_packet` to
+/// ignore them.
+pub trait PeerSocket {
+    /// The address that the peer's data comes from.
+    fn peer_addr(&self) -> SocketAddr;
+    /// Send `buf` to the peer.
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+    /// Receive a datagram from the peer, skipping datagrams from anyone else. Blocks for at most
+    /// the timeout set with `set_read_timeout`.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Set the timeout for `recv`.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+}
+
+impl PeerSocket for PunchedUdpSocket {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, &*self.peer_addr)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (n, addr) = try!(self.socket.recv_from(buf));
+            if SocketAddr(addr) == self.peer_addr {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(dur)
+    }
 }
 
 quick_error! {
@@ -88,6 +178,22 @@ quick_error! {
             display("IO error trying to send a message to endpoint {:?}. {}", endpoint, err)
             cause(err)
         }
+        /// One of the peer's endpoints was dropped by the endpoint policy.
+        RejectedEndpoint {
+            err: RejectedEndpoint,
+        } {
+            description("One of the peer's endpoints was dropped by the endpoint policy")
+            display("One of the peer's endpoints was dropped by the endpoint policy: {}", err)
+            cause(err)
+        }
+        /// Could not open as many sockets as requested for birthday hole punching.
+        CreateSocket {
+            err: io::Error,
+        } {
+            description("Could not open as many sockets as requested for hole punching")
+            display("Could not open as many sockets as requested for hole punching: {}", err)
+            cause(err)
+        }
     }
 }
 
@@ -111,6 +217,22 @@ quick_error! {
             description("Error sending ACK to peer. Kept getting partial writes.")
             display("Error sending ACK to peer. Kept getting partial writes.")
         }
+        /// The peer's rendezvous info doesn't contain any endpoints.
+        NoEndpoints {
+            description("The peer's rendezvous info doesn't contain any endpoints.")
+        }
+        /// Hole punching was cancelled through a `CancellationToken`.
+        Cancelled {
+            description("Hole punching was cancelled")
+        }
+        /// Error exchanging session keys with the peer over the punched socket.
+        KeyExchange {
+            err: KeyExchangeError,
+        } {
+            description("Error exchanging session keys with the peer over the punched socket.")
+            display("Error exchanging session keys with the peer over the punched socket: {}", err)
+            cause(err)
+        }
     }
 }
 
@@ -121,6 +243,12 @@ impl From<UdpPunchHoleError> for io::Error {
             UdpPunchHoleError::TimedOut => io::ErrorKind::TimedOut,
             UdpPunchHoleError::Io { err } => err.kind(),
             UdpPunchHoleError::SendCompleteAck => io::ErrorKind::Other,
+            UdpPunchHoleError::NoEndpoints => io::ErrorKind::InvalidInput,
+            UdpPunchHoleError::Cancelled => io::ErrorKind::Other,
+            UdpPunchHoleError::KeyExchange { err } => {
+                let err: io::Error = From::from(err);
+                err.kind()
+            },
         };
         io::Error::new(kind, err_str)
     }
@@ -134,211 +262,576 @@ impl PunchedUdpSocket {
                       deadline: Instant)
         -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
     {
-        let mut warnings = Vec::new();
+        punch_hole_cancellable(socket,
+                               our_priv_rendezvous_info,
+                               their_pub_rendezvous_info,
+                               deadline,
+                               &CancellationToken::new())
+    }
+
+    /// Punch a hole with a mapped socket and the peer's rendezvous info. The socket's port
+    /// mappings are handed on to the punched socket, or removed if punching fails.
+    pub fn punch_hole_mapped(mapped_socket: MappedUdpSocket,
+                             our_priv_rendezvous_info: PrivRendezvousInfo,
+                             their_pub_rendezvous_info: PubRendezvousInfo,
+                             deadline: Instant)
+        -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        let MappedUdpSocket { socket, mapping_guard, .. } = mapped_socket;
+        match punch_hole_cancellable(socket,
+                                     our_priv_rendezvous_info,
+                                     their_pub_rendezvous_info,
+                                     deadline,
+                                     &CancellationToken::new()) {
+            WOk(mut punched_socket, warnings) => {
+                punched_socket.mapping_guard = mapping_guard;
+                WOk(punched_socket, warnings)
+            },
+            WErr(e) => WErr(e),
+        }
+    }
 
+    /// Punch a udp socket using a mapped socket and the peer's rendezvous info. Returns
+    /// `UdpPunchHoleError::Cancelled` if `cancel` gets triggered first.
+    pub fn punch_hole_cancellable(socket: UdpSocket,
+                                  our_priv_rendezvous_info: PrivRendezvousInfo,
+                                  their_pub_rendezvous_info: PubRendezvousInfo,
+                                  deadline: Instant,
+                                  cancel: &CancellationToken)
+        -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        punch_hole_cancellable(socket,
+                               our_priv_rendezvous_info,
+                               their_pub_rendezvous_info,
+                               deadline,
+                               cancel)
+    }
+
+    /// Punch a udp socket then exchange session keys with the peer over it. Both rendezvous infos
+    /// must have been generated with `gen_rendezvous_info_with_key_pair`.
+    pub fn punch_hole_secure(socket: UdpSocket,
+                             our_priv_rendezvous_info: PrivRendezvousInfo,
+                             their_pub_rendezvous_info: PubRendezvousInfo,
+                             deadline: Instant)
+        -> WResult<(PunchedUdpSocket, SessionKeys), UdpPunchHoleWarning, UdpPunchHoleError>
+    {
+        let key_exchange = match KeyExchange::new(&our_priv_rendezvous_info,
+                                                  &their_pub_rendezvous_info) {
+            Ok(key_exchange) => key_exchange,
+            Err(e) => return WErr(UdpPunchHoleError::KeyExchange { err: e }),
+        };
+        let our_secret = rendezvous_info::priv_secret(&our_priv_rendezvous_info);
+        let their_secret = rendezvous_info::pub_secret(&their_pub_rendezvous_info);
+        // We keep answering the peer's hole punch messages during the key exchange, so hang on to
+        // our nonce.
+        let our_nonce = secret::gen_nonce();
+        let (punched, warnings) = match punch_hole_with_nonce(socket,
+                                                              our_priv_rendezvous_info,
+                                                              their_pub_rendezvous_info,
+                                                              &our_nonce,
+                                                              deadline,
+                                                              &CancellationToken::new()) {
+            WOk(punched, warnings) => (punched, warnings),
+            WErr(e) => return WErr(e),
+        };
+        match exchange_keys(&punched.socket,
+                            &punched.peer_addr,
+                            &key_exchange,
+                            &our_secret,
+                            &their_secret,
+                            &our_nonce,
+                            deadline) {
+            Ok(session_keys) => WOk((punched, session_keys), warnings),
+            Err(e) => WErr(UdpPunchHoleError::KeyExchange { err: e }),
+        }
+    }
+}
+
+/// Punch a udp socket using a mapped socket and the peer's rendezvous info, giving up if `cancel`
+/// gets triggered.
+pub fn punch_hole_cancellable(socket: UdpSocket,
+                              our_priv_rendezvous_info: PrivRendezvousInfo,
+                              their_pub_rendezvous_info: PubRendezvousInfo,
+                              deadline: Instant,
+                              cancel: &CancellationToken)
+    -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
+{
+    // A fresh nonce for every attempt means acks from an earlier attempt can't be replayed.
+    punch_hole_with_nonce(socket,
+                          our_priv_rendezvous_info,
+                          their_pub_rendezvous_info,
+                          &secret::gen_nonce(),
+                          deadline,
+                          cancel)
+}
+
+fn punch_hole_with_nonce(socket: UdpSocket,
+                         our_priv_rendezvous_info: PrivRendezvousInfo,
+                         their_pub_rendezvous_info: PubRendezvousInfo,
+                         our_nonce: &Nonce,
+                         deadline: Instant,
+                         cancel: &CancellationToken)
+    -> WResult<PunchedUdpSocket, UdpPunchHoleWarning, UdpPunchHoleError>
+{
+    let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
+
+    // TODO(canndrew): Have a hard think about whether this is the best possible algorithm for
+    // doing this.
+    //
+    // As far as I can see, the desired properties are:
+    //  (a) We shouldn't read from the socket if the peer might have already returned their
+    //      socket to the caller and started sending us real data. Otherwise we have to either.
+    //      drop that data or return it in the PunchedUdpSocket struct.
+    //  (b) We should only return the socket once there are no more hole-punch messages to
+    //      receive. Otherwise the caller will start reading from the socket and find crap on the wire.
+    //  (c) We should try to return as soon as possible after establishing a connection.
+    //  (d) We should account for the fact that UDP is unreliable by resending messages.
+    //
+    // The problem is none of these requirements are possible to fulfill 100% of the time and
+    // they all conflict with each other. So we need to decide how bad these problems are
+    // relative to each other. In the case of (a) sending back data inside PunchedUdpSocket
+    // wouldn't be the end of the world but it would be pretty annoying for the user as they'd
+    // need to process that data and couldn't just start using their socket whereever it's
+    // needed. Applications that use UDP should account for the fact that data can dissapear
+    // anyway but it might be problematic for some apps if the very first chunk of data very
+    // often dissapears. In the case of (b) applications need to account for the fact that
+    // random data can sometimes appear on a UDP socket but they're probably not expecting to
+    // get random data from the peer they're talking to. We should at the very least make sure
+    // our hole punch packets are easily recognizable and give the user a facility to identify
+    // them and throw them away. (c) is important but it conflicts with (b) and (d). In the
+    // case of (d) it would helpful to have some idea of the probability of a given packet
+    // being lost and balance that against (c).
+    //
+    // Assuming we successfully punch a hole there's four ways this can happen: (0) we get one
+    // of their hole punching messages and it's from an address we were sending to. In this
+    // case they likely got our hole punch message(s) aswell although it's possible the packet
+    // got dropped. (1) We get one of their hole punching messages from an address that we
+    // weren't sending to. In this case they haven't received any of our messages and we'll
+    // definitely need to send an ack to their address. (2) We receive an ack to one of our
+    // messages and it's from an address we weren't sending to. I don't think this should ever
+    // happen. (3) We receive an ack to one of our packets and it's from an address we were
+    // sending to. In this case they likely initially didn't have an address they could contact
+    // us on.
+    //
+    // See `UdpPunch` for how we go about it.
+
+    let socket_addr = match socket.local_addr() {
+        Ok(addr) => SocketAddr(addr),
+        Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
+    };
+    let mut punch = UdpPunch::new(socket_addr,
+                                  our_priv_rendezvous_info,
+                                  their_pub_rendezvous_info,
+                                  our_nonce);
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline || cancel.is_cancelled() {
+            break;
+        }
+
+        if let Some(i) = punch.nominate(now) {
+            let (candidate_pair, ack, warnings) = punch.into_punched(i);
+            let peer_addr = candidate_pair.remote.addr;
+            if let Some(ack) = ack {
+                if let Err(e) = send_ack_data(&socket, &peer_addr, &ack[..], deadline) {
+                    return WErr(e);
+                }
+            }
+            return WOk(PunchedUdpSocket {
+                socket: socket,
+                peer_addr: peer_addr,
+                candidate_pair: candidate_pair,
+                mapping_guard: PortMappingGuard::new(Vec::new()),
+            }, warnings);
+        }
+
+        if let Some((i, addr)) = punch.next_check(now) {
+            // TODO(canndrew): How should we handle partial write?
+            if let Err(e) = socket.send_to(punch.send_data(), &*addr) {
+                punch.check_failed(i, e);
+            }
+        }
+
+        // Wait for a message until it's time to send the next check, waking up periodically to
+        // check whether we've been cancelled.
+        let mut wake_at = cmp::min(deadline, now + Duration::from_millis(CANCEL_POLL_INTERVAL_MS));
+        if let Some(wakeup) = punch.next_wakeup() {
+            wake_at = cmp::min(wake_at, wakeup);
+        }
+        let (read_size, addr) = match socket.recv_until(&mut recv_data[..], wake_at) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
+        };
+        if let Some(ack) = punch.packet_received(&recv_data[..read_size], addr, Instant::now()) {
+            let _ = socket.send_to(&ack[..], &*addr);
+        }
+    }
+    if cancel.is_cancelled() {
+        return WErr(UdpPunchHoleError::Cancelled);
+    }
+    WErr(UdpPunchHoleError::TimedOut)
+}
+
+/// The state of an attempt to punch a hole with a single socket, independent of how the socket
+/// gets driven. `punch_hole` drives it with a blocking socket and `punch_hole_async` from a tokio
+/// event loop.
+///
+/// We send hole punch messages to the peer's endpoints, most promising first, following an
+/// ICE-style check list. Hole punch messages are challenges which get answered with an ack
+/// covering both peers' nonces. A hole punch message arriving from the peer only shows that the
+/// peer can reach us, so it just triggers a check back to where it came from. A pair succeeds once
+/// an ack to our nonce has come back from the address we sent to and the peer has punched through
+/// from there too, which means each of us has heard the other over it. Once no higher priority
+/// pair is likely to succeed we nominate the best pair that has.
+pub struct UdpPunch {
+    our_secret: Secret,
+    their_secret: Secret,
+    our_nonce: Nonce,
+    send_data: Vec<u8>,
+    check_list: CheckList,
+    /// Addresses we've received hole punch messages from, along with the nonce to ack.
+    punched_by_them: Vec<(SocketAddr, Nonce)>,
+    warnings: Vec<UdpPunchHoleWarning>,
+}
+
+impl UdpPunch {
+    /// Start punching a hole from the socket bound to `socket_addr`.
+    pub fn new(socket_addr: SocketAddr,
+               our_priv_rendezvous_info: PrivRendezvousInfo,
+               mut their_pub_rendezvous_info: PubRendezvousInfo,
+               our_nonce: &Nonce)
+               -> UdpPunch {
+        let warnings = apply_endpoint_policy(&mut their_pub_rendezvous_info);
+
+        let port_prediction = rendezvous_info::get_port_prediction(&their_pub_rendezvous_info);
         let (mut endpoints, their_secret)
             = rendezvous_info::decompose(their_pub_rendezvous_info);
-        let our_secret
-            = rendezvous_info::get_priv_secret(our_priv_rendezvous_info);
 
-        // Cbor seems to serialize into bytes of different sizes and
-        // it sometimes exceeded 16 bytes, let's be safe and use 128.
-        const MAX_DATAGRAM_SIZE: usize = 128;
+        // If the peer is behind a symmetric NAT, the port it gets for talking to us won't be any
+        // of the ports it listed. Also try the ports its NAT is likely to allocate next.
+        if let Some(port_prediction) = port_prediction {
+            for addr in port_prediction.predicted_addrs(PREDICTED_PORT_WINDOW) {
+                if endpoints.iter().all(|e| e.addr != addr) {
+                    endpoints.push(MappedSocketAddr::new(
+                        CandidateType::Predicted,
+                        addr,
+                        addr,
+                        true
+                    ));
+                }
+            }
+        }
+        let (our_endpoints, our_secret)
+            = rendezvous_info::decompose_priv(our_priv_rendezvous_info);
+
+        // Both peers need to agree on pair priorities, so one of us has to be controlling.
+        let controlling = our_secret > their_secret;
+        UdpPunch {
+            send_data: hole_punch_data(&our_secret, our_nonce),
+            check_list: CheckList::new(our_endpoints, endpoints, socket_addr, controlling),
+            our_secret: our_secret,
+            their_secret: their_secret,
+            our_nonce: *our_nonce,
+            punched_by_them: Vec::new(),
+            warnings: warnings,
+        }
+    }
 
-        let send_data = {
-            let hole_punch = HolePunch {
-                secret: our_secret,
-                ack: false,
-            };
+    /// The hole punch message to send for each check.
+    pub fn send_data(&self) -> &[u8] {
+        &self.send_data[..]
+    }
+
+    /// The pair to send a hole punch message to now, if any, along with the address to send it to.
+    pub fn next_check(&mut self, now: Instant) -> Option<(usize, SocketAddr)> {
+        self.check_list.next_check(now).map(|i| (i, self.check_list.pair(i).remote.addr))
+    }
 
-            serialise(&hole_punch).unwrap()
+    /// Record that sending the hole punch message for pair `i` failed.
+    pub fn check_failed(&mut self, i: usize, err: io::Error) {
+        self.warnings.push(UdpPunchHoleWarning::MsgEndpoint {
+            endpoint: self.check_list.pair(i).remote.clone(),
+            err: err,
+        });
+        self.check_list.check_failed(i);
+    }
+
+    /// When there's next something to do, if we're waiting on anything other than the network.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.check_list.next_wakeup()
+    }
+
+    /// Handle a datagram that arrived from `addr`. Returns an ack to send back if it was one of
+    /// the peer's hole punch messages.
+    pub fn packet_received(&mut self, data: &[u8], addr: SocketAddr, now: Instant)
+                           -> Option<Vec<u8>> {
+        match deserialise::<HolePunch>(data) {
+            Ok(hp) => {
+                if hp.is_ack_from(&self.their_secret, &self.our_nonce) {
+                    // Acks only count if they come from an address we sent a check to.
+                    let _ = self.check_list.response_received(addr, now);
+                    return None;
+                }
+                if hp.is_hole_punch_from(&self.their_secret) {
+                    self.punched_by_them.retain(|&(punched_addr, _)| punched_addr != addr);
+                    self.punched_by_them.push((addr, hp.nonce));
+                    let _ = self.check_list.check_received(addr, now);
+                    // Ack straight away so that the peer doesn't have to wait for us to nominate
+                    // a pair. We ack again once we've nominated one.
+                    return Some(ack_data(&self.our_secret, &self.our_nonce, &hp.nonce));
+                }
+                // Protect against a malicious peer sending us loads of spurious data.
+                if self.warnings.len() < 10 {
+                    self.warnings.push(UdpPunchHoleWarning::UnexpectedHolePunchPacket {
+                        hole_punch: HolePunchPacketData {
+                            data: hp,
+                        },
+                    });
+                }
+            }
+            Err(e) => {
+                // Protect against a malicious peer sending us loads of spurious data.
+                if self.warnings.len() < 10 {
+                    self.warnings.push(UdpPunchHoleWarning::InvalidHolePunchPacket {
+                        err: e,
+                    });
+                }
+            }
         };
+        None
+    }
+
+    /// The pair to nominate, once no higher priority pair is likely to succeed.
+    pub fn nominate(&self, now: Instant) -> Option<usize> {
+        self.check_list.nominate(now)
+    }
+
+    /// Finish with nominated pair `i`. Returns the pair, the ack to send to the peer if it has
+    /// punched through to us over it, and the warnings raised along the way.
+    pub fn into_punched(self, i: usize)
+                        -> (CandidatePair, Option<Vec<u8>>, Vec<UdpPunchHoleWarning>) {
+        let UdpPunch { our_secret, our_nonce, check_list, punched_by_them, warnings, .. } = self;
+        let candidate_pair = check_list.into_pair(i);
+        let peer_addr = candidate_pair.remote.addr;
+        let ack = punched_by_them.iter()
+                                 .find(|&&(addr, _)| addr == peer_addr)
+                                 .map(|&(_, their_nonce)| {
+                                     ack_data(&our_secret, &our_nonce, &their_nonce)
+                                 });
+        (candidate_pair, ack, warnings)
+    }
+}
+
+/// Drop the endpoints in the peer's info that the default `EndpointPolicy` doesn't allow, returning
+/// a warning for each one.
+pub fn apply_endpoint_policy(their_pub_rendezvous_info: &mut PubRendezvousInfo)
+                             -> Vec<UdpPunchHoleWarning> {
+    their_pub_rendezvous_info.apply_endpoint_policy(&EndpointPolicy::default())
+                             .into_iter()
+                             .map(|e| UdpPunchHoleWarning::RejectedEndpoint { err: e })
+                             .collect()
+}
+
+/// Serialise a hole punch message carrying `our_nonce`, authenticated with `our_secret`.
+pub fn hole_punch_data(our_secret: &Secret, our_nonce: &Nonce) -> Vec<u8> {
+    serialise_hole_punch(&HolePunch {
+        nonce: *our_nonce,
+        acked_nonce: None,
+        mac: secret::mac(our_secret, MacPurpose::UdpHolePunch, &[our_nonce]),
+    })
+}
+
+/// Serialise an ack of the peer's hole punch message with nonce `their_nonce`, authenticated with
+/// `our_secret` over both `their_nonce` and `our_nonce`.
+pub fn ack_data(our_secret: &Secret, our_nonce: &Nonce, their_nonce: &Nonce) -> Vec<u8> {
+    serialise_hole_punch(&HolePunch {
+        nonce: *our_nonce,
+        acked_nonce: Some(*their_nonce),
+        mac: secret::mac(our_secret, MacPurpose::UdpAck, &[their_nonce, our_nonce]),
+    })
+}
 
-        assert!(send_data.len() <= MAX_DATAGRAM_SIZE,
-                format!("Data exceed MAX_DATAGRAM_SIZE in blocking_udp_punch_hole: {} > {}",
-                        send_data.len(),
-                        MAX_DATAGRAM_SIZE));
-
-        let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
-
-        // TODO(canndrew): Have a hard think about whether this is the best possible algorithm for
-        // doing this.
-        //
-        // As far as I can see, the desired properties are:
-        //  (a) We shouldn't read from the socket if the peer might have already returned their
-        //      socket to the caller and started sending us real data. Otherwise we have to either.
-        //      drop that data or return it in the PunchedUdpSocket struct.
-        //  (b) We should only return the socket once there are no more hole-punch messages to
-        //      receive. Otherwise the caller will start reading from the socket and find crap on the wire.
-        //  (c) We should try to return as soon as possible after establishing a connection.
-        //  (d) We should account for the fact that UDP is unreliable by resending messages.
-        //
-        // The problem is none of these requirements are possible to fulfill 100% of the time and
-        // they all conflict with each other. So we need to decide how bad these problems are
-        // relative to each other. In the case of (a) sending back data inside PunchedUdpSocket
-        // wouldn't be the end of the world but it would be pretty annoying for the user as they'd
-        // need to process that data and couldn't just start using their socket whereever it's
-        // needed. Applications that use UDP should account for the fact that data can dissapear
-        // anyway but it might be problematic for some apps if the very first chunk of data very
-        // often dissapears. In the case of (b) applications need to account for the fact that
-        // random data can sometimes appear on a UDP socket but they're probably not expecting to
-        // get random data from the peer they're talking to. We should at the very least make sure
-        // our hole punch packets are easily recognizable and give the user a facility to identify
-        // them and throw them away. (c) is important but it conflicts with (b) and (d). In the
-        // case of (d) it would helpful to have some idea of the probability of a given packet
-        // being lost and balance that against (c).
-        //
-        // Assuming we successfully punch a hole there's four ways this can happen: (0) we get one
-        // of their hole punching messages and it's from an address we were sending to. In this
-        // case they likely got our hole punch message(s) aswell although it's possible the packet
-        // got dropped. (1) We get one of their hole punching messages from an address that we
-        // weren't sending to. In this case they haven't received any of our messages and we'll
-        // definitely need to send an ack to their address. (2) We receive an ack to one of our
-        // messages and it's from an address we weren't sending to. I don't think this should ever
-        // happen. (3) We receive an ack to one of our packets and it's from an address we were
-        // sending to. In this case they likely initially didn't have an address they could contact
-        // us on.
-        //
-        // For now we keep the algorithm simple: If we get a hole punch message we send back two
-        // acks with a delay in between before returning. If we get an ack we return immediately.
-
-        // Spend TOTAL_TIMEOUT_MS trying to get their actual address that we can
-        // communicate with.
-
-        const DELAY_BETWEEN_RESENDS_MS: u64 = 600;
-
-        let mut recv_deadline = Instant::now();
-        while recv_deadline < deadline {
-            recv_deadline = recv_deadline + Duration::from_millis(DELAY_BETWEEN_RESENDS_MS);
-            let mut i = 0;
-            while i < endpoints.len() {
-                // TODO(canndrew): How should we handle partial write?
-                let _ = match socket.send_to(&send_data[..], &*endpoints[i].addr) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        warnings.push(UdpPunchHoleWarning::MsgEndpoint {
-                            endpoint: endpoints.swap_remove(i),
-                            err: e,
-                        });
-                        continue;
+fn serialise_hole_punch(hole_punch: &HolePunch) -> Vec<u8> {
+    let send_data = unwrap_result!(serialise(hole_punch));
+
+    assert!(send_data.len() <= MAX_DATAGRAM_SIZE,
+            format!("Data exceed MAX_DATAGRAM_SIZE in blocking_udp_punch_hole: {} > {}",
+                    send_data.len(),
+                    MAX_DATAGRAM_SIZE));
+    send_data
+}
+
+/// Acknowledge a hole punch packet with nonce `their_nonce` from the peer. We send two acks with a
+/// delay in between in case one of them gets lost.
+pub fn send_acks(socket: &UdpSocket,
+                 addr: &SocketAddr,
+                 our_secret: &Secret,
+                 our_nonce: &Nonce,
+                 their_nonce: &Nonce,
+                 deadline: Instant)
+                 -> Result<(), UdpPunchHoleError> {
+    send_ack_data(socket, addr, &ack_data(our_secret, our_nonce, their_nonce)[..], deadline)
+}
+
+/// Send the ack `send_data` twice, as in `send_acks`.
+fn send_ack_data(socket: &UdpSocket, addr: &SocketAddr, send_data: &[u8], deadline: Instant)
+                 -> Result<(), UdpPunchHoleError> {
+    let mut attempts = 0;
+    let mut successful_attempts = 0;
+    let mut error = None;
+    while attempts < 2 || Instant::now() < deadline {
+        attempts += 1;
+        match socket.send_to(send_data, &**addr) {
+            Ok(n) => {
+                if n == send_data.len() {
+                    successful_attempts += 1;
+                    if successful_attempts == 2 {
+                        break;
                     }
-                };
-                i += 1;
+                }
             }
-            // Keep reading until it's time to send to all endpoints again.
-            loop {
-                let (read_size, addr) = match socket.recv_until(&mut recv_data[..], recv_deadline) {
-                    Ok(Some(x)) => x,
-                    Ok(None) => break,
-                    Err(e) => return WErr(UdpPunchHoleError::Io { err: e }),
-                };
-                match deserialise::<HolePunch>(&recv_data[..read_size]) {
-                    Ok(hp) => {
-                        if hp.secret == our_secret && hp.ack {
-                            return WOk(PunchedUdpSocket {
-                                socket: socket,
-                                peer_addr: addr,
-                            }, warnings);
-                        }
-                        if hp.secret == their_secret {
-                            let send_data = {
-                                let hole_punch = HolePunch {
-                                    secret: their_secret,
-                                    ack: true,
-                                };
-
-                                serialise(&hole_punch).unwrap()
-                            };
-
-                            assert!(send_data.len() <= MAX_DATAGRAM_SIZE,
-                                    format!("Data exceed MAX_DATAGRAM_SIZE in blocking_udp_punch_hole: {} > {}",
-                                            send_data.len(),
-                                            MAX_DATAGRAM_SIZE));
-
-                            let mut attempts = 0;
-                            let mut successful_attempts = 0;
-                            let mut error = None;
-                            while attempts < 2 || Instant::now() < deadline {
-                                attempts += 1;
-                                match socket.send_to(&send_data[..], &*addr) {
-                                    Ok(n) => {
-                                        if n == send_data.len() {
-                                            successful_attempts += 1;
-                                            if successful_attempts == 2 {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        if error.is_none() {
-                                            error = Some(e);
-                                        }
-                                    }
-                                };
-                                thread::sleep(Duration::from_millis(100));
-                            }
-                            if successful_attempts == 0 {
-                                let ret = match error {
-                                    Some(e) => UdpPunchHoleError::Io { err: e },
-                                    None => UdpPunchHoleError::SendCompleteAck,
-                                };
-                                return WErr(ret);
-                            }
-                            else {
-                                return WOk(PunchedUdpSocket {
-                                    socket: socket,
-                                    peer_addr: addr,
-                                }, warnings);
-                            }
-                        }
-                        // Protect against a malicious peer sending us loads of spurious data.
-                        if warnings.len() < 10 {
-                            warnings.push(UdpPunchHoleWarning::UnexpectedHolePunchPacket {
-                                hole_punch: HolePunchPacketData {
-                                    data: hp,
-                                },
-                            });
-                        }
-                    }
-                    Err(e) => {
-                        // Protect against a malicious peer sending us loads of spurious data.
-                        if warnings.len() < 10 {
-                            warnings.push(UdpPunchHoleWarning::InvalidHolePunchPacket {
-                                err: e,
-                            });
-                        }
-                    }
-                };
+            Err(e) => {
+                if error.is_none() {
+                    error = Some(e);
+                }
+            }
+        };
+        thread::sleep(Duration::from_millis(ACK_INTERVAL_MS));
+    }
+    if successful_attempts == 0 {
+        return Err(match error {
+            Some(e) => UdpPunchHoleError::Io { err: e },
+            None => UdpPunchHoleError::SendCompleteAck,
+        });
+    }
+    Ok(())
+}
+
+/// Exchange keys with the peer over a punched socket. We keep resending our half of the exchange
+/// until the peer says it has got it. The peer may still be hole punching, so we also keep acking
+/// its hole punch messages.
+fn exchange_keys(socket: &UdpSocket,
+                 peer_addr: &SocketAddr,
+                 key_exchange: &KeyExchange,
+                 our_secret: &Secret,
+                 their_secret: &Secret,
+                 our_nonce: &Nonce,
+                 deadline: Instant)
+                 -> Result<SessionKeys, KeyExchangeError> {
+    let mut got_theirs = false;
+    let mut recv_data = [0u8; MAX_DATAGRAM_SIZE];
+    let mut send_at = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(KeyExchangeError::TimedOut);
+        }
+        if now >= send_at {
+            let send_data = key_exchange.our_half(got_theirs);
+            if let Err(e) = socket.send_to(&send_data[..], &**peer_addr) {
+                return Err(KeyExchangeError::Io { err: e });
+            }
+            send_at = now + Duration::from_millis(KEY_EXCHANGE_RESEND_MS);
+        }
+
+        let (read_size, addr) = match socket.recv_until(&mut recv_data[..],
+                                                        cmp::min(send_at, deadline)) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => return Err(KeyExchangeError::Io { err: e }),
+        };
+        if addr != *peer_addr {
+            continue;
+        }
+        if let Ok(hp) = deserialise::<HolePunch>(&recv_data[..read_size]) {
+            if hp.is_hole_punch_from(their_secret) {
+                let ack = ack_data(our_secret, our_nonce, &hp.nonce);
+                let _ = socket.send_to(&ack[..], &**peer_addr);
+            }
+            continue;
+        }
+        // Anything that doesn't authenticate is either stray or forged, so just ignore it.
+        let half = match key_exchange.their_half(&recv_data[..read_size]) {
+            Ok(half) => half,
+            Err(_) => continue,
+        };
+        if !half.got_ours {
+            // Let the peer know straight away that we've got its half.
+            send_at = now;
+            got_theirs = true;
+            continue;
+        }
+
+        // The peer has our half and we have theirs. Tell them we're done a couple of times in
+        // case a message gets lost, as with hole punch acks.
+        let send_data = key_exchange.our_half(true);
+        for i in 0..2 {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(KEY_EXCHANGE_RESEND_MS));
             }
+            let _ = socket.send_to(&send_data[..], &**peer_addr);
         }
-        WErr(UdpPunchHoleError::TimedOut)
+        return Ok(key_exchange.session_keys(&half));
     }
 }
 
-/// Returns `None` if `data` looks like a hole punching message. Otherwise returns the data it was
-/// given.
+/// Returns `None` if `data` looks like a hole punching or key exchange message. Otherwise returns
+/// the data it was given.
 ///
 /// Punching a hole with a udp socket involves packets being sent and received on the socket. After
 /// hole punching succeeds it's possible that more hole punching packets sent by the remote peer
 /// may yet arrive on the socket. This function can be used to filter out those packets.
 pub fn filter_udp_hole_punch_packet(data: &[u8]) -> Option<&[u8]> {
-    match deserialise::<HolePunch>(data){
-        Ok(_) => None,
-        _ => Some(data),
+    if deserialise::<HolePunch>(data).is_ok() || deserialise::<KeyExchangeMsg>(data).is_ok() {
+        return None;
     }
+    Some(data)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::mpsc;
-    use std::thread;
+    use std::io;
+    use std::net::UdpSocket;
     use std::time::{Instant, Duration};
     use rand;
+    use sodiumoxide::crypto::box_;
+
+    use maidsafe_utilities::serialisation::deserialise;
 
+    use check_list::CandidatePairState;
     use mapping_context::MappingContext;
     use mapped_udp_socket::MappedUdpSocket;
-    use punched_udp_socket::{PunchedUdpSocket, filter_udp_hole_punch_packet};
-    use rendezvous_info::gen_rendezvous_info;
+    use punched_udp_socket::{HolePunch, PeerSocket, PunchedUdpSocket, ack_data,
+                             filter_udp_hole_punch_packet, hole_punch_data};
+    use rendezvous_info::{gen_rendezvous_info, gen_rendezvous_info_with_key_pair};
+    use secret;
+
+    #[test]
+    fn acks_are_bound_to_both_nonces() {
+        let (our_secret, their_secret) = (secret::gen_secret(), secret::gen_secret());
+        let (our_nonce, their_nonce) = (secret::gen_nonce(), secret::gen_nonce());
+
+        let punch: HolePunch = unwrap_result!(deserialise(&hole_punch_data(&our_secret,
+                                                                           &our_nonce)));
+        assert!(punch.is_hole_punch_from(&our_secret));
+        assert!(!punch.is_hole_punch_from(&their_secret));
+        assert!(!punch.is_ack_from(&our_secret, &our_nonce));
+
+        let ack: HolePunch = unwrap_result!(deserialise(&ack_data(&their_secret,
+                                                                  &their_nonce,
+                                                                  &our_nonce)));
+        assert!(ack.is_ack_from(&their_secret, &our_nonce));
+        assert!(!ack.is_ack_from(&their_secret, &their_nonce));
+        assert!(!ack.is_ack_from(&our_secret, &our_nonce));
+        assert!(!ack.is_hole_punch_from(&their_secret));
+
+        // An ack whose nonce has been swapped for another doesn't verify.
+        let mut forged = ack;
+        forged.nonce = secret::gen_nonce();
+        assert!(!forged.is_ack_from(&their_secret, &our_nonce));
+    }
 
     #[test]
     fn two_peers_udp_hole_punch_over_loopback() {
@@ -347,70 +840,114 @@ mod tests {
         let mapped_socket_0 = unwrap_result!(MappedUdpSocket::new(&mapping_context, deadline).result_discard());
         let mapped_socket_1 = unwrap_result!(MappedUdpSocket::new(&mapping_context, deadline).result_discard());
 
+        // The second peer hands its port mappings on to the punched socket.
         let socket_0 = mapped_socket_0.socket;
-        let socket_1 = mapped_socket_1.socket;
         let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
-        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints);
-
-        let (tx_0, rx_0) = mpsc::channel();
-        let (tx_1, rx_1) = mpsc::channel();
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints.clone());
 
         let deadline = Instant::now() + Duration::from_secs(3);
         let jh_0 = thread!("two_peers_hole_punch_over_loopback punch socket 0", move || {
-            let res = PunchedUdpSocket::punch_hole(socket_0,
-                                                   priv_info_0,
-                                                   pub_info_1,
-                                                   deadline);
-            unwrap_result!(tx_0.send(res));
+            PunchedUdpSocket::punch_hole(socket_0,
+                                         priv_info_0,
+                                         pub_info_1,
+                                         deadline)
         });
         let jh_1 = thread!("two_peers_hole_punch_over_loopback punch socket 1", move || {
-            let res = PunchedUdpSocket::punch_hole(socket_1,
-                                                   priv_info_1,
-                                                   pub_info_0,
-                                                   deadline);
-            unwrap_result!(tx_1.send(res));
+            PunchedUdpSocket::punch_hole_mapped(mapped_socket_1,
+                                                priv_info_1,
+                                                pub_info_0,
+                                                deadline)
         });
 
-        thread::sleep(Duration::from_millis(500));
-        let punched_socket_0 = unwrap_result!(unwrap_result!(rx_0.try_recv()).result_discard());
-        let punched_socket_1 = unwrap_result!(unwrap_result!(rx_1.try_recv()).result_discard());
+        let punched_socket_0 = unwrap_result!(unwrap_result!(jh_0.join()).result_discard());
+        let punched_socket_1 = unwrap_result!(unwrap_result!(jh_1.join()).result_discard());
+
+        for punched_socket in &[&punched_socket_0, &punched_socket_1] {
+            assert_eq!(punched_socket.candidate_pair.remote.addr, punched_socket.peer_addr);
+            assert_eq!(punched_socket.candidate_pair.state, CandidatePairState::Succeeded);
+        }
 
         const DATA_LEN: usize = 8;
         let data_send: [u8; DATA_LEN] = rand::random();
-        let mut data_recv;
+        let deadline = Instant::now() + Duration::from_secs(3);
 
         // Send data from 0 to 1
-        data_recv = [0u8; 1024];
         let n = unwrap_result!(punched_socket_0.socket.send_to(&data_send[..], &*punched_socket_0.peer_addr));
         assert_eq!(n, DATA_LEN);
-        loop {
-            let (n, _) = unwrap_result!(punched_socket_1.socket.recv_from(&mut data_recv[..]));
-            match filter_udp_hole_punch_packet(&data_recv[..n]) {
-                Some(d) => {
-                    assert_eq!(data_send, d);
-                    break;
-                },
-                None => continue,
-            }
-        }
+        recv_data(&punched_socket_1.socket, &data_send[..], deadline);
 
         // Send data from 1 to 0
-        data_recv = [0u8; 1024];
         let n = unwrap_result!(punched_socket_1.socket.send_to(&data_send[..], &*punched_socket_1.peer_addr));
         assert_eq!(n, DATA_LEN);
+        recv_data(&punched_socket_0.socket, &data_send[..], deadline);
+
+        // The same again through `PeerSocket`.
+        let mut data_recv = [0u8; 1024];
+        let timeout = Some(Duration::from_secs(3));
+        unwrap_result!(PeerSocket::set_read_timeout(&punched_socket_1, timeout));
+        assert_eq!(unwrap_result!(PeerSocket::send(&punched_socket_0, &data_send[..])), DATA_LEN);
         loop {
-            let (n, _) = unwrap_result!(punched_socket_0.socket.recv_from(&mut data_recv[..]));
-            match filter_udp_hole_punch_packet(&data_recv[..n]) {
-                Some(d) => {
-                    assert_eq!(data_send, d);
-                    break;
+            let n = unwrap_result!(PeerSocket::recv(&punched_socket_1, &mut data_recv[..]));
+            if filter_udp_hole_punch_packet(&data_recv[..n]) == Some(&data_send[..]) {
+                break;
+            }
+        }
+    }
+
+    /// Read from `socket` until `data` arrives, skipping any hole punching messages still in
+    /// flight. Panics if `data` doesn't arrive before `deadline`.
+    fn recv_data(socket: &UdpSocket, data: &[u8], deadline: Instant) {
+        let mut data_recv = [0u8; 1024];
+        loop {
+            let now = Instant::now();
+            assert!(now < deadline, "Timed out waiting for data");
+            unwrap_result!(socket.set_read_timeout(Some(deadline - now)));
+            match socket.recv_from(&mut data_recv[..]) {
+                Ok((n, _)) => {
+                    if filter_udp_hole_punch_packet(&data_recv[..n]) == Some(data) {
+                        return;
+                    }
                 },
-                None => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => panic!("Error receiving data: {}", e),
             }
         }
+    }
 
-        unwrap_result!(jh_0.join());
-        unwrap_result!(jh_1.join());
+    #[test]
+    fn two_peers_udp_hole_punch_secure_over_loopback() {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let mapping_context = unwrap_result!(MappingContext::new().result_discard());
+        let mapped_socket_0 = unwrap_result!(MappedUdpSocket::new(&mapping_context, deadline).result_discard());
+        let mapped_socket_1 = unwrap_result!(MappedUdpSocket::new(&mapping_context, deadline).result_discard());
+
+        let (public_key_0, secret_key_0) = box_::gen_keypair();
+        let (public_key_1, secret_key_1) = box_::gen_keypair();
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info_with_key_pair(mapped_socket_0.endpoints,
+                                                                          None,
+                                                                          &public_key_0,
+                                                                          &secret_key_0);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info_with_key_pair(mapped_socket_1.endpoints,
+                                                                          None,
+                                                                          &public_key_1,
+                                                                          &secret_key_1);
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let socket_0 = mapped_socket_0.socket;
+        let jh_0 = thread!("two_peers_udp_hole_punch_secure_over_loopback punch socket 0", move || {
+            PunchedUdpSocket::punch_hole_secure(socket_0, priv_info_0, pub_info_1, deadline)
+        });
+        let socket_1 = mapped_socket_1.socket;
+        let jh_1 = thread!("two_peers_udp_hole_punch_secure_over_loopback punch socket 1", move || {
+            PunchedUdpSocket::punch_hole_secure(socket_1, priv_info_1, pub_info_0, deadline)
+        });
+
+        let (_, session_keys_0) = unwrap_result!(unwrap_result!(jh_0.join()).result_discard());
+        let (_, session_keys_1) = unwrap_result!(unwrap_result!(jh_1.join()).result_discard());
+        assert!(session_keys_0.peer_public_key == public_key_1);
+        assert!(session_keys_1.peer_public_key == public_key_0);
+        assert!(session_keys_0.encrypt_key == session_keys_1.decrypt_key);
+        assert!(session_keys_0.decrypt_key == session_keys_1.encrypt_key);
     }
 }
-
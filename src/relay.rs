@@ -0,0 +1,809 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Relaying udp traffic through a `RelayServer` for peers that can't punch a hole to each other,
+//! for instance because both are behind symmetric NATs. A client allocates a relayed address on
+//! the server. The server forwards datagrams which permitted peers send to the relayed address on
+//! to the client and sends datagrams from the relayed address to permitted peers on the client's
+//! behalf.
+//!
+//! Requests to the server are authenticated with a MAC keyed with a secret that the server shares
+//! with its clients. The MAC also covers a nonce which the server gives each client address, so a
+//! request captured from one address can't be replayed from another.
+
+use std::cmp;
+use std::io;
+use std::net::{self, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use maidsafe_utilities::serialisation::{deserialise, serialise, SerialisationError};
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+use socket_addr::SocketAddr;
+use sodiumoxide::crypto::hash::sha512;
+use w_result::{WResult, WOk, WErr};
+
+use endpoint_policy::{EndpointPolicy, RejectedEndpoint};
+use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+use mapping_context::{self, MappingContext};
+use punched_udp_socket::{self, HolePunch, PeerSocket};
+use rendezvous_info::{self, PrivRendezvousInfo, PubRendezvousInfo};
+use secret::{self, Mac, MacPurpose, Nonce, NONCE_LEN, Secret, SECRET_LEN};
+use socket_utils::{self, PollSocket};
+
+/// How often we resend allocation requests to the relay servers.
+const ALLOCATE_RESEND_MS: u64 = 500;
+/// How often we resend permissions and hole punch messages while connecting to the peer.
+const CONNECT_RESEND_MS: u64 = 100;
+/// How often the refresh thread checks whether it should stop.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// The largest datagram that can be relayed, including the relay's own framing.
+pub const MAX_RELAY_DATAGRAM_SIZE: usize = 65507;
+
+/// A relay server and the secret it shares with its clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayServerInfo {
+    /// The address of the server.
+    pub addr: SocketAddr,
+    /// The secret the server shares with its clients.
+    pub secret: String,
+}
+
+/// Messages exchanged between relay clients and a `RelayServer`.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub enum RelayMsg {
+    /// Client makes a request, authenticated with the nonce the server gave it.
+    Request(RelayRequest, Nonce, Mac),
+    /// Server answers a request which didn't carry the nonce it gave the client, giving the nonce
+    /// to use.
+    Challenge(Nonce),
+    /// Server answers a request which carried the right nonce but didn't authenticate.
+    Rejected,
+    /// Server gives the client's relayed address and how many seconds it lasts if not refreshed.
+    Allocated(SocketAddr, u32),
+    /// Client asks the server to send data to the given address from the relayed address. The
+    /// server only does so if the client has permitted the address.
+    Send(SocketAddr, Vec<u8>),
+    /// Server passes on data that arrived on the relayed address from the given address.
+    Data(SocketAddr, Vec<u8>),
+    /// Server answers an authenticated allocation request which it has no room for.
+    Full,
+}
+
+/// Requests which a relay client has to authenticate.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+pub enum RelayRequest {
+    /// Client asks for an allocation, or for its existing allocation to be refreshed.
+    Allocate,
+    /// Client gives up its allocation.
+    Release,
+    /// Client lets datagrams be exchanged with the IP address of the given address.
+    Permit(SocketAddr),
+}
+
+/// The key that authenticates requests to a relay server, derived from the secret it shares with
+/// its clients.
+pub fn shared_key(secret: &str) -> Secret {
+    let sha512::Digest(digest) = sha512::hash(secret.as_bytes());
+    let mut key = [0; SECRET_LEN];
+    key.copy_from_slice(&digest[..SECRET_LEN]);
+    key
+}
+
+/// Serialise `request`, authenticated with `key` and the nonce the server gave us.
+pub fn request_data(key: &Secret, request: RelayRequest, nonce: &Nonce) -> Vec<u8> {
+    let mac = secret::mac_data(key,
+                               MacPurpose::RelayRequest,
+                               &[nonce],
+                               &unwrap_result!(serialise(&request))[..]);
+    unwrap_result!(serialise(&RelayMsg::Request(request, *nonce, mac)))
+}
+
+/// Whether `mac` authenticates `request` and `nonce` with `key`.
+pub fn verify_request(key: &Secret, request: &RelayRequest, nonce: &Nonce, mac: &Mac) -> bool {
+    secret::verify_mac_data(key,
+                            MacPurpose::RelayRequest,
+                            &[nonce],
+                            &unwrap_result!(serialise(request))[..],
+                            mac)
+}
+
+quick_error! {
+    /// Errors returned by `RelayAllocation::allocate`.
+    #[derive(Debug)]
+    pub enum RelayAllocateError {
+        /// The mapping context doesn't know about any relay servers.
+        NoRelayServers {
+            description("The mapping context doesn't know about any relay servers")
+        }
+        /// Error creating or using the socket that talks to the relay servers.
+        Socket { err: io::Error } {
+            description("Error creating or using the socket that talks to the relay servers")
+            display("Error creating or using the socket that talks to the relay servers: {}",
+                    err)
+            cause(err)
+        }
+        /// None of the relay servers gave us an allocation before the deadline.
+        TimedOut {
+            description("None of the relay servers gave us an allocation before the deadline")
+        }
+        /// None of the relay servers accepted the secrets we have for them.
+        Rejected {
+            description("None of the relay servers accepted the secrets we have for them")
+        }
+        /// The relay servers which accepted our secrets have no room for another allocation.
+        Full {
+            description("The relay servers have no room for another allocation")
+        }
+    }
+}
+
+impl From<RelayAllocateError> for io::Error {
+    fn from(e: RelayAllocateError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            RelayAllocateError::NoRelayServers => io::ErrorKind::NotFound,
+            RelayAllocateError::Socket { err } => err.kind(),
+            RelayAllocateError::TimedOut => io::ErrorKind::TimedOut,
+            RelayAllocateError::Rejected => io::ErrorKind::PermissionDenied,
+            RelayAllocateError::Full => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// An address allocated for us on a relay server. A background thread refreshes the allocation
+/// until this is dropped, at which point it is released.
+pub struct RelayAllocation {
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+    relayed_addr: SocketAddr,
+    local_addr: SocketAddr,
+    key: Secret,
+    nonce: Nonce,
+    /// Where `recv_from` receives datagrams from the server, so that it doesn't have to allocate
+    /// a new buffer for each one.
+    recv_buf: Mutex<Vec<u8>>,
+    stop_flag: Arc<AtomicBool>,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+impl RelayAllocation {
+    /// Ask the relay servers known to `mc` for an allocation and take the first one offered.
+    /// Each server is asked from a socket of its own address family.
+    pub fn allocate(mc: &MappingContext, deadline: Instant)
+                    -> Result<RelayAllocation, RelayAllocateError> {
+        let servers = mapping_context::relay_servers(mc);
+        if servers.is_empty() {
+            return Err(RelayAllocateError::NoRelayServers);
+        }
+        // A socket for each address family that the servers use, along with its local address.
+        let mut sockets: Vec<(UdpSocket, net::SocketAddr)> = Vec::new();
+        let mut bind_err = None;
+        for &(bind_addr, is_ipv4) in &[("0.0.0.0:0", true), ("[::]:0", false)] {
+            if !servers.iter().any(|s| s.addr.is_ipv4() == is_ipv4) {
+                continue;
+            }
+            let res = UdpSocket::bind(bind_addr).and_then(|socket| {
+                try!(socket.set_nonblocking(true));
+                let local_addr = try!(socket.local_addr());
+                Ok((socket, local_addr))
+            });
+            // The host may not support this address family, the servers of the other family
+            // may still be reachable.
+            match res {
+                Ok(socket) => sockets.push(socket),
+                Err(e) => bind_err = Some(e),
+            };
+        }
+        if sockets.is_empty() {
+            let err = bind_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "No socket could be bound")
+            });
+            return Err(RelayAllocateError::Socket { err: err });
+        }
+        // The servers that haven't rejected us yet, the keys we share with them, the nonces
+        // they've given us and the sockets we talk to them from.
+        let mut servers: Vec<(SocketAddr, Secret, Option<Nonce>, usize)> =
+            servers.into_iter()
+                   .filter_map(|s| {
+                       sockets.iter()
+                              .position(|&(_, local_addr)| {
+                                  s.addr.is_ipv4() == local_addr.is_ipv4()
+                              })
+                              .map(|i| (s.addr, shared_key(&s.secret), None, i))
+                   })
+                   .collect();
+
+        let mut buf = [0; 256];
+        let mut next_send = Instant::now();
+        let mut any_full = false;
+        loop {
+            if servers.is_empty() {
+                if any_full {
+                    return Err(RelayAllocateError::Full);
+                }
+                return Err(RelayAllocateError::Rejected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RelayAllocateError::TimedOut);
+            }
+            if now >= next_send {
+                for &(ref server, ref key, nonce, i) in &servers {
+                    // Until a server gives us a nonce, any nonce will do to get challenged.
+                    let nonce = nonce.unwrap_or([0; NONCE_LEN]);
+                    let request = request_data(key, RelayRequest::Allocate, &nonce);
+                    let _ = sockets[i].0.send_to(&request[..], &**server);
+                }
+                next_send = now + Duration::from_millis(ALLOCATE_RESEND_MS);
+            }
+            let timeout = cmp::min(next_send, deadline) - now;
+            let res = {
+                let poll_sockets: Vec<PollSocket> = sockets.iter()
+                                                           .map(|&(ref socket, _)| {
+                                                               PollSocket::ReadUdp(socket)
+                                                           })
+                                                           .collect();
+                socket_utils::wait_until_ready(&poll_sockets, timeout)
+            };
+            if let Err(e) = res {
+                return Err(RelayAllocateError::Socket { err: e });
+            }
+
+            let mut allocated = None;
+            for (s, &(ref socket, _)) in sockets.iter().enumerate() {
+                while allocated.is_none() {
+                    let (n, addr) = match socket.recv_from(&mut buf[..]) {
+                        Ok(x) => x,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if is_transient(e) => continue,
+                        Err(e) => return Err(RelayAllocateError::Socket { err: e }),
+                    };
+                    let addr = SocketAddr(addr);
+                    let i = match servers.iter()
+                                         .position(|&(server, _, _, i)| server == addr && i == s) {
+                        Some(i) => i,
+                        None => continue,
+                    };
+                    match (deserialise(&buf[..n]), servers[i].2) {
+                        (Ok(RelayMsg::Challenge(nonce)), _) => {
+                            servers[i].2 = Some(nonce);
+                            let request = request_data(&servers[i].1,
+                                                       RelayRequest::Allocate,
+                                                       &nonce);
+                            let _ = socket.send_to(&request[..], &*addr);
+                        },
+                        // Only take a rejection seriously once we've used the server's nonce.
+                        (Ok(RelayMsg::Rejected), Some(_)) => {
+                            let _ = servers.remove(i);
+                        },
+                        (Ok(RelayMsg::Full), Some(_)) => {
+                            let _ = servers.remove(i);
+                            any_full = true;
+                        },
+                        (Ok(RelayMsg::Allocated(relayed_addr, lifetime_secs)), Some(nonce)) => {
+                            allocated = Some((s, addr, relayed_addr, lifetime_secs,
+                                              servers[i].1, nonce));
+                        },
+                        _ => (),
+                    };
+                }
+            }
+            let (s, addr, relayed_addr, lifetime_secs, key, nonce) = match allocated {
+                Some(allocated) => allocated,
+                None => continue,
+            };
+
+            let (socket, local_addr) = sockets.swap_remove(s);
+            if let Err(e) = socket.set_nonblocking(false) {
+                return Err(RelayAllocateError::Socket { err: e });
+            }
+            let cloned_socket = match socket.try_clone() {
+                Ok(cloned_socket) => cloned_socket,
+                Err(e) => return Err(RelayAllocateError::Socket { err: e }),
+            };
+            let stop_flag = Arc::new(AtomicBool::new(false));
+            let cloned_stop_flag = stop_flag.clone();
+            let raii_joiner = RaiiThreadJoiner::new(thread!("RelayAllocation", move || {
+                Self::refresh(cloned_socket, addr, key, nonce, lifetime_secs, cloned_stop_flag);
+            }));
+            return Ok(RelayAllocation {
+                socket: socket,
+                server_addr: addr,
+                relayed_addr: relayed_addr,
+                local_addr: SocketAddr(local_addr),
+                key: key,
+                nonce: nonce,
+                recv_buf: Mutex::new(vec![0; MAX_RELAY_DATAGRAM_SIZE]),
+                stop_flag: stop_flag,
+                _raii_joiner: raii_joiner,
+            });
+        }
+    }
+
+    fn refresh(socket: UdpSocket,
+               server_addr: SocketAddr,
+               key: Secret,
+               nonce: Nonce,
+               lifetime_secs: u32,
+               stop_flag: Arc<AtomicBool>) {
+        let request = request_data(&key, RelayRequest::Allocate, &nonce);
+        let interval = Duration::from_secs(cmp::max(lifetime_secs / 2, 1) as u64);
+        let mut refresh_at = Instant::now() + interval;
+        while !stop_flag.load(Ordering::SeqCst) {
+            if Instant::now() >= refresh_at {
+                let _ = socket.send_to(&request[..], &*server_addr);
+                refresh_at = Instant::now() + interval;
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        let release = request_data(&key, RelayRequest::Release, &nonce);
+        let _ = socket.send_to(&release[..], &*server_addr);
+    }
+
+    /// The address of the relay server that made the allocation.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    /// The address on the relay server that is relayed to us.
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+}
+
+/// An address allocated on a relay, through which `RelayedUdpSocket` can connect to a peer.
+pub trait Relay {
+    /// The relayed address as a candidate to put in our rendezvous info.
+    fn endpoint(&self) -> MappedSocketAddr;
+
+    /// Let datagrams sent to the relayed address from the IP address of `peer_addr` through to us.
+    /// Permissions may not be acknowledged so send them again if unsure.
+    fn permit(&self, peer_addr: &SocketAddr) -> io::Result<()>;
+
+    /// Send `buf` to `peer_addr` from the relayed address.
+    fn send_to(&self, buf: &[u8], peer_addr: &SocketAddr) -> io::Result<usize>;
+
+    /// Receive a datagram sent to the relayed address, returning its length and the address it
+    /// came from. Data that doesn't fit in `buf` is discarded. Blocks for at most the timeout set
+    /// with `set_read_timeout`.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Set the timeout for `recv_from`.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+}
+
+impl Relay for RelayAllocation {
+    fn endpoint(&self) -> MappedSocketAddr {
+        MappedSocketAddr::new(CandidateType::Relayed, self.relayed_addr, self.local_addr, true)
+    }
+
+    fn permit(&self, peer_addr: &SocketAddr) -> io::Result<()> {
+        let msg = request_data(&self.key, RelayRequest::Permit(*peer_addr), &self.nonce);
+        self.socket.send_to(&msg[..], &*self.server_addr).map(|_| ())
+    }
+
+    fn send_to(&self, buf: &[u8], peer_addr: &SocketAddr) -> io::Result<usize> {
+        let msg = unwrap_result!(serialise(&RelayMsg::Send(*peer_addr, buf.to_vec())));
+        if msg.len() > MAX_RELAY_DATAGRAM_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Datagram too large to be relayed"));
+        }
+        try!(self.socket.send_to(&msg[..], &*self.server_addr));
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut recv_buf = unwrap_result!(self.recv_buf.lock());
+        loop {
+            let (n, addr) = try!(self.socket.recv_from(&mut recv_buf[..]));
+            if SocketAddr(addr) != self.server_addr {
+                continue;
+            }
+            if let Ok(RelayMsg::Data(peer_addr, data)) = deserialise(&recv_buf[..n]) {
+                let len = cmp::min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok((len, peer_addr));
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(dur)
+    }
+}
+
+impl Drop for RelayAllocation {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::ConnectionReset => true,
+        _ => false,
+    }
+}
+
+quick_error! {
+    /// Warnings raised by `RelayedUdpSocket::connect`.
+    #[derive(Debug)]
+    pub enum RelayConnectWarning {
+        /// One of the peer's endpoints was dropped by the endpoint policy.
+        RejectedEndpoint { err: RejectedEndpoint } {
+            description("One of the peer's endpoints was dropped by the endpoint policy")
+            display("One of the peer's endpoints was dropped by the endpoint policy: {}", err)
+            cause(err)
+        }
+        /// Received invalid data through the relay while connecting.
+        InvalidPacket { err: SerialisationError } {
+            description("Received invalid data through the relay while connecting")
+            display("Received invalid data through the relay while connecting. \
+                     deserialisation produced the error: {}", err)
+            cause(err)
+        }
+    }
+}
+
+quick_error! {
+    /// Errors returned by `RelayedUdpSocket::connect`.
+    #[derive(Debug)]
+    pub enum RelayConnectError {
+        /// The peer's rendezvous info doesn't contain a relayed address.
+        NoRelayedEndpoints {
+            description("The peer's rendezvous info doesn't contain a relayed address")
+        }
+        /// IO error talking to the relay server.
+        Io { err: io::Error } {
+            description("IO error talking to the relay server")
+            display("IO error talking to the relay server: {}", err)
+            cause(err)
+        }
+        /// Didn't hear from the peer through the relay before the deadline.
+        TimedOut {
+            description("Didn't hear from the peer through the relay before the deadline")
+        }
+    }
+}
+
+impl From<RelayConnectError> for io::Error {
+    fn from(e: RelayConnectError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            RelayConnectError::NoRelayedEndpoints => io::ErrorKind::InvalidInput,
+            RelayConnectError::Io { err } => err.kind(),
+            RelayConnectError::TimedOut => io::ErrorKind::TimedOut,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// A relay allocation connected to a peer that has a relay allocation of its own.
+pub struct RelayedUdpSocket<R: Relay = RelayAllocation> {
+    /// The allocation that traffic is relayed through.
+    pub allocation: R,
+    /// The peer's relayed address. Traffic is exchanged with this address.
+    pub peer_addr: SocketAddr,
+}
+
+impl<R: Relay> RelayedUdpSocket<R> {
+    /// Connect to the peer through relays. Both peers must advertise the relayed address of their
+    /// allocation (see `Relay::endpoint`) in their rendezvous info and call this at
+    /// about the same time. Each peer permits the peer's relayed addresses and authenticates the
+    /// peer with hole punch messages sent through the relays.
+    ///
+    /// Hole punch messages may still arrive after this returns. Use
+    /// `filter_udp_hole_punch_packet` to ignore them.
+    pub fn connect(allocation: R,
+                   our_priv_rendezvous_info: PrivRendezvousInfo,
+                   mut their_pub_rendezvous_info: PubRendezvousInfo,
+                   deadline: Instant)
+                   -> WResult<RelayedUdpSocket<R>, RelayConnectWarning, RelayConnectError> {
+        let mut warnings: Vec<RelayConnectWarning> = their_pub_rendezvous_info
+            .apply_endpoint_policy(&EndpointPolicy::default())
+            .into_iter()
+            .map(|e| RelayConnectWarning::RejectedEndpoint { err: e })
+            .collect();
+        let their_addrs: Vec<SocketAddr> = their_pub_rendezvous_info.endpoints()
+            .iter()
+            .filter(|e| e.candidate_type == CandidateType::Relayed)
+            .map(|e| e.addr)
+            .collect();
+        if their_addrs.is_empty() {
+            return WErr(RelayConnectError::NoRelayedEndpoints);
+        }
+        let our_secret = rendezvous_info::priv_secret(&our_priv_rendezvous_info);
+        let their_secret = rendezvous_info::pub_secret(&their_pub_rendezvous_info);
+        let our_nonce = secret::gen_nonce();
+        let hole_punch = punched_udp_socket::hole_punch_data(&our_secret, &our_nonce);
+
+        // The peer's relayed address that we've acked a hole punch message from, along with the
+        // ack, and whether the peer has acked ours.
+        let mut peer: Option<(SocketAddr, Vec<u8>)> = None;
+        let mut acked = false;
+        let mut buf = [0; punched_udp_socket::MAX_DATAGRAM_SIZE];
+        let mut next_send = Instant::now();
+        loop {
+            if acked {
+                if let Some((peer_addr, ack)) = peer.take() {
+                    // Send our ack again in case the peer is still waiting for it.
+                    let _ = allocation.send_to(&ack[..], &peer_addr);
+                    return WOk(RelayedUdpSocket {
+                        allocation: allocation,
+                        peer_addr: peer_addr,
+                    }, warnings);
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return WErr(RelayConnectError::TimedOut);
+            }
+            if now >= next_send {
+                for addr in &their_addrs {
+                    let res = allocation.permit(addr)
+                                        .and_then(|()| allocation.send_to(&hole_punch[..], addr));
+                    if let Err(e) = res {
+                        return WErr(RelayConnectError::Io { err: e });
+                    }
+                }
+                next_send = now + Duration::from_millis(CONNECT_RESEND_MS);
+            }
+            let timeout = cmp::min(next_send, deadline) - now;
+            if let Err(e) = allocation.set_read_timeout(Some(timeout)) {
+                return WErr(RelayConnectError::Io { err: e });
+            }
+            let (n, addr) = match allocation.recv_from(&mut buf[..]) {
+                Ok(x) => x,
+                Err(ref e) if is_transient(e) => continue,
+                Err(e) => return WErr(RelayConnectError::Io { err: e }),
+            };
+            if !their_addrs.contains(&addr) {
+                continue;
+            }
+            let msg: HolePunch = match deserialise(&buf[..n]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warnings.push(RelayConnectWarning::InvalidPacket { err: e });
+                    continue;
+                },
+            };
+            if msg.is_hole_punch_from(&their_secret) {
+                let ack = punched_udp_socket::ack_data(&our_secret, &our_nonce, &msg.nonce);
+                if let Err(e) = allocation.send_to(&ack[..], &addr) {
+                    return WErr(RelayConnectError::Io { err: e });
+                }
+                peer = Some((addr, ack));
+            } else if msg.is_ack_from(&their_secret, &our_nonce) {
+                acked = true;
+            }
+        }
+    }
+
+    /// Send `buf` to the peer.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.allocation.send_to(buf, &self.peer_addr)
+    }
+
+    /// Receive a datagram from the peer. Blocks for at most the timeout set with
+    /// `set_read_timeout`.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (n, addr) = try!(self.allocation.recv_from(buf));
+            if addr == self.peer_addr {
+                return Ok(n);
+            }
+        }
+    }
+
+    /// Set the timeout for `recv`.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.allocation.set_read_timeout(dur)
+    }
+}
+
+impl<R: Relay> PeerSocket for RelayedUdpSocket<R> {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        RelayedUdpSocket::send(self, buf)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        RelayedUdpSocket::recv(self, buf)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        RelayedUdpSocket::set_read_timeout(self, dur)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::time::{Duration, Instant};
+
+    use socket_addr::SocketAddr;
+
+    use mapping_context::MappingContext;
+    use punched_udp_socket::PeerSocket;
+    use relay::{Relay, RelayAllocateError, RelayAllocation, RelayServerInfo, RelayedUdpSocket};
+    use relay_server::{self, RelayServer};
+    use rendezvous_info::gen_rendezvous_info;
+
+    const SECRET: &'static str = "two_peers_connect_through_relay_over_loopback";
+
+    fn relay_server() -> (RelayServer, RelayServerInfo) {
+        let server = unwrap_result!(RelayServer::new(&unwrap_result!("127.0.0.1:0".parse()),
+                                                     SECRET));
+        let info = RelayServerInfo {
+            addr: SocketAddr(server.local_addr()),
+            secret: SECRET.to_owned(),
+        };
+        (server, info)
+    }
+
+    #[test]
+    fn two_peers_connect_through_relay_over_loopback() {
+        let (_server, info) = relay_server();
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_relay_servers(Some(info));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let allocation_0 = unwrap_result!(RelayAllocation::allocate(&mc, deadline));
+        let allocation_1 = unwrap_result!(RelayAllocation::allocate(&mc, deadline));
+        assert!(allocation_0.relayed_addr() != allocation_1.relayed_addr());
+        let relayed_addr_0 = allocation_0.relayed_addr();
+        let relayed_addr_1 = allocation_1.relayed_addr();
+
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(vec![allocation_0.endpoint()]);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(vec![allocation_1.endpoint()]);
+        let jh = thread!("two_peers_connect_through_relay_over_loopback 1", move || {
+            let res = RelayedUdpSocket::connect(allocation_1, priv_info_1, pub_info_0, deadline);
+            let relayed_socket = unwrap_result!(res.result_discard());
+            assert_eq!(relayed_socket.peer_addr, relayed_addr_0);
+            assert_eq!(unwrap_result!(relayed_socket.send(b"hello")), 5);
+            relayed_socket
+        });
+        let res = RelayedUdpSocket::connect(allocation_0, priv_info_0, pub_info_1, deadline);
+        let relayed_socket = unwrap_result!(res.result_discard());
+        assert_eq!(relayed_socket.peer_addr, relayed_addr_1);
+        let relayed_socket_1 = unwrap_result!(jh.join());
+
+        unwrap_result!(relayed_socket.set_read_timeout(Some(Duration::from_secs(3))));
+        let mut buf = [0; 64];
+        loop {
+            let n = unwrap_result!(relayed_socket.recv(&mut buf));
+            if &buf[..n] == b"hello" {
+                break;
+            }
+        }
+
+        // Relayed sockets can be used wherever a `PeerSocket` is wanted.
+        send_through_peer_sockets(&relayed_socket, &relayed_socket_1);
+    }
+
+    fn send_through_peer_sockets<S: PeerSocket>(from: &S, to: &S) {
+        unwrap_result!(to.set_read_timeout(Some(Duration::from_secs(3))));
+        assert_eq!(unwrap_result!(from.send(b"hi")), 2);
+        let mut buf = [0; 64];
+        loop {
+            let n = unwrap_result!(to.recv(&mut buf));
+            if &buf[..n] == b"hi" {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn allocations_need_the_shared_secret() {
+        let (_server, mut info) = relay_server();
+        info.secret = "not the secret".to_owned();
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_relay_servers(Some(info));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        match RelayAllocation::allocate(&mc, deadline) {
+            Err(RelayAllocateError::Rejected) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(..) => panic!("Allocated an address without the shared secret"),
+        }
+    }
+
+    #[test]
+    fn servers_of_either_address_family_are_asked() {
+        let (_server, info) = relay_server();
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        // Nothing answers at this address, but it mustn't stop us asking the ipv4 server.
+        mc.add_relay_servers(vec![RelayServerInfo {
+                                      addr: SocketAddr(unwrap_result!("[::1]:9".parse())),
+                                      secret: SECRET.to_owned(),
+                                  },
+                                  info]);
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let allocation = unwrap_result!(RelayAllocation::allocate(&mc, deadline));
+        assert!(allocation.relayed_addr().is_ipv4());
+    }
+
+    #[test]
+    fn full_servers_say_so() {
+        let server = unwrap_result!(relay_server::new_with_max_allocations(
+            &unwrap_result!("127.0.0.1:0".parse()), SECRET, 1
+        ));
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_relay_servers(Some(RelayServerInfo {
+            addr: SocketAddr(server.local_addr()),
+            secret: SECRET.to_owned(),
+        }));
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let _allocation = unwrap_result!(RelayAllocation::allocate(&mc, deadline));
+        match RelayAllocation::allocate(&mc, deadline) {
+            Err(RelayAllocateError::Full) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(..) => panic!("Allocated more addresses than the server holds"),
+        }
+    }
+
+    #[test]
+    fn dropping_the_server_frees_relayed_ports() {
+        let (server, info) = relay_server();
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_relay_servers(Some(info));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let allocation = unwrap_result!(RelayAllocation::allocate(&mc, deadline));
+
+        drop(server);
+        let _ = unwrap_result!(UdpSocket::bind(&*allocation.relayed_addr()));
+    }
+
+    #[test]
+    fn only_permitted_peers_are_sent_to() {
+        let (_server, info) = relay_server();
+        let mc = unwrap_result!(MappingContext::new().result_discard());
+        mc.add_relay_servers(Some(info));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let allocation = unwrap_result!(RelayAllocation::allocate(&mc, deadline));
+
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let peer_addr = SocketAddr(unwrap_result!(peer.local_addr()));
+        unwrap_result!(peer.set_read_timeout(Some(Duration::from_millis(500))));
+        let mut buf = [0; 64];
+        let _ = unwrap_result!(allocation.send_to(b"unpermitted", &peer_addr));
+        assert!(peer.recv_from(&mut buf).is_err());
+
+        unwrap_result!(allocation.permit(&peer_addr));
+        unwrap_result!(peer.set_read_timeout(Some(Duration::from_secs(3))));
+        loop {
+            let _ = unwrap_result!(allocation.send_to(b"permitted", &peer_addr));
+            let (n, addr) = unwrap_result!(peer.recv_from(&mut buf));
+            assert_eq!(SocketAddr(addr), allocation.relayed_addr());
+            if &buf[..n] == b"permitted" {
+                break;
+            }
+        }
+    }
+}
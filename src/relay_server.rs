@@ -0,0 +1,328 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A server which relays udp traffic for clients that can't punch a hole to their peers. The
+//! protocol is described in the `relay` module.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{self, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+use socket_addr::SocketAddr;
+
+use relay::{self, MAX_RELAY_DATAGRAM_SIZE, RelayMsg, RelayRequest};
+use secret::{self, MacPurpose, Nonce, NONCE_LEN, Secret};
+use socket_utils;
+
+/// How long an allocation lasts unless the client refreshes it.
+const ALLOCATION_LIFETIME_SECS: u32 = 600;
+/// How often the server threads check whether they should stop.
+const POLL_INTERVAL_MS: u64 = 500;
+/// The most allocations the server holds at once.
+const MAX_ALLOCATIONS: usize = 1024;
+
+/// An address allocated to a client. Dropping this stops the thread relaying traffic from the
+/// address to the client and waits for it to exit.
+struct Allocation {
+    relayed_socket: UdpSocket,
+    relayed_addr: SocketAddr,
+    permissions: Arc<Mutex<HashSet<net::IpAddr>>>,
+    expires_at: Instant,
+    stop_flag: Arc<AtomicBool>,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        // Wake the thread up rather than wait for its read to time out.
+        let _ = self.relayed_socket.send_to(&[], &*self.relayed_addr);
+    }
+}
+
+/// RAII type for a relay server. Relays traffic from a background thread until dropped.
+pub struct RelayServer {
+    socket: UdpSocket,
+    stop_flag: Arc<AtomicBool>,
+    local_addr: net::SocketAddr,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+quick_error! {
+    /// Errors returned by `RelayServer::new`.
+    #[derive(Debug)]
+    pub enum RelayServerNewError {
+        /// The server must be bound to a specific address so that it can tell clients where their
+        /// relayed addresses are.
+        UnspecifiedAddress {
+            description("The relay server must be bound to a specific address")
+        }
+        /// Error binding the server socket.
+        Bind { err: io::Error } {
+            description("Error binding the server socket.")
+            display("Error binding the server socket: {}", err)
+            cause(err)
+        }
+        /// Error configuring the server socket.
+        ConfigureSocket { err: io::Error } {
+            description("Error configuring the server socket.")
+            display("Error configuring the server socket: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<RelayServerNewError> for io::Error {
+    fn from(e: RelayServerNewError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            RelayServerNewError::UnspecifiedAddress => io::ErrorKind::InvalidInput,
+            RelayServerNewError::Bind { err } => err.kind(),
+            RelayServerNewError::ConfigureSocket { err } => err.kind(),
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl RelayServer {
+    /// Start a relay server on `bind_addr`. Relayed addresses are allocated on the same IP address
+    /// so it must be one that the clients' peers can reach. Only clients that know `secret` can
+    /// allocate addresses.
+    pub fn new(bind_addr: &net::SocketAddr, secret: &str)
+               -> Result<RelayServer, RelayServerNewError> {
+        new_with_max_allocations(bind_addr, secret, MAX_ALLOCATIONS)
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    fn run(socket: UdpSocket, key: Secret, max_allocations: usize, stop_flag: Arc<AtomicBool>) {
+        // Nonces are derived from the client's address with a key of our own, so that we don't
+        // need to remember the nonces we've given out.
+        let nonce_key = secret::gen_secret();
+        let mut allocations: HashMap<net::SocketAddr, Allocation> = HashMap::new();
+        let mut buf = vec![0; MAX_RELAY_DATAGRAM_SIZE];
+        while !stop_flag.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            let expired: Vec<net::SocketAddr> = allocations.iter()
+                                                           .filter(|&(_, a)| a.expires_at <= now)
+                                                           .map(|(client, _)| *client)
+                                                           .collect();
+            for client in expired {
+                let _ = allocations.remove(&client);
+            }
+
+            let (n, client) = match socket.recv_from(&mut buf[..]) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let (request, nonce, mac) = match deserialise(&buf[..n]) {
+                Ok(RelayMsg::Request(request, nonce, mac)) => (request, nonce, mac),
+                Ok(RelayMsg::Send(peer_addr, data)) => {
+                    if let Some(allocation) = allocations.get(&client) {
+                        if unwrap_result!(allocation.permissions.lock()).contains(&peer_addr.ip()) {
+                            let _ = allocation.relayed_socket.send_to(&data[..], &*peer_addr);
+                        }
+                    }
+                    continue;
+                },
+                _ => continue,
+            };
+            let expected_nonce = client_nonce(&nonce_key, &client);
+            if nonce != expected_nonce {
+                let resp = unwrap_result!(serialise(&RelayMsg::Challenge(expected_nonce)));
+                let _ = socket.send_to(&resp[..], client);
+                continue;
+            }
+            if !relay::verify_request(&key, &request, &nonce, &mac) {
+                let _ = socket.send_to(&unwrap_result!(serialise(&RelayMsg::Rejected))[..], client);
+                continue;
+            }
+            match request {
+                RelayRequest::Allocate => {
+                    Self::allocate(&socket, client, &mut allocations, max_allocations, &stop_flag);
+                },
+                RelayRequest::Release => {
+                    let _ = allocations.remove(&client);
+                },
+                RelayRequest::Permit(peer_addr) => {
+                    if let Some(allocation) = allocations.get(&client) {
+                        let mut permissions = unwrap_result!(allocation.permissions.lock());
+                        let _ = permissions.insert(peer_addr.ip());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Give `client` an allocation, or refresh its existing one, and tell it its relayed address.
+    fn allocate(socket: &UdpSocket,
+                client: net::SocketAddr,
+                allocations: &mut HashMap<net::SocketAddr, Allocation>,
+                max_allocations: usize,
+                server_stop_flag: &Arc<AtomicBool>) {
+        let expires_at = Instant::now() + Duration::from_secs(ALLOCATION_LIFETIME_SECS as u64);
+        if let Some(allocation) = allocations.get_mut(&client) {
+            allocation.expires_at = expires_at;
+            let resp = RelayMsg::Allocated(allocation.relayed_addr, ALLOCATION_LIFETIME_SECS);
+            let _ = socket.send_to(&unwrap_result!(serialise(&resp))[..], client);
+            return;
+        }
+        if allocations.len() >= max_allocations {
+            let _ = socket.send_to(&unwrap_result!(serialise(&RelayMsg::Full))[..], client);
+            return;
+        }
+
+        let local_ip = match socket.local_addr() {
+            Ok(local_addr) => local_addr.ip(),
+            Err(_) => return,
+        };
+        let relayed_socket = match UdpSocket::bind(&net::SocketAddr::new(local_ip, 0)) {
+            Ok(relayed_socket) => relayed_socket,
+            Err(_) => return,
+        };
+        let (relayed_addr, cloned_relayed_socket, cloned_socket) =
+            match (relayed_socket.local_addr(), relayed_socket.try_clone(), socket.try_clone()) {
+                (Ok(relayed_addr), Ok(cloned_relayed_socket), Ok(cloned_socket)) => {
+                    (SocketAddr(relayed_addr), cloned_relayed_socket, cloned_socket)
+                },
+                _ => return,
+            };
+        if cloned_relayed_socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))
+                                .is_err() {
+            return;
+        }
+
+        let permissions = Arc::new(Mutex::new(HashSet::new()));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_permissions = permissions.clone();
+        let cloned_stop_flag = stop_flag.clone();
+        let cloned_server_stop_flag = server_stop_flag.clone();
+        let raii_joiner = RaiiThreadJoiner::new(thread!("RelayServer::allocate", move || {
+            Self::relay_to_client(cloned_relayed_socket,
+                                  cloned_socket,
+                                  client,
+                                  cloned_permissions,
+                                  cloned_stop_flag,
+                                  cloned_server_stop_flag);
+        }));
+        let _ = allocations.insert(client, Allocation {
+            relayed_socket: relayed_socket,
+            relayed_addr: relayed_addr,
+            permissions: permissions,
+            expires_at: expires_at,
+            stop_flag: stop_flag,
+            _raii_joiner: raii_joiner,
+        });
+
+        let resp = RelayMsg::Allocated(relayed_addr, ALLOCATION_LIFETIME_SECS);
+        let _ = socket.send_to(&unwrap_result!(serialise(&resp))[..], client);
+    }
+
+    /// Pass datagrams arriving on a relayed address from permitted peers on to the client.
+    fn relay_to_client(relayed_socket: UdpSocket,
+                       socket: UdpSocket,
+                       client: net::SocketAddr,
+                       permissions: Arc<Mutex<HashSet<net::IpAddr>>>,
+                       stop_flag: Arc<AtomicBool>,
+                       server_stop_flag: Arc<AtomicBool>) {
+        let mut buf = vec![0; MAX_RELAY_DATAGRAM_SIZE];
+        while !stop_flag.load(Ordering::SeqCst) && !server_stop_flag.load(Ordering::SeqCst) {
+            let (n, peer_addr) = match relayed_socket.recv_from(&mut buf[..]) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            if stop_flag.load(Ordering::SeqCst) {
+                break;
+            }
+            if !unwrap_result!(permissions.lock()).contains(&peer_addr.ip()) {
+                continue;
+            }
+            let msg = RelayMsg::Data(SocketAddr(peer_addr), buf[..n].to_vec());
+            let data = unwrap_result!(serialise(&msg));
+            if data.len() <= MAX_RELAY_DATAGRAM_SIZE {
+                let _ = socket.send_to(&data[..], client);
+            }
+        }
+    }
+}
+
+/// As `RelayServer::new` but holding at most `max_allocations` allocations at once.
+pub fn new_with_max_allocations(bind_addr: &net::SocketAddr, secret: &str, max_allocations: usize)
+                                -> Result<RelayServer, RelayServerNewError> {
+    let unspecified = match *bind_addr {
+        net::SocketAddr::V4(ref addr) => socket_utils::ipv4_is_unspecified(addr.ip()),
+        net::SocketAddr::V6(ref addr) => socket_utils::ipv6_is_unspecified(addr.ip()),
+    };
+    if unspecified {
+        return Err(RelayServerNewError::UnspecifiedAddress);
+    }
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(socket) => socket,
+        Err(e) => return Err(RelayServerNewError::Bind { err: e }),
+    };
+    let local_addr = match socket.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(e) => return Err(RelayServerNewError::ConfigureSocket { err: e }),
+    };
+    if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))) {
+        return Err(RelayServerNewError::ConfigureSocket { err: e });
+    }
+    let cloned_socket = match socket.try_clone() {
+        Ok(cloned_socket) => cloned_socket,
+        Err(e) => return Err(RelayServerNewError::ConfigureSocket { err: e }),
+    };
+
+    let key = relay::shared_key(secret);
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let cloned_stop_flag = stop_flag.clone();
+    let raii_joiner = RaiiThreadJoiner::new(thread!("RelayServer", move || {
+        RelayServer::run(cloned_socket, key, max_allocations, cloned_stop_flag);
+    }));
+
+    Ok(RelayServer {
+        socket: socket,
+        stop_flag: stop_flag,
+        local_addr: local_addr,
+        _raii_joiner: raii_joiner,
+    })
+}
+
+/// The nonce which `client` has to authenticate its requests with.
+fn client_nonce(nonce_key: &Secret, client: &net::SocketAddr) -> Nonce {
+    let client = format!("{}", client);
+    let mac = secret::mac_data(nonce_key, MacPurpose::RelayNonce, &[], client.as_bytes());
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&mac[..NONCE_LEN]);
+    nonce
+}
+
+impl Drop for RelayServer {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        // Wake the server thread up rather than wait for its read to time out. It then stops the
+        // threads relaying to clients and waits for them.
+        let _ = self.socket.send_to(&[], self.local_addr);
+    }
+}
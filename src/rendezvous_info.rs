@@ -18,45 +18,711 @@
 //! # `nat_traversal`
 //! NAT traversal utilities.
 
-use rand;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::{Cursor, Read};
+use std::mem;
+use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use mapped_socket_addr::MappedSocketAddr;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use maidsafe_utilities::serialisation::{deserialise, serialise, SerialisationError};
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use rustc_serialize::base64::{FromBase64, FromBase64Error, ToBase64, URL_SAFE};
+use socket_addr::SocketAddr;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::sign;
+
+use endpoint_policy::{self, EndpointPolicy, RejectedEndpoint};
+use mapped_socket_addr::{self, CandidateType, MappedSocketAddr};
+use port_prediction::{self, PortPrediction};
+use secret::{self, Nonce, Secret, SECRET_LEN};
+
+/// The version of the wire format written by `PubRendezvousInfo::to_bytes`. Bump this whenever
+/// the format changes so that peers running different versions of the library can tell that they
+/// can't understand each other's info.
+const WIRE_FORMAT_VERSION: u8 = 1;
+/// Prefix of the string form of a `PubRendezvousInfo`.
+const URI_SCHEME: &'static str = "nat://";
+
+const FLAG_PORT_PREDICTION: u8 = 0x01;
+const FLAG_PUBLIC_KEY: u8 = 0x02;
+const FLAG_NAT_RESTRICTED: u8 = 0x01;
 
 /// Info exchanged by both parties before performing a rendezvous connection.
-#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+///
+/// Serialises to its wire format, so that decoding checks it just as `from_bytes` does.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PubRendezvousInfo {
-    /// A vector of all the mapped addresses that the peer can try connecting to.
+    /// A vector of all the mapped addresses that the peer can try connecting to, highest priority
+    /// first.
     endpoints: Vec<MappedSocketAddr>,
-    /// Used to identify the peer.
-    secret: [u8; 4],
+    /// Used to authenticate the peer while hole punching. It never gets sent over the connection
+    /// being punched, the peer just proves that it knows it.
+    secret: Secret,
+    /// How the peer's NAT allocates external ports, if it could be predicted.
+    port_prediction: Option<PortPrediction>,
+    /// The peer's box public key, if it wants to set up session keys while hole punching.
+    public_key: Option<[u8; box_::PUBLICKEYBYTES]>,
+}
+
+impl PubRendezvousInfo {
+    /// The addresses that we can try connecting to the peer on, highest priority first.
+    pub fn endpoints(&self) -> &[MappedSocketAddr] {
+        &self.endpoints
+    }
+
+    /// The box public key embedded by the peer, if any.
+    pub fn public_key(&self) -> Option<box_::PublicKey> {
+        self.public_key.map(box_::PublicKey)
+    }
+
+    /// Drop the endpoints, and the port prediction, that `policy` doesn't allow us to send to.
+    /// Should be called on infos received from untrusted peers. Returns the rejected endpoints.
+    pub fn apply_endpoint_policy(&mut self, policy: &EndpointPolicy) -> Vec<RejectedEndpoint> {
+        let endpoints = mem::replace(&mut self.endpoints, Vec::new());
+        let (endpoints, mut rejected) = endpoint_policy::apply(policy, endpoints);
+        self.endpoints = endpoints;
+        if let Some(port_prediction) = self.port_prediction {
+            if let Err(e) = policy.check(&port_prediction.last_addr) {
+                rejected.push(e);
+                self.port_prediction = None;
+            }
+        }
+        rejected
+    }
+
+    /// Sign this info with `secret_key` so that the peer can check that it came from us. The peer
+    /// will reject it once `valid_for` has elapsed.
+    pub fn sign(self, secret_key: &sign::SecretKey, valid_for: Duration)
+                -> SignedPubRendezvousInfo {
+        sign_with_expiry(self, secret_key, unix_time_secs().saturating_add(valid_for.as_secs()))
+    }
+}
+
+quick_error! {
+    /// Error returned when decoding a `PubRendezvousInfo` from its wire format or string form.
+    #[derive(Debug)]
+    pub enum DecodeRendezvousInfoError {
+        /// The string doesn't start with `nat://`.
+        MissingScheme {
+            description("Rendezvous info strings must start with nat://")
+        }
+        /// The string isn't valid base64.
+        Base64 {
+            err: FromBase64Error,
+        } {
+            description("Rendezvous info string isn't valid base64")
+            display("Rendezvous info string isn't valid base64: {}", err)
+            cause(err)
+        }
+        /// The info was written with a version of the wire format that we don't understand.
+        UnsupportedVersion {
+            version: u8,
+        } {
+            description("Rendezvous info was encoded with an unsupported version of the wire \
+                         format")
+            display("Rendezvous info was encoded with version {} of the wire format but only \
+                     version {} is supported. The peer is running an incompatible version of \
+                     nat_traversal", version, WIRE_FORMAT_VERSION)
+        }
+        /// The data ends part way through the info. The info may have been cut off when copying
+        /// it.
+        Truncated {
+            description("Rendezvous info is truncated")
+        }
+        /// There is more data after the end of the info.
+        TrailingData {
+            len: usize,
+        } {
+            description("Rendezvous info is followed by unexpected data")
+            display("Rendezvous info is followed by {} bytes of unexpected data", len)
+        }
+        /// The info uses flags that we don't know about.
+        UnknownFlags {
+            flags: u8,
+        } {
+            description("Rendezvous info uses unknown flags")
+            display("Rendezvous info uses unknown flags: {:#04x}", flags)
+        }
+        /// The port prediction has an implausible port allocation delta.
+        BadPortPrediction {
+            delta: i32,
+        } {
+            description("Rendezvous info contains an implausible port prediction")
+            display("Rendezvous info contains a port prediction with implausible delta {}", delta)
+        }
+        /// An endpoint has an unknown candidate type.
+        UnknownCandidateType {
+            value: u8,
+        } {
+            description("Rendezvous info contains an endpoint with an unknown candidate type")
+            display("Rendezvous info contains an endpoint with unknown candidate type {}", value)
+        }
+        /// An address has an unknown address family.
+        UnknownAddressFamily {
+            value: u8,
+        } {
+            description("Rendezvous info contains an address with an unknown address family")
+            display("Rendezvous info contains an address with unknown address family {}", value)
+        }
+    }
+}
+
+impl From<DecodeRendezvousInfoError> for io::Error {
+    fn from(e: DecodeRendezvousInfoError) -> io::Error {
+        let err_str = format!("{}", e);
+        io::Error::new(io::ErrorKind::InvalidData, err_str)
+    }
+}
+
+impl PubRendezvousInfo {
+    /// Encode the info in a compact binary format. The first byte is the format version.
+    ///
+    /// At most 255 endpoints are encoded, the lowest priority endpoints are dropped beyond that.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.port_prediction.is_some() {
+            flags |= FLAG_PORT_PREDICTION;
+        }
+        if self.public_key.is_some() {
+            flags |= FLAG_PUBLIC_KEY;
+        }
+
+        let mut data = Vec::new();
+        data.push(WIRE_FORMAT_VERSION);
+        data.extend_from_slice(&self.secret[..]);
+        data.push(flags);
+        if let Some(ref public_key) = self.public_key {
+            data.extend_from_slice(&public_key[..]);
+        }
+        if let Some(ref port_prediction) = self.port_prediction {
+            write_addr(&mut data, &port_prediction.last_addr);
+            unwrap_result!(data.write_i32::<BigEndian>(port_prediction.delta));
+        }
+        let num_endpoints = cmp::min(self.endpoints.len(), u8::max_value() as usize);
+        data.push(num_endpoints as u8);
+        for endpoint in &self.endpoints[..num_endpoints] {
+            let endpoint_flags = if endpoint.nat_restricted {
+                FLAG_NAT_RESTRICTED
+            } else {
+                0
+            };
+            data.push(candidate_type_to_u8(endpoint.candidate_type));
+            data.push(endpoint_flags);
+            write_addr(&mut data, &endpoint.addr);
+            write_addr(&mut data, &endpoint.base);
+        }
+        data
+    }
+
+    /// Decode an info encoded with `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> Result<PubRendezvousInfo, DecodeRendezvousInfoError> {
+        let mut cursor = Cursor::new(data);
+        let version = try!(read_u8(&mut cursor));
+        if version != WIRE_FORMAT_VERSION {
+            return Err(DecodeRendezvousInfoError::UnsupportedVersion { version: version });
+        }
+        let mut secret = [0u8; SECRET_LEN];
+        try!(read_exact(&mut cursor, &mut secret));
+        let flags = try!(read_u8(&mut cursor));
+        if flags & !(FLAG_PORT_PREDICTION | FLAG_PUBLIC_KEY) != 0 {
+            return Err(DecodeRendezvousInfoError::UnknownFlags { flags: flags });
+        }
+        let public_key = if flags & FLAG_PUBLIC_KEY != 0 {
+            let mut public_key = [0u8; box_::PUBLICKEYBYTES];
+            try!(read_exact(&mut cursor, &mut public_key));
+            Some(public_key)
+        } else {
+            None
+        };
+        let port_prediction = if flags & FLAG_PORT_PREDICTION != 0 {
+            let last_addr = try!(read_addr(&mut cursor));
+            let delta = try!(cursor.read_i32::<BigEndian>()
+                                   .map_err(|_| DecodeRendezvousInfoError::Truncated));
+            if !port_prediction::is_valid_delta(delta) {
+                return Err(DecodeRendezvousInfoError::BadPortPrediction { delta: delta });
+            }
+            Some(PortPrediction {
+                last_addr: last_addr,
+                delta: delta,
+            })
+        } else {
+            None
+        };
+
+        let num_endpoints = try!(read_u8(&mut cursor));
+        let mut endpoints = Vec::with_capacity(num_endpoints as usize);
+        for _ in 0..num_endpoints {
+            let candidate_type = try!(candidate_type_from_u8(try!(read_u8(&mut cursor))));
+            let endpoint_flags = try!(read_u8(&mut cursor));
+            if endpoint_flags & !FLAG_NAT_RESTRICTED != 0 {
+                return Err(DecodeRendezvousInfoError::UnknownFlags { flags: endpoint_flags });
+            }
+            let addr = try!(read_addr(&mut cursor));
+            let base = try!(read_addr(&mut cursor));
+            endpoints.push(MappedSocketAddr::new(candidate_type,
+                                                 addr,
+                                                 base,
+                                                 endpoint_flags & FLAG_NAT_RESTRICTED != 0));
+        }
+        mapped_socket_addr::sort_by_priority(&mut endpoints);
+
+        let remaining = data.len() - cursor.position() as usize;
+        if remaining != 0 {
+            return Err(DecodeRendezvousInfoError::TrailingData { len: remaining });
+        }
+        Ok(PubRendezvousInfo {
+            endpoints: endpoints,
+            secret: secret,
+            port_prediction: port_prediction,
+            public_key: public_key,
+        })
+    }
+}
+
+impl Encodable for PubRendezvousInfo {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.to_bytes().encode(s)
+    }
+}
+
+impl Decodable for PubRendezvousInfo {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PubRendezvousInfo, D::Error> {
+        let data: Vec<u8> = try!(Decodable::decode(d));
+        PubRendezvousInfo::from_bytes(&data).map_err(|e| d.error(&format!("{}", e)))
+    }
+}
+
+/// Formats the info as a `nat://` URI holding the wire format in unpadded url-safe base64. This
+/// is short enough to paste into a chat message or put in a QR code.
+impl fmt::Display for PubRendezvousInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", URI_SCHEME, self.to_bytes().to_base64(URL_SAFE))
+    }
+}
+
+/// Parses the `nat://` form of an info. Surrounding whitespace is ignored.
+impl FromStr for PubRendezvousInfo {
+    type Err = DecodeRendezvousInfoError;
+
+    fn from_str(s: &str) -> Result<PubRendezvousInfo, DecodeRendezvousInfoError> {
+        let s = s.trim();
+        if !s.starts_with(URI_SCHEME) {
+            return Err(DecodeRendezvousInfoError::MissingScheme);
+        }
+        let data = match s[URI_SCHEME.len()..].from_base64() {
+            Ok(data) => data,
+            Err(e) => return Err(DecodeRendezvousInfoError::Base64 { err: e }),
+        };
+        PubRendezvousInfo::from_bytes(&data)
+    }
+}
+
+fn candidate_type_to_u8(candidate_type: CandidateType) -> u8 {
+    match candidate_type {
+        CandidateType::Host => 0,
+        CandidateType::Mapped => 1,
+        CandidateType::PeerReflexive => 2,
+        CandidateType::ServerReflexive => 3,
+        CandidateType::Predicted => 4,
+        CandidateType::Relayed => 5,
+    }
+}
+
+fn candidate_type_from_u8(value: u8) -> Result<CandidateType, DecodeRendezvousInfoError> {
+    Ok(match value {
+        0 => CandidateType::Host,
+        1 => CandidateType::Mapped,
+        2 => CandidateType::PeerReflexive,
+        3 => CandidateType::ServerReflexive,
+        4 => CandidateType::Predicted,
+        5 => CandidateType::Relayed,
+        _ => return Err(DecodeRendezvousInfoError::UnknownCandidateType { value: value }),
+    })
+}
+
+/// Addresses are written as the address family (4 or 6), the IP address and the port.
+fn write_addr(data: &mut Vec<u8>, addr: &SocketAddr) {
+    match **addr {
+        net::SocketAddr::V4(ref addr_v4) => {
+            data.push(4);
+            data.extend_from_slice(&addr_v4.ip().octets()[..]);
+        },
+        net::SocketAddr::V6(ref addr_v6) => {
+            data.push(6);
+            for segment in &addr_v6.ip().segments() {
+                unwrap_result!(data.write_u16::<BigEndian>(*segment));
+            }
+        },
+    }
+    unwrap_result!(data.write_u16::<BigEndian>(addr.port()));
+}
+
+fn read_addr(cursor: &mut Cursor<&[u8]>) -> Result<SocketAddr, DecodeRendezvousInfoError> {
+    match try!(read_u8(cursor)) {
+        4 => {
+            let mut octets = [0u8; 4];
+            try!(read_exact(cursor, &mut octets));
+            let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+            let port = try!(read_u16(cursor));
+            Ok(SocketAddr(net::SocketAddr::V4(SocketAddrV4::new(ip, port))))
+        },
+        6 => {
+            let mut segments = [0u16; 8];
+            for segment in &mut segments {
+                *segment = try!(read_u16(cursor));
+            }
+            let ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                   segments[4], segments[5], segments[6], segments[7]);
+            let port = try!(read_u16(cursor));
+            Ok(SocketAddr(net::SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))))
+        },
+        value => Err(DecodeRendezvousInfoError::UnknownAddressFamily { value: value }),
+    }
+}
+
+fn read_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8, DecodeRendezvousInfoError> {
+    cursor.read_u8().map_err(|_| DecodeRendezvousInfoError::Truncated)
+}
+
+fn read_u16(cursor: &mut Cursor<&[u8]>) -> Result<u16, DecodeRendezvousInfoError> {
+    cursor.read_u16::<BigEndian>().map_err(|_| DecodeRendezvousInfoError::Truncated)
+}
+
+fn read_exact(cursor: &mut Cursor<&[u8]>, buf: &mut [u8]) -> Result<(), DecodeRendezvousInfoError> {
+    cursor.read_exact(buf).map_err(|_| DecodeRendezvousInfoError::Truncated)
 }
 
 /// The local half of a `PubRendezvousInfo`.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PrivRendezvousInfo {
-    secret: [u8; 4],
+    /// Our own endpoints, used to pair them up with the peer's when hole punching.
+    endpoints: Vec<MappedSocketAddr>,
+    secret: Secret,
+    key_pair: Option<([u8; box_::PUBLICKEYBYTES], [u8; box_::SECRETKEYBYTES])>,
 }
 
 /// Create a `(PrivRendezvousInfo, PubRendezvousInfo)` pair from a list of
 /// mapped socket addresses.
 pub fn gen_rendezvous_info(endpoints: Vec<MappedSocketAddr>)
                            -> (PrivRendezvousInfo, PubRendezvousInfo) {
-    let secret = rand::random();
+    gen_rendezvous_info_with_port_prediction(endpoints, None)
+}
+
+/// Like `gen_rendezvous_info` but also tells the peer how our NAT allocates ports. Pass the
+/// `port_prediction` of a `MappedUdpSocket` so that a peer can reach us through a symmetric NAT.
+pub fn gen_rendezvous_info_with_port_prediction(endpoints: Vec<MappedSocketAddr>,
+                                                port_prediction: Option<PortPrediction>)
+                                                -> (PrivRendezvousInfo, PubRendezvousInfo) {
+    gen(endpoints, port_prediction, None)
+}
+
+/// Like `gen_rendezvous_info_with_port_prediction` but also embeds `public_key` so that
+/// `tcp_punch_hole_secure` and `PunchedUdpSocket::punch_hole_secure` can set up session keys bound
+/// to it. The key pair can be long-term, to identify us to the peer, or ephemeral.
+pub fn gen_rendezvous_info_with_key_pair(endpoints: Vec<MappedSocketAddr>,
+                                         port_prediction: Option<PortPrediction>,
+                                         public_key: &box_::PublicKey,
+                                         secret_key: &box_::SecretKey)
+                                         -> (PrivRendezvousInfo, PubRendezvousInfo) {
+    gen(endpoints, port_prediction, Some((public_key.0, secret_key.0)))
+}
+
+fn gen(mut endpoints: Vec<MappedSocketAddr>,
+       port_prediction: Option<PortPrediction>,
+       key_pair: Option<([u8; box_::PUBLICKEYBYTES], [u8; box_::SECRETKEYBYTES])>)
+       -> (PrivRendezvousInfo, PubRendezvousInfo) {
+    mapped_socket_addr::sort_by_priority(&mut endpoints);
+    let secret = secret::gen_secret();
     let priv_info = PrivRendezvousInfo {
+        endpoints: endpoints.clone(),
         secret: secret,
+        key_pair: key_pair,
     };
     let pub_info = PubRendezvousInfo {
         endpoints: endpoints,
         secret: secret,
+        port_prediction: port_prediction,
+        public_key: key_pair.map(|(public_key, _)| public_key),
     };
     (priv_info, pub_info)
 }
 
-pub fn decompose(info: PubRendezvousInfo) -> (Vec<MappedSocketAddr>, [u8; 4]) {
-    let PubRendezvousInfo { endpoints, secret } = info;
+/// A `PubRendezvousInfo` signed with an ed25519 key, so that whoever relays it to the peer can't
+/// swap in their own endpoints. The signature also covers an expiry time and a nonce so that stale
+/// or replayed infos get rejected.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct SignedPubRendezvousInfo {
+    /// A serialised `SignedContent`, signed with `sodiumoxide::crypto::sign`.
+    signed: Vec<u8>,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct SignedContent {
+    info: PubRendezvousInfo,
+    /// Seconds since the unix epoch after which the info must not be used.
+    expires_at: u64,
+    nonce: Nonce,
+}
+
+fn sign_with_expiry(info: PubRendezvousInfo, secret_key: &sign::SecretKey, expires_at: u64)
+                    -> SignedPubRendezvousInfo {
+    let content = SignedContent {
+        info: info,
+        expires_at: expires_at,
+        nonce: secret::gen_nonce(),
+    };
+    SignedPubRendezvousInfo {
+        signed: sign::sign(&unwrap_result!(serialise(&content)), secret_key),
+    }
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+quick_error! {
+    /// Error returned by `RendezvousInfoVerifier::verify`.
+    #[derive(Debug)]
+    pub enum VerifyRendezvousInfoError {
+        /// The info wasn't signed by the expected key.
+        BadSignature {
+            description("The rendezvous info wasn't signed by the expected key")
+        }
+        /// The signed data couldn't be deserialised.
+        Deserialise {
+            err: SerialisationError,
+        } {
+            description("The signed rendezvous info couldn't be deserialised")
+            display("The signed rendezvous info couldn't be deserialised: {}", err)
+            cause(err)
+        }
+        /// The info has expired.
+        Expired {
+            description("The rendezvous info has expired")
+        }
+        /// We've already accepted this info once.
+        Replayed {
+            description("The rendezvous info has already been used")
+        }
+    }
+}
+
+impl From<VerifyRendezvousInfoError> for io::Error {
+    fn from(e: VerifyRendezvousInfoError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            VerifyRendezvousInfoError::BadSignature => io::ErrorKind::InvalidData,
+            VerifyRendezvousInfoError::Deserialise { .. } => io::ErrorKind::InvalidData,
+            VerifyRendezvousInfoError::Expired => io::ErrorKind::InvalidData,
+            VerifyRendezvousInfoError::Replayed => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// Verifies `SignedPubRendezvousInfo`s received from a peer. Remembers the nonces of the infos it
+/// has accepted, until they expire, so that each info is only accepted once.
+pub struct RendezvousInfoVerifier {
+    public_key: sign::PublicKey,
+    seen: Vec<(Nonce, u64)>,
+}
+
+impl RendezvousInfoVerifier {
+    /// Create a verifier for infos signed by the peer owning `public_key`.
+    pub fn new(public_key: sign::PublicKey) -> RendezvousInfoVerifier {
+        RendezvousInfoVerifier {
+            public_key: public_key,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Check the signature, expiry and nonce of `signed_info` and return the info inside it.
+    pub fn verify(&mut self, signed_info: &SignedPubRendezvousInfo)
+                  -> Result<PubRendezvousInfo, VerifyRendezvousInfoError> {
+        let data = match sign::verify(&signed_info.signed, &self.public_key) {
+            Ok(data) => data,
+            Err(()) => return Err(VerifyRendezvousInfoError::BadSignature),
+        };
+        let content = match deserialise::<SignedContent>(&data) {
+            Ok(content) => content,
+            Err(e) => return Err(VerifyRendezvousInfoError::Deserialise { err: e }),
+        };
+
+        let now = unix_time_secs();
+        self.seen.retain(|&(_, expires_at)| expires_at >= now);
+        if content.expires_at < now {
+            return Err(VerifyRendezvousInfoError::Expired);
+        }
+        if self.seen.iter().any(|&(nonce, _)| nonce == content.nonce) {
+            return Err(VerifyRendezvousInfoError::Replayed);
+        }
+        self.seen.push((content.nonce, content.expires_at));
+        Ok(content.info)
+    }
+}
+
+pub fn decompose(info: PubRendezvousInfo) -> (Vec<MappedSocketAddr>, Secret) {
+    let PubRendezvousInfo { endpoints, secret, .. } = info;
     (endpoints, secret)
 }
 
-pub fn get_priv_secret(info: PrivRendezvousInfo) -> [u8; 4] {
+pub fn get_port_prediction(info: &PubRendezvousInfo) -> Option<PortPrediction> {
+    info.port_prediction
+}
+
+pub fn get_priv_secret(info: PrivRendezvousInfo) -> Secret {
     info.secret
 }
+
+pub fn decompose_priv(info: PrivRendezvousInfo) -> (Vec<MappedSocketAddr>, Secret) {
+    let PrivRendezvousInfo { endpoints, secret, .. } = info;
+    (endpoints, secret)
+}
+
+pub fn get_key_pair(info: &PrivRendezvousInfo) -> Option<(box_::PublicKey, box_::SecretKey)> {
+    info.key_pair.map(|(public_key, secret_key)| {
+        (box_::PublicKey(public_key), box_::SecretKey(secret_key))
+    })
+}
+
+pub fn priv_secret(info: &PrivRendezvousInfo) -> Secret {
+    info.secret
+}
+
+pub fn pub_secret(info: &PubRendezvousInfo) -> Secret {
+    info.secret
+}
+
+#[cfg(test)]
+mod test {
+    use std::net;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use rustc_serialize::json;
+    use socket_addr::SocketAddr;
+    use sodiumoxide::crypto::{box_, sign};
+
+    use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+    use port_prediction::PortPrediction;
+    use rendezvous_info::{self, DecodeRendezvousInfoError, PubRendezvousInfo,
+                          RendezvousInfoVerifier, VerifyRendezvousInfoError, gen_rendezvous_info,
+                          gen_rendezvous_info_with_key_pair,
+                          gen_rendezvous_info_with_port_prediction};
+
+    fn socket_addr(s: &str) -> SocketAddr {
+        SocketAddr(unwrap_result!(net::SocketAddr::from_str(s)))
+    }
+
+    #[test]
+    fn wire_format_round_trips() {
+        let endpoints = vec![
+            MappedSocketAddr::new(CandidateType::Host,
+                                  socket_addr("192.168.0.2:5000"),
+                                  socket_addr("192.168.0.2:5000"),
+                                  false),
+            MappedSocketAddr::new(CandidateType::ServerReflexive,
+                                  socket_addr("[2a00:1450::1]:6000"),
+                                  socket_addr("[::]:5000"),
+                                  true),
+        ];
+        let port_prediction = PortPrediction {
+            last_addr: socket_addr("8.8.8.8:40000"),
+            delta: -2,
+        };
+        let (public_key, secret_key) = box_::gen_keypair();
+        let (_, pub_info) = gen_rendezvous_info_with_key_pair(endpoints,
+                                                              Some(port_prediction),
+                                                              &public_key,
+                                                              &secret_key);
+
+        assert_eq!(unwrap_result!(PubRendezvousInfo::from_bytes(&pub_info.to_bytes())), pub_info);
+        let s = format!("{}", pub_info);
+        assert!(s.starts_with("nat://"));
+        assert_eq!(unwrap_result!(PubRendezvousInfo::from_str(&format!(" {}\n", s))), pub_info);
+
+        let (_, pub_info) = gen_rendezvous_info(Vec::new());
+        let s = format!("{}", pub_info);
+        assert_eq!(unwrap_result!(s.parse::<PubRendezvousInfo>()), pub_info);
+
+        let port_prediction = PortPrediction {
+            last_addr: socket_addr("8.8.8.8:40000"),
+            delta: i32::max_value(),
+        };
+        let (_, pub_info) = gen_rendezvous_info_with_port_prediction(Vec::new(),
+                                                                     Some(port_prediction));
+        match PubRendezvousInfo::from_bytes(&pub_info.to_bytes()) {
+            Err(DecodeRendezvousInfoError::BadPortPrediction { delta }) => {
+                assert_eq!(delta, i32::max_value())
+            },
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn incompatible_or_damaged_infos_are_rejected() {
+        let (_, pub_info) = gen_rendezvous_info(Vec::new());
+        let mut data = pub_info.to_bytes();
+
+        match PubRendezvousInfo::from_bytes(&data[..data.len() - 1]) {
+            Err(DecodeRendezvousInfoError::Truncated) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        data.push(0);
+        match PubRendezvousInfo::from_bytes(&data) {
+            Err(DecodeRendezvousInfoError::TrailingData { len: 1 }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        data[0] = 2;
+        match PubRendezvousInfo::from_bytes(&data) {
+            Err(DecodeRendezvousInfoError::UnsupportedVersion { version: 2 }) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match "http://example.com".parse::<PubRendezvousInfo>() {
+            Err(DecodeRendezvousInfoError::MissingScheme) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn decoding_checks_the_wire_format() {
+        let (_, pub_info) = gen_rendezvous_info(Vec::new());
+        let encoded = unwrap_result!(json::encode(&pub_info));
+        assert_eq!(unwrap_result!(json::decode::<PubRendezvousInfo>(&encoded)), pub_info);
+
+        let mut data = pub_info.to_bytes();
+        data[0] = 2;
+        let encoded = unwrap_result!(json::encode(&data));
+        assert!(json::decode::<PubRendezvousInfo>(&encoded).is_err());
+    }
+
+    #[test]
+    fn signed_infos_are_verified_once() {
+        let (public_key, secret_key) = sign::gen_keypair();
+        let (other_public_key, _) = sign::gen_keypair();
+        let (_, pub_info) = gen_rendezvous_info(Vec::new());
+
+        let signed_info = pub_info.clone().sign(&secret_key, Duration::from_secs(60));
+        let mut verifier = RendezvousInfoVerifier::new(public_key);
+        assert_eq!(unwrap_result!(verifier.verify(&signed_info)), pub_info);
+        match verifier.verify(&signed_info) {
+            Err(VerifyRendezvousInfoError::Replayed) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let signed_info = pub_info.clone().sign(&secret_key, Duration::from_secs(60));
+        match RendezvousInfoVerifier::new(other_public_key).verify(&signed_info) {
+            Err(VerifyRendezvousInfoError::BadSignature) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let signed_info = rendezvous_info::sign_with_expiry(pub_info, &secret_key, 1);
+        match verifier.verify(&signed_info) {
+            Err(VerifyRendezvousInfoError::Expired) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A server that introduces peers to each other. Peers register with the server under an id and
+//! then ask it to swap rendezvous info with another registered peer. Once both peers have sent
+//! their info the server forwards each peer's info to the other, along with a delay after which
+//! both should start hole punching.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+
+use rendezvous_info::PubRendezvousInfo;
+use signalling::{self, EarlyInfos, ExchangeTag, Frame, FrameReader, Signaller, TagMatch};
+
+/// How long the server waits for a new connection to register, and how long a client waits for
+/// the server to accept its registration.
+const REGISTER_TIMEOUT_SECS: u64 = 20;
+/// How long the server waits to write a frame to a peer before giving up on the peer.
+const WRITE_TIMEOUT_SECS: u64 = 5;
+/// The most infos a peer can have waiting for other peers at once.
+const MAX_PENDING_INFOS: usize = 16;
+
+// The first byte of a frame's payload says what kind of message it is.
+const MSG_REGISTER: u8 = 0;
+const MSG_REGISTERED: u8 = 1;
+const MSG_INFO: u8 = 2;
+const MSG_ERROR: u8 = 3;
+
+struct Registry {
+    /// Streams to the registered peers, by id. Frames are written to a peer with the lock on its
+    /// stream held so that they don't get interleaved.
+    peers: HashMap<String, Arc<Mutex<TcpStream>>>,
+    /// Infos waiting for the peer they're addressed to to send its own for the same attempt, keyed
+    /// by (from, to). Only the latest info from a peer to another is kept.
+    pending: HashMap<(String, String), (ExchangeTag, Vec<u8>)>,
+}
+
+/// RAII type for a rendezvous server. Serves peers from background threads until dropped.
+pub struct RendezvousServer {
+    stop_flag: Arc<AtomicBool>,
+    local_addr: net::SocketAddr,
+    registry: Arc<Mutex<Registry>>,
+    _raii_joiner: RaiiThreadJoiner,
+}
+
+quick_error! {
+    /// Errors returned by `RendezvousServer::new`.
+    #[derive(Debug)]
+    pub enum RendezvousServerNewError {
+        /// Error binding the listening socket.
+        Bind { err: io::Error } {
+            description("Error binding the listening socket.")
+            display("Error binding the listening socket: {}", err)
+            cause(err)
+        }
+        /// Error getting the local address of the listening socket.
+        SocketLocalAddr { err: io::Error } {
+            description("Error getting the local address of the listening socket.")
+            display("Error getting the local address of the listening socket: {}", err)
+            cause(err)
+        }
+    }
+}
+
+impl From<RendezvousServerNewError> for io::Error {
+    fn from(e: RendezvousServerNewError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            RendezvousServerNewError::Bind { err } => err.kind(),
+            RendezvousServerNewError::SocketLocalAddr { err } => err.kind(),
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+impl RendezvousServer {
+    /// Start a server listening on `bind_addr`. When forwarding infos the server tells both peers
+    /// to wait `start_delay` before they start punching. The delay gives the peers time to get
+    /// ready so that their punching overlaps even if one receives the other's info late.
+    pub fn new(bind_addr: &net::SocketAddr, start_delay: Duration)
+               -> Result<RendezvousServer, RendezvousServerNewError> {
+        let listener = match TcpListener::bind(bind_addr) {
+            Ok(listener) => listener,
+            Err(e) => return Err(RendezvousServerNewError::Bind { err: e }),
+        };
+        let local_addr = match listener.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => return Err(RendezvousServerNewError::SocketLocalAddr { err: e }),
+        };
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let registry = Arc::new(Mutex::new(Registry {
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+        }));
+
+        let cloned_stop_flag = stop_flag.clone();
+        let cloned_registry = registry.clone();
+        let raii_joiner = RaiiThreadJoiner::new(thread!("RendezvousServer", move || {
+            Self::run(listener, cloned_stop_flag, cloned_registry, start_delay);
+        }));
+
+        Ok(RendezvousServer {
+            stop_flag: stop_flag,
+            local_addr: local_addr,
+            registry: registry,
+            _raii_joiner: raii_joiner,
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    fn run(listener: TcpListener,
+           stop_flag: Arc<AtomicBool>,
+           registry: Arc<Mutex<Registry>>,
+           start_delay: Duration) {
+        while !stop_flag.load(Ordering::SeqCst) {
+            if let Ok((stream, _)) = listener.accept() {
+                if stop_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                let registry = registry.clone();
+                let _ = thread!("RendezvousServer::run", move || {
+                    serve_peer(stream, registry, start_delay);
+                });
+            }
+        }
+    }
+}
+
+impl Drop for RendezvousServer {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::SeqCst);
+        // Unblock the threads reading from peers.
+        for stream in unwrap_result!(self.registry.lock()).peers.values() {
+            let _ = unwrap_result!(stream.lock()).shutdown(Shutdown::Both);
+        }
+        // Unblock the acceptor.
+        let _ = TcpStream::connect(self.local_addr);
+    }
+}
+
+/// Register a newly connected peer then forward its infos until it disconnects.
+fn serve_peer(mut stream: TcpStream, registry: Arc<Mutex<Registry>>, start_delay: Duration) {
+    if stream.set_read_timeout(Some(Duration::from_secs(REGISTER_TIMEOUT_SECS))).is_err() {
+        return;
+    }
+    let our_id = match signalling::read_frame(&mut stream) {
+        Ok(ref frame) if frame.payload == [MSG_REGISTER] => frame.from.clone(),
+        _ => return,
+    };
+    if unwrap_result!(registry.lock()).peers.contains_key(&our_id) {
+        let _ = send_error(&mut stream, &our_id, "That id is already registered");
+        return;
+    }
+    // Acknowledge the registration before other peers can find us, so that the acknowledgement is
+    // the first frame we send.
+    if stream.set_read_timeout(None).is_err() ||
+       stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS))).is_err() ||
+       send(&mut stream, "", &our_id, vec![MSG_REGISTERED]).is_err() {
+        return;
+    }
+    let our_stream = match stream.try_clone() {
+        Ok(cloned_stream) => Arc::new(Mutex::new(cloned_stream)),
+        Err(_) => return,
+    };
+    {
+        let mut registry = unwrap_result!(registry.lock());
+        // Another connection may have taken the id while we were acknowledging.
+        if registry.peers.contains_key(&our_id) {
+            let _ = send_error(&mut stream, &our_id, "That id is already registered");
+            return;
+        }
+        let _ = registry.peers.insert(our_id.clone(), our_stream.clone());
+    }
+
+    while let Ok(frame) = signalling::read_frame(&mut stream) {
+        if frame.payload.first() != Some(&MSG_INFO) || frame.from != our_id {
+            continue;
+        }
+        let their_id = frame.to;
+        let our_info = frame.payload[1..].to_vec();
+        let our_tag = match signalling::split_tagged_info(&our_info) {
+            Ok((our_tag, _)) => our_tag,
+            Err(_) => continue,
+        };
+
+        let (their_info, their_stream) = {
+            let mut registry = unwrap_result!(registry.lock());
+            let their_key = (their_id.clone(), our_id.clone());
+            let paired_info = match registry.pending.remove(&their_key) {
+                Some((their_tag, their_info)) => {
+                    match signalling::match_tag(&our_tag, &their_tag) {
+                        TagMatch::Matched => Some(their_info),
+                        // The peer has moved on to a later attempt and won't take our info.
+                        TagMatch::Early => {
+                            let _ = registry.pending.insert(their_key, (their_tag, their_info));
+                            continue;
+                        },
+                        // The peer's info is from an earlier attempt. Drop it and wait for the
+                        // peer to catch up with us.
+                        TagMatch::Stale => None,
+                    }
+                },
+                None => None,
+            };
+            match paired_info {
+                Some(their_info) => (their_info, registry.peers.get(&their_id).cloned()),
+                None => {
+                    let key = (our_id.clone(), their_id);
+                    let num_pending = registry.pending
+                                              .keys()
+                                              .filter(|&&(ref from, _)| *from == our_id)
+                                              .count();
+                    if num_pending < MAX_PENDING_INFOS || registry.pending.contains_key(&key) {
+                        let _ = registry.pending.insert(key, (our_tag, our_info));
+                        continue;
+                    }
+                    drop(registry);
+                    let _ = send_error(&mut *unwrap_result!(our_stream.lock()),
+                                       &our_id,
+                                       "Too many infos waiting for peers");
+                    continue;
+                },
+            }
+        };
+        // Both peers are waiting. Send each of them the other's info. This happens outside the
+        // registry lock so that a slow peer only holds up the peers talking to it.
+        let res = match their_stream {
+            Some(their_stream) => {
+                let mut their_stream = unwrap_result!(their_stream.lock());
+                let res = send(&mut *their_stream,
+                               &our_id,
+                               &their_id,
+                               info_payload(start_delay, &our_info));
+                // A write that timed out may have left part of a frame on the stream. Disconnect
+                // the peer rather than send it anything more.
+                if res.is_err() {
+                    let _ = their_stream.shutdown(Shutdown::Both);
+                }
+                res
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Peer disconnected")),
+        };
+        let mut our_stream = unwrap_result!(our_stream.lock());
+        let _ = match res {
+            Ok(()) => {
+                send(&mut *our_stream,
+                     &their_id,
+                     &our_id,
+                     info_payload(start_delay, &their_info))
+            },
+            Err(_) => send_error(&mut *our_stream, &our_id, "The peer has disconnected"),
+        };
+    }
+    unregister(&registry, &our_id);
+}
+
+fn unregister(registry: &Arc<Mutex<Registry>>, id: &str) {
+    let mut registry = unwrap_result!(registry.lock());
+    let _ = registry.peers.remove(id);
+    let stale: Vec<(String, String)> = registry.pending
+                                              .keys()
+                                              .filter(|&&(ref from, ref to)| from == id || to == id)
+                                              .cloned()
+                                              .collect();
+    for key in stale {
+        let _ = registry.pending.remove(&key);
+    }
+}
+
+fn info_payload(start_delay: Duration, info: &[u8]) -> Vec<u8> {
+    let start_delay_ms = start_delay.as_secs() * 1000 +
+                         (start_delay.subsec_nanos() / 1_000_000) as u64;
+    let mut payload = vec![MSG_INFO, 0, 0, 0, 0];
+    BigEndian::write_u32(&mut payload[1..5], start_delay_ms as u32);
+    payload.extend_from_slice(info);
+    payload
+}
+
+fn send(stream: &mut TcpStream, from: &str, to: &str, payload: Vec<u8>) -> io::Result<()> {
+    signalling::write_frame(stream, &Frame {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        payload: payload,
+    })
+}
+
+fn send_error(stream: &mut TcpStream, to: &str, msg: &str) -> io::Result<()> {
+    let mut payload = vec![MSG_ERROR];
+    payload.extend_from_slice(msg.as_bytes());
+    send(stream, "", to, payload)
+}
+
+/// A client for a `RendezvousServer`. Use it as the `Signaller` for `connect`.
+pub struct RendezvousClient {
+    stream: TcpStream,
+    our_id: String,
+    reader: FrameReader,
+    early: EarlyInfos,
+}
+
+impl RendezvousClient {
+    /// Connect to the rendezvous server at `server_addr` and register with it under `our_id`.
+    pub fn register(server_addr: &net::SocketAddr, our_id: &str) -> io::Result<RendezvousClient> {
+        let mut stream = try!(TcpStream::connect(server_addr));
+        try!(send(&mut stream, our_id, "", vec![MSG_REGISTER]));
+        try!(stream.set_read_timeout(Some(Duration::from_secs(REGISTER_TIMEOUT_SECS))));
+        let frame = try!(signalling::read_frame(&mut stream));
+        try!(stream.set_read_timeout(None));
+        match frame.payload.first() {
+            Some(&MSG_REGISTERED) => {
+                Ok(RendezvousClient {
+                    stream: stream,
+                    our_id: our_id.to_owned(),
+                    reader: FrameReader::new(),
+                    early: Vec::new(),
+                })
+            },
+            Some(&MSG_ERROR) => Err(server_error(&frame.payload[1..])),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected message from server")),
+        }
+    }
+}
+
+fn server_error(msg: &[u8]) -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   format!("Rendezvous server error: {}", String::from_utf8_lossy(msg)))
+}
+
+impl Signaller for RendezvousClient {
+    /// Swap infos with `peer_id` through the server, then wait for the start delay that the server
+    /// asked for.
+    fn exchange(&mut self,
+                peer_id: &str,
+                tag: &ExchangeTag,
+                our_info: &PubRendezvousInfo,
+                deadline: Instant)
+                -> io::Result<PubRendezvousInfo> {
+        let mut payload = vec![MSG_INFO];
+        payload.extend_from_slice(&try!(signalling::tagged_info(tag, &our_info.to_bytes())));
+        try!(send(&mut self.stream, &self.our_id, peer_id, payload));
+        // The start delay of an info which arrived early has already passed.
+        if let Some(info) = signalling::take_early_info(&mut self.early, peer_id, tag) {
+            return PubRendezvousInfo::from_bytes(&info).map_err(From::from);
+        }
+        loop {
+            let frame = try!(self.reader.read_before(&mut self.stream, deadline));
+            match frame.payload.first() {
+                Some(&MSG_INFO) if frame.from == peer_id && frame.payload.len() >= 5 => {
+                    let tagged_info = &frame.payload[5..];
+                    let (their_tag, info) = try!(signalling::split_tagged_info(tagged_info));
+                    match signalling::match_tag(tag, &their_tag) {
+                        TagMatch::Matched => (),
+                        TagMatch::Early => {
+                            signalling::keep_early_info(&mut self.early, peer_id, their_tag, info);
+                            continue;
+                        },
+                        TagMatch::Stale => continue,
+                    }
+                    let their_info = try!(PubRendezvousInfo::from_bytes(info));
+                    let start_delay_ms = BigEndian::read_u32(&frame.payload[1..5]) as u64;
+                    let start_at = Instant::now() + Duration::from_millis(start_delay_ms);
+                    let now = Instant::now();
+                    if start_at > now && start_at < deadline {
+                        thread::sleep(start_at - now);
+                    }
+                    return Ok(their_info);
+                },
+                Some(&MSG_ERROR) => return Err(server_error(&frame.payload[1..])),
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpStream;
+    use std::time::{Duration, Instant};
+
+    use rendezvous_info::gen_rendezvous_info;
+    use rendezvous_server::{MAX_PENDING_INFOS, MSG_ERROR, MSG_INFO, MSG_REGISTER, MSG_REGISTERED,
+                            RendezvousClient, RendezvousServer, send};
+    use signalling::{self, ExchangeTag, Signaller};
+
+    #[test]
+    fn server_introduces_registered_peers() {
+        let server = unwrap_result!(RendezvousServer::new(&unwrap_result!("127.0.0.1:0".parse()),
+                                                          Duration::from_millis(100)));
+        let server_addr = server.local_addr();
+        let mut client_0 = unwrap_result!(RendezvousClient::register(&server_addr, "alice"));
+        let mut client_1 = unwrap_result!(RendezvousClient::register(&server_addr, "bob"));
+        assert!(RendezvousClient::register(&server_addr, "alice").is_err());
+
+        let (_, pub_info_0) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info_1) = gen_rendezvous_info(Vec::new());
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let tag = ExchangeTag {
+            attempt: 0,
+            protocol: "udp".to_owned(),
+        };
+        let info = pub_info_1.clone();
+        let their_tag = tag.clone();
+        let jh = thread!("server_introduces_registered_peers bob", move || {
+            unwrap_result!(client_1.exchange("alice", &their_tag, &info, deadline))
+        });
+        let res = client_0.exchange("bob", &tag, &pub_info_0, deadline);
+        assert_eq!(unwrap_result!(res), pub_info_1);
+        assert_eq!(unwrap_result!(jh.join()), pub_info_0);
+    }
+
+    #[test]
+    fn infos_are_only_paired_with_infos_for_the_same_attempt() {
+        let server = unwrap_result!(RendezvousServer::new(&unwrap_result!("127.0.0.1:0".parse()),
+                                                          Duration::from_millis(100)));
+        let server_addr = server.local_addr();
+        let mut client_0 = unwrap_result!(RendezvousClient::register(&server_addr, "alice"));
+        let mut client_1 = unwrap_result!(RendezvousClient::register(&server_addr, "bob"));
+
+        let (_, pub_info_0) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info_1) = gen_rendezvous_info(Vec::new());
+        let first_tag = ExchangeTag {
+            attempt: 0,
+            protocol: "udp".to_owned(),
+        };
+        let retry_tag = ExchangeTag {
+            attempt: 1,
+            protocol: "tcp".to_owned(),
+        };
+
+        // Alice gives up on the first attempt before Bob gets to it, leaving a stale info on the
+        // server. Bob has skipped the first attempt, so the stale info mustn't be paired with his.
+        let deadline = Instant::now() + Duration::from_millis(200);
+        assert!(client_0.exchange("bob", &first_tag, &pub_info_0, deadline).is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let info = pub_info_1.clone();
+        let their_tag = retry_tag.clone();
+        let jh = thread!("infos_are_only_paired_with_infos_for_the_same_attempt bob", move || {
+            unwrap_result!(client_1.exchange("alice", &their_tag, &info, deadline))
+        });
+        let res = client_0.exchange("bob", &retry_tag, &pub_info_0, deadline);
+        assert_eq!(unwrap_result!(res), pub_info_1);
+        assert_eq!(unwrap_result!(jh.join()), pub_info_0);
+    }
+
+    #[test]
+    fn pending_infos_are_capped() {
+        let server = unwrap_result!(RendezvousServer::new(&unwrap_result!("127.0.0.1:0".parse()),
+                                                          Duration::from_millis(100)));
+        let mut stream = unwrap_result!(TcpStream::connect(server.local_addr()));
+        unwrap_result!(send(&mut stream, "alice", "", vec![MSG_REGISTER]));
+        unwrap_result!(stream.set_read_timeout(Some(Duration::from_secs(3))));
+        assert_eq!(unwrap_result!(signalling::read_frame(&mut stream)).payload, [MSG_REGISTERED]);
+
+        // Nobody answers, so the infos pile up until the server won't hold any more.
+        let tag = ExchangeTag {
+            attempt: 0,
+            protocol: "udp".to_owned(),
+        };
+        let mut payload = vec![MSG_INFO];
+        payload.extend_from_slice(&unwrap_result!(signalling::tagged_info(&tag, &[])));
+        for i in 0..MAX_PENDING_INFOS + 1 {
+            unwrap_result!(send(&mut stream, "alice", &format!("peer {}", i), payload.clone()));
+        }
+        let frame = unwrap_result!(signalling::read_frame(&mut stream));
+        assert_eq!(frame.payload.first(), Some(&MSG_ERROR));
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Secrets exchanged in rendezvous info and the MACs that prove knowledge of them during hole
+//! punching. The secrets themselves are never sent over the connections being punched.
+
+use sodiumoxide;
+use sodiumoxide::crypto::auth;
+use sodiumoxide::randombytes;
+
+/// Length of a rendezvous secret. Secrets are used directly as HMAC-SHA-512-256 keys.
+pub const SECRET_LEN: usize = auth::KEYBYTES;
+/// Length of the nonces which MACs are computed over.
+pub const NONCE_LEN: usize = 16;
+/// Length of a MAC.
+pub const MAC_LEN: usize = auth::TAGBYTES;
+
+pub type Secret = [u8; SECRET_LEN];
+pub type Nonce = [u8; NONCE_LEN];
+pub type Mac = [u8; MAC_LEN];
+
+/// What a MAC is for. This gets mixed into the MAC so that a MAC taken from one kind of message
+/// can't be passed off as another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacPurpose {
+    UdpHolePunch,
+    UdpAck,
+    TcpHandshake,
+    RelayRequest,
+    RelayNonce,
+}
+
+impl MacPurpose {
+    fn tag(&self) -> u8 {
+        match *self {
+            MacPurpose::UdpHolePunch => 0,
+            MacPurpose::UdpAck => 1,
+            MacPurpose::TcpHandshake => 2,
+            MacPurpose::RelayRequest => 3,
+            MacPurpose::RelayNonce => 4,
+        }
+    }
+}
+
+/// Generate a new random secret.
+pub fn gen_secret() -> Secret {
+    let _ = sodiumoxide::init();
+    let mut secret = [0u8; SECRET_LEN];
+    randombytes::randombytes_into(&mut secret);
+    secret
+}
+
+/// Generate a new random nonce.
+pub fn gen_nonce() -> Nonce {
+    let _ = sodiumoxide::init();
+    let mut nonce = [0u8; NONCE_LEN];
+    randombytes::randombytes_into(&mut nonce);
+    nonce
+}
+
+/// Compute the MAC of `nonces` for `purpose`, keyed with `secret`.
+pub fn mac(secret: &Secret, purpose: MacPurpose, nonces: &[&Nonce]) -> Mac {
+    mac_data(secret, purpose, nonces, &[])
+}
+
+/// Check, in constant time, that `mac` is the MAC of `nonces` for `purpose` keyed with `secret`.
+pub fn verify_mac(secret: &Secret, purpose: MacPurpose, nonces: &[&Nonce], mac: &Mac) -> bool {
+    verify_mac_data(secret, purpose, nonces, &[], mac)
+}
+
+/// Compute the MAC of `nonces` followed by `data` for `purpose`, keyed with `secret`.
+pub fn mac_data(secret: &Secret, purpose: MacPurpose, nonces: &[&Nonce], data: &[u8]) -> Mac {
+    let message = message(purpose, nonces, data);
+    let auth::Tag(mac) = auth::authenticate(&message, &auth::Key(*secret));
+    mac
+}
+
+/// Check, in constant time, that `mac` is the MAC of `nonces` followed by `data` for `purpose`
+/// keyed with `secret`.
+pub fn verify_mac_data(secret: &Secret,
+                       purpose: MacPurpose,
+                       nonces: &[&Nonce],
+                       data: &[u8],
+                       mac: &Mac)
+                       -> bool {
+    auth::verify(&auth::Tag(*mac), &message(purpose, nonces, data), &auth::Key(*secret))
+}
+
+fn message(purpose: MacPurpose, nonces: &[&Nonce], data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(1 + nonces.len() * NONCE_LEN + data.len());
+    message.push(purpose.tag());
+    for nonce in nonces {
+        message.extend_from_slice(&nonce[..]);
+    }
+    message.extend_from_slice(data);
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn macs_are_bound_to_key_purpose_and_nonces() {
+        let secret = gen_secret();
+        let other_secret = gen_secret();
+        let nonce_0 = gen_nonce();
+        let nonce_1 = gen_nonce();
+        assert!(secret != other_secret);
+        assert!(nonce_0 != nonce_1);
+
+        let m = mac(&secret, MacPurpose::TcpHandshake, &[&nonce_0, &nonce_1]);
+        assert!(verify_mac(&secret, MacPurpose::TcpHandshake, &[&nonce_0, &nonce_1], &m));
+        assert!(!verify_mac(&other_secret, MacPurpose::TcpHandshake, &[&nonce_0, &nonce_1], &m));
+        assert!(!verify_mac(&secret, MacPurpose::UdpHolePunch, &[&nonce_0, &nonce_1], &m));
+        assert!(!verify_mac(&secret, MacPurpose::TcpHandshake, &[&nonce_1, &nonce_0], &m));
+    }
+}
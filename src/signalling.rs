@@ -0,0 +1,501 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Exchanging rendezvous info with peers. Hole punching needs each peer to get the other's
+//! `PubRendezvousInfo` through some channel they can already both reach, such as a server. A
+//! `Signaller` abstracts over that channel.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use rendezvous_info::PubRendezvousInfo;
+
+/// The largest signalling frame we'll accept.
+pub const MAX_FRAME_SIZE: usize = 4096;
+/// How much to read from a signalling stream at once.
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// Says which attempt at connecting an exchanged info belongs to, so that an info left over from
+/// an earlier attempt isn't mistaken for the answer to a later one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExchangeTag {
+    /// Counts up from zero with each attempt.
+    pub attempt: u32,
+    /// The protocol the info is meant to be used with.
+    pub protocol: String,
+}
+
+/// A way of swapping `PubRendezvousInfo`s with a peer.
+pub trait Signaller {
+    /// Send `our_info` to the peer identified by `peer_id` and wait for the peer to send us its
+    /// info for the same `tag`. Infos for earlier attempts, or for another protocol, are
+    /// discarded. Infos for later attempts are kept for the exchange that asks for them. Should
+    /// fail with `io::ErrorKind::TimedOut` if the peer's info doesn't arrive before `deadline`.
+    fn exchange(&mut self,
+                peer_id: &str,
+                tag: &ExchangeTag,
+                our_info: &PubRendezvousInfo,
+                deadline: Instant)
+                -> io::Result<PubRendezvousInfo>;
+}
+
+/// What to do with an info the peer sent with `theirs` while we're waiting for `ours`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TagMatch {
+    /// It's the info we're waiting for.
+    Matched,
+    /// It's for a later attempt. Keep it until we get there.
+    Early,
+    /// It's for an earlier attempt or for a protocol we aren't trying. Drop it.
+    Stale,
+}
+
+/// Compare the tag of a received info with the tag we're waiting for.
+pub fn match_tag(ours: &ExchangeTag, theirs: &ExchangeTag) -> TagMatch {
+    if theirs.attempt > ours.attempt {
+        TagMatch::Early
+    } else if theirs == ours {
+        TagMatch::Matched
+    } else {
+        TagMatch::Stale
+    }
+}
+
+/// Prefix `info` with `tag`.
+pub fn tagged_info(tag: &ExchangeTag, info: &[u8]) -> io::Result<Vec<u8>> {
+    if tag.protocol.len() > u8::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Protocol name is too long"));
+    }
+    let mut data = Vec::with_capacity(5 + tag.protocol.len() + info.len());
+    try!(data.write_u32::<BigEndian>(tag.attempt));
+    data.push(tag.protocol.len() as u8);
+    data.extend_from_slice(tag.protocol.as_bytes());
+    data.extend_from_slice(info);
+    Ok(data)
+}
+
+/// Split data written by `tagged_info` into the tag and the info.
+pub fn split_tagged_info(data: &[u8]) -> io::Result<(ExchangeTag, &[u8])> {
+    if data.len() < 4 {
+        return Err(invalid_data());
+    }
+    let attempt = BigEndian::read_u32(&data[..4]);
+    let mut pos = 4;
+    let protocol = try!(read_id(data, &mut pos));
+    let tag = ExchangeTag {
+        attempt: attempt,
+        protocol: protocol,
+    };
+    Ok((tag, &data[pos..]))
+}
+
+type Mailboxes = HashMap<(String, String), VecDeque<(ExchangeTag, PubRendezvousInfo)>>;
+
+/// Passes rendezvous info between `MemorySignaller`s in the same process. Useful for tests.
+#[derive(Clone)]
+pub struct MemorySignallingHub {
+    inner: Arc<(Mutex<Mailboxes>, Condvar)>,
+}
+
+impl MemorySignallingHub {
+    /// Create a hub with no peers.
+    pub fn new() -> MemorySignallingHub {
+        MemorySignallingHub {
+            inner: Arc::new((Mutex::new(HashMap::new()), Condvar::new())),
+        }
+    }
+
+    /// Create a signaller for the peer identified by `our_id`.
+    pub fn signaller(&self, our_id: &str) -> MemorySignaller {
+        MemorySignaller {
+            hub: self.clone(),
+            our_id: our_id.to_owned(),
+        }
+    }
+}
+
+/// A `Signaller` which passes rendezvous info through a `MemorySignallingHub`.
+pub struct MemorySignaller {
+    hub: MemorySignallingHub,
+    our_id: String,
+}
+
+impl Signaller for MemorySignaller {
+    fn exchange(&mut self,
+                peer_id: &str,
+                tag: &ExchangeTag,
+                our_info: &PubRendezvousInfo,
+                deadline: Instant)
+                -> io::Result<PubRendezvousInfo> {
+        let (ref mailboxes, ref cond_var) = *self.hub.inner;
+        let mut mailboxes = unwrap_result!(mailboxes.lock());
+        mailboxes.entry((self.our_id.clone(), peer_id.to_owned()))
+                 .or_insert_with(VecDeque::new)
+                 .push_back((tag.clone(), our_info.clone()));
+        cond_var.notify_all();
+
+        let from_peer = (peer_id.to_owned(), self.our_id.clone());
+        loop {
+            if let Some(mailbox) = mailboxes.get_mut(&from_peer) {
+                mailbox.retain(|&(ref their_tag, _)| match_tag(tag, their_tag) != TagMatch::Stale);
+                let pos = mailbox.iter().position(|&(ref their_tag, _)| their_tag == tag);
+                if let Some((_, info)) = pos.and_then(|pos| mailbox.remove(pos)) {
+                    return Ok(info);
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(timed_out());
+            }
+            mailboxes = unwrap_result!(cond_var.wait_timeout(mailboxes, deadline - now)).0;
+        }
+    }
+}
+
+/// The most infos for later attempts that a signaller keeps at once.
+const MAX_EARLY_INFOS: usize = 16;
+
+/// Infos received for attempts we haven't got to yet, with the id of the peer that sent them.
+pub type EarlyInfos = Vec<(String, ExchangeTag, Vec<u8>)>;
+
+/// Keep an info from `peer_id` that arrived before we asked for it.
+pub fn keep_early_info(early: &mut EarlyInfos, peer_id: &str, tag: ExchangeTag, info: &[u8]) {
+    if early.len() < MAX_EARLY_INFOS {
+        early.push((peer_id.to_owned(), tag, info.to_vec()));
+    }
+}
+
+/// Drop the infos from `peer_id` that are stale for `tag` and take the one matching it, if any.
+pub fn take_early_info(early: &mut EarlyInfos, peer_id: &str, tag: &ExchangeTag)
+                       -> Option<Vec<u8>> {
+    early.retain(|&(ref from, ref their_tag, _)| {
+        from != peer_id || match_tag(tag, their_tag) != TagMatch::Stale
+    });
+    early.iter()
+         .position(|&(ref from, ref their_tag, _)| from == peer_id && their_tag == tag)
+         .map(|pos| early.remove(pos).2)
+}
+
+/// A `Signaller` which talks to the peer, or to a server which forwards messages between peers,
+/// over a tcp stream. Each message is framed with the ids of its sender and recipient followed by
+/// the exchange tag and the wire format of the rendezvous info.
+pub struct TcpSignaller {
+    stream: TcpStream,
+    our_id: String,
+    reader: FrameReader,
+    early: EarlyInfos,
+}
+
+impl TcpSignaller {
+    /// Create a signaller for the peer identified by `our_id`, sending messages over `stream`.
+    pub fn new(stream: TcpStream, our_id: &str) -> TcpSignaller {
+        TcpSignaller {
+            stream: stream,
+            our_id: our_id.to_owned(),
+            reader: FrameReader::new(),
+            early: Vec::new(),
+        }
+    }
+}
+
+impl Signaller for TcpSignaller {
+    fn exchange(&mut self,
+                peer_id: &str,
+                tag: &ExchangeTag,
+                our_info: &PubRendezvousInfo,
+                deadline: Instant)
+                -> io::Result<PubRendezvousInfo> {
+        try!(write_frame(&mut self.stream, &Frame {
+            from: self.our_id.clone(),
+            to: peer_id.to_owned(),
+            payload: try!(tagged_info(tag, &our_info.to_bytes())),
+        }));
+        if let Some(info) = take_early_info(&mut self.early, peer_id, tag) {
+            return PubRendezvousInfo::from_bytes(&info).map_err(From::from);
+        }
+        loop {
+            let frame = try!(self.reader.read_before(&mut self.stream, deadline));
+            // Messages from anyone else, or for anyone else, aren't what we're waiting for.
+            if frame.from != peer_id || frame.to != self.our_id {
+                continue;
+            }
+            let (their_tag, info) = try!(split_tagged_info(&frame.payload));
+            match match_tag(tag, &their_tag) {
+                TagMatch::Matched => return PubRendezvousInfo::from_bytes(info).map_err(From::from),
+                TagMatch::Early => keep_early_info(&mut self.early, peer_id, their_tag, info),
+                TagMatch::Stale => (),
+            }
+        }
+    }
+}
+
+/// A signalling message.
+pub struct Frame {
+    pub from: String,
+    pub to: String,
+    pub payload: Vec<u8>,
+}
+
+/// Write a frame as its length followed by the sender's id, the recipient's id and the payload.
+/// Ids are prefixed with their length.
+pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> io::Result<()> {
+    if frame.from.len() > u8::max_value() as usize || frame.to.len() > u8::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Peer id is too long"));
+    }
+    let len = 2 + frame.from.len() + frame.to.len() + frame.payload.len();
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Signalling message is too long"));
+    }
+    let mut data = Vec::with_capacity(2 + len);
+    try!(data.write_u16::<BigEndian>(len as u16));
+    data.push(frame.from.len() as u8);
+    data.extend_from_slice(frame.from.as_bytes());
+    data.push(frame.to.len() as u8);
+    data.extend_from_slice(frame.to.as_bytes());
+    data.extend_from_slice(&frame.payload);
+    w.write_all(&data)
+}
+
+/// Read a frame written by `write_frame`. A read timeout that fires part way through a frame
+/// leaves the rest of it on `r`, so use a `FrameReader` when reading with a deadline.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Frame> {
+    let len = try!(r.read_u16::<BigEndian>()) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(frame_too_long());
+    }
+    let mut data = vec![0u8; len];
+    try!(r.read_exact(&mut data));
+    parse_frame(&data)
+}
+
+/// Reads frames from a stream with a deadline. Whatever part of a frame has arrived when the
+/// deadline passes is kept, so the next read carries on from the same place in the stream.
+pub struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Create a reader with nothing buffered.
+    pub fn new() -> FrameReader {
+        FrameReader { buf: Vec::new() }
+    }
+
+    /// Read the next frame from `stream`. Fails with `io::ErrorKind::TimedOut` if the frame
+    /// doesn't arrive before `deadline`. The stream's read timeout is cleared afterwards.
+    pub fn read_before(&mut self, stream: &mut TcpStream, deadline: Instant) -> io::Result<Frame> {
+        let res = self.read_with_timeout(stream, deadline);
+        let reset = stream.set_read_timeout(None);
+        let frame = try!(res);
+        try!(reset);
+        Ok(frame)
+    }
+
+    fn read_with_timeout(&mut self, stream: &mut TcpStream, deadline: Instant)
+                         -> io::Result<Frame> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            if let Some(frame) = try!(take_frame(&mut self.buf)) {
+                return Ok(frame);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(timed_out());
+            }
+            try!(stream.set_read_timeout(Some(deadline - now)));
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "Signalling stream closed"))
+                },
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                // A read timeout shows up as WouldBlock on unix and TimedOut on windows.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => return Err(timed_out()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Remove the first frame from `buf` if all of it has arrived.
+fn take_frame(buf: &mut Vec<u8>) -> io::Result<Option<Frame>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let len = BigEndian::read_u16(&buf[..2]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(frame_too_long());
+    }
+    if buf.len() < 2 + len {
+        return Ok(None);
+    }
+    let data: Vec<u8> = buf.drain(..2 + len).collect();
+    parse_frame(&data[2..]).map(Some)
+}
+
+fn parse_frame(data: &[u8]) -> io::Result<Frame> {
+    let mut pos = 0;
+    let from = try!(read_id(data, &mut pos));
+    let to = try!(read_id(data, &mut pos));
+    Ok(Frame {
+        from: from,
+        to: to,
+        payload: data[pos..].to_vec(),
+    })
+}
+
+/// The error returned when the peer's info doesn't arrive in time.
+pub fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut,
+                   "Timed out waiting for the peer's rendezvous info")
+}
+
+fn frame_too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Signalling message is too long")
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed signalling message")
+}
+
+fn read_id(data: &[u8], pos: &mut usize) -> io::Result<String> {
+    let len = match data.get(*pos) {
+        Some(len) => *len as usize,
+        None => return Err(invalid_data()),
+    };
+    let start = *pos + 1;
+    if start + len > data.len() {
+        return Err(invalid_data());
+    }
+    *pos = start + len;
+    String::from_utf8(data[start..*pos].to_vec()).map_err(|_| invalid_data())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    use rendezvous_info::gen_rendezvous_info;
+    use signalling::{self, ExchangeTag, Frame, MemorySignallingHub, Signaller, TcpSignaller};
+
+    fn tag(attempt: u32, protocol: &str) -> ExchangeTag {
+        ExchangeTag {
+            attempt: attempt,
+            protocol: protocol.to_owned(),
+        }
+    }
+
+    #[test]
+    fn memory_and_tcp_signallers_exchange_infos() {
+        let udp = tag(0, "udp");
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let (_, pub_info_0) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info_1) = gen_rendezvous_info(Vec::new());
+
+        let hub = MemorySignallingHub::new();
+        let mut signaller_0 = hub.signaller("alice");
+        let mut signaller_1 = hub.signaller("bob");
+        let info = pub_info_1.clone();
+        let their_udp = udp.clone();
+        let jh = thread!("memory_signaller bob", move || {
+            unwrap_result!(signaller_1.exchange("alice", &their_udp, &info, deadline))
+        });
+        let res = signaller_0.exchange("bob", &udp, &pub_info_0, deadline);
+        assert_eq!(unwrap_result!(res), pub_info_1);
+        assert_eq!(unwrap_result!(jh.join()), pub_info_0);
+
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(listener.local_addr());
+        let mut signaller_0 = TcpSignaller::new(unwrap_result!(TcpStream::connect(addr)), "alice");
+        let mut signaller_1 = TcpSignaller::new(unwrap_result!(listener.accept()).0, "bob");
+        let info = pub_info_1.clone();
+        let their_udp = udp.clone();
+        let jh = thread!("tcp_signaller bob", move || {
+            unwrap_result!(signaller_1.exchange("alice", &their_udp, &info, deadline))
+        });
+        let res = signaller_0.exchange("bob", &udp, &pub_info_0, deadline);
+        assert_eq!(unwrap_result!(res), pub_info_1);
+        assert_eq!(unwrap_result!(jh.join()), pub_info_0);
+
+        // Nobody is answering carol.
+        let mut signaller_2 = hub.signaller("carol");
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(signaller_2.exchange("dave", &udp, &pub_info_0, deadline).is_err());
+    }
+
+    #[test]
+    fn infos_for_other_attempts_are_not_taken_as_answers() {
+        let (_, pub_info_0) = gen_rendezvous_info(Vec::new());
+        let (_, pub_info_1) = gen_rendezvous_info(Vec::new());
+        let hub = MemorySignallingHub::new();
+        let mut signaller_0 = hub.signaller("alice");
+        let mut signaller_1 = hub.signaller("bob");
+
+        // Bob gets ahead of alice, giving up on udp and trying tcp before she tries anything.
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(signaller_1.exchange("alice", &tag(0, "udp"), &pub_info_1, deadline).is_err());
+        assert!(signaller_1.exchange("alice", &tag(1, "tcp"), &pub_info_1, deadline).is_err());
+
+        // Bob's udp info is stale once alice is on tcp, and his tcp info is waiting for her.
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(unwrap_result!(signaller_0.exchange("bob", &tag(1, "tcp"), &pub_info_0,
+                                                       deadline)),
+                   pub_info_1);
+        match signaller_0.exchange("bob", &tag(2, "relay"), &pub_info_0, deadline) {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+            res => panic!("Expected a timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn tcp_signaller_keeps_frames_intact_across_timeouts() {
+        let (_, pub_info) = gen_rendezvous_info(Vec::new());
+        let udp = tag(0, "udp");
+        let listener = unwrap_result!(TcpListener::bind("127.0.0.1:0"));
+        let addr = unwrap_result!(listener.local_addr());
+        let mut signaller = TcpSignaller::new(unwrap_result!(TcpStream::connect(addr)), "alice");
+        let mut stream = unwrap_result!(listener.accept()).0;
+
+        let mut data = Vec::new();
+        unwrap_result!(signalling::write_frame(&mut data, &Frame {
+            from: "bob".to_owned(),
+            to: "alice".to_owned(),
+            payload: unwrap_result!(signalling::tagged_info(&udp, &pub_info.to_bytes())),
+        }));
+
+        // Only part of the frame arrives in time.
+        unwrap_result!(stream.write_all(&data[..5]));
+        let deadline = Instant::now() + Duration::from_millis(100);
+        match signaller.exchange("bob", &udp, &pub_info, deadline) {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+            res => panic!("Expected a timeout, got {:?}", res),
+        }
+
+        unwrap_result!(stream.write_all(&data[5..]));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        assert_eq!(unwrap_result!(signaller.exchange("bob", &udp, &pub_info, deadline)), pub_info);
+    }
+}
@@ -34,6 +34,7 @@ use socket_addr::SocketAddr;
 use listener_message;
 use socket_utils;
 use mapping_context::MappingContext;
+use port_mapping::PortMappingGuard;
 use mapped_tcp_socket::{MappedTcpSocket, MappedTcpSocketNewError, MappedTcpSocketMapWarning};
 
 const TCP_RW_TIMEOUT: u64 = 20;
@@ -45,6 +46,7 @@ pub struct SimpleTcpHolePunchServer<T: AsRef<MappingContext>> {
     stop_flag: Arc<AtomicBool>,
     local_addr: net::SocketAddr,
     _raii_joiner: RaiiThreadJoiner,
+    _mapping_guard: PortMappingGuard,
     known_endpoints: Vec<SocketAddr>,
 }
 
@@ -141,6 +143,7 @@ impl<T: AsRef<MappingContext>> SimpleTcpHolePunchServer<T> {
             _mapping_context: mapping_context,
             stop_flag: stop_flag,
             _raii_joiner: raii_joiner,
+            _mapping_guard: mapped_socket.mapping_guard,
             local_addr: local_addr,
             known_endpoints: unrestricted_endpoints,
         }, warnings)
@@ -30,18 +30,22 @@ use w_result::{WResult, WOk, WErr};
 
 use socket_addr::SocketAddr;
 use listener_message;
+use stun;
 
 use mapping_context::MappingContext;
+use port_mapping::PortMappingGuard;
 use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketNewError, MappedUdpSocketMapWarning};
 
 const UDP_READ_TIMEOUT_SECS: u64 = 2;
 
-/// RAII type for a hole punch server which speaks the simple hole punching protocol.
+/// RAII type for a hole punch server which speaks the simple hole punching protocol. The server
+/// also answers STUN (RFC 5389) Binding requests so it can be used as a STUN server.
 pub struct SimpleUdpHolePunchServer<T: AsRef<MappingContext>> {
     // TODO(canndrew): Use this to refresh our external addrs.
     _mapping_context: T,
     stop_flag: Arc<AtomicBool>,
     _raii_joiner: RaiiThreadJoiner,
+    _mapping_guard: PortMappingGuard,
     known_endpoints: Vec<SocketAddr>,
 }
 
@@ -121,6 +125,7 @@ impl<T: AsRef<MappingContext>> SimpleUdpHolePunchServer<T> {
             _mapping_context: mapping_context,
             stop_flag: stop_flag,
             _raii_joiner: raii_joiner,
+            _mapping_guard: mapped_socket.mapping_guard,
             known_endpoints: unrestricted_endpoints,
         }, warnings)
     }
@@ -131,6 +136,14 @@ impl<T: AsRef<MappingContext>> SimpleUdpHolePunchServer<T> {
 
         while !stop_flag.load(Ordering::SeqCst) {
             if let Ok((bytes_read, peer_addr)) = udp_socket.recv_from(&mut read_buf) {
+                // Answer STUN Binding requests on the same socket so that off-the-shelf STUN
+                // clients can use this server too.
+                if let Some(transaction_id) = stun::decode_binding_request(&read_buf[..bytes_read]) {
+                    let resp = stun::binding_response(&transaction_id, &peer_addr);
+                    let _ = udp_socket.send_to(&resp[..], peer_addr);
+                    continue;
+                }
+
                 if read_buf[..bytes_read] != listener_message::REQUEST_MAGIC_CONSTANT {
                     continue;
                 }
@@ -15,12 +15,17 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use std::cmp;
 use std::io;
-use std::net::{TcpStream, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{TcpListener, TcpStream, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::net;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 #[cfg(target_family = "windows")]
 use std::mem;
+#[cfg(target_family = "windows")]
+use std::thread;
+#[cfg(target_family = "unix")]
+use libc;
 use socket_addr::SocketAddr;
 use std::io::ErrorKind;
 use net2;
@@ -134,6 +139,139 @@ pub fn is_loopback(addr: &IpAddr) -> bool {
     }
 }
 
+/// Whether an IPv6 address can be reached from outside the local network. ie. It's not
+/// unspecified, loopback, link-local, unique-local or multicast.
+pub fn ipv6_is_global(addr: &Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+    !ipv6_is_unspecified(addr) &&
+    !ipv6_is_loopback(addr) &&
+    (first & 0xffc0) != 0xfe80 &&
+    (first & 0xfe00) != 0xfc00 &&
+    (first & 0xff00) != 0xff00
+}
+
+/// Read the IPv4 default gateways out of the kernel's routing table.
+#[cfg(target_os = "linux")]
+pub fn default_gateways_v4() -> Vec<Ipv4Addr> {
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+
+    const RTF_GATEWAY: u32 = 0x2;
+
+    let file = match File::open("/proc/net/route") {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let mut gateways = Vec::new();
+    // Skip the header line. Each line after that is a route with the destination, gateway and
+    // flags in the second, third and fourth columns.
+    for line in BufReader::new(file).lines().skip(1) {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[1] != "00000000" {
+            continue;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16);
+        let flags = u32::from_str_radix(fields[3], 16);
+        if let (Ok(gateway), Ok(flags)) = (gateway, flags) {
+            if flags & RTF_GATEWAY != 0 {
+                // The kernel prints the address as a number in host byte order.
+                gateways.push(Ipv4Addr::from(gateway.to_be()));
+            }
+        }
+    }
+    gateways
+}
+
+// TODO: Read the routing table on other platforms.
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateways_v4() -> Vec<Ipv4Addr> {
+    Vec::new()
+}
+
+/// Find the IPv6 default gateway for the network interface named `if_name`. Gateways are usually
+/// link-local so this also returns the interface's index for use as a scope id.
+#[cfg(target_os = "linux")]
+pub fn default_gateway_v6(if_name: &str) -> Option<(Ipv6Addr, u32)> {
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read};
+
+    let file = match File::open("/proc/net/ipv6_route") {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    // Each line is a route with the destination, destination prefix length, next hop and device
+    // name in the first, second, fifth and tenth columns.
+    let mut gateway = None;
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || fields[9] != if_name || fields[1] != "00" ||
+           fields[0].chars().any(|c| c != '0') || fields[4].len() != 32 {
+            continue;
+        }
+        let mut segments = [0u16; 8];
+        let mut valid = true;
+        for (i, segment) in segments.iter_mut().enumerate() {
+            match u16::from_str_radix(&fields[4][4 * i..4 * i + 4], 16) {
+                Ok(s) => *segment = s,
+                Err(_) => valid = false,
+            }
+        }
+        let next_hop = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                     segments[4], segments[5], segments[6], segments[7]);
+        if valid && !ipv6_is_unspecified(&next_hop) {
+            gateway = Some(next_hop);
+            break;
+        }
+    }
+    let gateway = match gateway {
+        Some(gateway) => gateway,
+        None => return None,
+    };
+
+    let mut if_index = String::new();
+    let path = format!("/sys/class/net/{}/ifindex", if_name);
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut if_index)) {
+        Ok(_) => (),
+        Err(_) => return None,
+    };
+    match if_index.trim().parse() {
+        Ok(if_index) => Some((gateway, if_index)),
+        Err(_) => None,
+    }
+}
+
+// TODO: Read the routing table on other platforms.
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway_v6(_if_name: &str) -> Option<(Ipv6Addr, u32)> {
+    None
+}
+
+/// Guess the address of the gateway for the interface with address `addr`. We use a default
+/// gateway on the interface's subnet if there is one. Otherwise we guess the first address of the
+/// subnet, which is where most home routers live.
+pub fn guess_gateway_v4(addr: Ipv4Addr, netmask: Ipv4Addr) -> Option<Ipv4Addr> {
+    let addr_u32 = u32::from(addr);
+    let netmask_u32 = u32::from(netmask);
+    let network = addr_u32 & netmask_u32;
+    for gateway in default_gateways_v4() {
+        if u32::from(gateway) & netmask_u32 == network {
+            return Some(gateway);
+        }
+    }
+    if netmask_u32 == 0xffffffff || network + 1 == addr_u32 {
+        return None;
+    }
+    Some(Ipv4Addr::from(network + 1))
+}
+
 #[cfg(target_family = "unix")]
 pub fn enable_so_reuseport(sock: &net2::TcpBuilder) -> io::Result<()> {
     use net2::unix::UnixTcpBuilderExt;
@@ -170,3 +308,86 @@ pub fn tcp_builder_local_addr(sock: &net2::TcpBuilder) -> io::Result<net::Socket
     ret
 }
 
+/// A socket for `wait_until_ready` to watch, along with what we're waiting for it to do.
+pub enum PollSocket<'a> {
+    /// Wait for the listener to have a connection ready to accept.
+    Accept(&'a TcpListener),
+    /// Wait for the stream to have data ready to read.
+    Read(&'a TcpStream),
+    /// Wait for the stream to finish connecting or have room to write.
+    Write(&'a TcpStream),
+    /// Wait for the udp socket to have a datagram ready to read.
+    ReadUdp(&'a UdpSocket),
+}
+
+/// Block until one of `sockets` is ready or `timeout` elapses. Readiness is only a hint, the
+/// caller should still expect its non-blocking operations to return `WouldBlock`.
+#[cfg(target_family = "unix")]
+#[allow(unsafe_code)]
+pub fn wait_until_ready(sockets: &[PollSocket], timeout: Duration) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fds: Vec<libc::pollfd> = sockets.iter().map(|socket| {
+        let (fd, events) = match *socket {
+            PollSocket::Accept(listener) => (listener.as_raw_fd(), libc::POLLIN),
+            PollSocket::Read(stream) => (stream.as_raw_fd(), libc::POLLIN),
+            PollSocket::Write(stream) => (stream.as_raw_fd(), libc::POLLOUT),
+            PollSocket::ReadUdp(socket) => (socket.as_raw_fd(), libc::POLLIN),
+        };
+        libc::pollfd {
+            fd: fd,
+            events: events,
+            revents: 0,
+        }
+    }).collect();
+    // Round up so that we don't spin when there's less than a millisecond left.
+    let timeout_ms = timeout.as_secs()
+                            .saturating_mul(1000)
+                            .saturating_add((timeout.subsec_nanos() as u64 + 999_999) / 1_000_000);
+    let timeout_ms = cmp::min(timeout_ms, libc::c_int::max_value() as u64) as libc::c_int;
+    let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+    if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Block until one of `sockets` is ready or `timeout` elapses. Readiness is only a hint, the
+/// caller should still expect its non-blocking operations to return `WouldBlock`.
+#[cfg(target_family = "windows")]
+pub fn wait_until_ready(_sockets: &[PollSocket], timeout: Duration) -> io::Result<()> {
+    // We don't have a binding to WSAPoll, so just sleep for a bit and let the caller retry all of
+    // its sockets.
+    thread::sleep(cmp::min(timeout, Duration::from_millis(10)));
+    Ok(())
+}
+
+/// Check whether the error returned by a non-blocking `connect` just means that the connection
+/// is still being established.
+#[cfg(target_family = "unix")]
+pub fn connect_in_progress(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EINPROGRESS) || err.kind() == ErrorKind::WouldBlock
+}
+
+/// Check whether the error returned by a non-blocking `connect` just means that the connection
+/// is still being established.
+#[cfg(target_family = "windows")]
+pub fn connect_in_progress(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock
+}
+
+/// Check whether a non-blocking `connect` on `stream` has finished. Returns an error if the
+/// connection attempt failed.
+pub fn finished_connecting(stream: &TcpStream) -> io::Result<bool> {
+    if let Some(e) = try!(stream.take_error()) {
+        return Err(e);
+    }
+    match stream.peer_addr() {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Cleaning up IGD port mappings left behind by processes which died before they could remove
+//! them.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{self, Ipv4Addr, TcpStream, UdpSocket};
+use std::str;
+use std::time::Duration;
+
+use igd::{self, PortMappingProtocol};
+use net2;
+use w_result::{WResult, WOk, WErr};
+
+use mapping_context::{self, MappingContext};
+
+const SERVICE_TYPE: &'static str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const HTTP_TIMEOUT_SECS: u64 = 2;
+/// Stop listing a gateway's mappings after this many, in case it never tells us we've reached
+/// the end.
+const MAX_ENTRIES: u32 = 1024;
+/// UPnP error codes which gateways use to say that we've asked for an index past the end of the
+/// mapping table. 713 (SpecifiedArrayIndexInvalid) is the correct one but some gateways use 714
+/// (NoSuchEntryInArray) or 402 (Invalid Args).
+const END_OF_TABLE_ERROR_CODES: [u16; 3] = [713, 714, 402];
+
+quick_error! {
+    /// Errors raised when listing the port mappings on an IGD gateway.
+    #[derive(Debug)]
+    pub enum GetPortMappingEntryError {
+        /// IO error talking to the gateway.
+        Io { err: io::Error } {
+            description("IO error talking to IGD gateway")
+            display("IO error talking to IGD gateway: {}", err)
+            cause(err)
+        }
+        /// The gateway sent a response we couldn't parse.
+        InvalidResponse {
+            description("IGD gateway sent an invalid response")
+        }
+        /// The gateway responded with a UPnP error.
+        UpnpError { code: u16 } {
+            description("IGD gateway returned an error")
+            display("IGD gateway returned UPnP error code {}", code)
+        }
+    }
+}
+
+quick_error! {
+    /// Errors returned by `MappingContext::remove_stale_port_mappings`.
+    #[derive(Debug)]
+    pub enum RemoveStalePortMappingsError {
+        /// The context doesn't know of any IGD gateways.
+        NoGateways {
+            description("No IGD gateways are known to the mapping context")
+        }
+    }
+}
+
+impl From<RemoveStalePortMappingsError> for io::Error {
+    fn from(e: RemoveStalePortMappingsError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            RemoveStalePortMappingsError::NoGateways => io::ErrorKind::NotFound,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+quick_error! {
+    /// Warnings raised by `MappingContext::remove_stale_port_mappings`.
+    #[derive(Debug)]
+    pub enum RemoveStalePortMappingsWarning {
+        /// Error listing the port mappings on the gateway at `gateway_addr`.
+        ListMappings {
+            gateway_addr: net::SocketAddrV4,
+            err: GetPortMappingEntryError,
+        } {
+            description("Error listing the port mappings on an IGD gateway")
+            display("Error listing the port mappings on the IGD gateway at {}: {}",
+                    gateway_addr, err)
+            cause(err)
+        }
+        /// Error removing a stale port mapping from the gateway at `gateway_addr`.
+        RemoveMapping {
+            gateway_addr: net::SocketAddrV4,
+            external_port: u16,
+            err: igd::RemovePortError,
+        } {
+            description("Error removing a stale port mapping from an IGD gateway")
+            display("Error removing the stale port mapping for external port {} from the IGD \
+                     gateway at {}: {}", external_port, gateway_addr, err)
+            cause(err)
+        }
+    }
+}
+
+/// A port mapping removed by `MappingContext::remove_stale_port_mappings`.
+#[derive(Debug, Clone)]
+pub struct RemovedPortMapping {
+    /// The gateway the mapping was removed from.
+    pub gateway_addr: net::SocketAddrV4,
+    /// The protocol of the mapping.
+    pub protocol: PortMappingProtocol,
+    /// The external port of the mapping.
+    pub external_port: u16,
+    /// The local address that the mapping forwarded to.
+    pub internal_addr: net::SocketAddrV4,
+}
+
+/// An entry in a gateway's port mapping table.
+#[derive(Debug, Clone)]
+pub struct PortMappingEntry {
+    pub protocol: PortMappingProtocol,
+    pub external_port: u16,
+    pub internal_addr: net::SocketAddrV4,
+    pub description: String,
+}
+
+pub fn remove_stale_port_mappings(mc: &MappingContext)
+        -> WResult<Vec<RemovedPortMapping>,
+                   RemoveStalePortMappingsWarning,
+                   RemoveStalePortMappingsError>
+{
+    let mut warnings = Vec::new();
+    let mut removed = Vec::new();
+
+    let interfaces = mapping_context::interfaces_v4(mc);
+    let local_ips: Vec<Ipv4Addr> = interfaces.iter().map(|iface_v4| iface_v4.addr).collect();
+    let mut gateways: Vec<igd::Gateway> = Vec::new();
+    for iface_v4 in interfaces {
+        if let Some(gateway) = iface_v4.gateway {
+            if gateways.iter().all(|g| g.addr != gateway.addr) {
+                gateways.push(gateway);
+            }
+        }
+    }
+    if gateways.is_empty() {
+        return WErr(RemoveStalePortMappingsError::NoGateways);
+    }
+
+    let description = mapping_context::port_mapping_description(mc);
+    for gateway in gateways {
+        // Removing entries shuffles the indices of the ones after them so list everything first.
+        let mut entries = Vec::new();
+        for index in 0..MAX_ENTRIES {
+            match get_generic_port_mapping_entry(&gateway, index) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => break,
+                Err(e) => {
+                    warnings.push(RemoveStalePortMappingsWarning::ListMappings {
+                        gateway_addr: gateway.addr,
+                        err: e,
+                    });
+                    break;
+                },
+            }
+        }
+
+        for entry in entries {
+            if entry.description != description ||
+               !local_ips.contains(entry.internal_addr.ip()) ||
+               port_in_use(entry.protocol, entry.internal_addr) {
+                continue;
+            }
+            match gateway.remove_port(entry.protocol, entry.external_port) {
+                Ok(()) => {
+                    removed.push(RemovedPortMapping {
+                        gateway_addr: gateway.addr,
+                        protocol: entry.protocol,
+                        external_port: entry.external_port,
+                        internal_addr: entry.internal_addr,
+                    });
+                },
+                Err(e) => {
+                    warnings.push(RemoveStalePortMappingsWarning::RemoveMapping {
+                        gateway_addr: gateway.addr,
+                        external_port: entry.external_port,
+                        err: e,
+                    });
+                },
+            }
+        }
+    }
+
+    WOk(removed, warnings)
+}
+
+/// Whether some socket on this machine is bound to `addr`. We find out by trying to bind to it
+/// ourselves. Our own sockets set `SO_REUSEADDR` so we mustn't set it here, otherwise the bind
+/// could succeed on a port that's in use.
+fn port_in_use(protocol: PortMappingProtocol, addr: net::SocketAddrV4) -> bool {
+    let addr = net::SocketAddr::V4(addr);
+    match protocol {
+        PortMappingProtocol::UDP => UdpSocket::bind(&addr).is_err(),
+        PortMappingProtocol::TCP => {
+            net2::TcpBuilder::new_v4().and_then(|builder| builder.bind(&addr).map(|_| ())).is_err()
+        },
+    }
+}
+
+/// Ask the gateway for the entry at `index` in its port mapping table. Returns `None` once we're
+/// past the end of the table.
+pub fn get_generic_port_mapping_entry(gateway: &igd::Gateway, index: u32)
+        -> Result<Option<PortMappingEntry>, GetPortMappingEntryError>
+{
+    let body = format!("<?xml version=\"1.0\"?>\
+                        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+                        <s:Body>\
+                        <u:GetGenericPortMappingEntry xmlns:u=\"{}\">\
+                        <NewPortMappingIndex>{}</NewPortMappingIndex>\
+                        </u:GetGenericPortMappingEntry>\
+                        </s:Body>\
+                        </s:Envelope>", SERVICE_TYPE, index);
+    let response = match soap_request(gateway, "GetGenericPortMappingEntry", &body) {
+        Ok(response) => response,
+        Err(e) => return Err(GetPortMappingEntryError::Io { err: e }),
+    };
+    decode_entry_response(&response)
+}
+
+fn decode_entry_response(response: &str)
+        -> Result<Option<PortMappingEntry>, GetPortMappingEntryError>
+{
+    if let Some(code) = xml_element(response, "errorCode") {
+        let code = match code.trim().parse::<u16>() {
+            Ok(code) => code,
+            Err(_) => return Err(GetPortMappingEntryError::InvalidResponse),
+        };
+        if END_OF_TABLE_ERROR_CODES.contains(&code) {
+            return Ok(None);
+        }
+        return Err(GetPortMappingEntryError::UpnpError { code: code });
+    }
+
+    let protocol = match xml_element(response, "NewProtocol").map(|p| p.trim()) {
+        Some("UDP") => PortMappingProtocol::UDP,
+        Some("TCP") => PortMappingProtocol::TCP,
+        _ => return Err(GetPortMappingEntryError::InvalidResponse),
+    };
+    let external_port = xml_element(response, "NewExternalPort").and_then(|p| p.trim().parse().ok());
+    let internal_port = xml_element(response, "NewInternalPort").and_then(|p| p.trim().parse().ok());
+    let internal_ip = xml_element(response, "NewInternalClient").and_then(|ip| ip.trim().parse().ok());
+    let description = xml_element(response, "NewPortMappingDescription").unwrap_or("");
+    match (external_port, internal_port, internal_ip) {
+        (Some(external_port), Some(internal_port), Some(internal_ip)) => {
+            Ok(Some(PortMappingEntry {
+                protocol: protocol,
+                external_port: external_port,
+                internal_addr: net::SocketAddrV4::new(internal_ip, internal_port),
+                description: xml_unescape(description),
+            }))
+        },
+        _ => Err(GetPortMappingEntryError::InvalidResponse),
+    }
+}
+
+/// POST a SOAP request to the gateway's control URL and return the body of the response. UPnP
+/// errors come back as a SOAP fault with an HTTP error status so we don't check the status.
+fn soap_request(gateway: &igd::Gateway, action: &str, body: &str) -> io::Result<String> {
+    let mut stream = try!(TcpStream::connect(&net::SocketAddr::V4(gateway.addr)));
+    try!(stream.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS))));
+    // HTTP/1.0 so that the gateway doesn't use chunked encoding and closes the connection when
+    // it's done.
+    let request = format!("POST {} HTTP/1.0\r\n\
+                           Host: {}\r\n\
+                           Content-Type: text/xml; charset=\"utf-8\"\r\n\
+                           SOAPAction: \"{}#{}\"\r\n\
+                           Content-Length: {}\r\n\
+                           \r\n\
+                           {}", gateway.control_url, gateway.addr, SERVICE_TYPE, action,
+                          body.len(), body);
+    try!(stream.write_all(request.as_bytes()));
+    let mut response = Vec::new();
+    try!(stream.read_to_end(&mut response));
+    let response = match str::from_utf8(&response) {
+        Ok(response) => response,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                            "IGD gateway sent a non-UTF-8 response")),
+    };
+    match response.find("\r\n\r\n") {
+        Some(i) => Ok(response[i + 4..].to_owned()),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   "IGD gateway sent a malformed HTTP response")),
+    }
+}
+
+/// Find the text content of the first element named `name`, ignoring any namespace prefix.
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(i) = rest.find('<') {
+        rest = &rest[i + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => return None,
+        };
+        let tag = &rest[..end];
+        let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        let local_name = tag_name.rsplit(':').next().unwrap_or("");
+        if local_name != name || tag.starts_with('/') {
+            continue;
+        }
+        if tag.ends_with('/') {
+            return Some("");
+        }
+        let content = &rest[end + 1..];
+        return match content.find("</") {
+            Some(close) => Some(&content[..close]),
+            None => None,
+        };
+    }
+    None
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+     .replace("&gt;", ">")
+     .replace("&quot;", "\"")
+     .replace("&apos;", "'")
+     .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_entry_response;
+
+    use std::net;
+
+    use igd::PortMappingProtocol;
+
+    #[test]
+    fn decode_entry() {
+        let response = "<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+            <s:Body>\
+            <u:GetGenericPortMappingEntryResponse \
+             xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+            <NewRemoteHost></NewRemoteHost>\
+            <NewExternalPort>40123</NewExternalPort>\
+            <NewProtocol>UDP</NewProtocol>\
+            <NewInternalPort>5483</NewInternalPort>\
+            <NewInternalClient>192.168.1.10</NewInternalClient>\
+            <NewEnabled>1</NewEnabled>\
+            <NewPortMappingDescription>rust nat_traversal</NewPortMappingDescription>\
+            <NewLeaseDuration>0</NewLeaseDuration>\
+            </u:GetGenericPortMappingEntryResponse>\
+            </s:Body>\
+            </s:Envelope>";
+        let entry = unwrap_option!(unwrap_result!(decode_entry_response(response)), "");
+        assert_eq!(entry.protocol, PortMappingProtocol::UDP);
+        assert_eq!(entry.external_port, 40123);
+        assert_eq!(entry.internal_addr,
+                   net::SocketAddrV4::new(net::Ipv4Addr::new(192, 168, 1, 10), 5483));
+        assert_eq!(entry.description, "rust nat_traversal");
+    }
+
+    #[test]
+    fn decode_end_of_table() {
+        let response = "<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+            <s:Body>\
+            <s:Fault>\
+            <faultcode>s:Client</faultcode>\
+            <faultstring>UPnPError</faultstring>\
+            <detail>\
+            <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+            <errorCode>713</errorCode>\
+            <errorDescription>SpecifiedArrayIndexInvalid</errorDescription>\
+            </UPnPError>\
+            </detail>\
+            </s:Fault>\
+            </s:Body>\
+            </s:Envelope>";
+        assert!(unwrap_result!(decode_entry_response(response)).is_none());
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Minimal encoding and decoding of STUN (RFC 5389) Binding messages.
+//!
+//! Only the parts of the protocol needed to learn a server-reflexive address are implemented. We
+//! don't do authentication (MESSAGE-INTEGRITY) and we ignore any attributes we don't understand.
+//! The `turn` module builds its messages on top of the helpers here.
+
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr};
+
+use byteorder::{BigEndian, ByteOrder};
+use rand;
+
+pub const MAGIC_COOKIE: u32 = 0x2112a442;
+pub const HEADER_LEN: usize = 20;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS_RESPONSE: u16 = 0x0101;
+pub const BINDING_ERROR_RESPONSE: u16 = 0x0111;
+
+pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+// NAT behaviour discovery attribute from RFC 5780.
+pub const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+
+const CHANGE_IP_FLAG: u8 = 0x04;
+const CHANGE_PORT_FLAG: u8 = 0x02;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// A STUN transaction ID. Used to match responses to the requests we sent.
+pub type TransactionId = [u8; 12];
+
+quick_error! {
+    /// Errors raised when decoding a STUN message.
+    #[derive(Debug)]
+    pub enum StunDecodeError {
+        /// The data is not a STUN message.
+        NotStun {
+            description("Data is not a STUN message")
+        }
+        /// The message is not a response to a Binding request.
+        UnexpectedMessageType { msg_type: u16 } {
+            description("STUN message is not a Binding response")
+            display("STUN message is not a Binding response. Message type: {:#06x}", msg_type)
+        }
+        /// The server replied with a Binding error response.
+        ErrorResponse { code: u16, reason: String } {
+            description("STUN server replied with an error response")
+            display("STUN server replied with an error response: {} {}", code, reason)
+        }
+        /// An attribute was truncated or otherwise malformed.
+        MalformedAttribute { attr_type: u16 } {
+            description("STUN message contained a malformed attribute")
+            display("STUN message contained a malformed attribute of type {:#06x}", attr_type)
+        }
+        /// The response did not contain a MAPPED-ADDRESS or XOR-MAPPED-ADDRESS.
+        NoMappedAddress {
+            description("STUN Binding response did not contain a mapped address")
+        }
+    }
+}
+
+/// A decoded STUN Binding success response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindingResponse {
+    /// The transaction ID of the request this is a response to.
+    pub transaction_id: TransactionId,
+    /// Our address as seen by the server.
+    pub mapped_addr: net::SocketAddr,
+    /// The server's alternate address (RFC 5780 OTHER-ADDRESS), if it has one.
+    pub other_addr: Option<net::SocketAddr>,
+}
+
+/// Generate a new random transaction ID.
+pub fn new_transaction_id() -> TransactionId {
+    rand::random()
+}
+
+/// Encode a Binding request with the given transaction ID.
+pub fn binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+    encode_header(BINDING_REQUEST, transaction_id)
+}
+
+/// Encode a Binding request carrying a CHANGE-REQUEST attribute (RFC 5780). This asks the server
+/// to send its response from its alternate address and/or port.
+pub fn binding_request_with_change(transaction_id: &TransactionId,
+                                   change_ip: bool,
+                                   change_port: bool)
+                                   -> Vec<u8> {
+    let mut buf = encode_header(BINDING_REQUEST, transaction_id);
+    let mut flags = 0u8;
+    if change_ip {
+        flags |= CHANGE_IP_FLAG;
+    }
+    if change_port {
+        flags |= CHANGE_PORT_FLAG;
+    }
+    push_attribute(&mut buf, ATTR_CHANGE_REQUEST, &[0, 0, 0, flags]);
+    buf
+}
+
+/// Encode a Binding success response telling the client that its address is `mapped_addr`.
+pub fn binding_response(transaction_id: &TransactionId, mapped_addr: &net::SocketAddr) -> Vec<u8> {
+    let mut buf = encode_header(BINDING_SUCCESS_RESPONSE, transaction_id);
+    let value = encode_xor_address(mapped_addr, transaction_id);
+    push_attribute(&mut buf, ATTR_XOR_MAPPED_ADDRESS, &value[..]);
+    buf
+}
+
+/// If `data` is a Binding request, return its transaction ID.
+pub fn decode_binding_request(data: &[u8]) -> Option<TransactionId> {
+    if !is_stun_message(data) || BigEndian::read_u16(&data[0..2]) != BINDING_REQUEST {
+        return None;
+    }
+    Some(transaction_id(data))
+}
+
+/// Returns true if `data` looks like a STUN message. This checks the fixed parts of the header so
+/// that STUN traffic can be told apart from other traffic arriving on the same socket.
+pub fn is_stun_message(data: &[u8]) -> bool {
+    if data.len() < HEADER_LEN {
+        return false;
+    }
+    if data[0] & 0xc0 != 0 {
+        return false;
+    }
+    let len = BigEndian::read_u16(&data[2..4]) as usize;
+    if len % 4 != 0 || len + HEADER_LEN != data.len() {
+        return false;
+    }
+    BigEndian::read_u32(&data[4..8]) == MAGIC_COOKIE
+}
+
+/// Decode a Binding success response. Prefers XOR-MAPPED-ADDRESS but will fall back to
+/// MAPPED-ADDRESS for the sake of old (RFC 3489) servers.
+pub fn decode_binding_response(data: &[u8]) -> Result<BindingResponse, StunDecodeError> {
+    if !is_stun_message(data) {
+        return Err(StunDecodeError::NotStun);
+    }
+    let msg_type = BigEndian::read_u16(&data[0..2]);
+    let transaction_id = transaction_id(data);
+
+    let mut xor_mapped = None;
+    let mut mapped = None;
+    let mut other = None;
+    let mut error = None;
+    try!(for_each_attribute(data, |attr_type, value| {
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => {
+                xor_mapped = Some(try!(decode_address(attr_type, value, Some(&transaction_id))));
+            },
+            ATTR_MAPPED_ADDRESS => {
+                mapped = Some(try!(decode_address(attr_type, value, None)));
+            },
+            ATTR_OTHER_ADDRESS => {
+                other = Some(try!(decode_address(attr_type, value, None)));
+            },
+            ATTR_ERROR_CODE => {
+                if value.len() < 4 {
+                    return Err(StunDecodeError::MalformedAttribute { attr_type: attr_type });
+                }
+                let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+                let reason = String::from_utf8_lossy(&value[4..]).into_owned();
+                error = Some((code, reason));
+            },
+            _ => (),
+        };
+        Ok(())
+    }));
+
+    match msg_type {
+        BINDING_SUCCESS_RESPONSE => (),
+        BINDING_ERROR_RESPONSE => {
+            let (code, reason) = error.unwrap_or((0, String::new()));
+            return Err(StunDecodeError::ErrorResponse { code: code, reason: reason });
+        },
+        _ => return Err(StunDecodeError::UnexpectedMessageType { msg_type: msg_type }),
+    };
+
+    match xor_mapped.or(mapped) {
+        Some(mapped_addr) => Ok(BindingResponse {
+            transaction_id: transaction_id,
+            mapped_addr: mapped_addr,
+            other_addr: other,
+        }),
+        None => Err(StunDecodeError::NoMappedAddress),
+    }
+}
+
+pub fn encode_header(msg_type: u16, transaction_id: &TransactionId) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER_LEN];
+    BigEndian::write_u16(&mut buf[0..2], msg_type);
+    BigEndian::write_u32(&mut buf[4..8], MAGIC_COOKIE);
+    buf[8..20].copy_from_slice(&transaction_id[..]);
+    buf
+}
+
+/// Append an attribute to an encoded message and update the length field in its header.
+pub fn push_attribute(buf: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    let mut attr_header = [0u8; 4];
+    BigEndian::write_u16(&mut attr_header[0..2], attr_type);
+    BigEndian::write_u16(&mut attr_header[2..4], value.len() as u16);
+    buf.extend_from_slice(&attr_header[..]);
+    buf.extend_from_slice(value);
+    let padding = (4 - value.len() % 4) % 4;
+    buf.extend_from_slice(&[0u8; 3][..padding]);
+    let len = (buf.len() - HEADER_LEN) as u16;
+    BigEndian::write_u16(&mut buf[2..4], len);
+}
+
+pub fn transaction_id(data: &[u8]) -> TransactionId {
+    let mut transaction_id = [0u8; 12];
+    transaction_id.copy_from_slice(&data[8..20]);
+    transaction_id
+}
+
+/// Call `f` with the type and value of every attribute in the (already validated) message.
+pub fn for_each_attribute<F>(data: &[u8], mut f: F) -> Result<(), StunDecodeError>
+    where F: FnMut(u16, &[u8]) -> Result<(), StunDecodeError>
+{
+    let mut pos = HEADER_LEN;
+    while pos + 4 <= data.len() {
+        let attr_type = BigEndian::read_u16(&data[pos..pos + 2]);
+        let attr_len = BigEndian::read_u16(&data[pos + 2..pos + 4]) as usize;
+        let start = pos + 4;
+        let end = start + attr_len;
+        if end > data.len() {
+            return Err(StunDecodeError::MalformedAttribute { attr_type: attr_type });
+        }
+        try!(f(attr_type, &data[start..end]));
+        // Attributes are padded to a multiple of four bytes.
+        pos = end + (4 - attr_len % 4) % 4;
+    }
+    Ok(())
+}
+
+fn xor_mask(transaction_id: &TransactionId) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    BigEndian::write_u32(&mut mask[0..4], MAGIC_COOKIE);
+    mask[4..16].copy_from_slice(&transaction_id[..]);
+    mask
+}
+
+pub fn encode_xor_address(addr: &net::SocketAddr, transaction_id: &TransactionId) -> Vec<u8> {
+    let mask = xor_mask(transaction_id);
+    let mut value = vec![0u8; 4];
+    BigEndian::write_u16(&mut value[2..4], addr.port() ^ (MAGIC_COOKIE >> 16) as u16);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value[1] = FAMILY_IPV4;
+            value.extend(ip.octets().iter().zip(mask.iter()).map(|(o, m)| o ^ m));
+        },
+        IpAddr::V6(ip) => {
+            value[1] = FAMILY_IPV6;
+            let mut octets = [0u8; 16];
+            for (i, s) in ip.segments().iter().enumerate() {
+                BigEndian::write_u16(&mut octets[i * 2..i * 2 + 2], *s);
+            }
+            value.extend(octets.iter().zip(mask.iter()).map(|(o, m)| o ^ m));
+        },
+    };
+    value
+}
+
+pub fn decode_address(attr_type: u16,
+                      value: &[u8],
+                      xor_with: Option<&TransactionId>)
+                      -> Result<net::SocketAddr, StunDecodeError> {
+    if value.len() < 4 {
+        return Err(StunDecodeError::MalformedAttribute { attr_type: attr_type });
+    }
+    let mut port = BigEndian::read_u16(&value[2..4]);
+    if xor_with.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    let ip = match value[1] {
+        FAMILY_IPV4 => {
+            if value.len() != 8 {
+                return Err(StunDecodeError::MalformedAttribute { attr_type: attr_type });
+            }
+            let mut octets = [value[4], value[5], value[6], value[7]];
+            if let Some(transaction_id) = xor_with {
+                for (o, m) in octets.iter_mut().zip(xor_mask(transaction_id).iter()) {
+                    *o ^= *m;
+                }
+            }
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        },
+        FAMILY_IPV6 => {
+            if value.len() != 20 {
+                return Err(StunDecodeError::MalformedAttribute { attr_type: attr_type });
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&value[4..20]);
+            if let Some(transaction_id) = xor_with {
+                for (o, m) in octets.iter_mut().zip(xor_mask(transaction_id).iter()) {
+                    *o ^= *m;
+                }
+            }
+            let mut segments = [0u16; 8];
+            for (i, s) in segments.iter_mut().enumerate() {
+                *s = BigEndian::read_u16(&octets[i * 2..i * 2 + 2]);
+            }
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                     segments[4], segments[5], segments[6], segments[7]))
+        },
+        _ => return Err(StunDecodeError::MalformedAttribute { attr_type: attr_type }),
+    };
+    Ok(net::SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net;
+    use std::str::FromStr;
+
+    // Sample IPv4 response from RFC 5769 section 2.2.
+    const RFC5769_IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    #[test]
+    fn decode_rfc5769_ipv4_response() {
+        let resp = unwrap_result!(decode_binding_response(&RFC5769_IPV4_RESPONSE[..]));
+        assert_eq!(resp.transaction_id, [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa,
+                                         0x87, 0xdf, 0xae]);
+        assert_eq!(resp.mapped_addr, unwrap_result!(net::SocketAddr::from_str("192.0.2.1:32853")));
+    }
+
+    #[test]
+    fn binding_request_is_recognised() {
+        let transaction_id = new_transaction_id();
+        let req = binding_request(&transaction_id);
+        assert!(is_stun_message(&req[..]));
+        assert!(!is_stun_message(b"ECHO"));
+        assert_eq!(decode_binding_request(&req[..]), Some(transaction_id));
+        match decode_binding_response(&req[..]) {
+            Err(StunDecodeError::UnexpectedMessageType { msg_type: BINDING_REQUEST }) => (),
+            res => panic!("Unexpected result decoding a request: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn binding_response_round_trip() {
+        for addr_str in &["203.0.113.7:45000", "[2001:db8::1234]:3478"] {
+            let addr = unwrap_result!(net::SocketAddr::from_str(addr_str));
+            let transaction_id = new_transaction_id();
+            let resp_data = binding_response(&transaction_id, &addr);
+            assert_eq!(decode_binding_request(&resp_data[..]), None);
+            let resp = unwrap_result!(decode_binding_response(&resp_data[..]));
+            assert_eq!(resp.transaction_id, transaction_id);
+            assert_eq!(resp.mapped_addr, addr);
+            assert_eq!(resp.other_addr, None);
+        }
+    }
+
+    #[test]
+    fn change_request_is_well_formed() {
+        let transaction_id = new_transaction_id();
+        let req = binding_request_with_change(&transaction_id, true, false);
+        assert!(is_stun_message(&req[..]));
+        assert_eq!(decode_binding_request(&req[..]), Some(transaction_id));
+        assert_eq!(&req[HEADER_LEN..], &[0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04][..]);
+    }
+}
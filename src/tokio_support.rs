@@ -0,0 +1,986 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Futures-based versions of the socket mapping and hole punching functions, for use with tokio.
+//!
+//! Hole punching is driven by the caller's event loop: the sockets get registered with it and the
+//! futures make progress whenever one of them becomes ready. Mapping makes blocking requests to
+//! the local network's gateways so it still runs on a separate thread. Dropping one of the mapping
+//! futures before it completes tells the thread to give up.
+
+use std::{cmp, io, mem, net};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use net2;
+use rand::random;
+use socket_addr::SocketAddr;
+use tokio_core::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_core::reactor::{Handle, Timeout};
+use w_result::{WResult, WOk, WErr};
+
+use cancellation::CancellationToken;
+use check_list::CandidatePair;
+use mapping_context::MappingContext;
+use mapped_udp_socket;
+use mapped_udp_socket::{MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError,
+                        MappedUdpSocketNewError};
+use mapped_tcp_socket;
+use mapped_tcp_socket::{ConnectTarget, MappedTcpSocket, MappedTcpSocketMapWarning,
+                        MappedTcpSocketMapError, MappedTcpSocketNewError, PunchStream,
+                        TcpPunchHoleBrokenStream, TcpPunchHoleWarning, TcpPunchHoleError,
+                        TcpPunchSockets};
+use port_mapping::PortMappingGuard;
+use punched_udp_socket;
+use punched_udp_socket::{PunchedUdpSocket, UdpPunch, UdpPunchHoleWarning, UdpPunchHoleError};
+use rendezvous_info::{PrivRendezvousInfo, PubRendezvousInfo};
+use secret::{self, Secret};
+
+/// Errors that a `Worker` can fail with if its thread panics.
+pub trait WorkerError {
+    fn worker_panicked() -> Self;
+}
+
+impl WorkerError for MappedUdpSocketMapError {
+    fn worker_panicked() -> MappedUdpSocketMapError {
+        MappedUdpSocketMapError::WorkerPanicked
+    }
+}
+
+impl WorkerError for MappedUdpSocketNewError {
+    fn worker_panicked() -> MappedUdpSocketNewError {
+        MappedUdpSocketNewError::MapSocket { err: MappedUdpSocketMapError::WorkerPanicked }
+    }
+}
+
+impl WorkerError for MappedTcpSocketMapError {
+    fn worker_panicked() -> MappedTcpSocketMapError {
+        MappedTcpSocketMapError::WorkerPanicked
+    }
+}
+
+impl WorkerError for MappedTcpSocketNewError {
+    fn worker_panicked() -> MappedTcpSocketNewError {
+        MappedTcpSocketNewError::Map { err: MappedTcpSocketMapError::WorkerPanicked }
+    }
+}
+
+/// A blocking operation running on its own thread. Dropping this cancels the operation.
+struct Worker<T, W, E> {
+    result_rx: oneshot::Receiver<WResult<T, W, E>>,
+    cancel: CancellationToken,
+}
+
+impl<T, W, E> Worker<T, W, E>
+        where T: Send + 'static,
+              W: Send + 'static,
+              E: WorkerError + Send + 'static
+{
+    fn spawn<F>(name: &'static str, f: F) -> Worker<T, W, E>
+            where F: FnOnce(&CancellationToken) -> WResult<T, W, E> + Send + 'static
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let cancel = CancellationToken::new();
+        let cancel_clone = cancel.clone();
+        // We don't join this thread. If the future gets dropped the thread will notice that it's
+        // been cancelled and exit shortly afterwards.
+        let _ = thread!(name, move || {
+            let _ = result_tx.send(f(&cancel_clone));
+        });
+        Worker {
+            result_rx: result_rx,
+            cancel: cancel,
+        }
+    }
+
+    fn poll(&mut self) -> Poll<(T, Vec<W>), E> {
+        match self.result_rx.poll() {
+            Ok(Async::Ready(WOk(t, warnings))) => Ok(Async::Ready((t, warnings))),
+            Ok(Async::Ready(WErr(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // The worker only drops its end of the channel without sending if it panicked.
+            Err(oneshot::Canceled) => Err(E::worker_panicked()),
+        }
+    }
+}
+
+impl<T, W, E> Drop for Worker<T, W, E> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Future returned by `MappedUdpSocket::map_async`, `MappedUdpSocket::new_async`,
+/// `MappedTcpSocket::map_async` and `MappedTcpSocket::new_async`. Resolves to the mapped socket
+/// along with any warnings raised while mapping it.
+pub struct MapSocketFuture<S, W, E> {
+    worker: Worker<S, W, E>,
+}
+
+impl<S, W, E> Future for MapSocketFuture<S, W, E>
+        where S: Send + 'static,
+              W: Send + 'static,
+              E: WorkerError + Send + 'static
+{
+    type Item = (S, Vec<W>);
+    type Error = E;
+
+    fn poll(&mut self) -> Poll<(S, Vec<W>), E> {
+        self.worker.poll()
+    }
+}
+
+/// A hole punched udp socket registered with a tokio event loop.
+pub struct TokioPunchedUdpSocket {
+    /// The UDP socket.
+    pub socket: UdpSocket,
+    /// The remote address that this socket is able to send messages to and receive messages from.
+    pub peer_addr: SocketAddr,
+    /// The pair of endpoints that hole punching succeeded with.
+    pub candidate_pair: CandidatePair,
+    /// Owns the port mappings made for the socket when it was punched with
+    /// `punch_hole_mapped_async`. The mappings are removed when this is dropped so keep it alive
+    /// for as long as the socket is in use. Sockets punched any other way get a guard with no
+    /// mappings.
+    pub mapping_guard: PortMappingGuard,
+}
+
+/// Future returned by `PunchedUdpSocket::punch_hole_async` and
+/// `PunchedUdpSocket::punch_hole_mapped_async`.
+pub struct UdpPunchHoleFuture {
+    state: UdpPunchHoleState,
+    deadline: Instant,
+    /// Handed on to the punched socket.
+    mapping_guard: PortMappingGuard,
+}
+
+enum UdpPunchHoleState {
+    /// We couldn't get started. The error gets returned when we're first polled.
+    Failed(UdpPunchHoleError),
+    Punching {
+        socket: UdpSocket,
+        punch: UdpPunch,
+        timeout: Timeout,
+    },
+    /// We've nominated a pair. Ack the peer's hole punch message a couple of times, as
+    /// `punch_hole` does, before returning.
+    Acking {
+        punched: TokioPunchedUdpSocket,
+        warnings: Vec<UdpPunchHoleWarning>,
+        ack: Vec<u8>,
+        attempts: u32,
+        sent: u32,
+        timeout: Timeout,
+    },
+    Done,
+}
+
+impl UdpPunchHoleFuture {
+    fn start(socket: net::UdpSocket,
+             our_priv_rendezvous_info: PrivRendezvousInfo,
+             their_pub_rendezvous_info: PubRendezvousInfo,
+             handle: &Handle,
+             deadline: Instant)
+             -> Result<UdpPunchHoleState, UdpPunchHoleError> {
+        let socket_addr = try!(socket.local_addr().map_err(|e| UdpPunchHoleError::Io { err: e }));
+        let socket = try!(UdpSocket::from_socket(socket, handle)
+                                    .map_err(|e| UdpPunchHoleError::Io { err: e }));
+        let timeout = try!(Timeout::new_at(deadline, handle)
+                                   .map_err(|e| UdpPunchHoleError::Io { err: e }));
+        // A fresh nonce for every attempt means acks from an earlier attempt can't be replayed.
+        let punch = UdpPunch::new(SocketAddr(socket_addr),
+                                  our_priv_rendezvous_info,
+                                  their_pub_rendezvous_info,
+                                  &secret::gen_nonce());
+        Ok(UdpPunchHoleState::Punching {
+            socket: socket,
+            punch: punch,
+            timeout: timeout,
+        })
+    }
+}
+
+/// Send checks and handle the peer's messages until `punch` has a pair to nominate.
+fn poll_udp_punch(socket: &UdpSocket,
+                  punch: &mut UdpPunch,
+                  timeout: &mut Timeout,
+                  deadline: Instant)
+                  -> Poll<usize, UdpPunchHoleError> {
+    let mut recv_data = [0u8; punched_udp_socket::MAX_DATAGRAM_SIZE];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(UdpPunchHoleError::TimedOut);
+        }
+        if let Some(i) = punch.nominate(now) {
+            return Ok(Async::Ready(i));
+        }
+
+        if let Some((i, addr)) = punch.next_check(now) {
+            match socket.send_to(punch.send_data(), &*addr) {
+                Ok(_) => (),
+                // Checks get retransmitted anyway.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => punch.check_failed(i, e),
+            };
+        }
+
+        let mut received = false;
+        loop {
+            let (read_size, addr) = match socket.recv_from(&mut recv_data[..]) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // See the comment in `RecvUntil` about ICMP port unreachable on Windows.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(UdpPunchHoleError::Io { err: e }),
+            };
+            received = true;
+            let addr = SocketAddr(addr);
+            let now = Instant::now();
+            if let Some(ack) = punch.packet_received(&recv_data[..read_size], addr, now) {
+                let _ = socket.send_to(&ack[..], &*addr);
+            }
+        }
+        if received {
+            continue;
+        }
+
+        // The socket wakes us up when the next message arrives. Otherwise wait until it's time to
+        // send the next check.
+        let wake_at = match punch.next_wakeup() {
+            Some(wakeup) => cmp::min(wakeup, deadline),
+            None => deadline,
+        };
+        timeout.reset(wake_at);
+        match timeout.poll() {
+            Ok(Async::Ready(())) => (),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => return Err(UdpPunchHoleError::Io { err: e }),
+        };
+    }
+}
+
+impl Future for UdpPunchHoleFuture {
+    type Item = (TokioPunchedUdpSocket, Vec<UdpPunchHoleWarning>);
+    type Error = UdpPunchHoleError;
+
+    fn poll(&mut self) -> Poll<(TokioPunchedUdpSocket, Vec<UdpPunchHoleWarning>), UdpPunchHoleError> {
+        loop {
+            match mem::replace(&mut self.state, UdpPunchHoleState::Done) {
+                UdpPunchHoleState::Failed(e) => return Err(e),
+                UdpPunchHoleState::Punching { socket, mut punch, mut timeout } => {
+                    let i = match try!(poll_udp_punch(&socket, &mut punch, &mut timeout,
+                                                      self.deadline)) {
+                        Async::Ready(i) => i,
+                        Async::NotReady => {
+                            self.state = UdpPunchHoleState::Punching {
+                                socket: socket,
+                                punch: punch,
+                                timeout: timeout,
+                            };
+                            return Ok(Async::NotReady);
+                        },
+                    };
+                    let (candidate_pair, ack, warnings) = punch.into_punched(i);
+                    let punched = TokioPunchedUdpSocket {
+                        socket: socket,
+                        peer_addr: candidate_pair.remote.addr,
+                        candidate_pair: candidate_pair,
+                        mapping_guard: mem::replace(&mut self.mapping_guard,
+                                                    PortMappingGuard::new(Vec::new())),
+                    };
+                    let ack = match ack {
+                        Some(ack) => ack,
+                        None => return Ok(Async::Ready((punched, warnings))),
+                    };
+                    timeout.reset(Instant::now());
+                    self.state = UdpPunchHoleState::Acking {
+                        punched: punched,
+                        warnings: warnings,
+                        ack: ack,
+                        attempts: 0,
+                        sent: 0,
+                        timeout: timeout,
+                    };
+                },
+                UdpPunchHoleState::Acking { punched, warnings, ack, mut attempts, mut sent,
+                                            mut timeout } => {
+                    match timeout.poll() {
+                        Ok(Async::Ready(())) => (),
+                        Ok(Async::NotReady) => {
+                            self.state = UdpPunchHoleState::Acking {
+                                punched: punched,
+                                warnings: warnings,
+                                ack: ack,
+                                attempts: attempts,
+                                sent: sent,
+                                timeout: timeout,
+                            };
+                            return Ok(Async::NotReady);
+                        },
+                        Err(e) => return Err(UdpPunchHoleError::Io { err: e }),
+                    };
+                    attempts += 1;
+                    match punched.socket.send_to(&ack[..], &*punched.peer_addr) {
+                        Ok(n) if n == ack.len() => sent += 1,
+                        Ok(_) => (),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => return Err(UdpPunchHoleError::Io { err: e }),
+                    };
+                    if sent == 2 || (attempts >= 2 && Instant::now() >= self.deadline) {
+                        if sent == 0 {
+                            return Err(UdpPunchHoleError::SendCompleteAck);
+                        }
+                        return Ok(Async::Ready((punched, warnings)));
+                    }
+                    let ack_interval = Duration::from_millis(punched_udp_socket::ACK_INTERVAL_MS);
+                    timeout.reset(Instant::now() + ack_interval);
+                    self.state = UdpPunchHoleState::Acking {
+                        punched: punched,
+                        warnings: warnings,
+                        ack: ack,
+                        attempts: attempts,
+                        sent: sent,
+                        timeout: timeout,
+                    };
+                },
+                UdpPunchHoleState::Done => panic!("UdpPunchHoleFuture polled after completion"),
+            }
+        }
+    }
+}
+
+/// A hole punched tcp stream registered with a tokio event loop, along with the port mappings
+/// made for the socket it was punched from.
+pub struct TokioPunchedTcpStream {
+    /// The stream connected to the peer.
+    pub stream: TcpStream,
+    /// Owns the port mappings made for the stream's socket. The mappings are removed when this is
+    /// dropped so keep it alive for as long as the stream is in use.
+    pub mapping_guard: PortMappingGuard,
+}
+
+/// Future returned by `tcp_punch_hole_async`.
+pub struct TcpPunchHoleFuture {
+    state: TcpPunchHoleState,
+    handle: Handle,
+    deadline: Instant,
+}
+
+enum TcpPunchHoleState {
+    /// We couldn't get started. The error gets returned when we're first polled.
+    Failed(TcpPunchHoleError),
+    Punching(TcpPunch),
+    Choosing(ChooseStream),
+    Done,
+}
+
+/// Connecting to the peer's endpoints and accepting connections from it while authenticating the
+/// peer over each connection, as in `tcp_punch_hole`.
+struct TcpPunch {
+    warnings: Vec<TcpPunchHoleWarning>,
+    our_secret: Secret,
+    their_secret: Secret,
+    local_addr: net::SocketAddr,
+    targets: Vec<ConnectTarget>,
+    /// Connection attempts in progress, along with the index of the target they're for.
+    connecting: Vec<(usize, Box<Future<Item = TcpStream, Error = io::Error>>)>,
+    listener: TcpListener,
+    pending: Vec<PunchStream<TcpStream>>,
+    punched: Vec<(TcpStream, SocketAddr)>,
+    timeout: Timeout,
+}
+
+impl TcpPunch {
+    fn start(socket: net2::TcpBuilder,
+             our_priv_rendezvous_info: PrivRendezvousInfo,
+             their_pub_rendezvous_info: PubRendezvousInfo,
+             handle: &Handle,
+             deadline: Instant)
+             -> Result<TcpPunch, TcpPunchHoleError> {
+        let TcpPunchSockets {
+            warnings,
+            our_secret,
+            their_secret,
+            targets,
+            listener,
+        } = try!(mapped_tcp_socket::tcp_punch_sockets(socket,
+                                                      our_priv_rendezvous_info,
+                                                      their_pub_rendezvous_info));
+        let local_addr = try!(listener.local_addr()
+                                      .map_err(|e| TcpPunchHoleError::SocketLocalAddr { err: e }));
+        let listener = try!(TcpListener::from_listener(listener, &local_addr, handle)
+                                        .map_err(|e| TcpPunchHoleError::Listen { err: e }));
+        let timeout = try!(Timeout::new_at(deadline, handle)
+                                   .map_err(|e| TcpPunchHoleError::Poll { err: e }));
+        Ok(TcpPunch {
+            warnings: warnings,
+            our_secret: our_secret,
+            their_secret: their_secret,
+            local_addr: local_addr,
+            targets: targets,
+            connecting: Vec::new(),
+            listener: listener,
+            pending: Vec::new(),
+            punched: Vec::new(),
+            timeout: timeout,
+        })
+    }
+
+    /// Ready once we've authenticated the peer over at least one stream and aren't still
+    /// authenticating it over any others.
+    fn poll(&mut self, handle: &Handle, deadline: Instant) -> Poll<(), TcpPunchHoleError> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                let warnings = mem::replace(&mut self.warnings, Vec::new());
+                return Err(TcpPunchHoleError::TimedOut { warnings: warnings });
+            }
+
+            if self.punched.is_empty() {
+                // Start any connection attempts that are due.
+                for (i, target) in self.targets.iter_mut().enumerate() {
+                    if target.connect_at > now {
+                        continue;
+                    }
+                    if let Some(stream) = target.socket.take() {
+                        let connect = TcpStream::connect_stream(stream, &*target.addr, handle);
+                        self.connecting.push((i, connect));
+                    }
+                }
+
+                // Accept any incoming connections.
+                loop {
+                    let (stream, addr) = match self.listener.accept() {
+                        Ok(x) => x,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            self.warnings.push(TcpPunchHoleWarning::Accept { err: e });
+                            break;
+                        },
+                    };
+                    self.pending.push(PunchStream::accepted(stream, SocketAddr(addr)));
+                }
+            }
+
+            // Pick up any connection attempts that have finished.
+            let mut i = 0;
+            while i < self.connecting.len() {
+                let res = self.connecting[i].1.poll();
+                match res {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                    },
+                    Ok(Async::Ready(stream)) => {
+                        let (target, _) = self.connecting.swap_remove(i);
+                        let peer_addr = self.targets[target].addr;
+                        self.pending.push(PunchStream::accepted(stream, peer_addr));
+                    },
+                    Err(e) => {
+                        let (target, _) = self.connecting.swap_remove(i);
+                        let target = &mut self.targets[target];
+                        self.warnings.push(TcpPunchHoleWarning::Connect {
+                            peer_addr: target.addr,
+                            err: e,
+                        });
+                        // The failed attempt used up the socket, so try again later with a new
+                        // one.
+                        let res = mapped_tcp_socket::new_reusably_bound_tcp_socket(&self.local_addr)
+                                      .map_err(io::Error::from)
+                                      .and_then(|socket| socket.to_tcp_stream());
+                        match res {
+                            Ok(stream) => {
+                                let retry_delay = mapped_tcp_socket::CONNECT_RETRY_DELAY_SECS;
+                                target.socket = Some(stream);
+                                target.connect_at = now + Duration::from_secs(retry_delay);
+                            },
+                            Err(e) => {
+                                self.warnings.push(TcpPunchHoleWarning::Connect {
+                                    peer_addr: target.addr,
+                                    err: e,
+                                });
+                            },
+                        };
+                    },
+                }
+            }
+
+            // Move every handshake along as far as it can go without blocking.
+            let mut i = 0;
+            while i < self.pending.len() {
+                match self.pending[i].handshake(&self.our_secret, &self.their_secret) {
+                    Ok(false) => {
+                        i += 1;
+                    },
+                    Ok(true) => {
+                        let punch_stream = self.pending.swap_remove(i);
+                        self.punched.push((punch_stream.stream, punch_stream.peer_addr));
+                    },
+                    Err(e) => {
+                        let _ = self.pending.swap_remove(i);
+                        self.warnings.push(e);
+                    },
+                }
+            }
+
+            if !self.punched.is_empty() {
+                // Once we have a connection we stop making new ones. But connections which are
+                // already authenticating may get counted by the peer, so give them a chance to
+                // finish.
+                self.connecting.clear();
+                if self.pending.is_empty() {
+                    return Ok(Async::Ready(()));
+                }
+            }
+
+            // The sockets wake us up when there's more to do with them. Otherwise wait until the
+            // next connection attempt is due.
+            let mut wake_at = deadline;
+            if self.punched.is_empty() {
+                for target in &self.targets {
+                    if target.socket.is_some() {
+                        wake_at = cmp::min(wake_at, target.connect_at);
+                    }
+                }
+            }
+            self.timeout.reset(wake_at);
+            match self.timeout.poll() {
+                Ok(Async::Ready(())) => (),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(TcpPunchHoleError::Poll { err: e }),
+            };
+        }
+    }
+}
+
+/// Agreeing with the peer on which of several punched streams to keep, as in `tcp_punch_hole`. We
+/// each write a random u64 to every stream and keep the stream where the two values add up to the
+/// most.
+struct ChooseStream {
+    /// The streams we haven't finished exchanging values over.
+    exchanging: Vec<ExchangeValues>,
+    /// The stream with the highest sum so far.
+    chosen: Option<(TcpStream, u64)>,
+    errors: Vec<TcpPunchHoleBrokenStream>,
+    warnings: Vec<TcpPunchHoleWarning>,
+    timeout: Timeout,
+}
+
+impl ChooseStream {
+    fn new(streams: Vec<(TcpStream, SocketAddr)>,
+           warnings: Vec<TcpPunchHoleWarning>,
+           timeout: Timeout)
+           -> ChooseStream {
+        let exchanging = streams.into_iter().map(|(stream, peer_addr)| {
+            let mut ours = [0u8; 8];
+            BigEndian::write_u64(&mut ours, random());
+            ExchangeValues {
+                stream: stream,
+                peer_addr: peer_addr,
+                ours: ours,
+                written: 0,
+                theirs: [0u8; 8],
+                read: 0,
+            }
+        }).collect();
+        ChooseStream {
+            exchanging: exchanging,
+            chosen: None,
+            errors: Vec::new(),
+            warnings: warnings,
+            timeout: timeout,
+        }
+    }
+
+    fn poll(&mut self, deadline: Instant)
+            -> Poll<(TcpStream, Vec<TcpPunchHoleWarning>), TcpPunchHoleError> {
+        loop {
+            let timed_out = Instant::now() >= deadline;
+            let mut i = 0;
+            while i < self.exchanging.len() {
+                let res = if timed_out {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "timed out choosing a stream"))
+                }
+                else {
+                    self.exchanging[i].progress()
+                };
+                match res {
+                    Ok(None) => {
+                        i += 1;
+                    },
+                    Ok(Some(sum)) => {
+                        let exchange = self.exchanging.swap_remove(i);
+                        let replace = match self.chosen {
+                            Some((_, top_sum)) => sum > top_sum,
+                            None => true,
+                        };
+                        if replace {
+                            self.chosen = Some((exchange.stream, sum));
+                        }
+                    },
+                    Err(e) => {
+                        let exchange = self.exchanging.swap_remove(i);
+                        self.errors.push(TcpPunchHoleBrokenStream {
+                            peer_addr: exchange.peer_addr,
+                            error: e,
+                        });
+                    },
+                }
+            }
+
+            if self.exchanging.is_empty() {
+                let errors = mem::replace(&mut self.errors, Vec::new());
+                return match self.chosen.take() {
+                    Some((stream, _sum)) => {
+                        let mut warnings = mem::replace(&mut self.warnings, Vec::new());
+                        warnings.extend(errors.into_iter().map(|bs| {
+                            TcpPunchHoleWarning::StreamIo {
+                                peer_addr: bs.peer_addr,
+                                err: bs.error,
+                            }
+                        }));
+                        Ok(Async::Ready((stream, warnings)))
+                    },
+                    // Every stream died while deciding which stream to use.
+                    None => Err(TcpPunchHoleError::DecideStream { errors: errors }),
+                };
+            }
+
+            self.timeout.reset(deadline);
+            match self.timeout.poll() {
+                Ok(Async::Ready(())) => (),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(TcpPunchHoleError::Poll { err: e }),
+            };
+        }
+    }
+}
+
+/// One of the streams in `ChooseStream`.
+struct ExchangeValues {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    ours: [u8; 8],
+    /// How many bytes of `ours` we've written.
+    written: usize,
+    theirs: [u8; 8],
+    /// How many bytes of `theirs` we've read.
+    read: usize,
+}
+
+impl ExchangeValues {
+    /// Do as much of the exchange as we can without blocking. Returns the sum of the two values
+    /// once we're done.
+    fn progress(&mut self) -> io::Result<Option<u64>> {
+        while self.written < self.ours.len() {
+            match self.stream.write(&self.ours[self.written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                   "failed to write value")),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            };
+        }
+        while self.read < self.theirs.len() {
+            match self.stream.read(&mut self.theirs[self.read..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                   "connection closed while choosing a stream")),
+                Ok(n) => self.read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            };
+        }
+        let ours = BigEndian::read_u64(&self.ours[..]);
+        Ok(Some(BigEndian::read_u64(&self.theirs[..]).wrapping_add(ours)))
+    }
+}
+
+impl Future for TcpPunchHoleFuture {
+    type Item = (TcpStream, Vec<TcpPunchHoleWarning>);
+    type Error = TcpPunchHoleError;
+
+    fn poll(&mut self) -> Poll<(TcpStream, Vec<TcpPunchHoleWarning>), TcpPunchHoleError> {
+        loop {
+            match mem::replace(&mut self.state, TcpPunchHoleState::Done) {
+                TcpPunchHoleState::Failed(e) => return Err(e),
+                TcpPunchHoleState::Punching(mut punch) => {
+                    match try!(punch.poll(&self.handle, self.deadline)) {
+                        Async::Ready(()) => (),
+                        Async::NotReady => {
+                            self.state = TcpPunchHoleState::Punching(punch);
+                            return Ok(Async::NotReady);
+                        },
+                    };
+                    let TcpPunch { mut punched, warnings, timeout, .. } = punch;
+                    if punched.len() == 1 {
+                        let (stream, _) = punched.remove(0);
+                        return Ok(Async::Ready((stream, warnings)));
+                    }
+                    self.state = TcpPunchHoleState::Choosing(ChooseStream::new(punched,
+                                                                               warnings,
+                                                                               timeout));
+                },
+                TcpPunchHoleState::Choosing(mut choose) => {
+                    match try!(choose.poll(self.deadline)) {
+                        Async::Ready(x) => return Ok(Async::Ready(x)),
+                        Async::NotReady => {
+                            self.state = TcpPunchHoleState::Choosing(choose);
+                            return Ok(Async::NotReady);
+                        },
+                    };
+                },
+                TcpPunchHoleState::Done => panic!("TcpPunchHoleFuture polled after completion"),
+            }
+        }
+    }
+}
+
+/// Future returned by `tcp_punch_hole_mapped_async`.
+pub struct TcpPunchHoleMappedFuture {
+    punch: TcpPunchHoleFuture,
+    /// Handed on to the punched stream.
+    mapping_guard: Option<PortMappingGuard>,
+}
+
+impl Future for TcpPunchHoleMappedFuture {
+    type Item = (TokioPunchedTcpStream, Vec<TcpPunchHoleWarning>);
+    type Error = TcpPunchHoleError;
+
+    fn poll(&mut self)
+            -> Poll<(TokioPunchedTcpStream, Vec<TcpPunchHoleWarning>), TcpPunchHoleError> {
+        let (stream, warnings) = match try!(self.punch.poll()) {
+            Async::Ready(x) => x,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        let mapping_guard = match self.mapping_guard.take() {
+            Some(mapping_guard) => mapping_guard,
+            None => panic!("TcpPunchHoleMappedFuture polled after completion"),
+        };
+        Ok(Async::Ready((TokioPunchedTcpStream {
+            stream: stream,
+            mapping_guard: mapping_guard,
+        }, warnings)))
+    }
+}
+
+impl MappedUdpSocket {
+    /// Asynchronous version of `MappedUdpSocket::map`.
+    pub fn map_async<T>(socket: net::UdpSocket, mc: T, deadline: Instant)
+            -> MapSocketFuture<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketMapError>
+        where T: AsRef<MappingContext> + Send + 'static
+    {
+        MapSocketFuture {
+            worker: Worker::spawn("MappedUdpSocket::map_async", move |cancel| {
+                mapped_udp_socket::map_cancellable(socket, mc.as_ref(), deadline, cancel)
+            }),
+        }
+    }
+
+    /// Asynchronous version of `MappedUdpSocket::new`.
+    pub fn new_async<T>(mc: T, deadline: Instant)
+            -> MapSocketFuture<MappedUdpSocket, MappedUdpSocketMapWarning, MappedUdpSocketNewError>
+        where T: AsRef<MappingContext> + Send + 'static
+    {
+        MapSocketFuture {
+            worker: Worker::spawn("MappedUdpSocket::new_async", move |cancel| {
+                mapped_udp_socket::new_cancellable(mc.as_ref(), deadline, cancel)
+            }),
+        }
+    }
+}
+
+impl MappedTcpSocket {
+    /// Asynchronous version of `MappedTcpSocket::map`.
+    pub fn map_async<T>(socket: net2::TcpBuilder, mc: T, deadline: Instant)
+            -> MapSocketFuture<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketMapError>
+        where T: AsRef<MappingContext> + Send + 'static
+    {
+        MapSocketFuture {
+            worker: Worker::spawn("MappedTcpSocket::map_async", move |cancel| {
+                mapped_tcp_socket::map_cancellable(socket, mc.as_ref(), deadline, cancel)
+            }),
+        }
+    }
+
+    /// Asynchronous version of `MappedTcpSocket::new`.
+    pub fn new_async<T>(mc: T, deadline: Instant)
+            -> MapSocketFuture<MappedTcpSocket, MappedTcpSocketMapWarning, MappedTcpSocketNewError>
+        where T: AsRef<MappingContext> + Send + 'static
+    {
+        MapSocketFuture {
+            worker: Worker::spawn("MappedTcpSocket::new_async", move |cancel| {
+                mapped_tcp_socket::new_cancellable(mc.as_ref(), deadline, cancel)
+            }),
+        }
+    }
+}
+
+impl PunchedUdpSocket {
+    /// Asynchronous version of `PunchedUdpSocket::punch_hole`. The socket is registered with the
+    /// event loop behind `handle`, which drives the hole punching.
+    pub fn punch_hole_async(socket: net::UdpSocket,
+                            our_priv_rendezvous_info: PrivRendezvousInfo,
+                            their_pub_rendezvous_info: PubRendezvousInfo,
+                            handle: &Handle,
+                            deadline: Instant)
+        -> UdpPunchHoleFuture
+    {
+        let state = match UdpPunchHoleFuture::start(socket,
+                                                    our_priv_rendezvous_info,
+                                                    their_pub_rendezvous_info,
+                                                    handle,
+                                                    deadline) {
+            Ok(state) => state,
+            Err(e) => UdpPunchHoleState::Failed(e),
+        };
+        UdpPunchHoleFuture {
+            state: state,
+            deadline: deadline,
+            mapping_guard: PortMappingGuard::new(Vec::new()),
+        }
+    }
+
+    /// Asynchronous version of `PunchedUdpSocket::punch_hole_mapped`. The socket's port mappings
+    /// are handed on to the punched socket, or removed if punching fails.
+    pub fn punch_hole_mapped_async(mapped_socket: MappedUdpSocket,
+                                   our_priv_rendezvous_info: PrivRendezvousInfo,
+                                   their_pub_rendezvous_info: PubRendezvousInfo,
+                                   handle: &Handle,
+                                   deadline: Instant)
+        -> UdpPunchHoleFuture
+    {
+        let MappedUdpSocket { socket, mapping_guard, .. } = mapped_socket;
+        let mut future = PunchedUdpSocket::punch_hole_async(socket,
+                                                            our_priv_rendezvous_info,
+                                                            their_pub_rendezvous_info,
+                                                            handle,
+                                                            deadline);
+        future.mapping_guard = mapping_guard;
+        future
+    }
+}
+
+/// Asynchronous version of `tcp_punch_hole`. The sockets are registered with the event loop behind
+/// `handle`, which drives the hole punching.
+pub fn tcp_punch_hole_async(socket: net2::TcpBuilder,
+                            our_priv_rendezvous_info: PrivRendezvousInfo,
+                            their_pub_rendezvous_info: PubRendezvousInfo,
+                            handle: &Handle,
+                            deadline: Instant)
+                            -> TcpPunchHoleFuture {
+    let state = match TcpPunch::start(socket,
+                                      our_priv_rendezvous_info,
+                                      their_pub_rendezvous_info,
+                                      handle,
+                                      deadline) {
+        Ok(punch) => TcpPunchHoleState::Punching(punch),
+        Err(e) => TcpPunchHoleState::Failed(e),
+    };
+    TcpPunchHoleFuture {
+        state: state,
+        handle: handle.clone(),
+        deadline: deadline,
+    }
+}
+
+/// Asynchronous version of `tcp_punch_hole_mapped`. The socket's port mappings are handed on to
+/// the punched stream, or removed if punching fails.
+pub fn tcp_punch_hole_mapped_async(mapped_socket: MappedTcpSocket,
+                                   our_priv_rendezvous_info: PrivRendezvousInfo,
+                                   their_pub_rendezvous_info: PubRendezvousInfo,
+                                   handle: &Handle,
+                                   deadline: Instant)
+                                   -> TcpPunchHoleMappedFuture {
+    let MappedTcpSocket { socket, mapping_guard, .. } = mapped_socket;
+    TcpPunchHoleMappedFuture {
+        punch: tcp_punch_hole_async(socket,
+                                    our_priv_rendezvous_info,
+                                    their_pub_rendezvous_info,
+                                    handle,
+                                    deadline),
+        mapping_guard: Some(mapping_guard),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::{Instant, Duration};
+
+    use futures::Future;
+    use tokio_core::reactor::Core;
+
+    use mapping_context::MappingContext;
+    use mapped_tcp_socket::MappedTcpSocket;
+    use mapped_udp_socket::MappedUdpSocket;
+    use punched_udp_socket::PunchedUdpSocket;
+    use rendezvous_info::gen_rendezvous_info;
+
+    #[test]
+    fn two_peers_async_udp_hole_punch_over_loopback() {
+        let mut core = unwrap_result!(Core::new());
+        let handle = core.handle();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mapping_context = Arc::new(unwrap_result!(MappingContext::new().result_log()));
+
+        let map_0 = MappedUdpSocket::new_async(mapping_context.clone(), deadline);
+        let map_1 = MappedUdpSocket::new_async(mapping_context, deadline);
+        let ((mapped_socket_0, _), (mapped_socket_1, _)) = unwrap_result!(core.run(map_0.join(map_1)));
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints.clone());
+
+        // Both punches are driven by the same event loop, so neither can block the other. The
+        // second peer hands its port mappings on to the punched socket.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let punch_0 = PunchedUdpSocket::punch_hole_async(mapped_socket_0.socket, priv_info_0, pub_info_1, &handle, deadline);
+        let punch_1 = PunchedUdpSocket::punch_hole_mapped_async(mapped_socket_1, priv_info_1, pub_info_0, &handle, deadline);
+        let ((punched_0, _), (punched_1, _)) = unwrap_result!(core.run(punch_0.join(punch_1)));
+        // The sockets are bound to 0.0.0.0 so only the ports can be compared.
+        assert_eq!(unwrap_result!(punched_0.socket.local_addr()).port(), punched_1.peer_addr.port());
+        assert_eq!(unwrap_result!(punched_1.socket.local_addr()).port(), punched_0.peer_addr.port());
+    }
+
+    #[test]
+    fn two_peers_async_tcp_hole_punch_over_loopback() {
+        let mut core = unwrap_result!(Core::new());
+        let handle = core.handle();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mapping_context = Arc::new(unwrap_result!(MappingContext::new().result_log()));
+
+        let map_0 = MappedTcpSocket::new_async(mapping_context.clone(), deadline);
+        let map_1 = MappedTcpSocket::new_async(mapping_context, deadline);
+        let ((mapped_socket_0, _), (mapped_socket_1, _)) = unwrap_result!(core.run(map_0.join(map_1)));
+        let (priv_info_0, pub_info_0) = gen_rendezvous_info(mapped_socket_0.endpoints);
+        let (priv_info_1, pub_info_1) = gen_rendezvous_info(mapped_socket_1.endpoints.clone());
+
+        // The second peer hands its port mappings on to the punched stream.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let punch_0 = tcp_punch_hole_async(mapped_socket_0.socket, priv_info_0, pub_info_1, &handle, deadline);
+        let punch_1 = tcp_punch_hole_mapped_async(mapped_socket_1, priv_info_1, pub_info_0, &handle, deadline);
+        let ((stream_0, _), (punched_1, _)) = unwrap_result!(core.run(punch_0.join(punch_1)));
+        let stream_1 = punched_1.stream;
+        assert_eq!(unwrap_result!(stream_0.local_addr()), unwrap_result!(stream_1.peer_addr()));
+        assert_eq!(unwrap_result!(stream_0.peer_addr()), unwrap_result!(stream_1.local_addr()));
+    }
+}
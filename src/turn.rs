@@ -0,0 +1,947 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! A client for TURN (RFC 5766) servers such as coturn, so that they can be used as relays. We
+//! allocate a relayed address over udp using long-term credentials, create permissions and bind
+//! channels for peers, and keep all of these refreshed until the allocation is dropped.
+//!
+//! Usernames and passwords are used as given, without SASLprep.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{self, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac};
+use md5;
+use sha1::Sha1;
+use socket_addr::SocketAddr;
+
+use mapped_socket_addr::{CandidateType, MappedSocketAddr};
+use relay::Relay;
+use stun::{self, StunDecodeError, TransactionId};
+
+const METHOD_ALLOCATE: u16 = 0x0003;
+const METHOD_REFRESH: u16 = 0x0004;
+const METHOD_SEND: u16 = 0x0006;
+const METHOD_DATA: u16 = 0x0007;
+const METHOD_CREATE_PERMISSION: u16 = 0x0008;
+const METHOD_CHANNEL_BIND: u16 = 0x0009;
+
+const CLASS_MASK: u16 = 0x0110;
+const CLASS_REQUEST: u16 = 0x0000;
+const CLASS_INDICATION: u16 = 0x0010;
+const CLASS_SUCCESS_RESPONSE: u16 = 0x0100;
+const CLASS_ERROR_RESPONSE: u16 = 0x0110;
+
+const ATTR_CHANNEL_NUMBER: u16 = 0x000c;
+const ATTR_LIFETIME: u16 = 0x000d;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+
+const TRANSPORT_UDP: u8 = 17;
+
+const ERROR_UNAUTHORIZED: u16 = 401;
+const ERROR_STALE_NONCE: u16 = 438;
+
+/// Channel numbers that may be bound to peers.
+const MIN_CHANNEL_NUMBER: u16 = 0x4000;
+const MAX_CHANNEL_NUMBER: u16 = 0x7fff;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// The allocation lifetime we ask for.
+const ALLOCATION_LIFETIME_SECS: u32 = 600;
+/// How often we refresh permissions and channel bindings. Permissions expire after five minutes.
+const PERMISSION_REFRESH_SECS: u64 = 240;
+/// How long to wait before trying again when refreshing fails.
+const RETRY_INTERVAL_SECS: u64 = 10;
+/// How long the background threads wait for a refresh to be answered.
+const REFRESH_TIMEOUT_SECS: u64 = 5;
+/// The initial retransmission timeout for requests. It doubles with each retransmission.
+const INITIAL_RTO_MS: u64 = 500;
+/// How often the background threads check whether they should stop.
+const POLL_INTERVAL_MS: u64 = 100;
+/// How many times we answer a challenge for new credentials before giving up on a request.
+const MAX_AUTH_ATTEMPTS: usize = 3;
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// A TURN server and the long-term credentials to use with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnServer {
+    /// The address of the server.
+    pub addr: SocketAddr,
+    /// Our username on the server.
+    pub username: String,
+    /// Our password on the server.
+    pub password: String,
+}
+
+quick_error! {
+    /// Errors returned by `TurnAllocation`.
+    #[derive(Debug)]
+    pub enum TurnError {
+        /// The mapping context doesn't know about any TURN servers.
+        NoTurnServers {
+            description("The mapping context doesn't know about any TURN servers")
+        }
+        /// Error creating or using the socket that talks to the TURN server.
+        Socket { err: io::Error } {
+            description("Error creating or using the socket that talks to the TURN server")
+            display("Error creating or using the socket that talks to the TURN server: {}", err)
+            cause(err)
+        }
+        /// The server didn't answer before the deadline.
+        TimedOut {
+            description("The TURN server didn't answer before the deadline")
+        }
+        /// The server sent an invalid response.
+        InvalidResponse { err: StunDecodeError } {
+            description("The TURN server sent an invalid response")
+            display("The TURN server sent an invalid response: {}", err)
+            cause(err)
+        }
+        /// The server's response wasn't authenticated with our credentials.
+        BadMessageIntegrity {
+            description("The TURN server's response wasn't authenticated with our credentials")
+        }
+        /// The server rejected a request.
+        ErrorResponse { code: u16, reason: String } {
+            description("The TURN server rejected a request")
+            display("The TURN server rejected a request: {} {}", code, reason)
+        }
+        /// The server ran out of channel numbers to bind to peers.
+        NoFreeChannels {
+            description("No free channel numbers left to bind to peers")
+        }
+    }
+}
+
+impl From<TurnError> for io::Error {
+    fn from(e: TurnError) -> io::Error {
+        let err_str = format!("{}", e);
+        let kind = match e {
+            TurnError::NoTurnServers => io::ErrorKind::NotFound,
+            TurnError::Socket { err } => err.kind(),
+            TurnError::TimedOut => io::ErrorKind::TimedOut,
+            TurnError::InvalidResponse { .. } |
+            TurnError::BadMessageIntegrity => io::ErrorKind::InvalidData,
+            TurnError::ErrorResponse { .. } => io::ErrorKind::Other,
+            TurnError::NoFreeChannels => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err_str)
+    }
+}
+
+/// The realm and nonce the server challenged us with and the key derived from our password.
+#[derive(Clone)]
+struct Credentials {
+    username: String,
+    realm: Vec<u8>,
+    nonce: Vec<u8>,
+    key: [u8; 16],
+}
+
+/// A decoded STUN message with the attributes that we care about.
+struct Message {
+    msg_type: u16,
+    transaction_id: TransactionId,
+    attrs: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    fn decode(data: &[u8]) -> Result<Message, StunDecodeError> {
+        if !stun::is_stun_message(data) {
+            return Err(StunDecodeError::NotStun);
+        }
+        let mut attrs = Vec::new();
+        try!(stun::for_each_attribute(data, |attr_type, value| {
+            attrs.push((attr_type, value.to_vec()));
+            Ok(())
+        }));
+        Ok(Message {
+            msg_type: BigEndian::read_u16(&data[0..2]),
+            transaction_id: stun::transaction_id(data),
+            attrs: attrs,
+        })
+    }
+
+    fn attr(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attrs.iter().find(|&&(t, _)| t == attr_type).map(|&(_, ref value)| &value[..])
+    }
+
+    fn xor_address(&self, attr_type: u16) -> Result<SocketAddr, StunDecodeError> {
+        match self.attr(attr_type) {
+            Some(value) => {
+                let addr = try!(stun::decode_address(attr_type, value, Some(&self.transaction_id)));
+                Ok(SocketAddr(addr))
+            },
+            None => Err(StunDecodeError::MalformedAttribute { attr_type: attr_type }),
+        }
+    }
+
+    fn error_code(&self) -> (u16, String) {
+        match self.attr(stun::ATTR_ERROR_CODE) {
+            Some(value) if value.len() >= 4 => {
+                let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+                (code, String::from_utf8_lossy(&value[4..]).into_owned())
+            },
+            _ => (0, String::new()),
+        }
+    }
+}
+
+/// The key used for long-term credentials: MD5(username ":" realm ":" password).
+fn long_term_key(username: &str, realm: &[u8], password: &str) -> [u8; 16] {
+    let mut md5 = md5::Context::new();
+    md5.consume(username.as_bytes());
+    md5.consume(b":");
+    md5.consume(realm);
+    md5.consume(b":");
+    md5.consume(password.as_bytes());
+    md5.compute().0
+}
+
+fn new_hmac_sha1(key: &[u8], data: &[u8]) -> Hmac<Sha1> {
+    // HMAC takes keys of any length.
+    let mut hmac = unwrap_result!(Hmac::<Sha1>::new_varkey(key));
+    hmac.input(data);
+    hmac
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut mac = [0u8; 20];
+    mac.copy_from_slice(&new_hmac_sha1(key, data).result().code()[..]);
+    mac
+}
+
+/// Encode a message, authenticating it with `credentials` if given.
+fn encode_message(msg_type: u16,
+                  transaction_id: &TransactionId,
+                  attrs: &[(u16, Vec<u8>)],
+                  credentials: Option<&Credentials>)
+                  -> Vec<u8> {
+    let mut buf = stun::encode_header(msg_type, transaction_id);
+    for &(attr_type, ref value) in attrs {
+        stun::push_attribute(&mut buf, attr_type, &value[..]);
+    }
+    if let Some(credentials) = credentials {
+        stun::push_attribute(&mut buf, stun::ATTR_USERNAME, credentials.username.as_bytes());
+        stun::push_attribute(&mut buf, stun::ATTR_REALM, &credentials.realm[..]);
+        stun::push_attribute(&mut buf, stun::ATTR_NONCE, &credentials.nonce[..]);
+        // The MAC covers the header with its length already including the MESSAGE-INTEGRITY
+        // attribute.
+        let len = (buf.len() - stun::HEADER_LEN + 24) as u16;
+        BigEndian::write_u16(&mut buf[2..4], len);
+        let mac = hmac_sha1(&credentials.key[..], &buf[..]);
+        stun::push_attribute(&mut buf, stun::ATTR_MESSAGE_INTEGRITY, &mac[..]);
+    }
+    buf
+}
+
+/// Check that `data` carries a MESSAGE-INTEGRITY attribute computed with `key`.
+fn check_message_integrity(data: &[u8], key: &[u8]) -> bool {
+    let mut pos = stun::HEADER_LEN;
+    while pos + 4 <= data.len() {
+        let attr_type = BigEndian::read_u16(&data[pos..pos + 2]);
+        let attr_len = BigEndian::read_u16(&data[pos + 2..pos + 4]) as usize;
+        if attr_type == stun::ATTR_MESSAGE_INTEGRITY {
+            if attr_len != 20 || pos + 24 > data.len() {
+                return false;
+            }
+            let mut covered = data[..pos].to_vec();
+            let len = (pos - stun::HEADER_LEN + 24) as u16;
+            BigEndian::write_u16(&mut covered[2..4], len);
+            // `verify` compares in constant time.
+            return new_hmac_sha1(key, &covered[..]).verify(&data[pos + 4..pos + 24]).is_ok();
+        }
+        pos += 4 + attr_len + (4 - attr_len % 4) % 4;
+    }
+    false
+}
+
+/// An XOR-PEER-ADDRESS attribute for a message with the given transaction ID.
+fn peer_attr(peer_addr: &SocketAddr, transaction_id: &TransactionId) -> (u16, Vec<u8>) {
+    (ATTR_XOR_PEER_ADDRESS, stun::encode_xor_address(&**peer_addr, transaction_id))
+}
+
+fn lifetime_attr(lifetime_secs: u32) -> (u16, Vec<u8>) {
+    let mut lifetime = vec![0u8; 4];
+    BigEndian::write_u32(&mut lifetime[..], lifetime_secs);
+    (ATTR_LIFETIME, lifetime)
+}
+
+fn encode_channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; CHANNEL_DATA_HEADER_LEN];
+    BigEndian::write_u16(&mut buf[0..2], channel);
+    BigEndian::write_u16(&mut buf[2..4], data.len() as u16);
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// If `data` is a ChannelData message, return its channel number and payload.
+fn decode_channel_data(data: &[u8]) -> Option<(u16, &[u8])> {
+    if data.len() < CHANNEL_DATA_HEADER_LEN {
+        return None;
+    }
+    let channel = BigEndian::read_u16(&data[0..2]);
+    let len = BigEndian::read_u16(&data[2..4]) as usize;
+    if channel < MIN_CHANNEL_NUMBER || channel > MAX_CHANNEL_NUMBER ||
+       CHANNEL_DATA_HEADER_LEN + len > data.len() {
+        return None;
+    }
+    Some((channel, &data[CHANNEL_DATA_HEADER_LEN..CHANNEL_DATA_HEADER_LEN + len]))
+}
+
+/// State shared between a `TurnAllocation` and its background threads.
+struct Inner {
+    server: TurnServer,
+    socket: UdpSocket,
+    credentials: Mutex<Option<Credentials>>,
+    /// Requests waiting for a response, by transaction ID.
+    transactions: Mutex<HashMap<TransactionId, Sender<Vec<u8>>>>,
+    /// Peers we've asked for permissions for. These are refreshed in the background.
+    permissions: Mutex<HashSet<SocketAddr>>,
+    /// Channels we've bound, by peer. These are refreshed in the background.
+    channels: Mutex<HashMap<SocketAddr, u16>>,
+    stop_flag: AtomicBool,
+}
+
+impl Inner {
+    /// Send a request and wait for the server's response, answering any challenge for
+    /// credentials along the way. `attrs` gives the attributes of the request for a transaction
+    /// ID, since XORed addresses depend on it.
+    fn transact<F>(&self, method: u16, attrs: F, deadline: Instant) -> Result<Message, TurnError>
+        where F: Fn(&TransactionId) -> Vec<(u16, Vec<u8>)>
+    {
+        let mut auth_attempts = 0;
+        loop {
+            let credentials = unwrap_result!(self.credentials.lock()).clone();
+            let transaction_id = stun::new_transaction_id();
+            let request = encode_message(method | CLASS_REQUEST,
+                                         &transaction_id,
+                                         &attrs(&transaction_id)[..],
+                                         credentials.as_ref());
+            let (tx, rx) = mpsc::channel();
+            let _ = unwrap_result!(self.transactions.lock()).insert(transaction_id, tx);
+            let res = self.send_request(&request[..], &rx, deadline);
+            let _ = unwrap_result!(self.transactions.lock()).remove(&transaction_id);
+            let data = try!(res);
+
+            let resp = match Message::decode(&data[..]) {
+                Ok(resp) => resp,
+                Err(e) => return Err(TurnError::InvalidResponse { err: e }),
+            };
+            if resp.msg_type & CLASS_MASK == CLASS_SUCCESS_RESPONSE {
+                if let Some(ref credentials) = credentials {
+                    if !check_message_integrity(&data[..], &credentials.key[..]) {
+                        return Err(TurnError::BadMessageIntegrity);
+                    }
+                }
+                return Ok(resp);
+            }
+
+            let (code, reason) = resp.error_code();
+            let challenged = (code == ERROR_UNAUTHORIZED && credentials.is_none()) ||
+                             code == ERROR_STALE_NONCE;
+            if !challenged || auth_attempts >= MAX_AUTH_ATTEMPTS {
+                return Err(TurnError::ErrorResponse { code: code, reason: reason });
+            }
+            auth_attempts += 1;
+            let realm = match (resp.attr(stun::ATTR_REALM), credentials.as_ref()) {
+                (Some(realm), _) => realm.to_vec(),
+                (None, Some(credentials)) => credentials.realm.clone(),
+                (None, None) => return Err(TurnError::ErrorResponse { code: code, reason: reason }),
+            };
+            let nonce = match resp.attr(stun::ATTR_NONCE) {
+                Some(nonce) => nonce.to_vec(),
+                None => return Err(TurnError::ErrorResponse { code: code, reason: reason }),
+            };
+            let key = long_term_key(&self.server.username, &realm[..], &self.server.password);
+            *unwrap_result!(self.credentials.lock()) = Some(Credentials {
+                username: self.server.username.clone(),
+                realm: realm,
+                nonce: nonce,
+                key: key,
+            });
+        }
+    }
+
+    /// Send a request, retransmitting it with exponential backoff until the reader thread passes
+    /// us the response.
+    fn send_request(&self,
+                    request: &[u8],
+                    rx: &Receiver<Vec<u8>>,
+                    deadline: Instant)
+                    -> Result<Vec<u8>, TurnError> {
+        let mut rto = Duration::from_millis(INITIAL_RTO_MS);
+        loop {
+            let now = Instant::now();
+            if now >= deadline || self.stop_flag.load(Ordering::SeqCst) {
+                return Err(TurnError::TimedOut);
+            }
+            if let Err(e) = self.socket.send_to(request, &*self.server.addr) {
+                return Err(TurnError::Socket { err: e });
+            }
+            match rx.recv_timeout(cmp::min(rto, deadline - now)) {
+                Ok(data) => return Ok(data),
+                Err(RecvTimeoutError::Timeout) => rto = rto * 2,
+                Err(RecvTimeoutError::Disconnected) => return Err(TurnError::TimedOut),
+            }
+        }
+    }
+
+    /// Send a request without waiting for the response.
+    fn send_request_once<F>(&self, method: u16, attrs: F) -> io::Result<()>
+        where F: Fn(&TransactionId) -> Vec<(u16, Vec<u8>)>
+    {
+        let credentials = unwrap_result!(self.credentials.lock()).clone();
+        let transaction_id = stun::new_transaction_id();
+        let request = encode_message(method | CLASS_REQUEST,
+                                     &transaction_id,
+                                     &attrs(&transaction_id)[..],
+                                     credentials.as_ref());
+        self.socket.send_to(&request[..], &*self.server.addr).map(|_| ())
+    }
+
+    fn create_permission(&self, peer_addr: &SocketAddr, deadline: Instant)
+                         -> Result<(), TurnError> {
+        let _ = unwrap_result!(self.permissions.lock()).insert(*peer_addr);
+        self.transact(METHOD_CREATE_PERMISSION,
+                      |transaction_id| vec![peer_attr(peer_addr, transaction_id)],
+                      deadline)
+            .map(|_| ())
+    }
+
+    fn bind_channel(&self, peer_addr: &SocketAddr, channel: u16, deadline: Instant)
+                    -> Result<(), TurnError> {
+        let mut channel_number = vec![0u8; 4];
+        BigEndian::write_u16(&mut channel_number[0..2], channel);
+        self.transact(METHOD_CHANNEL_BIND,
+                      |transaction_id| {
+                          vec![(ATTR_CHANNEL_NUMBER, channel_number.clone()),
+                               peer_attr(peer_addr, transaction_id)]
+                      },
+                      deadline)
+            .map(|_| ())
+    }
+}
+
+/// An address allocated for us on a TURN server. Background threads read from the server and
+/// refresh the allocation, permissions and channel bindings until this is dropped, at which point
+/// the allocation is released. Dropping doesn't wait for the threads, which stop on their own
+/// shortly afterwards.
+pub struct TurnAllocation {
+    inner: Arc<Inner>,
+    relayed_addr: SocketAddr,
+    mapped_addr: Option<SocketAddr>,
+    local_addr: SocketAddr,
+    data_rx: Mutex<Receiver<(SocketAddr, Vec<u8>)>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl TurnAllocation {
+    /// Allocate a relayed udp address on `server`.
+    pub fn allocate(server: &TurnServer, deadline: Instant)
+                    -> Result<TurnAllocation, TurnError> {
+        let bind_addr = match *server.addr {
+            net::SocketAddr::V4(..) => "0.0.0.0:0",
+            net::SocketAddr::V6(..) => "[::]:0",
+        };
+        let socket = match UdpSocket::bind(bind_addr) {
+            Ok(socket) => socket,
+            Err(e) => return Err(TurnError::Socket { err: e }),
+        };
+        let (local_addr, cloned_socket) = match (socket.local_addr(), socket.try_clone()) {
+            (Ok(local_addr), Ok(cloned_socket)) => (local_addr, cloned_socket),
+            (Err(e), _) | (_, Err(e)) => return Err(TurnError::Socket { err: e }),
+        };
+        let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
+        if let Err(e) = cloned_socket.set_read_timeout(Some(poll_interval)) {
+            return Err(TurnError::Socket { err: e });
+        }
+
+        let inner = Arc::new(Inner {
+            server: server.clone(),
+            socket: socket,
+            credentials: Mutex::new(None),
+            transactions: Mutex::new(HashMap::new()),
+            permissions: Mutex::new(HashSet::new()),
+            channels: Mutex::new(HashMap::new()),
+            stop_flag: AtomicBool::new(false),
+        });
+        let (data_tx, data_rx) = mpsc::channel();
+        let cloned_inner = inner.clone();
+        let _ = thread!("TurnAllocation reader", move || {
+            Self::read(cloned_socket, cloned_inner, data_tx);
+        });
+
+        let attrs = |_: &TransactionId| {
+            vec![(ATTR_REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0]),
+                 lifetime_attr(ALLOCATION_LIFETIME_SECS)]
+        };
+        let res = inner.transact(METHOD_ALLOCATE, attrs, deadline).and_then(|resp| {
+            match resp.xor_address(ATTR_XOR_RELAYED_ADDRESS) {
+                Ok(relayed_addr) => Ok((resp, relayed_addr)),
+                Err(e) => Err(TurnError::InvalidResponse { err: e }),
+            }
+        });
+        let (resp, relayed_addr) = match res {
+            Ok(x) => x,
+            Err(e) => {
+                inner.stop_flag.store(true, Ordering::SeqCst);
+                return Err(e);
+            },
+        };
+        let lifetime_secs = match resp.attr(ATTR_LIFETIME) {
+            Some(value) if value.len() == 4 => BigEndian::read_u32(value),
+            _ => ALLOCATION_LIFETIME_SECS,
+        };
+
+        let cloned_inner = inner.clone();
+        let _ = thread!("TurnAllocation refresher", move || {
+            Self::refresh(cloned_inner, lifetime_secs);
+        });
+
+        Ok(TurnAllocation {
+            inner: inner,
+            relayed_addr: relayed_addr,
+            mapped_addr: resp.xor_address(stun::ATTR_XOR_MAPPED_ADDRESS).ok(),
+            local_addr: SocketAddr(local_addr),
+            data_rx: Mutex::new(data_rx),
+            read_timeout: Mutex::new(None),
+        })
+    }
+
+    /// Read from the server until told to stop, passing responses to whoever is waiting for them
+    /// and data from peers to `data_tx`.
+    fn read(socket: UdpSocket, inner: Arc<Inner>, data_tx: Sender<(SocketAddr, Vec<u8>)>) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        while !inner.stop_flag.load(Ordering::SeqCst) {
+            let (n, addr) = match socket.recv_from(&mut buf[..]) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            if SocketAddr(addr) != inner.server.addr {
+                continue;
+            }
+            let data = &buf[..n];
+
+            if let Some((channel, payload)) = decode_channel_data(data) {
+                let channels = unwrap_result!(inner.channels.lock());
+                if let Some((peer_addr, _)) = channels.iter().find(|&(_, &c)| c == channel) {
+                    let _ = data_tx.send((*peer_addr, payload.to_vec()));
+                }
+                continue;
+            }
+
+            let msg = match Message::decode(data) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            if msg.msg_type == METHOD_DATA | CLASS_INDICATION {
+                if let (Ok(peer_addr), Some(payload)) = (msg.xor_address(ATTR_XOR_PEER_ADDRESS),
+                                                         msg.attr(ATTR_DATA)) {
+                    let _ = data_tx.send((peer_addr, payload.to_vec()));
+                }
+            } else if msg.msg_type & CLASS_MASK == CLASS_SUCCESS_RESPONSE ||
+                      msg.msg_type & CLASS_MASK == CLASS_ERROR_RESPONSE {
+                if let Some(tx) = unwrap_result!(inner.transactions.lock())
+                                      .get(&msg.transaction_id) {
+                    let _ = tx.send(data.to_vec());
+                }
+            }
+        }
+    }
+
+    /// Refresh the allocation, permissions and channel bindings until told to stop.
+    fn refresh(inner: Arc<Inner>, lifetime_secs: u32) {
+        let allocation_interval = Duration::from_secs(cmp::max(lifetime_secs / 2, 1) as u64);
+        let permission_interval = Duration::from_secs(PERMISSION_REFRESH_SECS);
+        let retry_interval = Duration::from_secs(RETRY_INTERVAL_SECS);
+        let mut refresh_allocation_at = Instant::now() + allocation_interval;
+        let mut refresh_permissions_at = Instant::now() + permission_interval;
+        while !inner.stop_flag.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            let deadline = now + Duration::from_secs(REFRESH_TIMEOUT_SECS);
+            if now >= refresh_allocation_at {
+                let attrs = |_: &TransactionId| vec![lifetime_attr(ALLOCATION_LIFETIME_SECS)];
+                refresh_allocation_at = match inner.transact(METHOD_REFRESH, attrs, deadline) {
+                    Ok(..) => now + allocation_interval,
+                    Err(..) => now + retry_interval,
+                };
+            }
+            if now >= refresh_permissions_at {
+                let permissions = unwrap_result!(inner.permissions.lock()).clone();
+                let channels = unwrap_result!(inner.channels.lock()).clone();
+                let mut ok = true;
+                for peer_addr in permissions {
+                    ok = inner.create_permission(&peer_addr, deadline).is_ok() && ok;
+                }
+                for (peer_addr, channel) in channels {
+                    ok = inner.bind_channel(&peer_addr, channel, deadline).is_ok() && ok;
+                }
+                refresh_permissions_at = if ok {
+                    now + permission_interval
+                } else {
+                    now + retry_interval
+                };
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    /// The address of the TURN server that made the allocation.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.inner.server.addr
+    }
+
+    /// The address on the TURN server that is relayed to us.
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+
+    /// Our address as seen by the TURN server, if the server told us.
+    pub fn mapped_addr(&self) -> Option<SocketAddr> {
+        self.mapped_addr
+    }
+
+    /// Ask the server to let datagrams from the IP address of `peer_addr` through to us, and wait
+    /// for it to agree. The permission is refreshed until the allocation is dropped.
+    pub fn create_permission(&self, peer_addr: &SocketAddr, deadline: Instant)
+                             -> Result<(), TurnError> {
+        self.inner.create_permission(peer_addr, deadline)
+    }
+
+    /// Bind a channel to `peer_addr` so that data exchanged with it is framed more compactly.
+    /// This also creates a permission for the peer. The binding is refreshed until the allocation
+    /// is dropped.
+    pub fn bind_channel(&self, peer_addr: &SocketAddr, deadline: Instant)
+                        -> Result<(), TurnError> {
+        let channel = {
+            let channels = unwrap_result!(self.inner.channels.lock());
+            if let Some(channel) = channels.get(peer_addr) {
+                *channel
+            } else {
+                let used: HashSet<u16> = channels.values().cloned().collect();
+                match (MIN_CHANNEL_NUMBER..MAX_CHANNEL_NUMBER + 1).find(|c| !used.contains(c)) {
+                    Some(channel) => channel,
+                    None => return Err(TurnError::NoFreeChannels),
+                }
+            }
+        };
+        try!(self.inner.bind_channel(peer_addr, channel, deadline));
+        let _ = unwrap_result!(self.inner.channels.lock()).insert(*peer_addr, channel);
+        Ok(())
+    }
+}
+
+impl Relay for TurnAllocation {
+    fn endpoint(&self) -> MappedSocketAddr {
+        MappedSocketAddr::new(CandidateType::Relayed, self.relayed_addr, self.local_addr, true)
+    }
+
+    /// Sends a CreatePermission request without waiting for the response. Use
+    /// `create_permission` to wait for it.
+    fn permit(&self, peer_addr: &SocketAddr) -> io::Result<()> {
+        let _ = unwrap_result!(self.inner.permissions.lock()).insert(*peer_addr);
+        self.inner.send_request_once(METHOD_CREATE_PERMISSION, |transaction_id| {
+            vec![peer_attr(peer_addr, transaction_id)]
+        })
+    }
+
+    /// Sends `buf` in a ChannelData message if a channel is bound to `peer_addr`, otherwise in a
+    /// Send indication.
+    fn send_to(&self, buf: &[u8], peer_addr: &SocketAddr) -> io::Result<usize> {
+        let channel = unwrap_result!(self.inner.channels.lock()).get(peer_addr).cloned();
+        let msg = match channel {
+            Some(channel) => encode_channel_data(channel, buf),
+            None => {
+                let transaction_id = stun::new_transaction_id();
+                let attrs = [peer_attr(peer_addr, &transaction_id), (ATTR_DATA, buf.to_vec())];
+                encode_message(METHOD_SEND | CLASS_INDICATION, &transaction_id, &attrs[..], None)
+            },
+        };
+        if msg.len() > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Datagram too large to be relayed"));
+        }
+        try!(self.inner.socket.send_to(&msg[..], &*self.inner.server.addr));
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let read_timeout = *unwrap_result!(self.read_timeout.lock());
+        let data_rx = unwrap_result!(self.data_rx.lock());
+        let res = match read_timeout {
+            Some(read_timeout) => {
+                data_rx.recv_timeout(read_timeout).map_err(|e| {
+                    match e {
+                        RecvTimeoutError::Timeout => {
+                            io::Error::new(io::ErrorKind::WouldBlock, "Timed out")
+                        },
+                        RecvTimeoutError::Disconnected => {
+                            io::Error::new(io::ErrorKind::NotConnected, "Allocation closed")
+                        },
+                    }
+                })
+            },
+            None => {
+                data_rx.recv().map_err(|_| {
+                    io::Error::new(io::ErrorKind::NotConnected, "Allocation closed")
+                })
+            },
+        };
+        let (peer_addr, data) = try!(res);
+        let len = cmp::min(data.len(), buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, peer_addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        if dur == Some(Duration::new(0, 0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Cannot set a 0 duration timeout"));
+        }
+        *unwrap_result!(self.read_timeout.lock()) = dur;
+        Ok(())
+    }
+}
+
+impl Drop for TurnAllocation {
+    fn drop(&mut self) {
+        self.inner.stop_flag.store(true, Ordering::SeqCst);
+        // Dropping the senders wakes up any request the refresher is waiting on, so that it
+        // doesn't refresh anything after the release below.
+        unwrap_result!(self.inner.transactions.lock()).clear();
+        // Release the allocation by refreshing it with a lifetime of zero.
+        let _ = self.inner.send_request_once(METHOD_REFRESH, |_| vec![lifetime_attr(0)]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{Credentials, Message, check_message_integrity, decode_channel_data,
+                encode_channel_data, encode_message, lifetime_attr, long_term_key, peer_attr};
+    use super::{ATTR_CHANNEL_NUMBER, ATTR_DATA, ATTR_REQUESTED_TRANSPORT,
+                ATTR_XOR_PEER_ADDRESS, ATTR_XOR_RELAYED_ADDRESS, CLASS_ERROR_RESPONSE, CLASS_MASK,
+                CLASS_INDICATION, CLASS_REQUEST, CLASS_SUCCESS_RESPONSE, INITIAL_RTO_MS,
+                METHOD_ALLOCATE, METHOD_CHANNEL_BIND, METHOD_DATA, METHOD_SEND, TRANSPORT_UDP};
+
+    use std::collections::HashMap;
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use byteorder::{BigEndian, ByteOrder};
+    use socket_addr::SocketAddr;
+
+    use relay::Relay;
+    use stun;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: "alice".to_owned(),
+            realm: b"example.org".to_vec(),
+            nonce: b"abcdefgh".to_vec(),
+            key: long_term_key("alice", b"example.org", "secret"),
+        }
+    }
+
+    #[test]
+    fn message_integrity_matches_long_term_credentials() {
+        let credentials = credentials();
+        assert_eq!(credentials.key, [0x54, 0x3e, 0x1a, 0xec, 0x5d, 0x36, 0x14, 0xf0, 0x31, 0x41,
+                                     0x65, 0x2d, 0x6a, 0xda, 0x51, 0xb2]);
+
+        let transaction_id = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let msg = encode_message(METHOD_ALLOCATE | CLASS_REQUEST,
+                                 &transaction_id,
+                                 &[(ATTR_REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0])],
+                                 Some(&credentials));
+        assert!(stun::is_stun_message(&msg[..]));
+        assert_eq!(&msg[msg.len() - 20..],
+                   &[0x4f, 0xf4, 0x11, 0xb1, 0x82, 0xcc, 0x80, 0x64, 0x6d, 0xa3, 0x5d, 0x2f, 0x10,
+                     0xe0, 0x1b, 0x2c, 0x79, 0x4d, 0x54, 0xb6][..]);
+        assert!(check_message_integrity(&msg[..], &credentials.key[..]));
+
+        let mut tampered = msg.clone();
+        tampered[stun::HEADER_LEN + 4] ^= 1;
+        assert!(!check_message_integrity(&tampered[..], &credentials.key[..]));
+    }
+
+    /// A TURN server just capable enough for `allocation_relays_to_peers`. It insists on our
+    /// credentials but doesn't check permissions and has a single relayed socket.
+    fn run_fake_server(socket: UdpSocket, relayed_socket: UdpSocket, stop_flag: Arc<AtomicBool>) {
+        let credentials = credentials();
+        let relayed_addr = unwrap_result!(relayed_socket.local_addr());
+        let mut client = None;
+        let mut channels = HashMap::new();
+        let mut buf = [0; 2048];
+        while !stop_flag.load(Ordering::SeqCst) {
+            if let (Ok((n, peer_addr)), Some(client)) = (relayed_socket.recv_from(&mut buf),
+                                                         client) {
+                let data = match channels.iter().find(|&(_, p)| *p == peer_addr) {
+                    Some((&channel, _)) => encode_channel_data(channel, &buf[..n]),
+                    None => {
+                        let tid = stun::new_transaction_id();
+                        let attrs = [peer_attr(&SocketAddr(peer_addr), &tid),
+                                     (ATTR_DATA, buf[..n].to_vec())];
+                        encode_message(METHOD_DATA | CLASS_INDICATION, &tid, &attrs[..], None)
+                    },
+                };
+                let _ = socket.send_to(&data[..], client);
+            }
+
+            let (n, addr) = match socket.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            client = Some(addr);
+            if let Some((channel, data)) = decode_channel_data(&buf[..n]) {
+                let _ = relayed_socket.send_to(data, channels[&channel]);
+                continue;
+            }
+            let req = unwrap_result!(Message::decode(&buf[..n]));
+            let tid = req.transaction_id;
+            let method = req.msg_type & !CLASS_MASK;
+            if req.msg_type == METHOD_SEND | CLASS_INDICATION {
+                let peer_addr = unwrap_result!(req.xor_address(ATTR_XOR_PEER_ADDRESS));
+                let _ = relayed_socket.send_to(unwrap_option!(req.attr(ATTR_DATA), ""),
+                                               *peer_addr);
+                continue;
+            }
+
+            let resp = if check_message_integrity(&buf[..n], &credentials.key[..]) {
+                let mut attrs = Vec::new();
+                if method == METHOD_ALLOCATE {
+                    attrs.push((ATTR_XOR_RELAYED_ADDRESS,
+                                stun::encode_xor_address(&relayed_addr, &tid)));
+                    attrs.push(lifetime_attr(600));
+                }
+                if method == METHOD_CHANNEL_BIND {
+                    let channel = unwrap_option!(req.attr(ATTR_CHANNEL_NUMBER), "");
+                    let peer_addr = unwrap_result!(req.xor_address(ATTR_XOR_PEER_ADDRESS));
+                    let _ = channels.insert(BigEndian::read_u16(channel), *peer_addr);
+                }
+                encode_message(method | CLASS_SUCCESS_RESPONSE,
+                               &tid,
+                               &attrs[..],
+                               Some(&credentials))
+            } else {
+                let attrs = [(stun::ATTR_ERROR_CODE, vec![0, 0, 4, 1]),
+                             (stun::ATTR_REALM, credentials.realm.clone()),
+                             (stun::ATTR_NONCE, credentials.nonce.clone())];
+                encode_message(method | CLASS_ERROR_RESPONSE, &tid, &attrs[..], None)
+            };
+            let _ = socket.send_to(&resp[..], addr);
+        }
+    }
+
+    #[test]
+    fn allocation_relays_to_peers() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let relayed_socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(socket.set_read_timeout(Some(Duration::from_millis(10))));
+        unwrap_result!(relayed_socket.set_read_timeout(Some(Duration::from_millis(10))));
+        let server = TurnServer {
+            addr: SocketAddr(unwrap_result!(socket.local_addr())),
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let relayed_addr = SocketAddr(unwrap_result!(relayed_socket.local_addr()));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let jh = thread!("allocation_relays_to_peers server", move || {
+            run_fake_server(socket, relayed_socket, cloned_stop_flag);
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let allocation = unwrap_result!(TurnAllocation::allocate(&server, deadline));
+        assert_eq!(allocation.relayed_addr(), relayed_addr);
+        unwrap_result!(allocation.set_read_timeout(Some(Duration::from_secs(3))));
+
+        let peer = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(peer.set_read_timeout(Some(Duration::from_secs(3))));
+        let peer_addr = SocketAddr(unwrap_result!(peer.local_addr()));
+        unwrap_result!(allocation.create_permission(&peer_addr, deadline));
+
+        // Exchange data with Send and Data indications and then over a channel.
+        let mut buf = [0; 64];
+        for &bind in &[false, true] {
+            if bind {
+                unwrap_result!(allocation.bind_channel(&peer_addr, deadline));
+            }
+            assert_eq!(unwrap_result!(allocation.send_to(b"ping", &peer_addr)), 4);
+            let (n, addr) = unwrap_result!(peer.recv_from(&mut buf));
+            assert_eq!((&buf[..n], SocketAddr(addr)), (&b"ping"[..], relayed_addr));
+
+            let _ = unwrap_result!(peer.send_to(b"pong", *relayed_addr));
+            let (n, addr) = unwrap_result!(allocation.recv_from(&mut buf));
+            assert_eq!((&buf[..n], addr), (&b"pong"[..], peer_addr));
+        }
+
+        drop(allocation);
+        stop_flag.store(true, Ordering::SeqCst);
+        unwrap_result!(jh.join());
+    }
+
+    #[test]
+    fn dropping_wakes_up_pending_requests() {
+        let socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        let relayed_socket = unwrap_result!(UdpSocket::bind("127.0.0.1:0"));
+        unwrap_result!(socket.set_read_timeout(Some(Duration::from_millis(10))));
+        unwrap_result!(relayed_socket.set_read_timeout(Some(Duration::from_millis(10))));
+        let server = TurnServer {
+            addr: SocketAddr(unwrap_result!(socket.local_addr())),
+            username: "alice".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let cloned_stop_flag = stop_flag.clone();
+        let jh = thread!("dropping_wakes_up_pending_requests server", move || {
+            run_fake_server(socket, relayed_socket, cloned_stop_flag);
+        });
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let allocation = unwrap_result!(TurnAllocation::allocate(&server, deadline));
+        stop_flag.store(true, Ordering::SeqCst);
+        unwrap_result!(jh.join());
+
+        // The server has gone, so this would wait until its deadline if nothing woke it up.
+        let inner = allocation.inner.clone();
+        let peer_addr = SocketAddr(unwrap_result!("127.0.0.1:1".parse()));
+        let jh = thread!("dropping_wakes_up_pending_requests request", move || {
+            inner.create_permission(&peer_addr, Instant::now() + Duration::from_secs(30)).is_err()
+        });
+        // Wait for the request to back off to a longer retransmission timeout.
+        thread::sleep(Duration::from_millis(INITIAL_RTO_MS * 3 + 100));
+        let start = Instant::now();
+        drop(allocation);
+        assert!(unwrap_result!(jh.join()));
+        assert!(start.elapsed() < Duration::from_millis(INITIAL_RTO_MS * 2));
+    }
+}
@@ -1,5 +1,8 @@
 use std::fmt;
 
+/// How often long-running loops wake up to check whether they've been cancelled.
+pub const CANCEL_POLL_INTERVAL_MS: u64 = 100;
+
 pub struct DisplaySlice<'a, T: 'a>(pub &'static str, pub &'a [T]);
 
 impl<'a, T> fmt::Display for DisplaySlice<'a, T>
@@ -15,4 +18,3 @@ impl<'a, T> fmt::Display for DisplaySlice<'a, T>
         Ok(())
     }
 }
-